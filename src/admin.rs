@@ -0,0 +1,460 @@
+//! An HTTP/JSON admin endpoint, behind the `admin` feature, to inspect and
+//! flush the caches of a running service.
+//!
+//! Caches are mounted under a name, with a [`KeyCodec`] parsing their keys
+//! from path segments:
+//!
+//! | Request                            | Response                            |
+//! |------------------------------------|-------------------------------------|
+//! | `GET /caches`                      | the names of the mounted caches     |
+//! | `GET /caches/{name}/stats`         | hits, misses, entries, load latency |
+//! | `GET /caches/{name}/hot-keys?n=10` | the hottest keys and their hits     |
+//! | `DELETE /caches/{name}/keys/{key}` | invalidates one key                 |
+//! | `DELETE /caches/{name}/keys`       | invalidates every key               |
+//!
+//! Invalidations go through [`Cache::invalidate`] and
+//! [`Cache::invalidate_all`], so they reach the peers of caches that joined
+//! an [`InvalidationBus`](crate::InvalidationBus). If the bus fails to
+//! publish, the entries are still dropped locally and the endpoint answers
+//! `502 Bad Gateway` with the error.
+//!
+//! Hot keys are listed as [`Cache::key_label`] renders them, so a
+//! configured [`key_redactor`](crate::CacheBuilder::key_redactor) keeps
+//! raw keys out of the responses.
+//!
+//! The server is a single thread serving one connection at a time, which
+//! is plenty for operators and keeps it from competing with the service.
+//! It has no authentication: bind it to a loopback or otherwise private
+//! address.
+
+use std::fmt::{self, Write as _};
+use std::hash::{BuildHasher, Hash};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::bus::Invalidation;
+use crate::cache::Cache;
+use crate::clock::Clock;
+use crate::codec::KeyCodec;
+use crate::tiered::BackendError;
+
+/// Longest request head read before giving up on a client.
+const MAX_HEAD: usize = 8 * 1024;
+/// How long a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Hot keys listed when the request does not say.
+const DEFAULT_HOT_KEYS: usize = 10;
+
+/// What the endpoint needs of a mounted cache, whatever its types.
+trait Mounted: Send + Sync {
+    /// The stats of the cache as a JSON object, or `None` if it was
+    /// dropped.
+    fn stats(&self) -> Option<String>;
+    fn hot_keys(&self, n: usize) -> Option<String>;
+    /// What the bus answered, or `None` if the key did not decode or the
+    /// cache was dropped.
+    fn invalidate(&self, key: &[u8]) -> Option<Result<(), BackendError>>;
+    fn invalidate_all(&self) -> Option<Result<(), BackendError>>;
+}
+
+struct MountedCache<K, D, S, C, E> {
+    cache: Weak<Cache<K, D, S, C>>,
+    codec: E,
+}
+
+impl<K, D, S, C, E> Mounted for MountedCache<K, D, S, C, E>
+where
+    K: Hash + Eq + Clone + fmt::Debug + Send + Sync,
+    D: Clone + Default + Send + Sync,
+    S: BuildHasher + Send + Sync,
+    C: Clock,
+    E: KeyCodec<K>,
+{
+    fn stats(&self) -> Option<String> {
+        let cache = self.cache.upgrade()?;
+        let stats = cache.stats();
+        let mut json = format!(
+            r#"{{"hits":{},"misses":{},"len":{},"hit_rate":{}"#,
+            stats.hits,
+            stats.misses,
+            stats.len,
+            stats
+                .hit_rate()
+                .map_or_else(|| "null".to_owned(), |rate| rate.to_string())
+        );
+        match cache.load_latency() {
+            Some(latency) => {
+                let _ = write!(
+                    json,
+                    r#","load_latency_us":{{"count":{},"p50":{},"p95":{},"p99":{},"max":{}}}}}"#,
+                    latency.count,
+                    latency.p50.as_micros(),
+                    latency.p95.as_micros(),
+                    latency.p99.as_micros(),
+                    latency.max.as_micros()
+                );
+            }
+            None => json.push_str(r#","load_latency_us":null}"#),
+        }
+        Some(json)
+    }
+
+    fn hot_keys(&self, n: usize) -> Option<String> {
+        let cache = self.cache.upgrade()?;
+        let keys: Vec<String> = cache
+            .hot_keys(n)
+            .into_iter()
+            .map(|(key, hits)| {
+                let key = cache.key_label(&key);
+                format!(r#"{{"key":{},"hits":{}}}"#, json_string(&key), hits)
+            })
+            .collect();
+        Some(format!("[{}]", keys.join(",")))
+    }
+
+    fn invalidate(&self, key: &[u8]) -> Option<Result<(), BackendError>> {
+        let cache = self.cache.upgrade()?;
+        Some(cache.invalidate(&self.codec.decode(key)?))
+    }
+
+    fn invalidate_all(&self) -> Option<Result<(), BackendError>> {
+        let cache = self.cache.upgrade()?;
+        cache.invalidate_all_locally();
+        Some(cache.broadcast(&Invalidation::All))
+    }
+}
+
+/// The caches an [`AdminServer`] will expose, before it is started.
+#[derive(Default)]
+pub struct Admin {
+    caches: Vec<(String, Box<dyn Mounted>)>,
+}
+
+impl Admin {
+    /// Creates an endpoint without caches.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Exposes `cache` under `name`, with its keys parsed by `codec`, e.g.
+    /// [`StrKeys`](crate::StrKeys), and shown by [`Cache::key_label`].
+    ///
+    /// Only a weak reference is kept: once the cache is dropped, its
+    /// routes answer `404 Not Found`.
+    pub fn mount<K, D, S, C, E>(
+        mut self,
+        name: impl Into<String>,
+        cache: &Arc<Cache<K, D, S, C>>,
+        codec: E,
+    ) -> Self
+    where
+        K: Hash + Eq + Clone + fmt::Debug + Send + Sync + 'static,
+        D: Clone + Default + Send + Sync + 'static,
+        S: BuildHasher + Send + Sync + 'static,
+        C: Clock + 'static,
+        E: KeyCodec<K> + 'static,
+    {
+        let mounted = MountedCache {
+            cache: Arc::downgrade(cache),
+            codec,
+        };
+        self.caches.push((name.into(), Box::new(mounted)));
+        self
+    }
+
+    /// Starts serving on `addr`, e.g. `127.0.0.1:9090`, or port 0 for any
+    /// free port.
+    pub fn serve(self, addr: impl ToSocketAddrs) -> io::Result<AdminServer> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stopping = stopping.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopping.load(Ordering::Acquire) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        let _ = self.handle(stream);
+                    }
+                }
+            })
+        };
+        Ok(AdminServer {
+            local_addr,
+            stopping,
+            thread: Some(thread),
+        })
+    }
+
+    fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream).take(MAX_HEAD as u64);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // The headers are not needed, but a client may wait for them to be
+        // read before reading the response.
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => self.route(method, target),
+            _ => error(400, "malformed request"),
+        };
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Bad Gateway",
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )?;
+        stream.flush()
+    }
+
+    fn route(&self, method: &str, target: &str) -> (u16, String) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        if segments == ["caches"] {
+            if method != "GET" {
+                return error(405, "use GET");
+            }
+            let names: Vec<String> = self
+                .caches
+                .iter()
+                .map(|(name, _)| json_string(name))
+                .collect();
+            return (200, format!(r#"{{"caches":[{}]}}"#, names.join(",")));
+        }
+        let (name, rest) = match segments.as_slice() {
+            ["caches", name, rest @ ..] if !rest.is_empty() => (percent_decode(name), rest),
+            _ => return error(404, "no such route"),
+        };
+        let Some((_, cache)) = self
+            .caches
+            .iter()
+            .find(|(mounted, _)| mounted.as_bytes() == name.as_slice())
+        else {
+            return error(404, "no such cache");
+        };
+        let found = |json: Option<String>| match json {
+            Some(json) => (200, json),
+            None => error(404, "the cache was dropped"),
+        };
+        match (method, rest) {
+            ("GET", ["stats"]) => found(cache.stats()),
+            ("GET", ["hot-keys"]) => {
+                let n = query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("n="))
+                    .map_or(Ok(DEFAULT_HOT_KEYS), str::parse);
+                match n {
+                    Ok(n) => found(cache.hot_keys(n)),
+                    Err(_) => error(400, "n must be a number"),
+                }
+            }
+            ("DELETE", ["keys"]) => match cache.invalidate_all() {
+                Some(Ok(())) => (200, r#"{"invalidated":"all"}"#.to_owned()),
+                Some(Err(error)) => bus_error(&error),
+                None => error(404, "the cache was dropped"),
+            },
+            ("DELETE", ["keys", key]) => match cache.invalidate(&percent_decode(key)) {
+                Some(Ok(())) => (200, r#"{"invalidated":1}"#.to_owned()),
+                Some(Err(error)) => bus_error(&error),
+                None => error(400, "not a valid key of this cache"),
+            },
+            (_, ["stats" | "hot-keys"]) => error(405, "use GET"),
+            (_, ["keys", ..]) => error(405, "use DELETE"),
+            _ => error(404, "no such route"),
+        }
+    }
+}
+
+/// A running admin endpoint, stopped when dropped.
+pub struct AdminServer {
+    local_addr: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AdminServer {
+    /// The address the endpoint listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::Release);
+        // Wakes the server up from `accept` to see the flag.
+        if TcpStream::connect(self.local_addr).is_ok() {
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+fn error(status: u16, message: &str) -> (u16, String) {
+    (status, format!(r#"{{"error":{}}}"#, json_string(message)))
+}
+
+/// The answer to an invalidation done locally but not published.
+fn bus_error(failure: &BackendError) -> (u16, String) {
+    error(
+        502,
+        &format!("invalidated locally, but the bus failed: {failure}"),
+    )
+}
+
+/// `text` as a JSON string literal.
+fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c < ' ' => {
+                let _ = write!(json, "\\u{:04x}", u32::from(c));
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Decodes the `%XX` escapes of a path segment, keeping malformed ones as
+/// they are.
+fn percent_decode(segment: &str) -> Vec<u8> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InvalidationBus, InvalidationListener, StrKeys};
+
+    fn request(server: &AdminServer, method: &str, target: &str) -> String {
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        write!(stream, "{method} {target} HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn the_endpoint_reports_and_invalidates() {
+        let cache = Arc::new(
+            Cache::builder(10)
+                .hot_key_tracking(1, Duration::from_secs(60))
+                .key_redactor(|key: &String| format!("<{} bytes>", key.len()))
+                .miss_handler(|key: &String, data: &mut u32, _: &mut u8| {
+                    *data = key.len() as u32;
+                    true
+                })
+                .build(),
+        );
+        cache.retrieve_or_compute(&"a b".to_owned());
+        cache.retrieve_or_compute(&"a b".to_owned());
+        cache.insert("c".to_owned(), 1);
+        let server = Admin::new()
+            .mount("names", &cache, StrKeys)
+            .serve("127.0.0.1:0")
+            .unwrap();
+
+        let caches = request(&server, "GET", "/caches");
+        assert!(caches.starts_with("HTTP/1.1 200 OK"));
+        assert!(caches.ends_with(r#"{"caches":["names"]}"#));
+        let stats = request(&server, "GET", "/caches/names/stats");
+        assert!(stats.contains(r#""hits":1,"misses":1,"len":2"#), "{stats}");
+        let hot = request(&server, "GET", "/caches/names/hot-keys?n=1");
+        assert!(hot.ends_with(r#"[{"key":"<3 bytes>","hits":1}]"#), "{hot}");
+
+        let removed = request(&server, "DELETE", "/caches/names/keys/a%20b");
+        assert!(removed.ends_with(r#"{"invalidated":1}"#));
+        assert_eq!(cache.get(&"a b".to_owned()), None);
+        request(&server, "DELETE", "/caches/names/keys");
+        assert_eq!(cache.get(&"c".to_owned()), None);
+
+        assert!(request(&server, "GET", "/caches/other/stats").starts_with("HTTP/1.1 404"));
+        assert!(request(&server, "POST", "/caches/names/stats").starts_with("HTTP/1.1 405"));
+        drop(cache);
+        assert!(request(&server, "GET", "/caches/names/stats").starts_with("HTTP/1.1 404"));
+    }
+
+    /// A bus that cannot publish.
+    struct Down;
+
+    impl InvalidationBus<String> for Down {
+        fn publish(&self, _: u64, _: &Invalidation<String>) -> Result<(), BackendError> {
+            Err("connection refused".into())
+        }
+
+        fn subscribe(&self, _: Box<InvalidationListener<String>>) -> Result<(), BackendError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bus_failures_are_reported() {
+        let cache = Arc::new(
+            Cache::builder(10)
+                .miss_handler(|_: &String, _: &mut u32, _: &mut u8| false)
+                .build(),
+        );
+        cache.join_bus(Arc::new(Down)).unwrap();
+        cache.insert("a".to_owned(), 1);
+        let server = Admin::new()
+            .mount("names", &cache, StrKeys)
+            .serve("127.0.0.1:0")
+            .unwrap();
+
+        let removed = request(&server, "DELETE", "/caches/names/keys/a");
+        assert!(removed.starts_with("HTTP/1.1 502 Bad Gateway"), "{removed}");
+        assert!(removed.contains("connection refused"), "{removed}");
+        assert_eq!(cache.get(&"a".to_owned()), None);
+        let cleared = request(&server, "DELETE", "/caches/names/keys");
+        assert!(cleared.starts_with("HTTP/1.1 502 Bad Gateway"), "{cleared}");
+    }
+
+    #[test]
+    fn json_strings_and_paths_are_escaped() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), r#""a\"b\\c\n\u0001""#);
+        assert_eq!(percent_decode("a%2Fb%zz%4"), b"a/b%zz%4");
+    }
+}
@@ -0,0 +1,78 @@
+//! Storing values behind an `Arc`, for large values read often.
+//!
+//! Every hit returns a clone of the cached value. With values stored as
+//! `Arc<D>`, that clone is a reference count increment, however large the
+//! value, and `D` itself need not be `Clone`.
+
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lru::DefaultHasher;
+
+use crate::builder::CacheBuilder;
+use crate::cache::Cache;
+use crate::clock::SystemClock;
+use crate::config::Capacity;
+
+/// A cache sharing its values; see the [module documentation](self).
+pub type ArcCache<K, D, S = DefaultHasher, C = SystemClock> = Cache<K, Arc<D>, S, C>;
+
+impl<K, D> Cache<K, Arc<D>>
+where
+    K: Hash + Eq + Clone,
+    D: Default,
+{
+    /// Like [`new`](Self::new), with a miss handler filling in a plain `D`
+    /// that the cache puts behind an `Arc`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn new_arc<F>(
+        size: impl Into<Capacity>,
+        positive_ttl: Duration,
+        negative_ttl: Duration,
+        miss_handler: F,
+    ) -> Self
+    where
+        F: Fn(&K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        CacheBuilder::new(size)
+            .positive_ttl(positive_ttl)
+            .negative_ttl(negative_ttl)
+            .arc_miss_handler(miss_handler)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A large value that cannot be cloned.
+    #[derive(Default)]
+    struct Payload(Vec<u8>);
+
+    #[test]
+    fn hits_share_the_value() {
+        let cache: ArcCache<u32, Payload> = Cache::new_arc(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |key: &u32, data: &mut Payload, _: &mut u8| {
+                data.0 = vec![*key as u8; 1 << 20];
+                *key != 0
+            },
+        );
+        let (first, success, _) = cache.retrieve_or_compute(&1);
+        assert!(success);
+        let second = cache.get(&1).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(second.0.len(), 1 << 20);
+        assert!(!cache.retrieve_or_compute(&0).1);
+
+        cache.insert(2, Arc::new(Payload(vec![2])));
+        assert_eq!(cache.get(&2).unwrap().0, [2]);
+    }
+}
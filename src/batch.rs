@@ -0,0 +1,203 @@
+//! Bulk retrieval through a loader that shares one context per batch.
+
+use std::hash::{BuildHasher, Hash};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+use crate::cache::{Cache, Lookup};
+use crate::clock::Clock;
+use crate::pool::Loaded;
+use crate::time::Instant;
+use crate::timeout::Timeout;
+
+/// Computes the values of a batch of missing keys, returning one
+/// `(data, success, adhoc_code)` per key, in order.
+///
+/// Built by [`CacheBuilder::batch_loader`](crate::CacheBuilder::batch_loader),
+/// which guarantees the output has the same length as the input.
+pub(crate) type BatchMissHandler<K, D> = dyn Fn(&[K]) -> Vec<(D, bool, u8)> + Send + Sync;
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Like [`retrieve_or_compute`](Self::retrieve_or_compute) for several
+    /// keys at once, returning results in the order of `keys`.
+    ///
+    /// All misses are computed in a single call to the batch loader, if one
+    /// is configured, so its context is set up once for the whole batch.
+    /// Each key is otherwise looked up, counted and rate limited as by
+    /// [`retrieve_or_compute`](Self::retrieve_or_compute). Keys that
+    /// another thread is already computing are waited for after this batch
+    /// has been loaded.
+    pub fn retrieve_or_compute_many(&self, keys: &[K]) -> Vec<(D, bool, u8)> {
+        let mut results: Vec<Option<(D, bool, u8)>> = vec![None; keys.len()];
+        let mut claimed = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            match self.lookup_or_claim(key, Some(Instant::now())) {
+                Err(Timeout) => {}
+                Ok(Lookup::Found(found)) => results[i] = Some(found),
+                Ok(Lookup::Panicked | Lookup::Cancelled | Lookup::Unavailable) => {
+                    results[i] = Some((D::default(), false, 0));
+                }
+                Ok(Lookup::Claimed(started)) if self.admits_load(key) => {
+                    claimed.push((i, started));
+                }
+                Ok(Lookup::Claimed(started)) => {
+                    results[i] = Some(
+                        self.shed_load(key, started)
+                            .unwrap_or_else(|| (D::default(), false, 0)),
+                    );
+                }
+            }
+        }
+
+        if !claimed.is_empty() {
+            let claimed_keys: Vec<K> = claimed.iter().map(|&(i, _)| keys[i].clone()).collect();
+            let started: Vec<u64> = claimed.iter().map(|&(_, started)| started).collect();
+            let computed = self
+                .load_many(&claimed_keys, &started)
+                .unwrap_or_else(|payload| panic::resume_unwind(payload));
+            for ((i, _), outcome) in claimed.into_iter().zip(computed) {
+                results[i] = Some(outcome);
+            }
+        }
+
+        results
+            .into_iter()
+            .zip(keys)
+            .map(|(result, key)| result.unwrap_or_else(|| self.retrieve_or_compute(key)))
+            .collect()
+    }
+
+    /// Computes the claimed `keys` in one call to the batch loader, or with
+    /// [`load`](Self::load) per key without one, and stores the outcomes.
+    /// `started` holds the write sequence numbers of their placeholders.
+    ///
+    /// A panic of the loader is returned once every key is marked as
+    /// failed.
+    pub(crate) fn load_many(
+        &self,
+        keys: &[K],
+        started: &[u64],
+    ) -> thread::Result<Vec<(D, bool, u8)>> {
+        let _permit = self.load_permit(keys, true);
+        let span = self.load_span(&keys[0], keys.len());
+        let load_start = Instant::now();
+        let computed = panic::catch_unwind(AssertUnwindSafe(|| match &self.batch_miss_handler {
+            Some(batch_miss_handler) => batch_miss_handler(keys)
+                .into_iter()
+                .map(Loaded::from)
+                .collect::<Vec<_>>(),
+            None => keys
+                .iter()
+                .zip(started)
+                .map(|(key, &started)| {
+                    self.load(key, started)
+                        .unwrap_or_else(|payload| panic::resume_unwind(payload))
+                })
+                .collect(),
+        }));
+        let load_time = load_start.elapsed();
+        self.load_latency.record(load_time);
+        let all_succeeded = computed
+            .as_ref()
+            .is_ok_and(|computed| computed.iter().all(|loaded| loaded.success));
+        span.finish(all_succeeded, load_time);
+        let computed = computed.inspect_err(|_| {
+            for (key, &started) in keys.iter().zip(started) {
+                self.complete_panicked(key, started);
+            }
+        })?;
+        Ok(keys
+            .iter()
+            .zip(started)
+            .zip(computed)
+            .map(|((key, &started), loaded)| self.complete(key, started, loaded, load_time))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn context_is_opened_once_per_batch() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let opened_by_loader = opened.clone();
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| unreachable!())
+            .batch_loader(
+                move || {
+                    opened_by_loader.fetch_add(1, Ordering::SeqCst);
+                    Vec::new()
+                },
+                |seen: &mut Vec<u32>, key: &u32, data: &mut u32, _: &mut u8| {
+                    seen.push(*key);
+                    *data = *key + seen.len() as u32 * 100;
+                    true
+                },
+            )
+            .build();
+
+        cache.insert(2, 2);
+        let results = cache.retrieve_or_compute_many(&[1, 2, 3]);
+        assert_eq!(results, vec![(101, true, 0), (2, true, 0), (203, true, 0)]);
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+
+        cache.retrieve_or_compute_many(&[1, 2, 3]);
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn duplicate_keys_are_computed_once() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler_calls = calls.clone();
+        let cache = Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            move |key: &u32, data: &mut u32, _: &mut u8| {
+                handler_calls.fetch_add(1, Ordering::SeqCst);
+                *data = *key;
+                true
+            },
+        );
+
+        let results = cache.retrieve_or_compute_many(&[5, 5]);
+        assert_eq!(results, vec![(5, true, 0), (5, true, 0)]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn batches_are_counted_and_rate_limited() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler_calls = calls.clone();
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .miss_handler(move |key: &u32, data: &mut u32, _: &mut u8| {
+                handler_calls.fetch_add(1, Ordering::SeqCst);
+                *data = *key;
+                true
+            })
+            .max_loads_per_second(2)
+            .build();
+
+        let results = cache.retrieve_or_compute_many(&[1, 2, 3]);
+        assert_eq!(results, vec![(1, true, 0), (2, true, 0), (0, false, 0)]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(cache.get_entry(&3).is_none());
+
+        cache.retrieve_or_compute_many(&[1, 2]);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 3));
+    }
+}
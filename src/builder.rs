@@ -0,0 +1,1123 @@
+//! Builder for [`Cache`].
+
+use std::hash::{BuildHasher, Hash};
+
+use lru::DefaultHasher;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::batch::BatchMissHandler;
+use crate::cache::{Cache, MissHandler, StoreError, StoreHandler, SWEEP_MIN_LEN};
+use crate::cache_policy::{CacheDecision, CachePolicy, LoadMeta};
+use crate::cancel::CancellationToken;
+use crate::checksum::CorruptionListener;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{Bound, Capacity, ConfigError};
+use crate::conflict::{ConflictListener, ConflictPolicy};
+use crate::debounce::Debouncer;
+use crate::events::Subscribers;
+use crate::eviction::{EvictDecision, EvictionVeto};
+use crate::expiry::ExpiryIndex;
+use crate::fallback::{self, Fallbacks};
+use crate::generation::{Generations, Revalidator};
+use crate::hot::HotKeys;
+use crate::latency::LatencyHistogram;
+use crate::limit::{LoadGroup, LoadLimiter};
+use crate::load::{self, current_args, SharePrevious};
+use crate::memory::{MemSize, SizeHint, Weigher};
+use crate::migrate::ValueMigration;
+use crate::oversize::OversizePolicy;
+use crate::pool::WorkerPool;
+use crate::rate::RateLimiter;
+use crate::readiness::ReadinessState;
+use crate::redact::KeyRedactor;
+use crate::retry::RetryPolicy;
+use crate::sketch::NegativeSketch;
+use crate::stats::StatsWindow;
+use crate::tier::SpillTier;
+use crate::time::Instant;
+use crate::ttl::AtomicDuration;
+use crate::wait::WaitStrategy;
+use crate::write_behind::{WriteBehind, WriteBehindConfig};
+
+const DEFAULT_POSITIVE_TTL: Duration = Duration::from_secs(60);
+const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+/// Starts the worker pool; captured by
+/// [`CacheBuilder::worker_pool`] where its `Send + 'static` bounds hold.
+type SpawnPool<K, D> = fn(Arc<MissHandler<K, D>>, usize) -> WorkerPool<K, D>;
+
+/// Chains fallback loaders after the miss handler; captured by
+/// [`CacheBuilder::fallback_loader`] where its `'static` bounds hold.
+type ChainFallbacks<K, D> = fn(Box<MissHandler<K, D>>, Fallbacks<K, D>) -> Box<MissHandler<K, D>>;
+
+/// Configures and creates a [`Cache`].
+///
+/// A miss handler must be set before calling [`build`](Self::build).
+pub struct CacheBuilder<K, D, S = DefaultHasher, C = SystemClock> {
+    size: Capacity,
+    hasher: S,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    max_staleness: Option<Duration>,
+    max_wait: Option<Duration>,
+    wait_strategy: WaitStrategy,
+    miss_handler: Option<Box<MissHandler<K, D>>>,
+    batch_miss_handler: Option<Box<BatchMissHandler<K, D>>>,
+    fallbacks: Option<(Fallbacks<K, D>, ChainFallbacks<K, D>)>,
+    share_previous: Option<SharePrevious<D>>,
+    store_handler: Option<Box<StoreHandler<K, D>>>,
+    write_behind: Option<WriteBehindConfig<K, D>>,
+    l2: Option<Box<dyn SpillTier<K, D>>>,
+    conflict_policy: ConflictPolicy,
+    conflict_listener: Option<Box<ConflictListener<K, D>>>,
+    eviction_veto: Option<Box<EvictionVeto<K, D>>>,
+    max_vetoes: usize,
+    value_version: u32,
+    migration: Option<Box<ValueMigration<K, D>>>,
+    key_redactor: Option<Arc<KeyRedactor<K>>>,
+    negative_sketch: Option<NegativeSketch>,
+    clock: C,
+    readiness_target: Option<(f64, u64)>,
+    size_hint: Option<Box<SizeHint<K, D>>>,
+    max_bytes: Option<usize>,
+    weigher: Option<Box<Weigher<K, D>>>,
+    max_weight: Option<u64>,
+    cache_policy: Option<Box<CachePolicy<K, D>>>,
+    generation_period: Option<Duration>,
+    revalidator: Option<Box<Revalidator<K, D>>>,
+    corruption_listener: Option<Box<CorruptionListener<K>>>,
+    early_expiration: Option<f64>,
+    stale_if_error: Option<Duration>,
+    near_expiry: Option<Duration>,
+    soft_ttl: Option<Duration>,
+    negative_caching: bool,
+    max_failed: Option<usize>,
+    retry_policy: Option<RetryPolicy>,
+    ttl_jitter: Option<f64>,
+    max_loads: Option<usize>,
+    max_loads_per_group: Option<(usize, Box<LoadGroup<K>>)>,
+    worker_pool: Option<(usize, SpawnPool<K, D>)>,
+    max_entry_bytes: Option<usize>,
+    max_entry_weight: Option<u64>,
+    oversize_policy: OversizePolicy,
+    batch_window: Option<Duration>,
+    max_load_rate: Option<u32>,
+    max_load_rate_per_key: Option<u32>,
+    hot_key_tracking: Option<(u32, Duration)>,
+    stats_window: Option<(Duration, usize)>,
+}
+
+impl<K, D> CacheBuilder<K, D>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+{
+    /// Starts a builder for a cache holding at most `size` entries, or
+    /// [`Capacity::Unbounded`].
+    pub fn new(size: impl Into<Capacity>) -> Self {
+        CacheBuilder {
+            size: size.into(),
+            hasher: DefaultHasher::default(),
+            positive_ttl: DEFAULT_POSITIVE_TTL,
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+            max_staleness: None,
+            max_wait: None,
+            wait_strategy: WaitStrategy::default(),
+            miss_handler: None,
+            batch_miss_handler: None,
+            fallbacks: None,
+            share_previous: None,
+            store_handler: None,
+            write_behind: None,
+            l2: None,
+            conflict_policy: ConflictPolicy::default(),
+            conflict_listener: None,
+            eviction_veto: None,
+            max_vetoes: 0,
+            value_version: 0,
+            migration: None,
+            key_redactor: None,
+            negative_sketch: None,
+            clock: SystemClock,
+            readiness_target: None,
+            size_hint: None,
+            max_bytes: None,
+            weigher: None,
+            max_weight: None,
+            cache_policy: None,
+            generation_period: None,
+            revalidator: None,
+            corruption_listener: None,
+            early_expiration: None,
+            stale_if_error: None,
+            near_expiry: None,
+            soft_ttl: None,
+            negative_caching: true,
+            max_failed: None,
+            retry_policy: None,
+            ttl_jitter: None,
+            max_loads: None,
+            max_loads_per_group: None,
+            worker_pool: None,
+            max_entry_bytes: None,
+            max_entry_weight: None,
+            oversize_policy: OversizePolicy::default(),
+            batch_window: None,
+            max_load_rate: None,
+            max_load_rate_per_key: None,
+            hot_key_tracking: None,
+            stats_window: None,
+        }
+    }
+}
+
+impl<K, D, S, C> CacheBuilder<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Hashes keys with `hasher` instead of the default hasher, e.g. a
+    /// faster non-DoS-resistant one for small integer keys. See the `ahash`
+    /// and `fxhash` features.
+    pub fn with_hasher<S2: BuildHasher>(self, hasher: S2) -> CacheBuilder<K, D, S2, C> {
+        self.retype(|_| hasher, |clock| clock)
+    }
+
+    /// Reads time from `clock` instead of the system clock, e.g. a
+    /// [`ManualClock`](crate::ManualClock) to test expiration without
+    /// sleeping.
+    ///
+    /// The clock is part of the cache's type. Pass an `Arc<dyn Clock>`
+    /// to pick it at runtime, at the cost of a dynamic call per read.
+    pub fn clock<C2: Clock>(self, clock: C2) -> CacheBuilder<K, D, S, C2> {
+        self.retype(|hasher| hasher, |_| clock)
+    }
+
+    /// Moves every setting into a builder with another hasher and clock.
+    fn retype<S2, C2>(
+        self,
+        hasher: impl FnOnce(S) -> S2,
+        clock: impl FnOnce(C) -> C2,
+    ) -> CacheBuilder<K, D, S2, C2> {
+        CacheBuilder {
+            size: self.size,
+            hasher: hasher(self.hasher),
+            positive_ttl: self.positive_ttl,
+            negative_ttl: self.negative_ttl,
+            max_staleness: self.max_staleness,
+            max_wait: self.max_wait,
+            wait_strategy: self.wait_strategy,
+            miss_handler: self.miss_handler,
+            batch_miss_handler: self.batch_miss_handler,
+            fallbacks: self.fallbacks,
+            share_previous: self.share_previous,
+            store_handler: self.store_handler,
+            write_behind: self.write_behind,
+            l2: self.l2,
+            conflict_policy: self.conflict_policy,
+            conflict_listener: self.conflict_listener,
+            eviction_veto: self.eviction_veto,
+            max_vetoes: self.max_vetoes,
+            value_version: self.value_version,
+            migration: self.migration,
+            key_redactor: self.key_redactor,
+            negative_sketch: self.negative_sketch,
+            clock: clock(self.clock),
+            readiness_target: self.readiness_target,
+            size_hint: self.size_hint,
+            max_bytes: self.max_bytes,
+            weigher: self.weigher,
+            max_weight: self.max_weight,
+            cache_policy: self.cache_policy,
+            generation_period: self.generation_period,
+            revalidator: self.revalidator,
+            corruption_listener: self.corruption_listener,
+            early_expiration: self.early_expiration,
+            stale_if_error: self.stale_if_error,
+            near_expiry: self.near_expiry,
+            soft_ttl: self.soft_ttl,
+            negative_caching: self.negative_caching,
+            max_failed: self.max_failed,
+            retry_policy: self.retry_policy,
+            ttl_jitter: self.ttl_jitter,
+            max_loads: self.max_loads,
+            max_loads_per_group: self.max_loads_per_group,
+            worker_pool: self.worker_pool,
+            max_entry_bytes: self.max_entry_bytes,
+            max_entry_weight: self.max_entry_weight,
+            oversize_policy: self.oversize_policy,
+            batch_window: self.batch_window,
+            max_load_rate: self.max_load_rate,
+            max_load_rate_per_key: self.max_load_rate_per_key,
+            hot_key_tracking: self.hot_key_tracking,
+            stats_window: self.stats_window,
+        }
+    }
+
+    /// How long successfully computed entries stay valid.
+    pub fn positive_ttl(mut self, ttl: Duration) -> Self {
+        self.positive_ttl = ttl;
+        self
+    }
+
+    /// The TTL set with [`positive_ttl`](Self::positive_ttl), for wrappers
+    /// that need it before the cache is built.
+    pub(crate) fn configured_positive_ttl(&self) -> Duration {
+        self.positive_ttl
+    }
+
+    /// How long failed computations are remembered before being retried.
+    pub fn negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// How long [`Cache::retrieve_or_compute`] waits for another thread's
+    /// computation of the same key before giving up on it and running the
+    /// miss handler itself.
+    ///
+    /// Guards against a computing thread that died or hangs. By default
+    /// callers wait indefinitely.
+    pub fn max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = Some(max_wait);
+        self
+    }
+
+    /// How callers wait for a key that another thread is computing.
+    /// Defaults to [`WaitStrategy::default`].
+    pub fn wait_strategy(mut self, wait_strategy: WaitStrategy) -> Self {
+        self.wait_strategy = wait_strategy;
+        self
+    }
+
+    /// Hard bound on how long past its expiration an entry may be served.
+    ///
+    /// Features that serve expired values, such as [`Cache::hold`], stop
+    /// doing so once an entry is this stale: lookups then treat it as a
+    /// miss, so [`Cache::get`] returns `None` and
+    /// [`Cache::retrieve_or_compute`] blocks on a fresh computation. Use it
+    /// for data that must never be served arbitrarily old.
+    pub fn max_staleness(mut self, max_staleness: Duration) -> Self {
+        self.max_staleness = Some(max_staleness);
+        self
+    }
+
+    /// Serves the last good value for up to `grace` past its expiration
+    /// when recomputing it fails, instead of the failure.
+    ///
+    /// The stale value is reported as a success, flagged by
+    /// [`EntryInfo::stale`](crate::EntryInfo::stale), and recomputed again
+    /// after the negative TTL. Invalidated values are never served stale,
+    /// and the [`max_staleness`](Self::max_staleness) still applies.
+    pub fn stale_if_error(mut self, grace: Duration) -> Self {
+        self.stale_if_error = Some(grace);
+        self
+    }
+
+    /// Flags values as stale once `ttl` has passed since they were stored,
+    /// while the positive TTL stays the hard TTL after which they are
+    /// misses ("stale-while-revalidate").
+    ///
+    /// Stale values are still served, reported as
+    /// [`Freshness::Stale`](crate::Freshness::Stale), and the first
+    /// [`Cache::retrieve_or_compute`] hitting one recomputes it while
+    /// everyone else keeps getting it;
+    /// [`Cache::retrieve_or_compute_in_background`] hands the refresh to the
+    /// worker pool instead. A failed refresh leaves the stale value in
+    /// place until its hard TTL. A soft TTL not below the TTL of an entry
+    /// has no effect on it.
+    pub fn soft_ttl(mut self, ttl: Duration) -> Self {
+        self.soft_ttl = Some(ttl);
+        self
+    }
+
+    /// Lets hits on entries close to expiration recompute them early, with
+    /// a probability growing as expiration nears and with the time the
+    /// value took to compute (XFetch). One caller recomputes while the
+    /// others keep getting the cached value, so hot keys do not stampede
+    /// the miss handler when they expire.
+    ///
+    /// `beta` scales how early: 1.0 is the usual choice, larger values
+    /// refresh earlier. Only [`Cache::retrieve_or_compute`] and its
+    /// variants refresh; [`Cache::get`] never computes.
+    pub fn early_expiration(mut self, beta: f64) -> Self {
+        self.early_expiration = Some(beta);
+        self
+    }
+
+    /// Has [`Cache::get_with_status`] report values expiring within
+    /// `window` as [`Freshness::NearExpiry`](crate::Freshness::NearExpiry).
+    /// Without it, live values are always reported fresh or stale.
+    pub fn near_expiry(mut self, window: Duration) -> Self {
+        self.near_expiry = Some(window);
+        self
+    }
+
+    /// Rotates generations every `period`, so that every entry is
+    /// revalidated or recomputed at least that often; see
+    /// [`Cache::rotate_generation`].
+    pub fn generations(mut self, period: Duration) -> Self {
+        self.generation_period = Some(period);
+        self
+    }
+
+    /// Keeps entries of the previous generation that `revalidator` accepts
+    /// instead of recomputing them.
+    ///
+    /// The revalidator runs while the write lock is held and must not call
+    /// back into the cache.
+    pub fn revalidator<F>(mut self, revalidator: F) -> Self
+    where
+        F: Fn(&K, &D) -> bool + Send + Sync + 'static,
+    {
+        self.revalidator = Some(Box::new(revalidator));
+        self
+    }
+
+    /// Sets the function used to compute missing values.
+    pub fn miss_handler<F>(mut self, miss_handler: F) -> Self
+    where
+        F: Fn(&K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        self.miss_handler = Some(Box::new(miss_handler));
+        self
+    }
+
+    /// Like [`miss_handler`](Self::miss_handler), with a token telling
+    /// whether the computation was cancelled with [`Cache::cancel`], so
+    /// that long backend work can stop early.
+    pub fn cancellable_miss_handler<F>(mut self, miss_handler: F) -> Self
+    where
+        F: Fn(&K, &mut D, &mut u8, &CancellationToken) -> bool + Send + Sync + 'static,
+    {
+        self.miss_handler = Some(Box::new(
+            move |key: &K, data: &mut D, adhoc_code: &mut u8| {
+                let token = current_args().token.unwrap_or_default();
+                miss_handler(key, data, adhoc_code, &token)
+            },
+        ));
+        self
+    }
+
+    /// Like [`miss_handler`](Self::miss_handler), with the deadline of the
+    /// caller that needs the value, if it set one with
+    /// [`Cache::retrieve_or_compute_with_deadline`].
+    pub fn deadline_aware_miss_handler<F>(mut self, miss_handler: F) -> Self
+    where
+        F: Fn(&K, &mut D, &mut u8, Option<Instant>) -> bool + Send + Sync + 'static,
+    {
+        self.miss_handler = Some(Box::new(
+            move |key: &K, data: &mut D, adhoc_code: &mut u8| {
+                miss_handler(key, data, adhoc_code, current_args().deadline)
+            },
+        ));
+        self
+    }
+
+    /// Like [`miss_handler`](Self::miss_handler), with the context the
+    /// caller passed to [`Cache::retrieve_or_compute_ctx`], or `None` if it
+    /// passed none or one of another type.
+    pub fn contextual_miss_handler<X, F>(mut self, miss_handler: F) -> Self
+    where
+        X: Send + Sync + 'static,
+        F: Fn(&K, &mut D, &mut u8, Option<&X>) -> bool + Send + Sync + 'static,
+    {
+        self.miss_handler = Some(Box::new(
+            move |key: &K, data: &mut D, adhoc_code: &mut u8| {
+                let context = current_args().context;
+                let context = context
+                    .as_deref()
+                    .and_then(|context| context.downcast_ref());
+                miss_handler(key, data, adhoc_code, context)
+            },
+        ));
+        self
+    }
+
+    /// Like [`miss_handler`](Self::miss_handler), with the expired value
+    /// being recomputed, so that the handler can revalidate it instead of
+    /// fetching it again, e.g. with a conditional request carrying its
+    /// ETag.
+    ///
+    /// The value is `None` for keys computed for the first time, and for
+    /// entries that failed or were invalidated since. Batches loaded with a
+    /// batch loader are not given their expired values.
+    pub fn revalidating_miss_handler<F>(mut self, miss_handler: F) -> Self
+    where
+        D: Send + Sync + 'static,
+        F: Fn(&K, &mut D, &mut u8, Option<&D>) -> bool + Send + Sync + 'static,
+    {
+        self.miss_handler = Some(Box::new(
+            move |key: &K, data: &mut D, adhoc_code: &mut u8| {
+                let previous = current_args().previous;
+                let previous = previous
+                    .as_deref()
+                    .and_then(|previous| previous.downcast_ref());
+                miss_handler(key, data, adhoc_code, previous)
+            },
+        ));
+        self.share_previous = Some(|data: &D| Arc::new(data.clone()));
+        self
+    }
+
+    /// Like [`miss_handler`](Self::miss_handler), for loaders that know how
+    /// long each outcome stays valid, e.g. a DNS resolver returning
+    /// `(answer, ttl, rcode)`.
+    ///
+    /// A code of 0, DNS `NOERROR`, is a success; any other code is a
+    /// failure. Either way the outcome is cached for the returned TTL
+    /// instead of the positive or negative one, so that an `NXDOMAIN` can
+    /// be cached for its SOA minimum while a `SERVFAIL` is retried after a
+    /// few seconds. Failures cached this way are not backed off by the
+    /// retry policy.
+    pub fn ttl_miss_handler<F>(mut self, miss_handler: F) -> Self
+    where
+        F: Fn(&K) -> (D, Duration, u8) + Send + Sync + 'static,
+    {
+        self.miss_handler = Some(Box::new(
+            move |key: &K, data: &mut D, adhoc_code: &mut u8| {
+                let (loaded, ttl, code) = miss_handler(key);
+                *data = loaded;
+                *adhoc_code = code;
+                load::set_ttl(Some(ttl));
+                code == 0
+            },
+        ));
+        self
+    }
+
+    /// Adds a loader to try when the miss handler and the fallback loaders
+    /// added before it fail, e.g. a remote service after a local disk, then
+    /// a default value. Its values are cached for `ttl` instead of the
+    /// positive TTL.
+    ///
+    /// Each loader starts from the default value. If every loader fails,
+    /// the last one's outcome is cached as a failure. Batches loaded with a
+    /// batch loader do not fall back.
+    pub fn fallback_loader<F>(mut self, ttl: Duration, loader: F) -> Self
+    where
+        K: 'static,
+        D: 'static,
+        F: Fn(&K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        let (fallbacks, _) = self
+            .fallbacks
+            .get_or_insert_with(|| (Vec::new(), fallback::chain));
+        fallbacks.push((ttl, Box::new(loader)));
+        self
+    }
+
+    /// Sets the loader used by
+    /// [`retrieve_or_compute_many`](Cache::retrieve_or_compute_many).
+    ///
+    /// `open` is called once per batch that has at least one miss, and the
+    /// context it returns is handed to `load` for every missing key of that
+    /// batch. This is the place for setup that is too expensive to repeat
+    /// per key, such as opening a transaction or checking out a connection.
+    /// Batches without a batch loader fall back to the miss handler.
+    pub fn batch_loader<T, O, L>(mut self, open: O, load: L) -> Self
+    where
+        O: Fn() -> T + Send + Sync + 'static,
+        L: Fn(&mut T, &K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        self.batch_miss_handler = Some(Box::new(move |keys: &[K]| {
+            let mut context = open();
+            keys.iter()
+                .map(|key| {
+                    let mut data = D::default();
+                    let mut adhoc_code = 0;
+                    let success = load(&mut context, key, &mut data, &mut adhoc_code);
+                    (data, success, adhoc_code)
+                })
+                .collect()
+        }));
+        self
+    }
+
+    /// Collects the misses of [`Cache::retrieve_or_compute`] and its
+    /// variants that occur within `window` of each other into one call to
+    /// the [`batch_loader`](Self::batch_loader), DataLoader style, e.g. to
+    /// turn the N+1 lookups of GraphQL resolvers into one query.
+    ///
+    /// Every miss then waits out the window, so a short one such as 2ms
+    /// suits most workloads. Batches run on the thread of their first
+    /// miss, bypassing the [`worker_pool`](Self::worker_pool).
+    pub fn batch_window(mut self, window: Duration) -> Self {
+        self.batch_window = Some(window);
+        self
+    }
+
+    /// Writes inserted and successfully loaded values through to a backing
+    /// store, turning the cache into a read/write-through cache.
+    ///
+    /// The handler runs under the cache lock, atomically with the cache
+    /// update. Inserts it rejects are not cached, and loads it rejects are
+    /// cached as failures.
+    pub fn store_handler<F, E>(mut self, store_handler: F) -> Self
+    where
+        F: Fn(&K, &D) -> Result<(), E> + Send + Sync + 'static,
+        E: Into<StoreError>,
+    {
+        self.store_handler = Some(Box::new(move |key: &K, data: &D| {
+            store_handler(key, data).map_err(Into::into)
+        }));
+        self
+    }
+
+    /// Queues store handler writes instead of performing them inline.
+    ///
+    /// Updates become visible in the cache immediately and reach the store
+    /// in batches from a background thread: every `flush_interval` if one
+    /// is given and as soon as `max_queue` writes are pending. They are
+    /// also written on [`Cache::flush`] and when the cache is dropped.
+    /// Batches are written one at a time, in the order they were queued,
+    /// and never by the thread that queued them.
+    ///
+    /// Requires a [`store_handler`](Self::store_handler).
+    pub fn write_behind(mut self, max_queue: usize, flush_interval: Option<Duration>) -> Self
+    where
+        K: Send + 'static,
+        D: Send + 'static,
+    {
+        self.write_behind = Some(WriteBehindConfig {
+            max_queue,
+            flush_interval,
+            spawn_flusher: WriteBehind::spawn_flusher,
+        });
+        self
+    }
+
+    /// Decides which value is kept when an insert lands while the same key
+    /// is being computed. Defaults to [`ConflictPolicy::LastWriteWins`].
+    pub fn conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    /// Called with the losing value whenever the conflict policy discards
+    /// one. Runs outside the cache lock.
+    pub fn conflict_listener<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(&K, &D) + Send + Sync + 'static,
+    {
+        self.conflict_listener = Some(Box::new(listener));
+        self
+    }
+
+    /// Consults `veto` before evicting an entry to make room, so that
+    /// entries the application still relies on are not yanked.
+    ///
+    /// Each eviction skips at most `max_vetoes` candidates; the next one is
+    /// then evicted regardless, so a veto cannot stall inserts. The hook
+    /// runs under the write lock and must not call back into the cache.
+    pub fn eviction_veto<F>(mut self, max_vetoes: usize, veto: F) -> Self
+    where
+        F: Fn(&K, &D) -> EvictDecision + Send + Sync + 'static,
+    {
+        self.eviction_veto = Some(Box::new(veto));
+        self.max_vetoes = max_vetoes;
+        self
+    }
+
+    /// Sets the current layout version of values, and the hook upgrading
+    /// values stored under an older one.
+    ///
+    /// `migrate` receives the key, the old value and its version, and
+    /// returns the upgraded value, or `None` to drop the entry so that it
+    /// is recomputed. It runs under the write lock on first access through
+    /// [`Cache::get`] or [`Cache::retrieve_or_compute`], and must not call
+    /// back into the cache.
+    pub fn value_version<F>(mut self, version: u32, migrate: F) -> Self
+    where
+        F: Fn(&K, D, u32) -> Option<D> + Send + Sync + 'static,
+    {
+        self.value_version = version;
+        self.migration = Some(Box::new(migrate));
+        self
+    }
+
+    /// Counts one hit in every `sample_every` against its key, so that
+    /// [`Cache::hot_keys`] can report the most hit keys over the last one
+    /// to two `window`s. Zero is treated as one, which counts every hit.
+    pub fn hot_key_tracking(mut self, sample_every: u32, window: Duration) -> Self {
+        self.hot_key_tracking = Some((sample_every, window));
+        self
+    }
+
+    /// Keeps hit, miss and eviction counts for each of the last
+    /// `intervals` periods of `interval`, e.g. 60 one-minute intervals, as
+    /// reported by [`Cache::stats_window`].
+    pub fn stats_window(mut self, interval: Duration, intervals: usize) -> Self {
+        self.stats_window = Some((interval, intervals));
+        self
+    }
+
+    /// Holds [`Cache::readiness`] back until the hit rate reaches
+    /// `hit_rate` over at least `min_lookups` lookups.
+    pub fn readiness_target(mut self, hit_rate: f64, min_lookups: u64) -> Self {
+        self.readiness_target = Some((hit_rate, min_lookups));
+        self
+    }
+
+    /// Records failed computations in a fixed-size probabilistic sketch
+    /// instead of caching them as entries, so that high-cardinality miss
+    /// traffic cannot flush the LRU.
+    ///
+    /// The sketch is sized for `capacity` failures per negative TTL at the
+    /// given `false_positive_rate`: that fraction of keys that never failed
+    /// may be reported as failed without running the miss handler. Failures
+    /// are forgotten after one to two negative TTLs and are reported with
+    /// the default value and an adhoc code of 0.
+    pub fn negative_sketch(mut self, capacity: usize, false_positive_rate: f64) -> Self {
+        self.negative_sketch = Some(NegativeSketch::new(capacity, false_positive_rate));
+        self
+    }
+
+    /// Whether failures are cached for the negative TTL, `true` by default.
+    ///
+    /// When disabled, every lookup of a failing key runs the miss handler
+    /// again. Failures of a panicking miss handler are still cached, so
+    /// that callers waiting on the computation learn about the panic.
+    pub fn negative_caching(mut self, enabled: bool) -> Self {
+        self.negative_caching = enabled;
+        self
+    }
+
+    /// Holds at most `max_failed` failed entries, dropping the least
+    /// recently used ones beyond that, so that failing traffic cannot take
+    /// over the LRU. See [`Cache::failed_len`].
+    ///
+    /// Finding the failure to drop scans the LRU from its cold end; for
+    /// caches where most entries are failures, prefer a
+    /// [`negative_sketch`](Self::negative_sketch).
+    pub fn max_failed(mut self, max_failed: usize) -> Self {
+        self.max_failed = Some(max_failed);
+        self
+    }
+
+    /// Runs at most `max` miss handlers at a time; further callers that
+    /// need to compute a key wait for one to finish. A batch loaded by
+    /// [`retrieve_or_compute_many`](Cache::retrieve_or_compute_many) counts
+    /// as one. See also
+    /// [`retrieve_or_compute_unless_overloaded`](Cache::retrieve_or_compute_unless_overloaded).
+    pub fn max_concurrent_loads(mut self, max: usize) -> Self {
+        self.max_loads = Some(max);
+        self
+    }
+
+    /// Runs at most `max` miss handlers at a time for the keys of each
+    /// group named by `group`, e.g. per table or tenant, on top of any
+    /// overall limit.
+    pub fn max_concurrent_loads_per<F>(mut self, max: usize, group: F) -> Self
+    where
+        F: Fn(&K) -> String + Send + Sync + 'static,
+    {
+        self.max_loads_per_group = Some((max, Box::new(group)));
+        self
+    }
+
+    /// Runs the miss handler at most `rate` times per second overall,
+    /// allowing a burst of `rate` at once. Misses over the limit are
+    /// served the value kept by [`stale_if_error`](Self::stale_if_error)
+    /// if there is one, and fail otherwise without caching the failure.
+    /// See also
+    /// [`retrieve_or_compute_unless_rate_limited`](Cache::retrieve_or_compute_unless_rate_limited).
+    pub fn max_loads_per_second(mut self, rate: u32) -> Self {
+        self.max_load_rate = Some(rate);
+        self
+    }
+
+    /// Like [`max_loads_per_second`](Self::max_loads_per_second), for each
+    /// key on its own, on top of any overall limit.
+    pub fn max_loads_per_key_per_second(mut self, rate: u32) -> Self {
+        self.max_load_rate_per_key = Some(rate);
+        self
+    }
+
+    /// Runs the miss handler on `threads` worker threads instead of on the
+    /// threads of the callers, which block until their value is ready. See
+    /// also [`retrieve_or_compute_in_background`](Cache::retrieve_or_compute_in_background).
+    ///
+    /// Zero threads are treated as one. Batches loaded by
+    /// [`retrieve_or_compute_many`](Cache::retrieve_or_compute_many) still
+    /// run on the caller's thread.
+    pub fn worker_pool(mut self, threads: usize) -> Self
+    where
+        K: Send + 'static,
+        D: Send + 'static,
+    {
+        self.worker_pool = Some((threads, WorkerPool::spawn));
+        self
+    }
+
+    /// Varies the positive and negative TTL of each entry at random by up
+    /// to `fraction` either way, e.g. 0.1 for ±10%, so that entries stored
+    /// in a burst do not all expire, and get recomputed, at once.
+    ///
+    /// Durations set by the cache policy are used as is.
+    pub fn ttl_jitter(mut self, fraction: f64) -> Self {
+        self.ttl_jitter = Some(fraction);
+        self
+    }
+
+    /// Backs off from keys that keep failing: each consecutive failure is
+    /// cached for twice as long as the previous one, up to the policy's
+    /// maximum, instead of for the negative TTL every time.
+    ///
+    /// Failures recorded in a [`negative_sketch`](Self::negative_sketch),
+    /// or cached for a duration set by the cache policy, do not back off.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// Estimates the heap memory of each entry with `size_hint`, for
+    /// [`Cache::memory_usage`] and [`max_bytes`](Self::max_bytes). Without
+    /// a hint only the inline size of entries is counted.
+    ///
+    /// The hint runs while the write lock is held and must not call back
+    /// into the cache.
+    pub fn size_hint<F>(mut self, size_hint: F) -> Self
+    where
+        F: Fn(&K, &D) -> usize + Send + Sync + 'static,
+    {
+        self.size_hint = Some(Box::new(size_hint));
+        self
+    }
+
+    /// Estimates the heap memory of each entry through [`MemSize`]. See
+    /// [`size_hint`](Self::size_hint).
+    pub fn mem_size(self) -> Self
+    where
+        K: MemSize,
+        D: MemSize,
+    {
+        self.size_hint(|key: &K, data: &D| key.heap_size() + data.heap_size())
+    }
+
+    /// Evicts least recently used entries whenever the approximate memory
+    /// held by the cache exceeds `max_bytes`, in addition to the entry
+    /// limit. The most recently stored entry is kept even if it alone is
+    /// larger. Same as `bound(Bound::Bytes(max_bytes))`; see
+    /// [`Cache::memory_usage`].
+    pub fn max_bytes(self, max_bytes: usize) -> Self {
+        self.bound(Bound::Bytes(max_bytes))
+    }
+
+    /// Weighs each entry with `weigher`, for [`Bound::Weight`] and
+    /// [`Cache::total_weight`], e.g. by the number of rows of a cached
+    /// result set.
+    ///
+    /// The weigher runs while the write lock is held and must not call
+    /// back into the cache.
+    pub fn weigher<F>(mut self, weigher: F) -> Self
+    where
+        F: Fn(&K, &D) -> u64 + Send + Sync + 'static,
+    {
+        self.weigher = Some(Box::new(weigher));
+        self
+    }
+
+    /// Adds a limit on what the cache may hold, in entries, weight or
+    /// bytes. Bounds combine with the capacity given to
+    /// [`new`](CacheBuilder::new) and with each other: whichever is reached
+    /// first evicts, and a unit bounded twice keeps the smaller limit.
+    pub fn bound(mut self, bound: Bound) -> Self {
+        match bound {
+            Bound::Entries(max) => {
+                self.size = match self.size {
+                    Capacity::Bounded(size) => Capacity::Bounded(size.min(max)),
+                    Capacity::Unbounded => Capacity::Bounded(max),
+                }
+            }
+            Bound::Weight(max) => {
+                self.max_weight = Some(self.max_weight.map_or(max, |weight| weight.min(max)))
+            }
+            Bound::Bytes(max) => {
+                self.max_bytes = Some(self.max_bytes.map_or(max, |bytes| bytes.min(max)))
+            }
+        }
+        self
+    }
+
+    /// Keeps values whose approximate memory, as counted by
+    /// [`Cache::memory_usage`], exceeds `max` out of the byte bound; see
+    /// [`oversize_policy`](Self::oversize_policy).
+    ///
+    /// Without such a limit, storing one huge value under a byte bound
+    /// evicts most of the working set to make room for it.
+    pub fn max_entry_bytes(mut self, max: usize) -> Self {
+        self.max_entry_bytes = Some(max);
+        self
+    }
+
+    /// Like [`max_entry_bytes`](Self::max_entry_bytes) for the weight given
+    /// by the [`weigher`](Self::weigher).
+    pub fn max_entry_weight(mut self, max: u64) -> Self {
+        self.max_entry_weight = Some(max);
+        self
+    }
+
+    /// Sets what happens to values over the per-entry limits. Defaults to
+    /// [`OversizePolicy::Reject`].
+    pub fn oversize_policy(mut self, policy: OversizePolicy) -> Self {
+        self.oversize_policy = policy;
+        self
+    }
+
+    /// Lets `policy` decide, for every completed load, whether the result
+    /// is cached normally, for another duration, or not at all, based on
+    /// the key, the value and [`LoadMeta`].
+    ///
+    /// The policy runs while the write lock is held and must not call back
+    /// into the cache.
+    pub fn cache_policy<F>(mut self, policy: F) -> Self
+    where
+        F: Fn(&K, &D, &LoadMeta) -> CacheDecision + Send + Sync + 'static,
+    {
+        self.cache_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Renders keys through `redactor` wherever the cache shows them, so
+    /// that keys carrying personal data never reach logs. See
+    /// [`hashed_key`](crate::hashed_key) for a redactor that keeps keys
+    /// correlatable, and [`Cache::subscribe_redacted`] for redacted
+    /// events.
+    pub fn key_redactor<F>(mut self, redactor: F) -> Self
+    where
+        F: Fn(&K) -> String + Send + Sync + 'static,
+    {
+        self.key_redactor = Some(Arc::new(redactor));
+        self
+    }
+
+    /// Spills entries evicted from memory to an append-only file at `path`
+    /// and promotes them back on access.
+    ///
+    /// The file is truncated when opened: it only extends the capacity of a
+    /// running cache and is not meant to survive restarts. It stays locked
+    /// while the cache is alive, and a file locked by another cache fails
+    /// with [`ErrorKind::WouldBlock`](std::io::ErrorKind::WouldBlock).
+    #[cfg(feature = "disk")]
+    pub fn disk_tier(mut self, path: impl AsRef<std::path::Path>) -> std::io::Result<Self>
+    where
+        K: serde::Serialize + serde::de::DeserializeOwned + 'static,
+        D: serde::Serialize + serde::de::DeserializeOwned + 'static,
+    {
+        self.l2 = Some(Box::new(crate::disk::DiskTier::open(path)?));
+        Ok(self)
+    }
+
+    /// Like [`disk_tier`](Self::disk_tier), with keys stored under the
+    /// names produced by `codec` rather than their bincode encoding.
+    #[cfg(feature = "disk")]
+    pub fn disk_tier_with_keys<E>(
+        mut self,
+        path: impl AsRef<std::path::Path>,
+        codec: E,
+    ) -> std::io::Result<Self>
+    where
+        K: 'static,
+        D: serde::Serialize + serde::de::DeserializeOwned + 'static,
+        E: crate::KeyCodec<K> + 'static,
+    {
+        self.l2 = Some(Box::new(crate::disk::DiskTier::open_with_codec(
+            path, codec,
+        )?));
+        Ok(self)
+    }
+
+    /// Calls `listener` with the key of every entry dropped from the second
+    /// tier because its checksum did not match; see
+    /// [`Cache::corruptions`].
+    ///
+    /// The listener runs while the write lock is held and must not call
+    /// back into the cache.
+    pub fn on_corruption<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(&K) + Send + Sync + 'static,
+    {
+        self.corruption_listener = Some(Box::new(listener));
+        self
+    }
+
+    /// Creates the cache.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the configuration is invalid; see
+    /// [`try_build`](Self::try_build).
+    pub fn build(self) -> Cache<K, D, S, C> {
+        self.try_build()
+            .unwrap_or_else(|error| panic!("invalid cache configuration: {error}"))
+    }
+
+    /// Creates the cache, or reports why the configuration is invalid: no
+    /// miss handler, a zero capacity or bound, a weight bound without a
+    /// weigher, or write-behind without a store handler.
+    pub fn try_build(self) -> Result<Cache<K, D, S, C>, ConfigError> {
+        let lru_cache = self.size.lru(self.hasher)?;
+        if self.max_bytes == Some(0)
+            || self.max_weight == Some(0)
+            || self.max_entry_bytes == Some(0)
+            || self.max_entry_weight == Some(0)
+        {
+            return Err(ConfigError::ZeroCapacity);
+        }
+        if (self.max_weight.is_some() || self.max_entry_weight.is_some()) && self.weigher.is_none()
+        {
+            return Err(ConfigError::WeightWithoutWeigher);
+        }
+        if self.generation_period == Some(Duration::ZERO) {
+            return Err(ConfigError::ZeroGenerationPeriod);
+        }
+        if self
+            .stats_window
+            .is_some_and(|(interval, intervals)| interval.is_zero() || intervals == 0)
+        {
+            return Err(ConfigError::ZeroStatsWindow);
+        }
+        if self.retry_policy.is_some_and(|policy| !policy.is_valid()) {
+            return Err(ConfigError::InvalidRetryPolicy);
+        }
+        if self
+            .ttl_jitter
+            .is_some_and(|jitter| !(0.0..=1.0).contains(&jitter))
+        {
+            return Err(ConfigError::InvalidTtlJitter);
+        }
+        let group_max = self.max_loads_per_group.as_ref().map(|&(max, _)| max);
+        if self.max_loads == Some(0)
+            || group_max == Some(0)
+            || self.max_load_rate == Some(0)
+            || self.max_load_rate_per_key == Some(0)
+        {
+            return Err(ConfigError::ZeroLoadLimit);
+        }
+        let load_limiter = (self.max_loads.is_some() || group_max.is_some())
+            .then(|| LoadLimiter::new(self.max_loads, self.max_loads_per_group));
+        let mut miss_handler = self.miss_handler.ok_or(ConfigError::MissingMissHandler)?;
+        if let Some((fallbacks, chain)) = self.fallbacks {
+            miss_handler = chain(miss_handler, fallbacks);
+        }
+        let miss_handler: Arc<MissHandler<K, D>> = Arc::from(miss_handler);
+        let worker_pool = self
+            .worker_pool
+            .map(|(threads, spawn)| spawn(miss_handler.clone(), threads));
+        let (store_handler, write_behind) = match self.write_behind {
+            None => (self.store_handler, None),
+            Some(config) => {
+                let store_handler = self
+                    .store_handler
+                    .ok_or(ConfigError::WriteBehindWithoutStoreHandler)?;
+                let write_behind = Arc::new(WriteBehind::new(store_handler, config.max_queue));
+                (config.spawn_flusher)(&write_behind, config.flush_interval);
+                (None, Some(write_behind))
+            }
+        };
+        let generations = Generations::new(self.generation_period, self.clock.now());
+        let stats_window = self
+            .stats_window
+            .map(|(interval, intervals)| StatsWindow::new(interval, intervals, self.clock.now()));
+        let rate_limiter = (self.max_load_rate.is_some() || self.max_load_rate_per_key.is_some())
+            .then(|| {
+                RateLimiter::new(
+                    self.max_load_rate,
+                    self.max_load_rate_per_key,
+                    self.clock.now(),
+                )
+            });
+        Ok(Cache {
+            lru_cache: RwLock::new(lru_cache),
+            positive_ttl: AtomicDuration::new(self.positive_ttl),
+            negative_ttl: AtomicDuration::new(self.negative_ttl),
+            max_staleness: self.max_staleness,
+            max_wait: self.max_wait,
+            wait_strategy: self.wait_strategy,
+            miss_handler,
+            share_previous: self.share_previous,
+            batch_miss_handler: self.batch_miss_handler,
+            store_handler,
+            write_behind,
+            l2: self.l2,
+            tag_epochs: RwLock::default(),
+            write_seq: AtomicU64::new(0),
+            epoch: AtomicU64::new(0),
+            conflict_policy: self.conflict_policy,
+            conflict_listener: self.conflict_listener,
+            eviction_veto: self.eviction_veto,
+            max_vetoes: self.max_vetoes,
+            value_version: self.value_version,
+            migration: self.migration,
+            key_redactor: self.key_redactor,
+            negative_sketch: self.negative_sketch,
+            sweep_at: AtomicUsize::new(SWEEP_MIN_LEN),
+            expiry_index: Mutex::new(ExpiryIndex::new()),
+            clock: self.clock,
+            readiness: ReadinessState::new(self.readiness_target),
+            size_hint: self.size_hint,
+            max_bytes: self.max_bytes,
+            mem_bytes: AtomicUsize::new(0),
+            cache_policy: self.cache_policy,
+            weigher: self.weigher,
+            max_weight: self.max_weight,
+            total_weight: AtomicU64::new(0),
+            generations,
+            revalidator: self.revalidator,
+            corruption_listener: self.corruption_listener,
+            corruptions: AtomicU64::new(0),
+            subscribers: Subscribers::default(),
+            early_expiration: self.early_expiration,
+            stale_if_error: self.stale_if_error,
+            near_expiry: self.near_expiry,
+            soft_ttl: self.soft_ttl,
+            loader_enabled: AtomicBool::new(true),
+            negative_caching: self.negative_caching,
+            max_failed: self.max_failed,
+            failed: AtomicUsize::new(0),
+            retry_policy: self.retry_policy,
+            ttl_jitter: self.ttl_jitter,
+            load_limiter,
+            worker_pool,
+            max_entry_bytes: self.max_entry_bytes,
+            max_entry_weight: self.max_entry_weight,
+            oversize_policy: self.oversize_policy,
+            debouncer: self.batch_window.map(Debouncer::new),
+            rate_limiter,
+            hot_keys: self
+                .hot_key_tracking
+                .map(|(sample_every, window)| HotKeys::new(sample_every, window)),
+            stats_window,
+            load_latency: LatencyHistogram::new(),
+            bus: RwLock::new(None),
+        })
+    }
+}
+
+impl<K, D, S, C> CacheBuilder<K, Arc<D>, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Like [`miss_handler`](Self::miss_handler) for an
+    /// [`ArcCache`](crate::ArcCache), with a handler filling in a plain
+    /// `D` that is then put behind an `Arc`.
+    pub fn arc_miss_handler<F>(self, miss_handler: F) -> Self
+    where
+        F: Fn(&K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        self.miss_handler(move |key: &K, data: &mut Arc<D>, adhoc_code: &mut u8| {
+            let mut value = D::default();
+            let success = miss_handler(key, &mut value, adhoc_code);
+            *data = Arc::new(value);
+            success
+        })
+    }
+}
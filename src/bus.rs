@@ -0,0 +1,363 @@
+//! Propagating invalidations to the caches of peer instances.
+//!
+//! A cache joined to an [`InvalidationBus`] publishes its
+//! [`invalidate`](Cache::invalidate) and
+//! [`invalidate_all`](Cache::invalidate_all) calls on it, and applies the
+//! ones published by its peers. Every message carries the id of the cache
+//! that sent it, so that a cache skips its own messages when the bus echoes
+//! them back.
+
+use std::hash::{BuildHasher, Hash, Hasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::cache::Cache;
+use crate::clock::Clock;
+use crate::lock::RwLockExt;
+use crate::tiered::BackendError;
+
+/// What a peer asks to invalidate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Invalidation<K> {
+    /// The entry of one key.
+    Key(K),
+    /// Every entry.
+    All,
+}
+
+/// Called by a bus with every message published on it and the id of its
+/// sender. Returns `false` once it no longer wants messages, e.g. because
+/// its cache was dropped.
+pub type InvalidationListener<K> = dyn Fn(u64, &Invalidation<K>) -> bool + Send + Sync;
+
+/// Carries invalidations between the caches of several instances.
+///
+/// Delivery is best effort: a bus may lose messages, e.g. while
+/// reconnecting to a broker, so entries should still have TTLs bounding how
+/// long a lost invalidation leaves them stale.
+pub trait InvalidationBus<K>: Send + Sync {
+    /// Delivers `invalidation`, sent by the cache `origin`, to every
+    /// listener of the bus, including the sender's own.
+    fn publish(&self, origin: u64, invalidation: &Invalidation<K>) -> Result<(), BackendError>;
+
+    /// Has `listener` called with every message published from now on.
+    fn subscribe(&self, listener: Box<InvalidationListener<K>>) -> Result<(), BackendError>;
+}
+
+/// An [`InvalidationBus`] between the caches of one process, e.g. one per
+/// tenant or per worker pool, delivering each message synchronously before
+/// [`publish`](InvalidationBus::publish) returns.
+pub struct BroadcastBus<K> {
+    listeners: RwLock<Vec<Box<InvalidationListener<K>>>>,
+}
+
+impl<K> BroadcastBus<K> {
+    /// Creates a bus without listeners.
+    pub fn new() -> Self {
+        BroadcastBus {
+            listeners: RwLock::new(Vec::new()),
+        }
+    }
+}
+
+impl<K> Default for BroadcastBus<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> InvalidationBus<K> for BroadcastBus<K> {
+    fn publish(&self, origin: u64, invalidation: &Invalidation<K>) -> Result<(), BackendError> {
+        self.listeners
+            .write_or_recover()
+            .retain(|listener| listener(origin, invalidation));
+        Ok(())
+    }
+
+    fn subscribe(&self, listener: Box<InvalidationListener<K>>) -> Result<(), BackendError> {
+        self.listeners.write_or_recover().push(listener);
+        Ok(())
+    }
+}
+
+/// A joined bus and the id this cache sends with its messages.
+pub(crate) struct BusLink<K> {
+    origin: u64,
+    bus: Arc<dyn InvalidationBus<K>>,
+}
+
+/// An id unlikely to be picked by any other cache, in this process or
+/// another.
+fn new_origin() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Joins `bus`, so that this cache applies the invalidations its peers
+    /// publish there and publishes its own.
+    ///
+    /// A cache joins at most one bus: joining another replaces the first
+    /// for publishing, though the first keeps delivering to this cache. The
+    /// listener holds a weak reference and unsubscribes once the cache is
+    /// dropped.
+    pub fn join_bus(self: &Arc<Self>, bus: Arc<dyn InvalidationBus<K>>) -> Result<(), BackendError>
+    where
+        K: Send + Sync + 'static,
+        D: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+        C: 'static,
+    {
+        let origin = new_origin();
+        let cache = Arc::downgrade(self);
+        bus.subscribe(Box::new(move |sender, invalidation| {
+            let Some(cache) = cache.upgrade() else {
+                return false;
+            };
+            if sender != origin {
+                match invalidation {
+                    Invalidation::Key(key) => {
+                        cache.remove(key);
+                    }
+                    Invalidation::All => cache.invalidate_all_locally(),
+                }
+            }
+            true
+        }))?;
+        *self.bus.write_or_recover() = Some(BusLink { origin, bus });
+        Ok(())
+    }
+
+    /// Drops the entry for `key` here and, if the cache joined a bus, in
+    /// the caches of its peers.
+    ///
+    /// Returns an error if the bus failed to publish, in which case the
+    /// entry is still dropped here.
+    pub fn invalidate(&self, key: &K) -> Result<(), BackendError> {
+        self.remove(key);
+        self.broadcast(&Invalidation::Key(key.clone()))
+    }
+
+    /// Publishes `invalidation` on the joined bus, if any.
+    pub(crate) fn broadcast(&self, invalidation: &Invalidation<K>) -> Result<(), BackendError> {
+        match &*self.bus.read_or_recover() {
+            Some(link) => link.bus.publish(link.origin, invalidation),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+pub use redis_bus::RedisBus;
+
+#[cfg(feature = "redis")]
+mod redis_bus {
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{Invalidation, InvalidationBus, InvalidationListener};
+    use crate::codec::KeyCodec;
+    use crate::lock::MutexExt;
+    use crate::tiered::BackendError;
+
+    /// Wait before reconnecting a subscription that lost its connection.
+    const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+    const KEY: u8 = 0;
+    const ALL: u8 = 1;
+
+    /// An [`InvalidationBus`] over a Redis pub/sub channel, behind the
+    /// `redis` feature, with keys encoded by a
+    /// [`KeyCodec`](crate::KeyCodec).
+    ///
+    /// Each subscription reads the channel on a thread of its own and
+    /// reconnects after losing its connection; messages published in the
+    /// meantime are lost.
+    pub struct RedisBus<E> {
+        client: redis::Client,
+        channel: String,
+        codec: Arc<E>,
+        publisher: Mutex<Option<redis::Connection>>,
+    }
+
+    impl<E> RedisBus<E> {
+        /// Creates a bus on `channel` of the server at `url`, e.g.
+        /// `redis://127.0.0.1/`. Connects lazily.
+        pub fn new(url: &str, channel: impl Into<String>, codec: E) -> Result<Self, BackendError> {
+            Ok(RedisBus {
+                client: redis::Client::open(url)?,
+                channel: channel.into(),
+                codec: Arc::new(codec),
+                publisher: Mutex::new(None),
+            })
+        }
+    }
+
+    /// Connects to `client`, retrying until it succeeds.
+    fn reconnect(client: &redis::Client) -> redis::Connection {
+        loop {
+            thread::sleep(RECONNECT_DELAY);
+            if let Ok(connection) = client.get_connection() {
+                return connection;
+            }
+        }
+    }
+
+    /// Encodes a message as the origin, a kind byte and the encoded key.
+    fn encode<K>(codec: &impl KeyCodec<K>, origin: u64, invalidation: &Invalidation<K>) -> Vec<u8> {
+        let mut payload = origin.to_le_bytes().to_vec();
+        match invalidation {
+            Invalidation::Key(key) => {
+                payload.push(KEY);
+                payload.extend(codec.encode(key));
+            }
+            Invalidation::All => payload.push(ALL),
+        }
+        payload
+    }
+
+    fn decode<K>(codec: &impl KeyCodec<K>, payload: &[u8]) -> Option<(u64, Invalidation<K>)> {
+        let origin = u64::from_le_bytes(payload.get(..8)?.try_into().ok()?);
+        let invalidation = match *payload.get(8)? {
+            KEY => Invalidation::Key(codec.decode(&payload[9..])?),
+            ALL => Invalidation::All,
+            _ => return None,
+        };
+        Some((origin, invalidation))
+    }
+
+    impl<K, E> InvalidationBus<K> for RedisBus<E>
+    where
+        E: KeyCodec<K> + 'static,
+        K: 'static,
+    {
+        fn publish(&self, origin: u64, invalidation: &Invalidation<K>) -> Result<(), BackendError> {
+            let payload = encode(&*self.codec, origin, invalidation);
+            let mut publisher = self.publisher.lock_or_recover();
+            let connection = match publisher.as_mut() {
+                Some(connection) => connection,
+                None => publisher.insert(self.client.get_connection()?),
+            };
+            let published = redis::cmd("PUBLISH")
+                .arg(&self.channel)
+                .arg(payload)
+                .query::<i64>(connection);
+            if let Err(error) = published {
+                *publisher = None;
+                return Err(error.into());
+            }
+            Ok(())
+        }
+
+        fn subscribe(&self, listener: Box<InvalidationListener<K>>) -> Result<(), BackendError> {
+            let mut connection = self.client.get_connection()?;
+            let client = self.client.clone();
+            let channel = self.channel.clone();
+            let codec = self.codec.clone();
+            let (reply, subscribed) = mpsc::channel();
+            // The subscription lives as long as its `PubSub`, so it is
+            // made on the thread reading it.
+            thread::spawn(move || {
+                let mut reply = Some(reply);
+                loop {
+                    let mut pubsub = connection.as_pubsub();
+                    let listening = match (pubsub.subscribe(&channel), reply.take()) {
+                        (Ok(()), reply) => {
+                            if let Some(reply) = reply {
+                                let _ = reply.send(Ok(()));
+                            }
+                            true
+                        }
+                        (Err(error), Some(reply)) => {
+                            let _ = reply.send(Err(error));
+                            return;
+                        }
+                        (Err(_), None) => false,
+                    };
+                    while let (true, Ok(message)) = (listening, pubsub.get_message()) {
+                        let payload = message.get_payload_bytes();
+                        if let Some((origin, invalidation)) = decode(&*codec, payload) {
+                            if !listener(origin, &invalidation) {
+                                return;
+                            }
+                        }
+                    }
+                    drop(pubsub);
+                    connection = reconnect(&client);
+                }
+            });
+            match subscribed.recv() {
+                Ok(result) => Ok(result?),
+                Err(error) => Err(error.into()),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::StrKeys;
+
+        #[test]
+        fn messages_round_trip_through_the_payload() {
+            let key = encode::<u32>(&StrKeys, 7, &Invalidation::Key(42));
+            assert_eq!(decode(&StrKeys, &key), Some((7, Invalidation::Key(42))));
+            let all = encode::<u32>(&StrKeys, 7, &Invalidation::All);
+            assert_eq!(decode::<u32>(&StrKeys, &all), Some((7, Invalidation::All)));
+            assert_eq!(decode::<u32>(&StrKeys, &key[..8]), None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn cache() -> Arc<Cache<u32, u32>> {
+        Arc::new(Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                true
+            },
+        ))
+    }
+
+    #[test]
+    fn invalidations_reach_the_peers_on_the_bus() {
+        let bus: Arc<BroadcastBus<u32>> = Arc::new(BroadcastBus::new());
+        let (first, second, apart) = (cache(), cache(), cache());
+        first.join_bus(bus.clone()).unwrap();
+        second.join_bus(bus.clone()).unwrap();
+        for cache in [&first, &second, &apart] {
+            cache.insert(1, 10);
+            cache.insert(2, 20);
+        }
+
+        first.invalidate(&1).unwrap();
+        assert_eq!(first.get(&1), None);
+        assert_eq!(second.get(&1), None);
+        assert_eq!(apart.get(&1), Some(10));
+
+        second.invalidate_all();
+        assert_eq!(first.get(&2), None);
+        assert_eq!(apart.get(&2), Some(20));
+
+        drop(second);
+        first.invalidate(&2).unwrap();
+        assert_eq!(bus.listeners.read().unwrap().len(), 1);
+    }
+}
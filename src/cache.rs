@@ -0,0 +1,1104 @@
+//! The cache itself: an LRU of entries guarded by a single `RwLock`.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::hash::{BuildHasher, Hash};
+use std::num::NonZeroUsize;
+use std::panic;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use lru::{DefaultHasher, LruCache};
+
+use crate::batch::BatchMissHandler;
+use crate::builder::CacheBuilder;
+use crate::bus::BusLink;
+use crate::cache_policy::{CacheDecision, CachePolicy, LoadMeta};
+use crate::cancel::CancellationToken;
+use crate::checksum::{Corrupted, CorruptionListener};
+use crate::clock::{Clock, SystemClock};
+use crate::config::Capacity;
+use crate::conflict::{ConflictListener, ConflictPolicy};
+use crate::debounce::Debouncer;
+use crate::events::{CacheEvent, Subscribers};
+use crate::eviction::EvictionVeto;
+use crate::expiry::ExpiryIndex;
+use crate::freshness::Freshness;
+use crate::generation::{Generations, Revalidator};
+use crate::hot::HotKeys;
+use crate::latency::LatencyHistogram;
+use crate::limit::LoadLimiter;
+use crate::load::SharePrevious;
+use crate::lock::{MutexExt, RwLockExt};
+use crate::memory::{SizeHint, Weigher};
+use crate::migrate::ValueMigration;
+use crate::oversize::OversizePolicy;
+use crate::pool::{Loaded, WorkerPool};
+use crate::rate::RateLimiter;
+use crate::readiness::ReadinessState;
+use crate::redact::KeyRedactor;
+use crate::retry::RetryPolicy;
+use crate::sketch::NegativeSketch;
+use crate::stats::StatsWindow;
+use crate::tier::{SpillTier, SpilledEntry};
+use crate::time::Instant;
+use crate::timeout::Timeout;
+use crate::ttl::AtomicDuration;
+use crate::wait::{Backoff, WaitStrategy};
+use crate::write_behind::WriteBehind;
+
+/// Function invoked to compute the value of a missing key.
+///
+/// The handler writes the value into `data`, may set `adhoc_code` to any
+/// value meaningful to the caller, and returns `true` on success. On `false`
+/// the entry is cached as failed for the negative TTL.
+pub type MissHandler<K, D> = dyn Fn(&K, &mut D, &mut u8) -> bool + Send + Sync;
+
+/// Error reported by a store handler.
+pub type StoreError = Box<dyn Error + Send + Sync>;
+
+/// Function writing inserted and successfully loaded values through to a
+/// backing store.
+pub type StoreHandler<K, D> = dyn Fn(&K, &D) -> Result<(), StoreError> + Send + Sync;
+
+/// Number of entries below which an unbounded cache never sweeps.
+pub(crate) const SWEEP_MIN_LEN: usize = 1024;
+
+/// Tags of an entry, each with the tag epoch at insertion time.
+pub(crate) type EntryTags = Box<[(Arc<str>, u64)]>;
+
+/// Outcome of [`Cache::lookup_or_claim`].
+pub(crate) enum Lookup<D> {
+    /// `(data, success, adhoc_code)` of a live entry.
+    Found((D, bool, u8)),
+    /// The caller must compute the key; holds the write sequence number of
+    /// the calculating entry.
+    Claimed(u64),
+    /// A failure because the miss handler panicked.
+    Panicked,
+    /// The computation waited on was cancelled.
+    Cancelled,
+    /// Nothing to serve, and the loader is disabled.
+    Unavailable,
+}
+
+/// State of a cache entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryStatus {
+    /// The miss handler is computing the value.
+    Calculating,
+    /// The value was computed or inserted successfully.
+    Ready,
+    /// The miss handler failed; the entry is cached for the negative TTL.
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CacheEntry<D> {
+    pub(crate) data: D,
+    pub(crate) status: EntryStatus,
+    pub(crate) adhoc_code: u8,
+    pub(crate) expiration: Instant,
+    /// Number of live [`HoldGuard`](crate::HoldGuard)s pinning the entry.
+    pub(crate) holds: u32,
+    /// Write sequence number of the entry these holds were first taken on,
+    /// carried along with them to replacements, so that guards of an entry
+    /// since removed leave the holds of a new one alone.
+    pub(crate) held_seq: u64,
+    /// Tags attached by [`Cache::insert_tagged`].
+    pub(crate) tags: Option<EntryTags>,
+    /// Whether the entry failed because its miss handler panicked.
+    pub(crate) panicked: bool,
+    /// Write sequence number assigned when the entry was stored.
+    pub(crate) seq: u64,
+    /// Cache epoch at the time the entry was stored; see
+    /// [`Cache::invalidate_all`].
+    pub(crate) epoch: u64,
+    /// Layout version of `data`; see [`Cache::insert_versioned`].
+    pub(crate) version: u32,
+    /// When the entry was stored; set by [`Cache::store`].
+    pub(crate) created: Instant,
+    /// Number of lookups served by the entry.
+    pub(crate) hits: u64,
+    /// Memory charged for the entry; see [`Cache::memory_usage`].
+    pub(crate) bytes: usize,
+    /// Weight charged for the entry; see [`Cache::total_weight`].
+    pub(crate) weight: u64,
+    /// Generation the entry was stored or last revalidated in; see
+    /// [`Cache::rotate_generation`].
+    pub(crate) generation: u64,
+    /// How long the miss handler took to compute the value, zero if it was
+    /// inserted.
+    pub(crate) load_time: Duration,
+    /// Whether the value came from the miss handler rather than an insert;
+    /// see [`Cache::refresh_due`].
+    pub(crate) computed: bool,
+    /// Whether a caller was chosen to recompute the entry before it
+    /// expires; see [`CacheBuilder::early_expiration`].
+    pub(crate) refreshing: bool,
+    /// Until when the value may stand in for failed refreshes: set on
+    /// placeholders carrying an expired value and on values served stale;
+    /// see [`CacheBuilder::stale_if_error`].
+    pub(crate) stale_until: Option<Instant>,
+    /// Whether a placeholder carries the expired value it replaces for the
+    /// miss handler to revalidate; see
+    /// [`CacheBuilder::revalidating_miss_handler`].
+    pub(crate) carries_previous: bool,
+    /// Consecutive failed computations of the key, carried by placeholders
+    /// while it is recomputed; see [`CacheBuilder::retry_policy`].
+    pub(crate) failures: u32,
+    /// When the entry was stored or last served a lookup; see
+    /// [`Cache::refresh_due`].
+    pub(crate) accessed: Instant,
+    /// Cancels the computation a placeholder stands for; see
+    /// [`Cache::cancel`].
+    pub(crate) cancel: Option<CancellationToken>,
+}
+
+impl<D: Default> CacheEntry<D> {
+    pub(crate) fn calculating(now: Instant) -> Self {
+        let mut entry = CacheEntry::new(D::default(), EntryStatus::Calculating, 0, now);
+        entry.cancel = Some(CancellationToken::new());
+        entry
+    }
+}
+
+impl<D> CacheEntry<D> {
+    pub(crate) fn new(data: D, status: EntryStatus, adhoc_code: u8, expiration: Instant) -> Self {
+        CacheEntry {
+            data,
+            status,
+            adhoc_code,
+            expiration,
+            holds: 0,
+            held_seq: 0,
+            tags: None,
+            panicked: false,
+            seq: 0,
+            epoch: 0,
+            version: 0,
+            created: expiration,
+            hits: 0,
+            bytes: 0,
+            weight: 0,
+            generation: 0,
+            load_time: Duration::ZERO,
+            computed: false,
+            refreshing: false,
+            stale_until: None,
+            carries_previous: false,
+            failures: 0,
+            accessed: expiration,
+            cancel: None,
+        }
+    }
+
+    /// Held and calculating entries never expire.
+    pub(crate) fn is_expired(&self, now: Instant) -> bool {
+        self.holds == 0 && self.status != EntryStatus::Calculating && self.expiration <= now
+    }
+}
+
+/// A thread-safe LRU cache that computes missing values on demand.
+///
+/// `S` builds the hasher of the underlying map; see
+/// [`CacheBuilder::with_hasher`]. `C` is the clock expiration is decided
+/// on; see [`CacheBuilder::clock`]. Both are type parameters so that the
+/// defaults compile down to direct calls, with `Arc<dyn Clock>` left for
+/// choosing a clock at runtime.
+pub struct Cache<K, D, S = DefaultHasher, C = SystemClock> {
+    pub(crate) lru_cache: RwLock<LruCache<K, CacheEntry<D>, S>>,
+    pub(crate) positive_ttl: AtomicDuration,
+    pub(crate) negative_ttl: AtomicDuration,
+    pub(crate) max_staleness: Option<Duration>,
+    pub(crate) max_wait: Option<Duration>,
+    pub(crate) wait_strategy: WaitStrategy,
+    pub(crate) miss_handler: Arc<MissHandler<K, D>>,
+    pub(crate) share_previous: Option<SharePrevious<D>>,
+    pub(crate) batch_miss_handler: Option<Box<BatchMissHandler<K, D>>>,
+    pub(crate) store_handler: Option<Box<StoreHandler<K, D>>>,
+    pub(crate) write_behind: Option<Arc<WriteBehind<K, D>>>,
+    pub(crate) l2: Option<Box<dyn SpillTier<K, D>>>,
+    pub(crate) tag_epochs: RwLock<HashMap<Arc<str>, u64>>,
+    pub(crate) write_seq: AtomicU64,
+    pub(crate) epoch: AtomicU64,
+    pub(crate) conflict_policy: ConflictPolicy,
+    pub(crate) conflict_listener: Option<Box<ConflictListener<K, D>>>,
+    pub(crate) eviction_veto: Option<Box<EvictionVeto<K, D>>>,
+    pub(crate) max_vetoes: usize,
+    pub(crate) value_version: u32,
+    pub(crate) migration: Option<Box<ValueMigration<K, D>>>,
+    pub(crate) key_redactor: Option<Arc<KeyRedactor<K>>>,
+    /// Length at which an unbounded cache next sweeps expired entries.
+    pub(crate) sweep_at: AtomicUsize,
+    pub(crate) expiry_index: Mutex<ExpiryIndex<K>>,
+    pub(crate) clock: C,
+    pub(crate) negative_sketch: Option<NegativeSketch>,
+    pub(crate) readiness: ReadinessState,
+    pub(crate) size_hint: Option<Box<SizeHint<K, D>>>,
+    pub(crate) max_bytes: Option<usize>,
+    pub(crate) mem_bytes: AtomicUsize,
+    pub(crate) cache_policy: Option<Box<CachePolicy<K, D>>>,
+    pub(crate) weigher: Option<Box<Weigher<K, D>>>,
+    pub(crate) max_weight: Option<u64>,
+    pub(crate) total_weight: AtomicU64,
+    pub(crate) generations: Generations,
+    pub(crate) revalidator: Option<Box<Revalidator<K, D>>>,
+    pub(crate) corruption_listener: Option<Box<CorruptionListener<K>>>,
+    pub(crate) corruptions: AtomicU64,
+    pub(crate) subscribers: Subscribers<K>,
+    pub(crate) early_expiration: Option<f64>,
+    pub(crate) stale_if_error: Option<Duration>,
+    pub(crate) near_expiry: Option<Duration>,
+    pub(crate) soft_ttl: Option<Duration>,
+    pub(crate) loader_enabled: AtomicBool,
+    pub(crate) negative_caching: bool,
+    pub(crate) max_failed: Option<usize>,
+    pub(crate) failed: AtomicUsize,
+    pub(crate) retry_policy: Option<RetryPolicy>,
+    pub(crate) ttl_jitter: Option<f64>,
+    pub(crate) load_limiter: Option<LoadLimiter<K>>,
+    pub(crate) worker_pool: Option<WorkerPool<K, D>>,
+    pub(crate) max_entry_bytes: Option<usize>,
+    pub(crate) max_entry_weight: Option<u64>,
+    pub(crate) oversize_policy: OversizePolicy,
+    pub(crate) debouncer: Option<Debouncer<K, D>>,
+    pub(crate) rate_limiter: Option<RateLimiter<K>>,
+    pub(crate) hot_keys: Option<HotKeys<K>>,
+    pub(crate) stats_window: Option<StatsWindow>,
+    pub(crate) load_latency: LatencyHistogram,
+    pub(crate) bus: RwLock<Option<BusLink<K>>>,
+}
+
+impl<K, D> Cache<K, D>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+{
+    /// Creates a cache holding at most `size` entries, or
+    /// [`Capacity::Unbounded`](crate::Capacity::Unbounded).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero; use
+    /// [`CacheBuilder::try_build`] to get an error instead.
+    pub fn new<F>(
+        size: impl Into<Capacity>,
+        positive_ttl: Duration,
+        negative_ttl: Duration,
+        miss_handler: F,
+    ) -> Self
+    where
+        F: Fn(&K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        CacheBuilder::new(size)
+            .positive_ttl(positive_ttl)
+            .negative_ttl(negative_ttl)
+            .miss_handler(miss_handler)
+            .build()
+    }
+
+    /// Starts building a cache holding at most `size` entries, or
+    /// [`Capacity::Unbounded`](crate::Capacity::Unbounded).
+    pub fn builder(size: impl Into<Capacity>) -> CacheBuilder<K, D> {
+        CacheBuilder::new(size)
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Returns the value of a successfully computed, unexpired entry.
+    ///
+    /// Failed entries and entries still being computed are reported as
+    /// missing. The key is promoted to most recently used.
+    pub fn get(&self, key: &K) -> Option<D> {
+        self.get_with_status(key).map(|(data, _)| data)
+    }
+
+    /// Like [`get`](Self::get), without promoting the key or counting as a
+    /// lookup, for inspection paths that must not distort the eviction
+    /// order. Spilled entries are not read back from the second tier.
+    pub fn peek(&self, key: &K) -> Option<D> {
+        let now = self.now();
+        let mut cache = self.lru_cache.write_or_recover();
+        self.migrate(&mut cache, key);
+        cache
+            .peek(key)
+            .filter(|entry| entry.status == EntryStatus::Ready && self.is_live(entry, now))
+            .map(|entry| entry.data.clone())
+    }
+
+    pub(crate) fn lookup(&self, key: &K) -> Option<(D, Freshness)> {
+        self.store_finished();
+        let now = self.now();
+        let mut cache = self.lru_cache.write_or_recover();
+        self.migrate(&mut cache, key);
+        self.revalidate(&mut cache, key, now);
+        match cache.get_mut(key) {
+            Some(entry) if !self.is_live(entry, now) => {
+                self.expired(key, entry, now);
+                self.unlink(&mut cache, key);
+            }
+            Some(entry) if entry.status == EntryStatus::Ready => {
+                entry.hits += 1;
+                entry.accessed = now;
+                return Some((entry.data.clone(), self.freshness(entry, now)));
+            }
+            Some(_) => return None,
+            None => {}
+        }
+        self.promote_from_l2(&mut cache, key, now).map(|entry| {
+            let freshness = self.freshness(&entry, now);
+            (entry.data, freshness)
+        })
+    }
+
+    /// Inserts a value as if it had been computed successfully.
+    ///
+    /// With a store handler configured, a value the store rejects is not
+    /// cached; use [`try_insert`](Self::try_insert) to see the error.
+    pub fn insert(&self, key: K, data: D) {
+        let _ = self.try_insert(key, data);
+    }
+
+    /// Inserts a value, writing it through to the store handler first.
+    ///
+    /// The write happens under the cache lock, so readers never observe a
+    /// value the store has not accepted. On error the cache is unchanged.
+    /// With write-behind enabled the write is only queued and never fails.
+    pub fn try_insert(&self, key: K, data: D) -> Result<(), StoreError> {
+        self.insert_with_tags(key, data, None)
+    }
+
+    pub(crate) fn insert_with_tags(
+        &self,
+        key: K,
+        data: D,
+        tags: Option<EntryTags>,
+    ) -> Result<(), StoreError> {
+        let now = self.now();
+        let mut cache = self.lru_cache.write_or_recover();
+        let ttl = self.jittered(self.positive_ttl());
+        let mut entry = CacheEntry::new(data, EntryStatus::Ready, 0, now + ttl);
+        entry.tags = tags;
+        self.insert_locked(&mut cache, key, entry).map(drop)
+    }
+
+    /// Writes `entry` through to the store handler and stores it, under
+    /// the cache lock held by the caller, returning its write sequence
+    /// number as [`store`](Self::store) does.
+    pub(crate) fn insert_locked(
+        &self,
+        cache: &mut LruCache<K, CacheEntry<D>, S>,
+        key: K,
+        entry: CacheEntry<D>,
+    ) -> Result<u64, StoreError> {
+        self.write_through(&key, &entry.data)?;
+        self.publish_tagged(|| CacheEvent::Insert(key.clone()), entry.tags.as_ref());
+        Ok(self.store(cache, key, entry))
+    }
+
+    /// Removes an entry, returning its value if it was successfully computed.
+    pub fn remove(&self, key: &K) -> Option<D> {
+        let mut cache = self.lru_cache.write_or_recover();
+        if let Some(l2) = &self.l2 {
+            l2.remove(key);
+        }
+        self.unlink(&mut cache, key)
+            .filter(|entry| entry.status == EntryStatus::Ready)
+            .map(|entry| entry.data)
+    }
+
+    /// Number of entries held in memory, including failed and expired ones
+    /// that have not been dropped yet.
+    pub fn len(&self) -> usize {
+        self.lru_cache.read_or_recover().len()
+    }
+
+    /// Returns `true` if no entries are held in memory.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of entries held by the second tier, if one is configured.
+    pub fn l2_len(&self) -> usize {
+        self.l2.as_ref().map_or(0, |l2| l2.len())
+    }
+
+    /// Drops every entry from memory and from the second tier.
+    pub fn clear(&self) {
+        let mut cache = self.lru_cache.write_or_recover();
+        cache.clear();
+        self.expiry_index.lock_or_recover().clear();
+        self.mem_bytes.store(0, Ordering::Relaxed);
+        self.total_weight.store(0, Ordering::Relaxed);
+        self.failed.store(0, Ordering::Relaxed);
+        if let Some(l2) = &self.l2 {
+            l2.clear();
+        }
+    }
+
+    /// Returns the cached value for `key`, computing it with the miss handler
+    /// if it is missing or expired.
+    ///
+    /// The result is `(data, success, adhoc_code)`. When another thread is
+    /// already computing the key, this call waits for it to finish rather
+    /// than invoking the miss handler a second time.
+    ///
+    /// If the cache was built with a
+    /// [`max_wait`](crate::CacheBuilder::max_wait), a caller that has waited
+    /// that long assumes the computing thread is stuck and computes the key
+    /// itself.
+    ///
+    /// If the miss handler panics, the entry is cached as failed for the
+    /// negative TTL and the panic is resumed in the calling thread; callers
+    /// that were waiting get a failure. Use
+    /// [`try_retrieve_or_compute`](Self::try_retrieve_or_compute) to tell
+    /// such failures apart.
+    pub fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        match self.lookup_or_take_over(key) {
+            Lookup::Found(found) => found,
+            Lookup::Panicked | Lookup::Cancelled | Lookup::Unavailable => (D::default(), false, 0),
+            Lookup::Claimed(started) => self.compute(key, started),
+        }
+    }
+
+    /// Like [`lookup_or_claim`](Self::lookup_or_claim), claiming the key
+    /// from a computation that has been running for longer than the
+    /// configured maximum wait.
+    pub(crate) fn lookup_or_take_over(&self, key: &K) -> Lookup<D> {
+        let deadline = self.max_wait.map(|max_wait| Instant::now() + max_wait);
+        self.lookup_or_claim(key, deadline)
+            .unwrap_or_else(|Timeout| {
+                if !self.loader_enabled() {
+                    return Lookup::Unavailable;
+                }
+                let mut cache = self.lru_cache.write_or_recover();
+                let now = self.now();
+                Lookup::Claimed(self.store(&mut cache, key.clone(), CacheEntry::calculating(now)))
+            })
+    }
+
+    /// Returns the live entry for `key`, or marks it as being computed by
+    /// the caller, waiting until `deadline` at most for another thread's
+    /// computation to finish.
+    pub(crate) fn lookup_or_claim(
+        &self,
+        key: &K,
+        deadline: Option<Instant>,
+    ) -> Result<Lookup<D>, Timeout> {
+        let lookup = self.claim(key, deadline)?;
+        let hit = matches!(lookup, Lookup::Found(_) | Lookup::Panicked);
+        self.readiness.record_lookup(hit);
+        self.record_window(hit);
+        if hit {
+            self.record_hit(key);
+        }
+        self.publish(|| {
+            if hit {
+                CacheEvent::Hit(key.clone())
+            } else {
+                CacheEvent::Miss(key.clone())
+            }
+        });
+        Ok(lookup)
+    }
+
+    fn claim(&self, key: &K, deadline: Option<Instant>) -> Result<Lookup<D>, Timeout> {
+        let mut backoff = Backoff::new(self.wait_strategy);
+        let mut waited_on: Option<CancellationToken> = None;
+        loop {
+            if waited_on
+                .as_ref()
+                .is_some_and(CancellationToken::is_cancelled)
+            {
+                return Ok(Lookup::Cancelled);
+            }
+            self.store_finished();
+            let now = self.now();
+            let mut cache = self.lru_cache.write_or_recover();
+            self.migrate(&mut cache, key);
+            self.revalidate(&mut cache, key, now);
+            match cache.get_mut(key) {
+                Some(entry)
+                    if entry.status == EntryStatus::Calculating
+                        && entry
+                            .cancel
+                            .as_ref()
+                            .is_some_and(CancellationToken::is_cancelled) =>
+                {
+                    return Ok(Lookup::Cancelled);
+                }
+                Some(entry) if entry.status == EntryStatus::Calculating => {
+                    waited_on = entry.cancel.clone();
+                    drop(cache);
+                    if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                        return Err(Timeout);
+                    }
+                    backoff.wait(deadline);
+                    continue;
+                }
+                Some(entry) if entry.panicked && self.is_live(entry, now) => {
+                    entry.hits += 1;
+                    return Ok(Lookup::Panicked);
+                }
+                Some(entry) if self.is_live(entry, now) => {
+                    entry.hits += 1;
+                    entry.accessed = now;
+                    if self.refresh_early(entry, now) || self.refresh_soft_expired(entry, now) {
+                        return Ok(Lookup::Claimed(entry.seq));
+                    }
+                    return Ok(Lookup::Found((
+                        entry.data.clone(),
+                        entry.status == EntryStatus::Ready,
+                        entry.adhoc_code,
+                    )));
+                }
+                Some(entry) => self.expired(key, entry, now),
+                None => {}
+            }
+            if let Some(entry) = self.promote_from_l2(&mut cache, key, now) {
+                return Ok(Lookup::Found((entry.data, true, entry.adhoc_code)));
+            }
+            if let Some(sketch) = &self.negative_sketch {
+                if sketch.contains(key, self.negative_ttl(), now) {
+                    return Ok(Lookup::Found((D::default(), false, 0)));
+                }
+            }
+            if !self.loader_enabled() {
+                return Ok(self.without_loader(&cache, key, now));
+            }
+            let mut placeholder = CacheEntry::calculating(now);
+            if let Some(expired) = cache.peek(key) {
+                if let Some(deadline) = self.stale_deadline(expired, now) {
+                    placeholder.data = expired.data.clone();
+                    placeholder.adhoc_code = expired.adhoc_code;
+                    placeholder.stale_until = Some(deadline);
+                }
+                if self.share_previous.is_some()
+                    && expired.status == EntryStatus::Ready
+                    && self.is_current(expired, now)
+                {
+                    placeholder.data = expired.data.clone();
+                    placeholder.carries_previous = true;
+                }
+                if expired.status == EntryStatus::Failed && self.is_current(expired, now) {
+                    placeholder.failures = expired.failures;
+                }
+            }
+            let started = self.store(&mut cache, key.clone(), placeholder);
+            return Ok(Lookup::Claimed(started));
+        }
+    }
+
+    /// Runs the miss handler and stores the outcome. `started` is the write
+    /// sequence number of the entry the computation replaces, 0 if none.
+    ///
+    /// A panic of the miss handler is resumed once the entry is marked as
+    /// failed.
+    pub(crate) fn compute(&self, key: &K, started: u64) -> (D, bool, u8) {
+        self.try_compute(key, started)
+            .unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    /// Stores the outcome of a computation and returns what the cache now
+    /// holds for `key`.
+    ///
+    /// If the entry was written since the computation started (`started`
+    /// is its write sequence number at that point), the conflict policy
+    /// decides which value is kept and the other one is reported to the
+    /// conflict listener. A successful value that the store handler rejects
+    /// is cached as failed instead. The cache policy, if any, has the last
+    /// word on whether and how long the outcome is cached; until then, an
+    /// outcome is cached for the TTL its loader chose, if any: that of the
+    /// fallback loader that produced it, or the one returned by a
+    /// [`ttl_miss_handler`](CacheBuilder::ttl_miss_handler).
+    pub(crate) fn complete(
+        &self,
+        key: &K,
+        started: u64,
+        loaded: Loaded<D>,
+        load_time: Duration,
+    ) -> (D, bool, u8) {
+        let Loaded {
+            data,
+            success,
+            adhoc_code,
+            ttl: loader_ttl,
+        } = loaded;
+        let now = self.now();
+        let mut cache = self.lru_cache.write_or_recover();
+        let cancelled = cache.peek(key).is_some_and(|entry| {
+            entry.seq == started
+                && entry.status == EntryStatus::Calculating
+                && entry
+                    .cancel
+                    .as_ref()
+                    .is_some_and(CancellationToken::is_cancelled)
+        });
+        if cancelled {
+            self.unlink(&mut cache, key);
+            return (D::default(), false, 0);
+        }
+        let conflicting = cache
+            .peek(key)
+            .filter(|entry| entry.seq != started && entry.status != EntryStatus::Calculating);
+        if let Some(winner) = conflicting {
+            if self.conflict_policy == ConflictPolicy::FirstWriteWins {
+                let kept = (
+                    winner.data.clone(),
+                    winner.status == EntryStatus::Ready,
+                    winner.adhoc_code,
+                );
+                drop(cache);
+                self.report_conflict(key, &data);
+                return kept;
+            }
+        }
+        let overwritten = conflicting
+            .filter(|entry| entry.status == EntryStatus::Ready && self.conflict_listener.is_some())
+            .map(|entry| entry.data.clone());
+
+        let decision = self
+            .cache_policy
+            .as_ref()
+            .map_or(CacheDecision::Cache, |policy| {
+                let meta = LoadMeta {
+                    success,
+                    adhoc_code,
+                    load_time,
+                };
+                policy(key, &data, &meta)
+            });
+        if decision == CacheDecision::Skip {
+            let ours = cache.peek(key).is_some_and(|entry| entry.seq == started);
+            if ours {
+                self.unlink(&mut cache, key);
+            }
+            if !success {
+                self.publish(|| CacheEvent::LoadFailed(key.clone()));
+            }
+            return (data, success, adhoc_code);
+        }
+        // A value the store rejects fails for the negative TTL, whatever
+        // its loader chose.
+        let stored = !success || self.write_through(key, &data).is_ok();
+        let loader_ttl = loader_ttl.filter(|_| stored);
+        let success = success && stored;
+        self.publish(|| {
+            if success {
+                CacheEvent::Insert(key.clone())
+            } else {
+                CacheEvent::LoadFailed(key.clone())
+            }
+        });
+        if !success {
+            if let Some(stale) = self.serve_stale(&mut cache, key, started, now) {
+                return stale;
+            }
+            if let Some(stale) = self.keep_soft_expired(&cache, key, started, now) {
+                return stale;
+            }
+        }
+        let (status, ttl) = if success {
+            let ttl = loader_ttl.unwrap_or_else(|| self.positive_ttl());
+            (EntryStatus::Ready, self.jittered(ttl))
+        } else {
+            let ttl = loader_ttl.unwrap_or_else(|| self.negative_ttl());
+            (EntryStatus::Failed, self.jittered(ttl))
+        };
+        let ttl = match decision {
+            CacheDecision::CacheFor(ttl) => ttl,
+            _ => ttl,
+        };
+        match &self.negative_sketch {
+            Some(sketch) if !success => {
+                sketch.insert(key, ttl, now);
+                self.unlink(&mut cache, key);
+            }
+            _ if !success && !self.negative_caching => {
+                self.unlink(&mut cache, key);
+            }
+            _ => {
+                let mut entry = CacheEntry::new(data.clone(), status, adhoc_code, now + ttl);
+                entry.load_time = load_time;
+                entry.computed = true;
+                if !success && decision == CacheDecision::Cache && loader_ttl.is_none() {
+                    self.back_off(&cache, key, started, &mut entry, now);
+                }
+                self.store(&mut cache, key.clone(), entry);
+                if !success {
+                    self.trim_failed(&mut cache);
+                }
+            }
+        }
+        drop(cache);
+
+        if let Some(overwritten) = overwritten {
+            self.report_conflict(key, &overwritten);
+        }
+        (data, success, adhoc_code)
+    }
+
+    fn report_conflict(&self, key: &K, loser: &D) {
+        if let Some(conflict_listener) = &self.conflict_listener {
+            conflict_listener(key, loser);
+        }
+    }
+
+    /// Returns `true` if the entry may be served: it is not expired, is
+    /// within the maximum staleness and was not invalidated.
+    ///
+    /// Calculating entries are always live so that waiters keep waiting on
+    /// the computation in flight.
+    pub(crate) fn is_live(&self, entry: &CacheEntry<D>, now: Instant) -> bool {
+        if entry.status == EntryStatus::Calculating {
+            return true;
+        }
+        if entry.is_expired(now) {
+            return false;
+        }
+        if let Some(max_staleness) = self.max_staleness {
+            if now.saturating_duration_since(entry.expiration) > max_staleness {
+                return false;
+            }
+        }
+        self.is_current(entry, now)
+    }
+
+    /// Returns `true` if the entry was not invalidated: it was stored after
+    /// the last `invalidate_all`, belongs to the current generation and
+    /// carries no invalidated tag.
+    pub(crate) fn is_current(&self, entry: &CacheEntry<D>, now: Instant) -> bool {
+        if entry.epoch != self.epoch.load(Ordering::Acquire)
+            || entry.generation != self.generations.current(now)
+        {
+            return false;
+        }
+        let Some(tags) = &entry.tags else {
+            return true;
+        };
+        let tag_epochs = self.tag_epochs.read_or_recover();
+        tags.iter()
+            .all(|(tag, epoch)| tag_epochs.get(tag) == Some(epoch))
+    }
+
+    /// Hands a value to the store handler, or queues it when write-behind
+    /// is enabled.
+    pub(crate) fn write_through(&self, key: &K, data: &D) -> Result<(), StoreError> {
+        if let Some(write_behind) = &self.write_behind {
+            write_behind.enqueue(key.clone(), data.clone());
+            Ok(())
+        } else if let Some(store_handler) = &self.store_handler {
+            store_handler(key, data)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Moves an entry from the second tier back into memory.
+    pub(crate) fn promote_from_l2(
+        &self,
+        cache: &mut LruCache<K, CacheEntry<D>, S>,
+        key: &K,
+        now: Instant,
+    ) -> Option<CacheEntry<D>> {
+        let spilled = match self.l2.as_ref()?.take(key, now) {
+            Ok(spilled) => spilled?,
+            Err(Corrupted) => {
+                self.report_corruption(key);
+                return None;
+            }
+        };
+        if spilled.expiration <= now {
+            return None;
+        }
+        let entry = CacheEntry::new(
+            spilled.data,
+            EntryStatus::Ready,
+            spilled.adhoc_code,
+            spilled.expiration,
+        );
+        if spilled.version == self.value_version {
+            self.store(cache, key.clone(), entry.clone());
+            return Some(entry);
+        }
+        if self.store(cache, key.clone(), entry) == 0 {
+            return None;
+        }
+        cache.peek_mut(key)?.version = spilled.version;
+        self.migrate(cache, key);
+        cache.peek(key).cloned()
+    }
+
+    /// Puts an entry into the LRU, evicting the least recently used entry
+    /// that is not held if the cache is full, and returns the write
+    /// sequence number assigned to it.
+    ///
+    /// When no entry can be evicted the new entry is not cached at all and 0
+    /// is returned.
+    pub(crate) fn store(
+        &self,
+        cache: &mut LruCache<K, CacheEntry<D>, S>,
+        key: K,
+        mut entry: CacheEntry<D>,
+    ) -> u64 {
+        if let Some(l2) = &self.l2 {
+            l2.remove(&key);
+        }
+        self.measure(&key, &mut entry);
+        if self.is_oversize(&entry) {
+            match self.oversize_policy {
+                OversizePolicy::Reject => {
+                    self.unlink(cache, &key);
+                    return 0;
+                }
+                OversizePolicy::Uncounted => {
+                    entry.bytes = 0;
+                    entry.weight = 0;
+                }
+            }
+        }
+        if let Some(existing) = cache.peek(&key) {
+            entry.holds = existing.holds;
+            entry.held_seq = existing.held_seq;
+        } else if cache.cap() == NonZeroUsize::MAX {
+            self.maybe_sweep(cache);
+        } else if cache.len() >= cache.cap().get() && !self.drop_dead(cache) {
+            let Some((victim_key, victim)) = self.pop_victim(cache) else {
+                return 0;
+            };
+            self.evicted(victim_key, victim);
+        }
+        entry.seq = self.write_seq.fetch_add(1, Ordering::Relaxed) + 1;
+        entry.epoch = self.epoch.load(Ordering::Acquire);
+        entry.version = self.value_version;
+        entry.created = self.now();
+        entry.accessed = entry.created;
+        entry.generation = self.generations.current(entry.created);
+        let seq = entry.seq;
+        let indexed =
+            (entry.status != EntryStatus::Calculating).then(|| (key.clone(), entry.expiration));
+        self.charge(&entry);
+        if let Some((_, replaced)) = cache.push(key, entry) {
+            self.uncharge(&replaced);
+        }
+        if let Some((key, expiration)) = indexed {
+            self.index_expiration(cache, key, expiration, seq);
+        }
+        self.trim_to_bounds(cache);
+        seq
+    }
+
+    /// Drops the entries that are no longer live once an unbounded cache
+    /// has doubled in size since the last sweep, for an amortized O(1) cost
+    /// per insert.
+    fn maybe_sweep(&self, cache: &mut LruCache<K, CacheEntry<D>, S>) {
+        if cache.len() < self.sweep_at.load(Ordering::Relaxed) {
+            return;
+        }
+        let now = self.now();
+        let dead: Vec<K> = cache
+            .iter()
+            .filter(|(_, entry)| !self.is_live(entry, now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &dead {
+            if let Some(entry) = self.unlink(cache, key) {
+                self.expired(key, &entry, now);
+            }
+        }
+        let sweep_at = (2 * cache.len()).max(SWEEP_MIN_LEN);
+        self.sweep_at.store(sweep_at, Ordering::Relaxed);
+    }
+
+    /// Counts a lookup in the stats window, if any.
+    pub(crate) fn record_window(&self, hit: bool) {
+        if let Some(window) = &self.stats_window {
+            window.record_lookup(hit, self.now());
+        }
+    }
+
+    /// The current instant according to the cache's clock.
+    pub(crate) fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Handles an entry that was evicted to make room, spilling it to the
+    /// second tier if it is still worth keeping.
+    pub(crate) fn evicted(&self, key: K, entry: CacheEntry<D>) {
+        self.trace_eviction(&key);
+        if let Some(window) = &self.stats_window {
+            window.record_eviction(self.now());
+        }
+        self.publish_tagged(|| CacheEvent::Evict(key.clone()), entry.tags.as_ref());
+        let Some(l2) = &self.l2 else {
+            return;
+        };
+        let now = self.now();
+        if entry.status == EntryStatus::Ready && entry.tags.is_none() && !entry.is_expired(now) {
+            l2.spill(
+                &key,
+                SpilledEntry {
+                    data: entry.data,
+                    adhoc_code: entry.adhoc_code,
+                    expiration: entry.expiration,
+                    version: entry.version,
+                },
+                now,
+            );
+        }
+    }
+}
+
+pub(crate) fn capacity(size: usize) -> NonZeroUsize {
+    NonZeroUsize::new(size).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EntryState;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    fn counting_cache(size: usize, calls: Arc<AtomicUsize>) -> Cache<u32, u32> {
+        Cache::new(
+            size,
+            Duration::from_millis(200),
+            Duration::from_millis(50),
+            move |key: &u32, data: &mut u32, adhoc_code: &mut u8| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                *data = key * 10;
+                *adhoc_code = 7;
+                key.is_multiple_of(2)
+            },
+        )
+    }
+
+    #[test]
+    fn computes_once_and_caches_success() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = counting_cache(10, calls.clone());
+
+        assert_eq!(cache.retrieve_or_compute(&2), (20, true, 7));
+        assert_eq!(cache.retrieve_or_compute(&2), (20, true, 7));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get(&2), Some(20));
+    }
+
+    #[test]
+    fn failures_are_cached_for_negative_ttl() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = counting_cache(10, calls.clone());
+
+        assert_eq!(cache.retrieve_or_compute(&3), (30, false, 7));
+        assert_eq!(cache.retrieve_or_compute(&3), (30, false, 7));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get(&3), None);
+        assert_eq!(cache.status(&3), EntryState::Failed);
+
+        thread::sleep(Duration::from_millis(60));
+        cache.retrieve_or_compute(&3);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn store_handler_writes_through_inserts_and_loads() {
+        let stored = Arc::new(RwLock::new(Vec::new()));
+        let sink = stored.clone();
+        let cache = Cache::builder(10)
+            .miss_handler(|key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                true
+            })
+            .store_handler(move |key: &u32, data: &u32| {
+                if *key == 0 {
+                    return Err(StoreError::from("rejected"));
+                }
+                sink.write().unwrap().push((*key, *data));
+                Ok(())
+            })
+            .build();
+
+        cache.insert(1, 10);
+        assert_eq!(cache.retrieve_or_compute(&2), (2, true, 0));
+        assert!(cache.try_insert(0, 1).is_err());
+        assert_eq!(cache.get(&0), None);
+        assert_eq!(cache.retrieve_or_compute(&0), (0, false, 0));
+        assert_eq!(*stored.read().unwrap(), vec![(1, 10), (2, 2)]);
+    }
+
+    #[test]
+    fn entries_expire_after_positive_ttl() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = counting_cache(10, calls.clone());
+
+        cache.retrieve_or_compute(&4);
+        thread::sleep(Duration::from_millis(210));
+        assert_eq!(cache.get(&4), None);
+        cache.retrieve_or_compute(&4);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = counting_cache(2, Arc::new(AtomicUsize::new(0)));
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.get(&1);
+        cache.insert(3, 3);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.get(&3), Some(3));
+    }
+
+    #[test]
+    fn concurrent_callers_share_one_computation() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler_calls = calls.clone();
+        let cache = Arc::new(Cache::new(
+            10,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            move |_: &u32, data: &mut u32, _: &mut u8| {
+                handler_calls.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                *data = 1;
+                true
+            },
+        ));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let cache = cache.clone();
+                thread::spawn(move || cache.retrieve_or_compute(&1))
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), (1, true, 0));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn peek_does_not_promote() {
+        let cache = counting_cache(2, Arc::new(AtomicUsize::new(0)));
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+
+        assert_eq!(cache.peek(&1), Some(1));
+        cache.insert(3, 3);
+        assert_eq!(cache.peek(&1), None);
+        assert_eq!(cache.peek(&2), Some(2));
+        assert_eq!(cache.readiness().lookups, 0);
+    }
+}
@@ -0,0 +1,67 @@
+//! Letting the application decide whether and how long a load is cached.
+
+use std::time::Duration;
+
+/// What [`CacheBuilder::cache_policy`](crate::CacheBuilder::cache_policy)
+/// is told about a completed load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LoadMeta {
+    /// Whether the miss handler succeeded and the store handler, if any,
+    /// accepted the value.
+    pub success: bool,
+    /// The adhoc code set by the miss handler.
+    pub adhoc_code: u8,
+    /// How long the miss handler ran.
+    pub load_time: Duration,
+}
+
+/// Whether a loaded value is cached, and for how long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheDecision {
+    /// Cache for the positive or negative TTL, as without a policy.
+    Cache,
+    /// Cache for this long instead, e.g. briefly for a partial result.
+    CacheFor(Duration),
+    /// Do not cache the value, e.g. for a response marked `no-store`. It is
+    /// still returned to the caller, but not written to the store handler,
+    /// and callers that were waiting for it compute the key themselves.
+    Skip,
+}
+
+/// Hook deciding per load whether and how long the result is cached.
+pub type CachePolicy<K, D> = dyn Fn(&K, &D, &LoadMeta) -> CacheDecision + Send + Sync;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cache, ManualClock};
+    use std::sync::Arc;
+
+    #[test]
+    fn the_policy_overrides_the_ttls() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .clock(clock.clone())
+            .miss_handler(|key: &u32, data: &mut u32, adhoc_code: &mut u8| {
+                *data = *key;
+                *adhoc_code = (*key % 3) as u8;
+                true
+            })
+            .cache_policy(|_: &u32, _: &u32, meta: &LoadMeta| match meta.adhoc_code {
+                1 => CacheDecision::CacheFor(Duration::from_secs(5)),
+                2 => CacheDecision::Skip,
+                _ => CacheDecision::Cache,
+            })
+            .build();
+
+        assert_eq!(cache.retrieve_or_compute(&3), (3, true, 0));
+        assert_eq!(cache.retrieve_or_compute(&4), (4, true, 1));
+        assert_eq!(cache.retrieve_or_compute(&5), (5, true, 2));
+        assert_eq!(cache.time_to_live(&3), Some(Duration::from_secs(60)));
+        assert_eq!(cache.time_to_live(&4), Some(Duration::from_secs(5)));
+        assert_eq!(cache.get(&5), None);
+        assert!(cache.get_entry(&5).is_none());
+    }
+}
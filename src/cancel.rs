@@ -0,0 +1,149 @@
+//! Cancelling computations nobody needs any more.
+//!
+//! Every computation gets a [`CancellationToken`]. [`Cache::cancel`] trips
+//! it: callers waiting on the key give up, the outcome is thrown away
+//! instead of cached, and a miss handler set with
+//! [`CacheBuilder::cancellable_miss_handler`](crate::CacheBuilder::cancellable_miss_handler)
+//! can notice and stop its backend work early.
+
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::cache::{Cache, EntryStatus, Lookup};
+use crate::clock::Clock;
+use crate::lock::RwLockExt;
+
+/// Error returned when the computation of the key was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the computation was cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// Tells a miss handler whether its computation is still wanted.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Whether the computation was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Cancels the computation of `key` in progress, returning `false` if
+    /// there is none.
+    ///
+    /// Callers waiting on the key stop waiting, and the outcome is not
+    /// cached; the next lookup starts over. The miss handler itself only
+    /// stops early if it checks its [`CancellationToken`].
+    pub fn cancel(&self, key: &K) -> bool {
+        let cache = self.lru_cache.read_or_recover();
+        let token = cache
+            .peek(key)
+            .filter(|entry| entry.status == EntryStatus::Calculating)
+            .and_then(|entry| entry.cancel.as_ref());
+        match token {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Like [`retrieve_or_compute`](Self::retrieve_or_compute), but returns
+    /// [`Cancelled`] if the computation this call ran or waited on was
+    /// cancelled with [`cancel`](Self::cancel), where the plain variant
+    /// reports a failure.
+    pub fn retrieve_or_compute_cancellable(&self, key: &K) -> Result<(D, bool, u8), Cancelled> {
+        match self.lookup_or_take_over(key) {
+            Lookup::Found(found) => Ok(found),
+            Lookup::Cancelled => Err(Cancelled),
+            Lookup::Panicked | Lookup::Unavailable => Ok((D::default(), false, 0)),
+            Lookup::Claimed(started) => {
+                let token = self.cancellation(key, started);
+                let outcome = self.compute(key, started);
+                match token {
+                    Some(token) if token.is_cancelled() => Err(Cancelled),
+                    _ => Ok(outcome),
+                }
+            }
+        }
+    }
+
+    /// Token of the placeholder with write sequence number `started`.
+    pub(crate) fn cancellation(&self, key: &K, started: u64) -> Option<CancellationToken> {
+        let cache = self.lru_cache.read_or_recover();
+        cache
+            .peek(key)
+            .filter(|entry| entry.seq == started)
+            .and_then(|entry| entry.cancel.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn cancelled_computations_stop_and_are_not_cached() {
+        let cache = Arc::new(
+            Cache::builder(10)
+                .positive_ttl(Duration::from_secs(60))
+                .cancellable_miss_handler(
+                    |key: &u32, data: &mut u32, _: &mut u8, token: &CancellationToken| {
+                        while *key == 1 && !token.is_cancelled() {
+                            thread::sleep(Duration::from_millis(1));
+                        }
+                        *data = *key;
+                        true
+                    },
+                )
+                .build(),
+        );
+        let computing = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.retrieve_or_compute_cancellable(&1))
+        };
+        thread::sleep(Duration::from_millis(20));
+        let waiting = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.retrieve_or_compute_cancellable(&1))
+        };
+        thread::sleep(Duration::from_millis(20));
+
+        assert!(cache.cancel(&1));
+        assert_eq!(computing.join().unwrap(), Err(Cancelled));
+        assert_eq!(waiting.join().unwrap(), Err(Cancelled));
+        assert!(cache.get_entry(&1).is_none());
+        assert!(!cache.cancel(&1));
+        assert_eq!(cache.retrieve_or_compute_cancellable(&2), Ok((2, true, 0)));
+    }
+}
@@ -0,0 +1,175 @@
+//! Optimistic conditional writes.
+//!
+//! Every write of a value gives it a new version, taken from the cache's
+//! write sequence, so versions only ever grow and are never reused for
+//! another value, even across removals.
+
+use std::hash::{BuildHasher, Hash};
+
+use crate::cache::Cache;
+use crate::clock::Clock;
+use crate::entry::Entry;
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Replaces the value of `key` with `new` if it currently equals
+    /// `expected`, or stores `new` if `expected` is `None` and there is no
+    /// value to serve.
+    ///
+    /// On mismatch, returns the current value so that the caller can retry.
+    /// A value the store handler rejects is not stored, and the current
+    /// value is returned as on a mismatch. The expiration of a replaced
+    /// value is kept.
+    pub fn compare_and_swap(&self, key: K, expected: Option<&D>, new: D) -> Result<(), Option<D>>
+    where
+        D: PartialEq,
+    {
+        match self.entry(key) {
+            Entry::Occupied(mut entry) => {
+                if Some(entry.get()) != expected {
+                    return Err(Some(entry.get().clone()));
+                }
+                entry
+                    .try_insert(new)
+                    .map(drop)
+                    .map_err(|_| Some(entry.get().clone()))
+            }
+            Entry::Vacant(entry) if expected.is_none() => {
+                entry.try_insert(new).map(drop).map_err(|_| None)
+            }
+            Entry::Vacant(_) => Err(None),
+        }
+    }
+
+    /// Returns the value of `key` with its version, for a later
+    /// [`insert_if_version`](Self::insert_if_version).
+    pub fn get_versioned(&self, key: &K) -> Option<(D, u64)> {
+        match self.entry(key.clone()) {
+            Entry::Occupied(entry) => Some((entry.get().clone(), entry.version())),
+            Entry::Vacant(_) => None,
+        }
+    }
+
+    /// Stores `data` if the value of `key` still has `version`, or if
+    /// `version` is 0 and there is no value, and returns the new version.
+    ///
+    /// Meant for writers that read a value, work on it outside the cache
+    /// and write it back: on a lost update this returns `None` and the
+    /// caller reads again. Like [`insert`](Self::insert), a value the
+    /// store handler rejects is not stored, and `None` is returned too.
+    pub fn insert_if_version(&self, key: K, data: D, version: u64) -> Option<u64> {
+        match self.entry(key) {
+            Entry::Occupied(mut entry) if entry.version() == version => {
+                entry.try_insert(data).ok()?;
+                Some(entry.version())
+            }
+            Entry::Vacant(entry) if version == 0 => {
+                entry.try_insert(data).ok().filter(|&version| version != 0)
+            }
+            _ => None,
+        }
+    }
+
+    /// Replaces the value of `key` with `new` if there is one and `pred`
+    /// accepts it, returning whether it was replaced.
+    ///
+    /// `pred` runs under the cache lock and must not call back into the
+    /// cache.
+    pub fn update_if(&self, key: K, pred: impl FnOnce(&D) -> bool, new: D) -> bool {
+        match self.entry(key) {
+            Entry::Occupied(mut entry) if pred(entry.get()) => entry.try_insert(new).is_ok(),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn concurrent_swaps_do_not_lose_updates() {
+        let cache = Arc::new(Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut u64, _: &mut u8| false,
+        ));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        let mut current = cache.peek(&1);
+                        loop {
+                            let new = current.map_or(1, |count| count + 1);
+                            match cache.compare_and_swap(1, current.as_ref(), new) {
+                                Ok(()) => break,
+                                Err(actual) => current = actual,
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(cache.get(&1), Some(2000));
+    }
+
+    #[test]
+    fn updates_depend_on_the_current_value() {
+        let cache = Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut u64, _: &mut u8| false,
+        );
+        assert_eq!(cache.compare_and_swap(1, Some(&0), 1), Err(None));
+        assert!(!cache.update_if(1, |_| true, 1));
+        cache.insert(1, 5);
+        assert_eq!(cache.compare_and_swap(1, None, 1), Err(Some(5)));
+        assert!(!cache.update_if(1, |&v| v > 5, 6));
+        assert!(cache.update_if(1, |&v| v == 5, 6));
+        assert_eq!(cache.compare_and_swap(1, Some(&6), 7), Ok(()));
+        assert_eq!(cache.get(&1), Some(7));
+    }
+
+    #[test]
+    fn lost_updates_are_detected() {
+        let cache = Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut u64, _: &mut u8| false,
+        );
+        let first = cache.insert_if_version(1, 1, 0).unwrap();
+        assert_eq!(cache.insert_if_version(1, 1, 0), None);
+        assert_eq!(cache.get_versioned(&1), Some((1, first)));
+
+        cache.touch(&1);
+        let (_, read) = cache.get_versioned(&1).unwrap();
+        assert_eq!(read, first);
+        cache.insert(1, 2);
+        assert_eq!(cache.insert_if_version(1, 3, read), None);
+
+        let (data, read) = cache.get_versioned(&1).unwrap();
+        assert!(read > first);
+        let written = cache.insert_if_version(1, data + 1, read).unwrap();
+        assert!(written > read);
+        assert_eq!(cache.get_versioned(&1), Some((3, written)));
+
+        cache.remove(&1);
+        assert_eq!(cache.get_versioned(&1), None);
+        assert_eq!(cache.insert_if_version(1, 4, written), None);
+    }
+}
@@ -0,0 +1,65 @@
+//! Detecting entries corrupted outside the cache's control.
+//!
+//! Backends that keep entries outside the process heap (the disk tier and
+//! the memory-mapped caches) store a CRC-32 of every value and verify it on read. A mismatch,
+//! e.g. from a bad writer in another process or a damaged file, is treated
+//! as a miss and reported to the corruption listener instead of feeding
+//! garbage to readers.
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::Ordering;
+
+use crate::cache::Cache;
+use crate::clock::Clock;
+
+/// Hook told about every entry whose checksum did not match on read.
+pub type CorruptionListener<K> = dyn Fn(&K) + Send + Sync;
+
+/// Returned by a backend for an entry that failed verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Corrupted;
+
+/// CRC-32 (IEEE 802.3) of `bytes`.
+#[cfg(any(feature = "disk", feature = "snapshot", feature = "mmap"))]
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Number of entries dropped because their checksum did not match.
+    pub fn corruptions(&self) -> u64 {
+        self.corruptions.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn report_corruption(&self, key: &K) {
+        self.corruptions.fetch_add(1, Ordering::Relaxed);
+        if let Some(corruption_listener) = &self.corruption_listener {
+            corruption_listener(key);
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "disk", feature = "snapshot", feature = "mmap")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_the_reference() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,135 @@
+//! The source of time used for expiration.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lru::DefaultHasher;
+
+use crate::cache::Cache;
+use crate::lock::MutexExt;
+use crate::time::Instant;
+
+/// Tells a cache what time it is.
+///
+/// Every TTL decision (expiration, max staleness, negative sketches) goes
+/// through the clock, so replacing [`SystemClock`] with a [`ManualClock`]
+/// makes expiration deterministic in tests. Waiting on other threads'
+/// computations always uses real time.
+pub trait Clock: Send + Sync {
+    /// The current instant. Must never go backwards.
+    ///
+    /// [`Instant`] is `std::time::Instant`, or `web_time::Instant` with the
+    /// `wasm` feature.
+    fn now(&self) -> Instant;
+}
+
+/// The real monotonic clock, used by default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to, for tests and simulations.
+#[derive(Debug)]
+pub struct ManualClock {
+    origin: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// Creates a clock stopped at the current instant.
+    pub fn new() -> Self {
+        ManualClock {
+            origin: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock_or_recover() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.origin + *self.elapsed.lock_or_recover()
+    }
+}
+
+/// Shares a clock, e.g. a [`ManualClock`] with the test advancing it, or
+/// an `Arc<dyn Clock>` picked at runtime.
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// A cache on a [`ManualClock`] shared with whoever advances it.
+pub type ManualClockCache<K, D, S = DefaultHasher> = Cache<K, D, S, Arc<ManualClock>>;
+
+/// A cache whose clock is chosen at runtime.
+pub type DynClockCache<K, D, S = DefaultHasher> = Cache<K, D, S, Arc<dyn Clock>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn entries_expire_on_the_injected_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .negative_ttl(Duration::from_secs(5))
+            .clock(clock.clone())
+            .miss_handler(move |key: &u32, data: &mut u32, _: &mut u8| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                *data = *key;
+                *key != 0
+            })
+            .build();
+        cache.insert(1, 1);
+        cache.retrieve_or_compute(&0);
+
+        clock.advance(Duration::from_secs(4));
+        assert!(!cache.retrieve_or_compute(&0).1);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        clock.advance(Duration::from_secs(55));
+        assert_eq!(cache.get(&1), Some(1));
+        assert!(!cache.retrieve_or_compute(&0).1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.get(&1), None);
+    }
+
+    #[test]
+    fn the_clock_can_be_chosen_at_runtime() {
+        let manual = Arc::new(ManualClock::new());
+        let clock: Arc<dyn Clock> = manual.clone();
+        let cache: DynClockCache<u32, u32> = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(1))
+            .clock(clock)
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| true)
+            .build();
+        cache.insert(1, 1);
+        assert_eq!(cache.get(&1), Some(1));
+
+        manual.advance(Duration::from_secs(1));
+        assert_eq!(cache.get(&1), None);
+    }
+}
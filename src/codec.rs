@@ -0,0 +1,126 @@
+//! Mapping keys onto the byte strings used by remote and disk tiers.
+
+use std::fmt::Display;
+use std::str::{self, FromStr};
+use std::time::Duration;
+
+use crate::tiered::{BackendError, CacheBackend};
+
+/// Turns keys into the byte strings a shared tier stores them under, and
+/// back.
+///
+/// Separate from how values are serialized, so that structured keys keep
+/// mapping onto the same Redis or file key names across releases of the
+/// key type and across processes written in other languages.
+pub trait KeyCodec<K>: Send + Sync {
+    /// Encodes `key`. Equal keys must encode to equal bytes.
+    fn encode(&self, key: &K) -> Vec<u8>;
+
+    /// Decodes bytes produced by [`encode`](Self::encode), or returns
+    /// `None` if they are not a valid key.
+    fn decode(&self, bytes: &[u8]) -> Option<K>;
+}
+
+/// Encodes keys as their UTF-8 [`Display`] form and decodes them with
+/// [`FromStr`], e.g. `user:42` for a key type printing that way.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StrKeys;
+
+impl<K: Display + FromStr> KeyCodec<K> for StrKeys {
+    fn encode(&self, key: &K) -> Vec<u8> {
+        key.to_string().into_bytes()
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<K> {
+        str::from_utf8(bytes).ok()?.parse().ok()
+    }
+}
+
+/// A [`CacheBackend`] keyed by bytes, used for any key type through a
+/// [`KeyCodec`].
+#[derive(Debug)]
+pub struct EncodedKeys<C, B> {
+    codec: C,
+    backend: B,
+}
+
+impl<C, B> EncodedKeys<C, B> {
+    /// Stores the keys of `backend` as encoded by `codec`.
+    pub fn new(codec: C, backend: B) -> Self {
+        EncodedKeys { codec, backend }
+    }
+
+    /// The byte-keyed backend.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+impl<K, D, C, B> CacheBackend<K, D> for EncodedKeys<C, B>
+where
+    C: KeyCodec<K>,
+    B: CacheBackend<Vec<u8>, D>,
+{
+    fn get(&self, key: &K) -> Result<Option<(D, Duration)>, BackendError> {
+        self.backend.get(&self.codec.encode(key))
+    }
+
+    fn put(&self, key: &K, data: &D, ttl: Duration) -> Result<(), BackendError> {
+        self.backend.put(&self.codec.encode(key), data, ttl)
+    }
+
+    fn remove(&self, key: &K) -> Result<(), BackendError> {
+        self.backend.remove(&self.codec.encode(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cache, TieredCache};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct ByteBackend(Mutex<HashMap<Vec<u8>, u32>>);
+
+    impl CacheBackend<Vec<u8>, u32> for ByteBackend {
+        fn get(&self, key: &Vec<u8>) -> Result<Option<(u32, Duration)>, BackendError> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .get(key)
+                .map(|&data| (data, Duration::from_secs(60))))
+        }
+
+        fn put(&self, key: &Vec<u8>, data: &u32, _: Duration) -> Result<(), BackendError> {
+            self.0.lock().unwrap().insert(key.clone(), *data);
+            Ok(())
+        }
+
+        fn remove(&self, key: &Vec<u8>) -> Result<(), BackendError> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn remote_keys_go_through_the_codec() {
+        let cache = TieredCache::new(
+            Cache::builder(10).positive_ttl(Duration::from_secs(60)),
+            EncodedKeys::new(StrKeys, ByteBackend::default()),
+            |key: &u32, data: &mut u32, _: &mut u8| {
+                *data = key * 2;
+                true
+            },
+        )
+        .unwrap();
+        cache.retrieve_or_compute(&42);
+
+        let stored = cache.backend().backend().0.lock().unwrap();
+        assert_eq!(stored.get(&b"42"[..]), Some(&84));
+        assert_eq!(KeyCodec::<u32>::decode(&StrKeys, b"42"), Some(42));
+        assert_eq!(KeyCodec::<u32>::decode(&StrKeys, b"x"), None);
+    }
+}
@@ -0,0 +1,186 @@
+//! Storing values serialized and compressed, trading CPU on every access
+//! for a smaller memory footprint.
+
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::cache::Cache;
+use crate::config::Capacity;
+
+/// A value as a [`CompressedCache`] stores it: serialized, and compressed
+/// with LZ4 if that made it smaller.
+#[derive(Clone, Default)]
+pub(crate) struct Packed {
+    bytes: Arc<[u8]>,
+    compressed: bool,
+}
+
+impl Packed {
+    /// Serializes `value`, compressing it if it takes at least `threshold`
+    /// bytes.
+    fn pack<D: Serialize>(value: &D, threshold: usize) -> Option<Packed> {
+        let bytes = bincode::serde::encode_to_vec(value, bincode::config::standard()).ok()?;
+        if bytes.len() >= threshold {
+            let compressed = lz4_flex::compress_prepend_size(&bytes);
+            if compressed.len() < bytes.len() {
+                return Some(Packed {
+                    bytes: compressed.into(),
+                    compressed: true,
+                });
+            }
+        }
+        Some(Packed {
+            bytes: bytes.into(),
+            compressed: false,
+        })
+    }
+
+    fn unpack<D: DeserializeOwned>(&self) -> Option<D> {
+        let decompressed;
+        let bytes = if self.compressed {
+            decompressed = lz4_flex::decompress_size_prepended(&self.bytes).ok()?;
+            &decompressed[..]
+        } else {
+            &self.bytes[..]
+        };
+        bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+            .ok()
+            .map(|(value, _)| value)
+    }
+}
+
+/// A cache storing its values serialized, and compressed once they reach a
+/// size threshold, so that large compressible values such as rendered pages
+/// or JSON documents take a fraction of their in-memory size.
+///
+/// Every hit deserializes, and decompresses if needed, a fresh copy of the
+/// value. A value that fails to serialize is not cached: an insert is
+/// dropped and a computation counts as failed.
+pub struct CompressedCache<K, D> {
+    cache: Cache<K, Packed>,
+    threshold: usize,
+    _marker: PhantomData<fn() -> D>,
+}
+
+impl<K, D> CompressedCache<K, D>
+where
+    K: Hash + Eq + Clone,
+    D: Serialize + DeserializeOwned + Default,
+{
+    /// Creates a cache holding at most `size` entries, compressing values
+    /// whose serialized form takes at least `threshold` bytes.
+    pub fn new<F>(
+        size: impl Into<Capacity>,
+        positive_ttl: Duration,
+        negative_ttl: Duration,
+        threshold: usize,
+        miss_handler: F,
+    ) -> Self
+    where
+        F: Fn(&K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        let cache = Cache::builder(size)
+            .positive_ttl(positive_ttl)
+            .negative_ttl(negative_ttl)
+            .size_hint(|_: &K, packed: &Packed| packed.bytes.len())
+            .miss_handler(move |key: &K, data: &mut Packed, adhoc_code: &mut u8| {
+                let mut value = D::default();
+                let success = miss_handler(key, &mut value, adhoc_code);
+                match Packed::pack(&value, threshold) {
+                    Some(packed) => {
+                        *data = packed;
+                        success
+                    }
+                    None => false,
+                }
+            })
+            .build();
+        CompressedCache {
+            cache,
+            threshold,
+            _marker: PhantomData,
+        }
+    }
+
+    /// See [`Cache::get`].
+    pub fn get(&self, key: &K) -> Option<D> {
+        self.cache.get(key)?.unpack()
+    }
+
+    /// See [`Cache::insert`].
+    pub fn insert(&self, key: K, data: &D) {
+        if let Some(packed) = Packed::pack(data, self.threshold) {
+            self.cache.insert(key, packed);
+        }
+    }
+
+    /// See [`Cache::retrieve_or_compute`].
+    pub fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        let (packed, success, adhoc_code) = self.cache.retrieve_or_compute(key);
+        match packed.unpack() {
+            Some(data) => (data, success, adhoc_code),
+            None => (D::default(), false, adhoc_code),
+        }
+    }
+
+    /// See [`Cache::remove`].
+    pub fn remove(&self, key: &K) -> Option<D> {
+        self.cache.remove(key)?.unpack()
+    }
+
+    /// See [`Cache::len`].
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// See [`Cache::is_empty`].
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// See [`Cache::memory_usage`]; values count with their stored,
+    /// compressed size.
+    pub fn memory_usage(&self) -> usize {
+        self.cache.memory_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> CompressedCache<u32, String> {
+        CompressedCache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            1024,
+            |key: &u32, data: &mut String, _: &mut u8| {
+                *data = format!("<p>{key}</p>").repeat(*key as usize);
+                *key != 0
+            },
+        )
+    }
+
+    #[test]
+    fn large_values_take_less_memory() {
+        let cache = cache();
+        let (page, success, _) = cache.retrieve_or_compute(&1000);
+        assert!(success);
+        assert_eq!(page.len(), 11_000);
+        assert_eq!(cache.get(&1000), Some(page));
+        let compressed = cache.memory_usage();
+        assert!(compressed < 4_000, "{compressed} bytes");
+
+        cache.insert(1, &"tiny".to_string());
+        assert_eq!(cache.get(&1).as_deref(), Some("tiny"));
+        assert_eq!(cache.retrieve_or_compute(&0), (String::new(), false, 0));
+        assert_eq!(cache.remove(&1000).map(|page| page.len()), Some(11_000));
+        assert_eq!(cache.len(), 2);
+    }
+}
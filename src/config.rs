@@ -0,0 +1,187 @@
+//! Capacity limits and configuration errors.
+
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+
+/// How many entries a cache may hold in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capacity {
+    /// At most this many entries; the least recently used one is evicted
+    /// to make room. Zero is invalid.
+    Bounded(usize),
+    /// No limit: entries only go away when removed, invalidated or expired,
+    /// for workloads whose working set is bounded by the TTLs alone.
+    /// Expired entries are swept as the cache grows.
+    Unbounded,
+}
+
+impl From<usize> for Capacity {
+    fn from(size: usize) -> Self {
+        Capacity::Bounded(size)
+    }
+}
+
+impl Capacity {
+    pub(crate) fn of<K, V, S>(cache: &LruCache<K, V, S>) -> Self
+    where
+        K: Hash + Eq,
+        S: BuildHasher,
+    {
+        match cache.cap() {
+            NonZeroUsize::MAX => Capacity::Unbounded,
+            cap => Capacity::Bounded(cap.get()),
+        }
+    }
+
+    /// Creates an empty LRU with this capacity.
+    pub(crate) fn lru<K, V, S>(self, hasher: S) -> Result<LruCache<K, V, S>, ConfigError>
+    where
+        K: Hash + Eq,
+        S: BuildHasher,
+    {
+        match self {
+            Capacity::Bounded(_) => Ok(LruCache::with_hasher(self.limit()?, hasher)),
+            Capacity::Unbounded => Ok(LruCache::unbounded_with_hasher(hasher)),
+        }
+    }
+
+    pub(crate) fn limit(self) -> Result<NonZeroUsize, ConfigError> {
+        match self {
+            Capacity::Bounded(size) => NonZeroUsize::new(size).ok_or(ConfigError::ZeroCapacity),
+            Capacity::Unbounded => Ok(NonZeroUsize::MAX),
+        }
+    }
+}
+
+/// One limit on what a cache may hold, set with
+/// [`CacheBuilder::bound`](crate::CacheBuilder::bound).
+///
+/// Bounds combine: whichever is reached first evicts the least recently
+/// used entries. Zero is invalid for every unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bound {
+    /// At most this many entries.
+    Entries(usize),
+    /// At most this total weight, as reported by the
+    /// [`weigher`](crate::CacheBuilder::weigher).
+    Weight(u64),
+    /// At most this many bytes; see
+    /// [`Cache::memory_usage`](crate::Cache::memory_usage).
+    Bytes(usize),
+}
+
+/// Why a [`CacheBuilder`](crate::CacheBuilder) could not build a cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The capacity is `Capacity::Bounded(0)`, or a [`Bound`] or per-entry
+    /// limit is zero.
+    ZeroCapacity,
+    /// No miss handler was set.
+    MissingMissHandler,
+    /// Write-behind was enabled without a store handler.
+    WriteBehindWithoutStoreHandler,
+    /// A [`Bound::Weight`] or per-entry weight limit was set without a
+    /// weigher.
+    WeightWithoutWeigher,
+    /// Generations were set to rotate on a zero period.
+    ZeroGenerationPeriod,
+    /// A [`RetryPolicy`](crate::RetryPolicy) has a jitter outside 0.0 to
+    /// 1.0, or zero maximum attempts.
+    InvalidRetryPolicy,
+    /// The TTL jitter is outside 0.0 to 1.0.
+    InvalidTtlJitter,
+    /// A limit on concurrent loads or on the load rate is zero.
+    ZeroLoadLimit,
+    /// The stats window has a zero interval or no intervals.
+    ZeroStatsWindow,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConfigError::ZeroCapacity => "the capacity must not be zero",
+            ConfigError::MissingMissHandler => "a miss handler is required",
+            ConfigError::WriteBehindWithoutStoreHandler => "write-behind requires a store handler",
+            ConfigError::WeightWithoutWeigher => "a weight bound requires a weigher",
+            ConfigError::ZeroGenerationPeriod => "the generation period must not be zero",
+            ConfigError::InvalidRetryPolicy => {
+                "the retry jitter must be within 0.0 to 1.0 and the attempts above zero"
+            }
+            ConfigError::InvalidTtlJitter => "the TTL jitter must be within 0.0 to 1.0",
+            ConfigError::ZeroLoadLimit => "load limits must not be zero",
+            ConfigError::ZeroStatsWindow => "the stats window must not be empty",
+        })
+    }
+}
+
+impl Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cache;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn invalid_configs_are_reported() {
+        let zero = Cache::<u32, u32>::builder(0)
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| true)
+            .try_build();
+        assert_eq!(zero.err(), Some(ConfigError::ZeroCapacity));
+
+        let unhandled = Cache::<u32, u32>::builder(1).try_build();
+        assert_eq!(unhandled.err(), Some(ConfigError::MissingMissHandler));
+    }
+
+    #[test]
+    fn unbounded_caches_sweep_expired_entries() {
+        let cache = Cache::builder(Capacity::Unbounded)
+            .positive_ttl(Duration::from_millis(20))
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| true)
+            .build();
+        assert_eq!(cache.capacity(), Capacity::Unbounded);
+        for key in 0..1000 {
+            cache.insert(key, key);
+        }
+        assert_eq!(cache.len(), 1000);
+
+        thread::sleep(Duration::from_millis(30));
+        for key in 1000..1100 {
+            cache.insert(key, key);
+        }
+        assert!(cache.len() < 1000);
+        assert_eq!(cache.get(&1050), Some(1050));
+    }
+
+    #[test]
+    fn the_first_bound_reached_evicts() {
+        let cache = Cache::builder(Capacity::Unbounded)
+            .positive_ttl(Duration::from_secs(60))
+            .miss_handler(|_: &u32, _: &mut Vec<u32>, _: &mut u8| true)
+            .weigher(|_: &u32, rows: &Vec<u32>| rows.len() as u64)
+            .bound(Bound::Entries(3))
+            .bound(Bound::Weight(10))
+            .build();
+        assert_eq!(cache.capacity(), Capacity::Bounded(3));
+        for key in 0..4 {
+            cache.insert(key, vec![0; 2]);
+        }
+        assert_eq!((cache.len(), cache.total_weight()), (3, 6));
+
+        cache.insert(4, vec![0; 7]);
+        assert_eq!((cache.len(), cache.total_weight()), (2, 9));
+        assert_eq!(cache.get(&1), None);
+
+        let unweighed = Cache::<u32, u32>::builder(1)
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| true)
+            .bound(Bound::Weight(1))
+            .try_build();
+        assert_eq!(unweighed.err(), Some(ConfigError::WeightWithoutWeigher));
+    }
+}
@@ -0,0 +1,79 @@
+//! Resolving races between inserts and in-flight computations.
+
+/// Which value survives when [`Cache::insert`](crate::Cache::insert) writes a
+/// key while the miss handler is computing it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The computation overwrites the inserted value when it completes.
+    #[default]
+    LastWriteWins,
+    /// The inserted value is kept and the computed one is discarded;
+    /// the caller that ran the computation receives the inserted value.
+    FirstWriteWins,
+}
+
+/// Function called with the value that lost a write conflict.
+pub type ConflictListener<K, D> = dyn Fn(&K, &D) + Send + Sync;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cache;
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    type Losers = Arc<Mutex<Vec<u32>>>;
+
+    fn racing_cache(policy: ConflictPolicy) -> (Arc<Cache<u32, u32>>, Losers) {
+        let losers = Losers::default();
+        let sink = losers.clone();
+        let cache = Cache::builder(10)
+            .miss_handler(|_: &u32, data: &mut u32, _: &mut u8| {
+                thread::sleep(Duration::from_millis(50));
+                *data = 1;
+                true
+            })
+            .conflict_policy(policy)
+            .conflict_listener(move |_: &u32, loser: &u32| sink.lock().unwrap().push(*loser))
+            .build();
+        (Arc::new(cache), losers)
+    }
+
+    fn race(cache: &Arc<Cache<u32, u32>>) -> (u32, bool, u8) {
+        let barrier = Arc::new(Barrier::new(2));
+        let (loader_cache, loader_barrier) = (cache.clone(), barrier.clone());
+        let loader = thread::spawn(move || {
+            loader_barrier.wait();
+            loader_cache.retrieve_or_compute(&7)
+        });
+        barrier.wait();
+        thread::sleep(Duration::from_millis(10));
+        cache.insert(7, 2);
+        loader.join().unwrap()
+    }
+
+    #[test]
+    fn last_write_wins_keeps_the_computed_value() {
+        let (cache, losers) = racing_cache(ConflictPolicy::LastWriteWins);
+        assert_eq!(race(&cache), (1, true, 0));
+        assert_eq!(cache.get(&7), Some(1));
+        assert_eq!(*losers.lock().unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn first_write_wins_keeps_the_inserted_value() {
+        let (cache, losers) = racing_cache(ConflictPolicy::FirstWriteWins);
+        assert_eq!(race(&cache), (2, true, 0));
+        assert_eq!(cache.get(&7), Some(2));
+        assert_eq!(*losers.lock().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn refresh_without_concurrent_writes_is_not_a_conflict() {
+        let (cache, losers) = racing_cache(ConflictPolicy::FirstWriteWins);
+        cache.insert(7, 2);
+        assert_eq!(cache.refresh(&7), (1, true, 0));
+        assert!(losers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+//! Collecting misses that occur close together into one batch load, as
+//! DataLoader does for the N+1 queries of GraphQL resolvers.
+//!
+//! The first caller to miss opens a batch and waits for the configured
+//! window; every key missed by other callers in the meantime joins it.
+//! The first caller then loads the whole batch in one call to the batch
+//! loader and hands each waiting caller its outcome.
+
+use std::any::Any;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::cache::Cache;
+use crate::clock::Clock;
+use crate::lock::MutexExt;
+use crate::unwind::LoadPanicked;
+
+/// Outcomes of a batch, in the order its keys joined.
+type Outcomes<D> = Result<Vec<(D, bool, u8)>, LoadPanicked>;
+
+/// The batch currently collecting misses, if any.
+pub(crate) struct Debouncer<K, D> {
+    window: Duration,
+    open: Mutex<Option<Arc<Batch<K, D>>>>,
+}
+
+struct Batch<K, D> {
+    state: Mutex<BatchState<K, D>>,
+    loaded: Condvar,
+}
+
+struct BatchState<K, D> {
+    /// Keys that joined, each with the write sequence number of its
+    /// placeholder.
+    claimed: Vec<(K, u64)>,
+    outcomes: Option<Outcomes<D>>,
+}
+
+impl<K, D> Debouncer<K, D> {
+    pub(crate) fn new(window: Duration) -> Self {
+        Debouncer {
+            window,
+            open: Mutex::new(None),
+        }
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Computes the claimed `key` as part of the open batch, opening one
+    /// and loading it after the window if there is none.
+    ///
+    /// If the loader panics, the caller that loaded the batch gets the
+    /// panic and every other caller of the batch gets [`LoadPanicked`].
+    pub(crate) fn compute_batched(
+        &self,
+        debouncer: &Debouncer<K, D>,
+        key: &K,
+        started: u64,
+    ) -> Result<(D, bool, u8), Box<dyn Any + Send>> {
+        let (batch, index) = {
+            let mut open = debouncer.open.lock_or_recover();
+            match &*open {
+                Some(batch) => {
+                    let mut state = batch.state.lock_or_recover();
+                    state.claimed.push((key.clone(), started));
+                    (batch.clone(), Some(state.claimed.len() - 1))
+                }
+                None => {
+                    let batch = Arc::new(Batch {
+                        state: Mutex::new(BatchState {
+                            claimed: vec![(key.clone(), started)],
+                            outcomes: None,
+                        }),
+                        loaded: Condvar::new(),
+                    });
+                    *open = Some(batch.clone());
+                    (batch, None)
+                }
+            }
+        };
+        match index {
+            Some(index) => {
+                let mut state = batch.state.lock_or_recover();
+                while state.outcomes.is_none() {
+                    state = batch
+                        .loaded
+                        .wait(state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+                match state.outcomes.as_ref().expect("the batch was loaded") {
+                    Ok(outcomes) => Ok(outcomes[index].clone()),
+                    Err(LoadPanicked) => Err(Box::new(LoadPanicked)),
+                }
+            }
+            None => {
+                thread::sleep(debouncer.window);
+                debouncer.open.lock_or_recover().take();
+                let claimed = mem::take(&mut batch.state.lock_or_recover().claimed);
+                let (keys, started): (Vec<K>, Vec<u64>) = claimed.into_iter().unzip();
+                let loaded = self.load_many(&keys, &started);
+                let mut state = batch.state.lock_or_recover();
+                state.outcomes = Some(match &loaded {
+                    Ok(outcomes) => Ok(outcomes.clone()),
+                    Err(_) => Err(LoadPanicked),
+                });
+                batch.loaded.notify_all();
+                loaded.map(|mut outcomes| outcomes.swap_remove(0))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn misses_within_the_window_are_loaded_together() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let seen = batches.clone();
+        let cache = Arc::new(
+            Cache::builder(100)
+                .positive_ttl(Duration::from_secs(60))
+                .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| unreachable!())
+                .batch_loader(
+                    move || {
+                        seen.lock().unwrap().push(0);
+                    },
+                    |_: &mut (), key: &u32, data: &mut u32, _: &mut u8| {
+                        *data = key * 10;
+                        *key != 0
+                    },
+                )
+                .batch_window(Duration::from_millis(100))
+                .build(),
+        );
+        let start = Arc::new(Barrier::new(8));
+        let callers: Vec<_> = (0..8)
+            .map(|key| {
+                let (cache, start) = (cache.clone(), start.clone());
+                thread::spawn(move || {
+                    start.wait();
+                    cache.retrieve_or_compute(&key)
+                })
+            })
+            .collect();
+        let outcomes: Vec<_> = callers
+            .into_iter()
+            .map(|caller| caller.join().unwrap())
+            .collect();
+        assert_eq!(outcomes[0], (0, false, 0));
+        for (key, outcome) in outcomes.iter().enumerate().skip(1) {
+            assert_eq!(*outcome, (key as u32 * 10, true, 0));
+        }
+        assert_eq!(batches.lock().unwrap().len(), 1);
+        assert_eq!(cache.get(&7), Some(70));
+    }
+}
@@ -0,0 +1,93 @@
+//! Switching the loader off at runtime to isolate a failing backend.
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::Ordering;
+
+use lru::LruCache;
+
+use crate::cache::{Cache, CacheEntry, EntryStatus, Lookup};
+use crate::clock::Clock;
+use crate::time::Instant;
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Enables or disables the miss handler, e.g. from an admin endpoint
+    /// while the backend behind it is failing.
+    ///
+    /// While disabled, [`retrieve_or_compute`](Self::retrieve_or_compute)
+    /// and its variants behave as plain lookups: live values are served,
+    /// then expired values still held in memory (unless invalidated), and
+    /// true misses fail with `(D::default(), false, 0)` without caching
+    /// the failure, so re-enabling takes effect at once. Computations
+    /// already running are waited for as usual; refreshes serve the cached
+    /// value instead.
+    pub fn set_loader_enabled(&self, enabled: bool) {
+        self.loader_enabled.store(enabled, Ordering::Release);
+    }
+
+    /// Whether the miss handler may run; see
+    /// [`set_loader_enabled`](Self::set_loader_enabled).
+    pub fn loader_enabled(&self) -> bool {
+        self.loader_enabled.load(Ordering::Acquire)
+    }
+
+    /// What a lookup that would compute `key` gets with the loader
+    /// disabled: the expired value still held, or nothing.
+    pub(crate) fn without_loader(
+        &self,
+        cache: &LruCache<K, CacheEntry<D>, S>,
+        key: &K,
+        now: Instant,
+    ) -> Lookup<D> {
+        match cache.peek(key) {
+            Some(entry) if entry.status == EntryStatus::Ready && self.is_current(entry, now) => {
+                Lookup::Found((entry.data.clone(), true, entry.adhoc_code))
+            }
+            _ => Lookup::Unavailable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, ManualClock};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn disabled_loader_serves_what_is_cached() {
+        let clock = Arc::new(ManualClock::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = {
+            let calls = calls.clone();
+            Cache::builder(10)
+                .positive_ttl(Duration::from_secs(10))
+                .clock(clock.clone())
+                .miss_handler(move |key: &u32, data: &mut u32, _: &mut u8| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    *data = *key;
+                    true
+                })
+                .build()
+        };
+        cache.retrieve_or_compute(&1);
+        cache.set_loader_enabled(false);
+        clock.advance(Duration::from_secs(10));
+
+        assert_eq!(cache.retrieve_or_compute(&1), (1, true, 0));
+        assert_eq!(cache.retrieve_or_compute(&2), (0, false, 0));
+        assert_eq!(cache.refresh(&1), (1, true, 0));
+        assert_eq!(cache.retrieve_or_compute_many(&[1, 2]).len(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        cache.set_loader_enabled(true);
+        assert_eq!(cache.retrieve_or_compute(&2), (2, true, 0));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,588 @@
+//! Append-only file used as the second tier behind the in-memory LRU.
+//!
+//! Values are serialized with bincode and appended to a single file; an
+//! in-memory index maps encoded keys to their location and metadata. Space
+//! left behind by removed, promoted or expired entries is reclaimed by
+//! rewriting the file once it is mostly dead.
+//!
+//! The file is truncated when the tier opens it and held under an
+//! exclusive `flock` while it is open, so a path already used by another
+//! cache is refused rather than wiped.
+
+use std::collections::HashMap;
+use std::fs::{self, File, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::checksum::{crc32, Corrupted};
+use crate::codec::KeyCodec;
+use crate::lock::MutexExt;
+use crate::tier::{SpillTier, SpilledEntry};
+use crate::time::Instant;
+
+/// Files smaller than this are never compacted.
+const COMPACT_MIN_BYTES: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    offset: u64,
+    len: u64,
+    adhoc_code: u8,
+    expiration: Instant,
+    version: u32,
+    /// CRC-32 of the value, verified on every read.
+    checksum: u32,
+}
+
+struct DiskLog {
+    path: PathBuf,
+    file: File,
+    index: HashMap<Vec<u8>, Slot>,
+    file_len: u64,
+    /// Bytes of the indexed slots, including those that expired since
+    /// they were last looked for.
+    live_len: u64,
+    /// File length after the last search for expired slots.
+    swept_len: u64,
+}
+
+/// Takes the `flock` of the tier file, held until the file is closed.
+fn lock(file: &File) -> io::Result<()> {
+    file.try_lock().map_err(|error| match error {
+        TryLockError::WouldBlock => io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "the disk tier file is open in another cache",
+        ),
+        TryLockError::Error(error) => error,
+    })
+}
+
+impl DiskLog {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        lock(&file)?;
+        file.set_len(0)?;
+        Ok(DiskLog {
+            path: path.to_path_buf(),
+            file,
+            index: HashMap::new(),
+            file_len: 0,
+            live_len: 0,
+            swept_len: 0,
+        })
+    }
+
+    fn append(
+        &mut self,
+        key: Vec<u8>,
+        value: &[u8],
+        adhoc_code: u8,
+        expiration: Instant,
+        version: u32,
+        now: Instant,
+    ) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.file_len))?;
+        self.file.write_all(value)?;
+        let slot = Slot {
+            offset: self.file_len,
+            len: value.len() as u64,
+            adhoc_code,
+            expiration,
+            version,
+            checksum: crc32(value),
+        };
+        self.file_len += slot.len;
+        self.live_len += slot.len;
+        if let Some(old) = self.index.insert(key, slot) {
+            self.live_len -= old.len;
+        }
+        self.maybe_compact(now)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Slot> {
+        let slot = self.index.remove(key)?;
+        self.live_len -= slot.len;
+        Some(slot)
+    }
+
+    fn read(&mut self, slot: Slot) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; slot.len as usize];
+        self.file.seek(SeekFrom::Start(slot.offset))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Reads the value of `slot`, or `Corrupted` if it no longer matches
+    /// its checksum.
+    fn read_verified(&mut self, slot: Slot) -> Result<Option<Vec<u8>>, Corrupted> {
+        match self.read(slot) {
+            Ok(value) if crc32(&value) == slot.checksum => Ok(Some(value)),
+            Ok(_) => Err(Corrupted),
+            // Truncated under us, which no writer of ours does either.
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Err(Corrupted),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.index.clear();
+        self.file.set_len(0)?;
+        self.file_len = 0;
+        self.live_len = 0;
+        self.swept_len = 0;
+        Ok(())
+    }
+
+    /// Compacts the file once it is mostly dead. Expired slots are only
+    /// looked for when the file has doubled since the last search, for an
+    /// amortized O(1) cost per append.
+    fn maybe_compact(&mut self, now: Instant) -> io::Result<()> {
+        if self.file_len < COMPACT_MIN_BYTES {
+            return Ok(());
+        }
+        if self.file_len > 2 * self.swept_len {
+            self.drop_expired(now);
+        }
+        if self.file_len > 2 * self.live_len {
+            self.compact(now)?;
+        }
+        Ok(())
+    }
+
+    /// Unindexes the slots that expired by `now`.
+    fn drop_expired(&mut self, now: Instant) {
+        let live_len = &mut self.live_len;
+        self.index.retain(|_, slot| {
+            let live = slot.expiration > now;
+            if !live {
+                *live_len -= slot.len;
+            }
+            live
+        });
+        self.swept_len = self.file_len;
+    }
+
+    /// Rewrites the entries that are still live at `now` into a fresh file
+    /// and swaps it in.
+    ///
+    /// The index only moves to the offsets of the new file once it has
+    /// replaced the old one, so that a failure at any step leaves the log
+    /// as it was, bar the expired slots.
+    fn compact(&mut self, now: Instant) -> io::Result<()> {
+        self.drop_expired(now);
+        let tmp_path = self.path.with_extension("compact");
+        let mut tmp = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        lock(&tmp)?;
+        let mut offset = 0;
+        let mut index = HashMap::with_capacity(self.index.len());
+        let slots: Vec<(Vec<u8>, Slot)> = self.index.iter().map(|(k, s)| (k.clone(), *s)).collect();
+        for (key, slot) in slots {
+            let value = self.read(slot)?;
+            tmp.write_all(&value)?;
+            index.insert(key, Slot { offset, ..slot });
+            offset += slot.len;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = tmp;
+        self.index = index;
+        self.file_len = offset;
+        self.live_len = offset;
+        self.swept_len = offset;
+        Ok(())
+    }
+}
+
+/// Encodes keys into the names they are indexed under.
+type KeyEncoder<K> = dyn Fn(&K) -> Option<Vec<u8>> + Send + Sync;
+
+/// Decodes the names produced by a [`KeyEncoder`] back into keys.
+type KeyDecoder<K> = dyn Fn(&[u8]) -> Option<K> + Send + Sync;
+
+/// Spill tier backed by a [`DiskLog`].
+///
+/// I/O and serialization errors are treated as misses: the tier only ever
+/// holds data that can be recomputed.
+pub(crate) struct DiskTier<K, D> {
+    log: Mutex<DiskLog>,
+    encode_key: Box<KeyEncoder<K>>,
+    decode_key: Box<KeyDecoder<K>>,
+    _marker: PhantomData<fn(D)>,
+}
+
+impl<K: Serialize + DeserializeOwned, D> DiskTier<K, D> {
+    /// Opens a tier whose keys are encoded with bincode.
+    pub(crate) fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        DiskTier::open_with(
+            path,
+            Box::new(|key: &K| encode(key)),
+            Box::new(|bytes: &[u8]| decode(bytes)),
+        )
+    }
+}
+
+impl<K, D> DiskTier<K, D> {
+    /// Opens a tier whose keys are encoded by `codec`.
+    pub(crate) fn open_with_codec<C>(path: impl AsRef<Path>, codec: C) -> io::Result<Self>
+    where
+        C: KeyCodec<K> + 'static,
+    {
+        let codec = Arc::new(codec);
+        let decoder = codec.clone();
+        DiskTier::open_with(
+            path,
+            Box::new(move |key: &K| Some(codec.encode(key))),
+            Box::new(move |bytes: &[u8]| decoder.decode(bytes)),
+        )
+    }
+
+    fn open_with(
+        path: impl AsRef<Path>,
+        encode_key: Box<KeyEncoder<K>>,
+        decode_key: Box<KeyDecoder<K>>,
+    ) -> io::Result<Self> {
+        Ok(DiskTier {
+            log: Mutex::new(DiskLog::open(path.as_ref())?),
+            encode_key,
+            decode_key,
+            _marker: PhantomData,
+        })
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> Option<Vec<u8>> {
+    bincode::serde::encode_to_vec(value, bincode::config::standard()).ok()
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Option<T> {
+    bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+        .ok()
+        .map(|(value, _)| value)
+}
+
+impl<K, D> SpillTier<K, D> for DiskTier<K, D>
+where
+    D: Serialize + DeserializeOwned,
+{
+    fn spill(&self, key: &K, entry: SpilledEntry<D>, now: Instant) {
+        let (Some(key), Some(value)) = ((self.encode_key)(key), encode(&entry.data)) else {
+            return;
+        };
+        let mut log = self.log.lock_or_recover();
+        if log
+            .append(
+                key.clone(),
+                &value,
+                entry.adhoc_code,
+                entry.expiration,
+                entry.version,
+                now,
+            )
+            .is_err()
+        {
+            log.remove(&key);
+        }
+    }
+
+    fn take(&self, key: &K, now: Instant) -> Result<Option<SpilledEntry<D>>, Corrupted> {
+        let Some(key) = (self.encode_key)(key) else {
+            return Ok(None);
+        };
+        let mut log = self.log.lock_or_recover();
+        let Some(slot) = log.remove(&key) else {
+            return Ok(None);
+        };
+        if slot.expiration <= now {
+            return Ok(None);
+        }
+        let Some(value) = log.read_verified(slot)? else {
+            return Ok(None);
+        };
+        Ok(decode(&value).map(|data| SpilledEntry {
+            data,
+            adhoc_code: slot.adhoc_code,
+            expiration: slot.expiration,
+            version: slot.version,
+        }))
+    }
+
+    fn remove(&self, key: &K) {
+        if let Some(key) = (self.encode_key)(key) {
+            self.log.lock_or_recover().remove(&key);
+        }
+    }
+
+    /// Reads the entries back one at a time, taking the lock for each so
+    /// that spills and promotions are not held up by a long pass.
+    fn retain(&self, f: &mut dyn FnMut(&K, &SpilledEntry<D>) -> bool) {
+        let names: Vec<Vec<u8>> = self.log.lock_or_recover().index.keys().cloned().collect();
+        for name in names {
+            let mut log = self.log.lock_or_recover();
+            let Some(&slot) = log.index.get(&name) else {
+                continue;
+            };
+            let data = log.read_verified(slot).ok().flatten();
+            let kept = match (
+                (self.decode_key)(&name),
+                data.and_then(|data| decode(&data)),
+            ) {
+                (Some(key), Some(data)) => f(
+                    &key,
+                    &SpilledEntry {
+                        data,
+                        adhoc_code: slot.adhoc_code,
+                        expiration: slot.expiration,
+                        version: slot.version,
+                    },
+                ),
+                _ => false,
+            };
+            if !kept {
+                log.remove(&name);
+            }
+        }
+    }
+
+    fn clear(&self) {
+        let _ = self.log.lock_or_recover().clear();
+    }
+
+    fn len(&self) -> usize {
+        self.log.lock_or_recover().index.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cache, StrKeys};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "rust-cache-{}-{}-{}.l2",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    fn disk_cache(path: &Path, positive_ttl: Duration) -> Cache<u32, String> {
+        Cache::builder(2)
+            .positive_ttl(positive_ttl)
+            .miss_handler(|key: &u32, data: &mut String, _: &mut u8| {
+                *data = format!("computed-{key}");
+                true
+            })
+            .disk_tier(path)
+            .unwrap()
+            .build()
+    }
+
+    #[test]
+    fn evicted_entries_spill_and_promote_back() {
+        let path = temp_path("promote");
+        let cache = disk_cache(&path, Duration::from_secs(60));
+        cache.insert(1, "one".to_string());
+        cache.insert(2, "two".to_string());
+        cache.insert(3, "three".to_string());
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.l2_len(), 1);
+        assert_eq!(cache.get(&1), Some("one".to_string()));
+        // Promoting 1 evicted 2, which now lives on disk instead.
+        assert_eq!(cache.l2_len(), 1);
+        assert_eq!(cache.retrieve_or_compute(&2), ("two".to_string(), true, 0));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn retain_tests_spilled_entries() {
+        let path = temp_path("retain");
+        let cache = disk_cache(&path, Duration::from_secs(60));
+        for (key, data) in [(1, "one"), (2, "two"), (3, "three"), (4, "four")] {
+            cache.insert(key, data.to_string());
+        }
+        assert_eq!(cache.l2_len(), 2);
+
+        cache.invalidate_entries_if(|key, _| *key == 1);
+        assert_eq!(cache.l2_len(), 1);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some("two".to_string()));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn expired_spilled_entries_are_recomputed() {
+        let path = temp_path("expired");
+        let cache = disk_cache(&path, Duration::from_millis(30));
+        cache.insert(1, "one".to_string());
+        cache.insert(2, "two".to_string());
+        cache.insert(3, "three".to_string());
+        std::thread::sleep(Duration::from_millis(40));
+
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.l2_len(), 0);
+        assert_eq!(cache.retrieve_or_compute(&1).0, "computed-1");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_entries_are_reported_as_misses() {
+        let path = temp_path("corrupt");
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let listener = reported.clone();
+        let cache = Cache::builder(1)
+            .positive_ttl(Duration::from_secs(60))
+            .miss_handler(|key: &u32, data: &mut String, _: &mut u8| {
+                *data = format!("computed-{key}");
+                true
+            })
+            .on_corruption(move |key: &u32| listener.lock().unwrap().push(*key))
+            .disk_tier(&path)
+            .unwrap()
+            .build();
+        cache.insert(1, "one".to_string());
+        cache.insert(2, "two".to_string());
+        // Another writer scribbles over the spilled value of 1.
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.write_all(b"\xff").unwrap();
+
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.corruptions(), 1);
+        assert_eq!(*reported.lock().unwrap(), [1]);
+        assert_eq!(cache.retrieve_or_compute(&1).0, "computed-1");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn codec_keys_index_the_log() {
+        let path = temp_path("codec");
+        let tier: DiskTier<u32, String> = DiskTier::open_with_codec(&path, StrKeys).unwrap();
+        let expiration = Instant::now() + Duration::from_secs(60);
+        let entry = SpilledEntry {
+            data: "seven".to_string(),
+            adhoc_code: 0,
+            expiration,
+            version: 0,
+        };
+        tier.spill(&7, entry, Instant::now());
+
+        assert!(tier.log.lock().unwrap().index.contains_key(&b"7"[..]));
+        assert_eq!(
+            tier.take(&7, Instant::now()).unwrap().unwrap().data,
+            "seven"
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn compaction_keeps_live_entries() {
+        let path = temp_path("compact");
+        let tier: DiskTier<u32, String> = DiskTier::open(&path).unwrap();
+        let now = Instant::now();
+        for key in 0..15 {
+            let ttl = if key < 10 { 60 } else { 0 };
+            tier.spill(
+                &key,
+                SpilledEntry {
+                    data: key.to_string(),
+                    adhoc_code: 1,
+                    expiration: now + Duration::from_secs(ttl),
+                    version: 0,
+                },
+                now,
+            );
+        }
+        for key in 0..5 {
+            tier.remove(&key);
+        }
+        let mut log = tier.log.lock().unwrap();
+        log.compact(now).unwrap();
+        let live: u64 = log.index.values().map(|slot| slot.len).sum();
+        assert_eq!((log.file_len, log.live_len), (live, live));
+        drop(log);
+
+        assert_eq!(tier.len(), 5);
+        for key in 5..10 {
+            assert_eq!(
+                tier.take(&key, Instant::now()).unwrap().unwrap().data,
+                key.to_string()
+            );
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn failed_compaction_leaves_the_log_as_it_was() {
+        let path = temp_path("compact-fail");
+        let mut log = DiskLog::open(&path).unwrap();
+        let expiration = Instant::now() + Duration::from_secs(60);
+        for key in 0..10u8 {
+            let value = vec![key; usize::from(key) + 1];
+            log.append(vec![key], &value, 0, expiration, 0, Instant::now())
+                .unwrap();
+        }
+        let past_the_end = Slot {
+            offset: log.file_len,
+            len: 8,
+            adhoc_code: 0,
+            expiration,
+            version: 0,
+            checksum: 0,
+        };
+        log.index.insert(b"lost".to_vec(), past_the_end);
+
+        assert!(log.compact(Instant::now()).is_err());
+        log.index.remove(b"lost".as_slice());
+        for key in 0..10u8 {
+            let slot = log.index[&vec![key]];
+            assert_eq!(
+                log.read_verified(slot),
+                Ok(Some(vec![key; usize::from(key) + 1]))
+            );
+        }
+        let _ = fs::remove_file(path.with_extension("compact"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn files_in_use_are_refused() {
+        let path = temp_path("in-use");
+        let tier: DiskTier<u32, String> = DiskTier::open(&path).unwrap();
+        let expiration = Instant::now() + Duration::from_secs(60);
+        let entry = SpilledEntry {
+            data: "one".to_string(),
+            adhoc_code: 0,
+            expiration,
+            version: 0,
+        };
+        tier.spill(&1, entry, Instant::now());
+        tier.log.lock().unwrap().compact(Instant::now()).unwrap();
+
+        let error = DiskTier::<u32, String>::open(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(tier.len(), 1);
+        drop(tier);
+        assert!(DiskTier::<u32, String>::open(&path).is_ok());
+        fs::remove_file(path).unwrap();
+    }
+}
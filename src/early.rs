@@ -0,0 +1,126 @@
+//! Probabilistic early expiration ("XFetch"), to prevent stampedes.
+//!
+//! When a hot key expires, every caller misses at once. With early
+//! expiration enabled, each hit on a value close to its expiration has a
+//! chance of being chosen to recompute it, growing as expiration nears and
+//! with the time the value took to compute. The chosen caller recomputes
+//! while everyone else keeps getting the cached value, so the entry is
+//! usually replaced before it ever expires.
+//!
+//! See Vattani, Chierichetti and Lowenstein, "Optimal Probabilistic Cache
+//! Stampede Prevention", VLDB 2015.
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+
+use crate::cache::{Cache, CacheEntry, EntryStatus};
+use crate::clock::Clock;
+use crate::time::Instant;
+
+thread_local! {
+    static RNG: Cell<u64> = Cell::new(RandomState::new().hash_one(0u8) | 1);
+}
+
+/// Uniform sample in (0, 1], from a per-thread xorshift generator.
+pub(crate) fn sample() -> f64 {
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        rng.set(x);
+        ((x >> 11) + 1) as f64 / (1u64 << 53) as f64
+    })
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Decides whether the caller that just hit `entry` recomputes it
+    /// ahead of its expiration, marking it as being refreshed if so.
+    ///
+    /// Entries that were inserted rather than computed have no load time
+    /// and are never refreshed early.
+    pub(crate) fn refresh_early(&self, entry: &mut CacheEntry<D>, now: Instant) -> bool {
+        let Some(beta) = self.early_expiration else {
+            return false;
+        };
+        if !self.loader_enabled() {
+            return false;
+        }
+        if entry.status != EntryStatus::Ready || entry.refreshing {
+            return false;
+        }
+        let remaining = entry.expiration.saturating_duration_since(now);
+        let lead = entry.load_time.as_secs_f64() * beta * -sample().ln();
+        entry.refreshing = lead >= remaining.as_secs_f64();
+        entry.refreshing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn cache(beta: f64, calls: Arc<AtomicUsize>) -> Arc<Cache<u32, usize>> {
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .early_expiration(beta)
+            .miss_handler(move |_: &u32, data: &mut usize, _: &mut u8| {
+                *data = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                thread::sleep(Duration::from_millis(20));
+                true
+            })
+            .build();
+        Arc::new(cache)
+    }
+
+    #[test]
+    fn samples_are_in_the_unit_interval() {
+        for _ in 0..10_000 {
+            let u = sample();
+            assert!(u > 0.0 && u <= 1.0);
+        }
+    }
+
+    #[test]
+    fn one_caller_refreshes_while_others_hit() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        // A beta this large refreshes on the first hit in all but a
+        // vanishing fraction of runs.
+        let cache = cache(1e9, calls.clone());
+        assert_eq!(cache.retrieve_or_compute(&1).0, 1);
+
+        let refresher = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.retrieve_or_compute(&1).0)
+        };
+        while calls.load(Ordering::SeqCst) < 2 {
+            thread::yield_now();
+        }
+        assert_eq!(cache.retrieve_or_compute(&1).0, 1);
+        assert_eq!(refresher.join().unwrap(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn fresh_entries_are_not_refreshed() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = cache(1.0, calls.clone());
+        for _ in 0..100 {
+            cache.retrieve_or_compute(&1);
+        }
+        cache.insert(2, 0);
+        cache.retrieve_or_compute(&2);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,346 @@
+//! In-place manipulation of a single entry, à la `HashMap::entry`.
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::RwLockWriteGuard;
+use std::time::Duration;
+
+use lru::{DefaultHasher, LruCache};
+
+use crate::cache::{Cache, CacheEntry, EntryStatus, StoreError};
+use crate::clock::{Clock, SystemClock};
+use crate::lock::RwLockExt;
+use crate::oversize::Oversize;
+
+type Guard<'a, K, D, S> = RwLockWriteGuard<'a, LruCache<K, CacheEntry<D>, S>>;
+
+/// A view into a single key of the cache; returned by [`Cache::entry`].
+///
+/// The cache stays locked for as long as the view exists, so a
+/// read-modify-write through it is atomic with respect to other callers.
+/// Keep it short-lived, and do not call back into the cache while holding
+/// it.
+pub enum Entry<'a, K, D, S = DefaultHasher, C = SystemClock>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// The key has a successfully computed, unexpired value.
+    Occupied(OccupiedEntry<'a, K, D, S, C>),
+    /// The key has no value to serve: it is missing, expired, failed or
+    /// still being computed.
+    Vacant(VacantEntry<'a, K, D, S, C>),
+}
+
+/// A key with a live value; see [`Entry`].
+pub struct OccupiedEntry<'a, K, D, S = DefaultHasher, C = SystemClock>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    cache: &'a Cache<K, D, S, C>,
+    guard: Guard<'a, K, D, S>,
+    key: K,
+}
+
+/// A key without a value to serve; see [`Entry`].
+pub struct VacantEntry<'a, K, D, S = DefaultHasher, C = SystemClock>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    cache: &'a Cache<K, D, S, C>,
+    guard: Guard<'a, K, D, S>,
+    key: K,
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Locks the cache and returns a view of the entry for `key`, for
+    /// compound operations such as incrementing a counter without racing
+    /// other writers.
+    ///
+    /// Looking the key up through the view promotes it like
+    /// [`get`](Self::get). Values written through it are written through
+    /// to the store handler like [`insert`](Self::insert), and a value the
+    /// store rejects is not cached.
+    pub fn entry(&self, key: K) -> Entry<'_, K, D, S, C> {
+        self.store_finished();
+        let now = self.now();
+        let mut guard = self.lru_cache.write_or_recover();
+        self.migrate(&mut guard, &key);
+        self.revalidate(&mut guard, &key, now);
+        let occupied = match guard.get_mut(&key) {
+            Some(entry) if !self.is_live(entry, now) => {
+                self.expired(&key, entry, now);
+                self.unlink(&mut guard, &key);
+                false
+            }
+            Some(entry) if entry.status == EntryStatus::Ready => {
+                entry.accessed = now;
+                true
+            }
+            Some(_) => false,
+            None => false,
+        };
+        let occupied = occupied || self.promote_from_l2(&mut guard, &key, now).is_some();
+        if occupied {
+            Entry::Occupied(OccupiedEntry {
+                cache: self,
+                guard,
+                key,
+            })
+        } else {
+            Entry::Vacant(VacantEntry {
+                cache: self,
+                guard,
+                key,
+            })
+        }
+    }
+}
+
+impl<'a, K, D, S, C> Entry<'a, K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// The key of the entry.
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Returns the value, inserting `data` if there is none.
+    pub fn or_insert(self, data: D) -> D {
+        self.or_insert_with(|| data)
+    }
+
+    /// Returns the value, inserting the result of `f` if there is none.
+    pub fn or_insert_with(self, f: impl FnOnce() -> D) -> D {
+        match self {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => entry.insert(f()),
+        }
+    }
+
+    /// Returns the value, inserting the default value if there is none.
+    pub fn or_default(self) -> D {
+        self.or_insert_with(D::default)
+    }
+
+    /// Updates the value in place if there is one.
+    pub fn and_modify(self, f: impl FnOnce(&mut D)) -> Self {
+        match self {
+            Entry::Occupied(mut entry) => {
+                let mut data = entry.get().clone();
+                f(&mut data);
+                entry.insert(data);
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+}
+
+impl<K, D, S, C> OccupiedEntry<'_, K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    fn entry(&self) -> &CacheEntry<D> {
+        self.guard
+            .peek(&self.key)
+            .expect("occupied entries stay cached while locked")
+    }
+
+    /// The key of the entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// The cached value.
+    pub fn get(&self) -> &D {
+        &self.entry().data
+    }
+
+    /// Version of the value, which changes on every write; see
+    /// [`Cache::get_versioned`].
+    pub fn version(&self) -> u64 {
+        self.entry().seq
+    }
+
+    /// Replaces the value, keeping its expiration, and returns the old one.
+    ///
+    /// If the store handler rejects the new value, or it is over the
+    /// per-entry limits under
+    /// [`OversizePolicy::Reject`](crate::OversizePolicy::Reject), the entry
+    /// is left unchanged and `data` is returned instead.
+    pub fn insert(&mut self, data: D) -> D {
+        let rejected = data.clone();
+        self.try_insert(data).unwrap_or(rejected)
+    }
+
+    /// Like [`insert`](Self::insert), reporting the store handler's error,
+    /// or [`Oversize`].
+    pub(crate) fn try_insert(&mut self, data: D) -> Result<D, StoreError> {
+        let old = self.entry();
+        let mut entry = CacheEntry::new(data, EntryStatus::Ready, old.adhoc_code, old.expiration);
+        entry.tags = old.tags.clone();
+        let old = old.data.clone();
+        if self.cache.rejects(&self.key, &mut entry) {
+            return Err(Box::new(Oversize));
+        }
+        self.cache
+            .insert_locked(&mut self.guard, self.key.clone(), entry)?;
+        Ok(old)
+    }
+
+    /// Removes the entry and returns its value.
+    pub fn remove(mut self) -> D {
+        if let Some(l2) = &self.cache.l2 {
+            l2.remove(&self.key);
+        }
+        self.cache
+            .unlink(&mut self.guard, &self.key)
+            .expect("occupied entries stay cached while locked")
+            .data
+    }
+
+    /// Time left before the entry expires.
+    pub fn time_to_live(&self) -> Duration {
+        self.entry()
+            .expiration
+            .saturating_duration_since(self.cache.now())
+    }
+
+    /// Makes the entry expire `ttl` from now.
+    pub fn set_time_to_live(&mut self, ttl: Duration) {
+        let expiration = self.cache.now() + ttl;
+        if let Some(entry) = self.guard.peek_mut(&self.key) {
+            entry.expiration = expiration;
+            let seq = entry.seq;
+            self.cache
+                .index_expiration(&self.guard, self.key.clone(), expiration, seq);
+        }
+    }
+}
+
+impl<K, D, S, C> VacantEntry<'_, K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// The key of the entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Inserts `data` for the positive TTL and returns it.
+    pub fn insert(self, data: D) -> D {
+        let _ = self.try_insert(data.clone());
+        data
+    }
+
+    /// Inserts `data` for `ttl` and returns it.
+    pub fn insert_with_ttl(self, data: D, ttl: Duration) -> D {
+        let _ = self.try_insert_with_ttl(data.clone(), ttl);
+        data
+    }
+
+    /// Like [`insert`](Self::insert), reporting the store handler's error,
+    /// and returning the version of the new value, or 0 if there was no
+    /// room to cache it.
+    pub(crate) fn try_insert(self, data: D) -> Result<u64, StoreError> {
+        let ttl = self.cache.jittered(self.cache.positive_ttl());
+        self.try_insert_with_ttl(data, ttl)
+    }
+
+    fn try_insert_with_ttl(mut self, data: D, ttl: Duration) -> Result<u64, StoreError> {
+        let expiration = self.cache.now() + ttl;
+        let entry = CacheEntry::new(data, EntryStatus::Ready, 0, expiration);
+        self.cache.insert_locked(&mut self.guard, self.key, entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn cache(clock: Arc<ManualClock>) -> Cache<u32, u64, DefaultHasher, Arc<ManualClock>> {
+        Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .clock(clock)
+            .miss_handler(|_: &u32, _: &mut u64, _: &mut u8| false)
+            .build()
+    }
+
+    #[test]
+    fn counters_are_incremented_atomically() {
+        let cache = Arc::new(cache(Arc::new(ManualClock::new())));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        cache.entry(1).and_modify(|count| *count += 1).or_insert(1);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(cache.get(&1), Some(4000));
+    }
+
+    #[test]
+    fn occupied_entries_can_be_inspected_and_removed() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = cache(clock.clone());
+        cache.insert(1, 10);
+        cache.retrieve_or_compute(&2);
+        assert!(matches!(cache.entry(2), Entry::Vacant(_)));
+
+        clock.advance(Duration::from_secs(20));
+        let Entry::Occupied(mut entry) = cache.entry(1) else {
+            panic!("the entry is live");
+        };
+        assert_eq!(*entry.get(), 10);
+        assert_eq!(entry.time_to_live(), Duration::from_secs(40));
+        assert_eq!(entry.insert(11), 10);
+        entry.set_time_to_live(Duration::from_secs(5));
+        assert_eq!(entry.time_to_live(), Duration::from_secs(5));
+        drop(entry);
+        assert_eq!(cache.time_to_live(&1), Some(Duration::from_secs(5)));
+
+        let Entry::Occupied(entry) = cache.entry(1) else {
+            panic!("the entry is live");
+        };
+        assert_eq!(entry.remove(), 11);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.entry(1).or_insert_with(|| 12), 12);
+
+        clock.advance(Duration::from_secs(60));
+        let Entry::Vacant(entry) = cache.entry(1) else {
+            panic!("the entry expired");
+        };
+        assert_eq!(entry.insert_with_ttl(13, Duration::from_secs(1)), 13);
+        assert_eq!(cache.time_to_live(&1), Some(Duration::from_secs(1)));
+    }
+}
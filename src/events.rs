@@ -0,0 +1,381 @@
+//! Lifecycle events published to subscribers.
+//!
+//! Each subscriber gets its own bounded channel. Publishing never blocks
+//! the cache: when a subscriber falls behind, events for it are dropped and
+//! counted in [`Cache::dropped_events`]. Subscribers that hung up are
+//! forgotten on the next event meant for them.
+//!
+//! Subscriptions can be narrowed to some keys or to a tag. Filters run as
+//! events are published, so a consumer interested in one namespace never
+//! receives, let alone buffers, the events of the others. Consumers that
+//! ship events out of the application subscribe with
+//! [`Cache::subscribe_redacted`], which renders keys with
+//! [`Cache::key_label`] before they leave the cache.
+
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use crate::cache::{Cache, CacheEntry, EntryTags};
+use crate::clock::Clock;
+use crate::lock::MutexExt;
+use crate::redact::KeyRedactor;
+use crate::time::Instant;
+
+/// Events buffered per subscriber before new ones are dropped.
+pub const EVENT_BUFFER: usize = 1024;
+
+/// Something that happened to a key, as seen by
+/// [`Cache::subscribe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CacheEvent<K> {
+    /// A value was inserted or successfully loaded.
+    Insert(K),
+    /// A lookup found a live value.
+    Hit(K),
+    /// A lookup found nothing to serve.
+    Miss(K),
+    /// The entry was evicted to make room.
+    Evict(K),
+    /// The entry was found expired and dropped or replaced.
+    Expire(K),
+    /// The miss handler failed, panicked, or the store rejected its value.
+    LoadFailed(K),
+}
+
+impl<K> CacheEvent<K> {
+    /// The key the event is about.
+    pub fn key(&self) -> &K {
+        match self {
+            CacheEvent::Insert(key)
+            | CacheEvent::Hit(key)
+            | CacheEvent::Miss(key)
+            | CacheEvent::Evict(key)
+            | CacheEvent::Expire(key)
+            | CacheEvent::LoadFailed(key) => key,
+        }
+    }
+
+    /// The same event about `f(key)`.
+    pub fn map_key<T>(&self, f: impl FnOnce(&K) -> T) -> CacheEvent<T> {
+        match self {
+            CacheEvent::Insert(key) => CacheEvent::Insert(f(key)),
+            CacheEvent::Hit(key) => CacheEvent::Hit(f(key)),
+            CacheEvent::Miss(key) => CacheEvent::Miss(f(key)),
+            CacheEvent::Evict(key) => CacheEvent::Evict(f(key)),
+            CacheEvent::Expire(key) => CacheEvent::Expire(f(key)),
+            CacheEvent::LoadFailed(key) => CacheEvent::LoadFailed(f(key)),
+        }
+    }
+}
+
+/// Which events a subscriber receives.
+enum EventFilter<K> {
+    All,
+    Keys(Box<dyn Fn(&K) -> bool + Send + Sync>),
+    Tag(Arc<str>),
+}
+
+impl<K> EventFilter<K> {
+    fn accepts(&self, key: &K, tags: Option<&EntryTags>) -> bool {
+        match self {
+            EventFilter::All => true,
+            EventFilter::Keys(filter) => filter(key),
+            EventFilter::Tag(tag) => {
+                tags.is_some_and(|tags| tags.iter().any(|(entry_tag, _)| entry_tag == tag))
+            }
+        }
+    }
+}
+
+/// Where a subscriber's events go: as published, or with their keys
+/// rendered into labels.
+enum Sink<K> {
+    Keys(SyncSender<CacheEvent<K>>),
+    Labels(SyncSender<CacheEvent<String>>, Arc<KeyRedactor<K>>),
+}
+
+impl<K: Clone> Sink<K> {
+    fn try_send(&self, event: &CacheEvent<K>) -> Result<(), TrySendError<()>> {
+        match self {
+            Sink::Keys(sender) => sender.try_send(event.clone()).map_err(forget),
+            Sink::Labels(sender, label) => sender
+                .try_send(event.map_key(|key| label(key)))
+                .map_err(forget),
+        }
+    }
+}
+
+/// Drops the event an unsuccessful send hands back.
+fn forget<T>(error: TrySendError<T>) -> TrySendError<()> {
+    match error {
+        TrySendError::Full(_) => TrySendError::Full(()),
+        TrySendError::Disconnected(_) => TrySendError::Disconnected(()),
+    }
+}
+
+struct Subscriber<K> {
+    sink: Sink<K>,
+    filter: EventFilter<K>,
+}
+
+/// The channels of the current subscribers.
+pub(crate) struct Subscribers<K> {
+    senders: Mutex<Vec<Subscriber<K>>>,
+    /// Whether there is anyone to publish to, checked without locking so
+    /// that a cache without subscribers does not pay for events.
+    active: AtomicBool,
+    dropped: AtomicU64,
+}
+
+impl<K> Default for Subscribers<K> {
+    fn default() -> Self {
+        Subscribers {
+            senders: Mutex::default(),
+            active: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<K: Clone> Subscribers<K> {
+    fn subscribe(&self, filter: EventFilter<K>) -> Receiver<CacheEvent<K>> {
+        let (sender, receiver) = mpsc::sync_channel(EVENT_BUFFER);
+        self.add(Sink::Keys(sender), filter);
+        receiver
+    }
+
+    fn subscribe_labeled(&self, label: Arc<KeyRedactor<K>>) -> Receiver<CacheEvent<String>> {
+        let (sender, receiver) = mpsc::sync_channel(EVENT_BUFFER);
+        self.add(Sink::Labels(sender, label), EventFilter::All);
+        receiver
+    }
+
+    fn add(&self, sink: Sink<K>, filter: EventFilter<K>) {
+        self.senders
+            .lock_or_recover()
+            .push(Subscriber { sink, filter });
+        self.active.store(true, Ordering::Release);
+    }
+
+    /// Sends the event built by `event` to every subscriber whose filter
+    /// accepts it. `tags` are those of the entry the event is about.
+    pub(crate) fn publish(&self, event: impl FnOnce() -> CacheEvent<K>, tags: Option<&EntryTags>) {
+        if !self.active.load(Ordering::Acquire) {
+            return;
+        }
+        let event = event();
+        let mut senders = self.senders.lock_or_recover();
+        senders.retain(|subscriber| {
+            if !subscriber.filter.accepts(event.key(), tags) {
+                return true;
+            }
+            match subscriber.sink.try_send(&event) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+        if senders.is_empty() {
+            self.active.store(false, Ordering::Release);
+        }
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Returns a channel receiving the events of this cache from now on.
+    ///
+    /// The channel buffers [`EVENT_BUFFER`] events; a subscriber that does
+    /// not keep up loses the events that do not fit rather than slowing
+    /// the cache down. Drop the receiver to unsubscribe.
+    pub fn subscribe(&self) -> Receiver<CacheEvent<K>> {
+        self.subscribers.subscribe(EventFilter::All)
+    }
+
+    /// Like [`subscribe`](Self::subscribe), receiving only the events of
+    /// keys accepted by `filter`, e.g. those of one prefix.
+    ///
+    /// The filter runs while the cache lock may be held and must not call
+    /// back into the cache.
+    pub fn subscribe_filtered<F>(&self, filter: F) -> Receiver<CacheEvent<K>>
+    where
+        F: Fn(&K) -> bool + Send + Sync + 'static,
+    {
+        self.subscribers
+            .subscribe(EventFilter::Keys(Box::new(filter)))
+    }
+
+    /// Like [`subscribe`](Self::subscribe), receiving only the events of
+    /// entries inserted with `tag` by
+    /// [`insert_tagged`](Self::insert_tagged): their insertion, eviction
+    /// and expiration. Hits and misses are not attributed to tags.
+    pub fn subscribe_tag(&self, tag: &str) -> Receiver<CacheEvent<K>> {
+        self.subscribers.subscribe(EventFilter::Tag(Arc::from(tag)))
+    }
+
+    /// Like [`subscribe`](Self::subscribe), with keys rendered by
+    /// [`key_label`](Self::key_label), so that a configured
+    /// [`key_redactor`](crate::CacheBuilder::key_redactor) keeps raw keys
+    /// out of consumers that ship events elsewhere.
+    pub fn subscribe_redacted(&self) -> Receiver<CacheEvent<String>>
+    where
+        K: fmt::Debug + 'static,
+    {
+        let label = match &self.key_redactor {
+            Some(key_redactor) => key_redactor.clone(),
+            None => Arc::new(|key: &K| format!("{key:?}")),
+        };
+        self.subscribers.subscribe_labeled(label)
+    }
+
+    /// Number of events dropped because a subscriber's buffer was full.
+    pub fn dropped_events(&self) -> u64 {
+        self.subscribers.dropped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn publish(&self, event: impl FnOnce() -> CacheEvent<K>) {
+        self.subscribers.publish(event, None);
+    }
+
+    /// Publishes an event about an entry carrying `tags`.
+    pub(crate) fn publish_tagged(
+        &self,
+        event: impl FnOnce() -> CacheEvent<K>,
+        tags: Option<&EntryTags>,
+    ) {
+        self.subscribers.publish(event, tags);
+    }
+
+    /// Reports that `entry` was found expired, if it is.
+    pub(crate) fn expired(&self, key: &K, entry: &CacheEntry<D>, now: Instant) {
+        if entry.is_expired(now) {
+            self.trace_expiration(key);
+            self.publish_tagged(|| CacheEvent::Expire(key.clone()), entry.tags.as_ref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn subscribers_see_the_lifecycle_of_keys() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(2)
+            .positive_ttl(Duration::from_secs(60))
+            .clock(clock.clone())
+            .miss_handler(|key: &u32, _: &mut u32, _: &mut u8| *key != 0)
+            .build();
+        let events = cache.subscribe();
+
+        cache.insert(1, 1);
+        cache.get(&1);
+        cache.retrieve_or_compute(&0);
+        cache.retrieve_or_compute(&2);
+        cache.insert(3, 3);
+        clock.advance(Duration::from_secs(60));
+        cache.get(&3);
+
+        let events: Vec<_> = events.try_iter().collect();
+        assert_eq!(
+            events,
+            [
+                CacheEvent::Insert(1),
+                CacheEvent::Hit(1),
+                CacheEvent::Miss(0),
+                CacheEvent::LoadFailed(0),
+                CacheEvent::Evict(0),
+                CacheEvent::Miss(2),
+                CacheEvent::Insert(2),
+                CacheEvent::Insert(3),
+                CacheEvent::Evict(1),
+                CacheEvent::Expire(3),
+                CacheEvent::Miss(3),
+            ]
+        );
+    }
+
+    #[test]
+    fn subscriptions_filter_by_key_and_tag() {
+        let cache = Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &String, _: &mut u32, _: &mut u8| true,
+        );
+        let users = cache.subscribe_filtered(|key: &String| key.starts_with("user:"));
+        let hot = cache.subscribe_tag("hot");
+
+        cache.insert("user:1".to_string(), 1);
+        cache.insert("order:1".to_string(), 1);
+        cache
+            .insert_tagged("order:2".to_string(), 2, &["hot"])
+            .unwrap();
+        cache.get(&"order:2".to_string());
+
+        assert_eq!(
+            users.try_iter().collect::<Vec<_>>(),
+            [CacheEvent::Insert("user:1".to_string())]
+        );
+        assert_eq!(
+            hot.try_iter().collect::<Vec<_>>(),
+            [CacheEvent::Insert("order:2".to_string())]
+        );
+    }
+
+    #[test]
+    fn redacted_subscriptions_receive_labels() {
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .key_redactor(|key: &String| format!("<{} bytes>", key.len()))
+            .miss_handler(|_: &String, _: &mut u32, _: &mut u8| true)
+            .build();
+        let events = cache.subscribe_redacted();
+
+        cache.insert("ada@example.com".to_string(), 1);
+        cache.get(&"ada@example.com".to_string());
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                CacheEvent::Insert("<15 bytes>".to_string()),
+                CacheEvent::Hit("<15 bytes>".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn slow_and_departed_subscribers_do_not_block() {
+        let cache = Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut u32, _: &mut u8| true,
+        );
+        let slow = cache.subscribe();
+        drop(cache.subscribe());
+        for _ in 0..EVENT_BUFFER + 5 {
+            cache.get(&1);
+        }
+
+        assert_eq!(slow.try_iter().count(), EVENT_BUFFER);
+        assert_eq!(cache.dropped_events(), 5);
+        assert_eq!(cache.subscribers.senders.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,329 @@
+//! Choosing which entry to evict when the cache is full.
+
+use std::hash::{BuildHasher, Hash};
+
+use lru::LruCache;
+
+use crate::cache::{capacity, Cache, CacheEntry, EntryStatus};
+use crate::clock::Clock;
+use crate::config::{Capacity, ConfigError};
+use crate::lock::RwLockExt;
+
+/// Answer of an eviction veto hook about a candidate victim.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictDecision {
+    /// Let the entry be evicted.
+    Evict,
+    /// Keep the entry and consider the next least recently used one.
+    Skip,
+}
+
+/// Hook consulted before a successfully computed entry is evicted to make
+/// room for another one.
+pub type EvictionVeto<K, D> = dyn Fn(&K, &D) -> EvictDecision + Send + Sync;
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Maximum number of entries held in memory.
+    pub fn capacity(&self) -> Capacity {
+        Capacity::of(&self.lru_cache.read_or_recover())
+    }
+
+    /// Grows or shrinks the cache to hold at most `size` entries, or lifts
+    /// the limit with [`Capacity::Unbounded`].
+    ///
+    /// Shrinking evicts as inserts into a full cache do: expired and failed
+    /// entries go first, then from the least recently used end, skipping
+    /// held entries. The eviction veto is
+    /// consulted and evicted entries spill to the second tier. If held
+    /// entries keep the cache above `size`, the capacity only shrinks to
+    /// the number of entries left.
+    ///
+    /// Fails with [`ConfigError::ZeroCapacity`] if `size` is zero, leaving
+    /// the cache as it was.
+    pub fn set_capacity(&self, size: impl Into<Capacity>) -> Result<(), ConfigError> {
+        let size = size.into().limit()?;
+        let mut cache = self.lru_cache.write_or_recover();
+        while cache.len() > size.get() {
+            if self.drop_dead(&mut cache) {
+                continue;
+            }
+            let Some((key, entry)) = self.pop_victim(&mut cache) else {
+                break;
+            };
+            self.evicted(key, entry);
+        }
+        let held = cache.len().max(1);
+        cache.resize(size.max(capacity(held)));
+        Ok(())
+    }
+
+    /// Removes and returns the least recently used successfully computed,
+    /// unexpired entry, e.g. to spill it somewhere or sample it.
+    ///
+    /// Held entries are skipped, as on eviction; expired entries found at
+    /// the cold end on the way are dropped. The eviction veto and the
+    /// second tier are not involved.
+    pub fn pop_lru(&self) -> Option<(K, D)> {
+        let now = self.now();
+        let mut cache = self.lru_cache.write_or_recover();
+        let mut dead = Vec::new();
+        let coldest = cache
+            .iter()
+            .rev()
+            .filter(|(_, entry)| entry.holds == 0 && entry.status != EntryStatus::Calculating)
+            .find(|(key, entry)| {
+                let live = self.is_live(entry, now);
+                if !live {
+                    dead.push((*key).clone());
+                }
+                live && entry.status == EntryStatus::Ready
+            })
+            .map(|(key, _)| key.clone());
+        for key in &dead {
+            self.unlink(&mut cache, key);
+        }
+        let key = coldest?;
+        let entry = self.unlink(&mut cache, &key)?;
+        Some((key, entry.data))
+    }
+
+    /// Returns the entry [`pop_lru`](Self::pop_lru) would remove, without
+    /// removing or promoting it.
+    pub fn peek_lru(&self) -> Option<(K, D)> {
+        let now = self.now();
+        let cache = self.lru_cache.read_or_recover();
+        cache
+            .iter()
+            .rev()
+            .find(|(_, entry)| {
+                entry.holds == 0 && entry.status == EntryStatus::Ready && self.is_live(entry, now)
+            })
+            .map(|(key, entry)| (key.clone(), entry.data.clone()))
+    }
+
+    /// Drops the entry that expired first, or else the least recently used
+    /// failed one, if any, to make room before evicting live entries.
+    /// Returns `false` if there is none.
+    ///
+    /// Expired entries are found through the expiry index; failed ones are
+    /// only scanned for while the cache holds some.
+    pub(crate) fn drop_dead(&self, cache: &mut LruCache<K, CacheEntry<D>, S>) -> bool {
+        let now = self.now();
+        let dead = self
+            .next_expired(cache, now)
+            .or_else(|| self.coldest_failed(cache));
+        let Some(key) = dead else {
+            return false;
+        };
+        let Some(entry) = self.unlink(cache, &key) else {
+            return false;
+        };
+        if self.is_live(&entry, now) {
+            self.evicted(key, entry);
+        } else {
+            self.expired(&key, &entry, now);
+        }
+        true
+    }
+
+    fn coldest_failed(&self, cache: &LruCache<K, CacheEntry<D>, S>) -> Option<K> {
+        if self.failed_len() == 0 {
+            return None;
+        }
+        cache
+            .iter()
+            .rev()
+            .find(|(_, entry)| entry.status == EntryStatus::Failed && entry.holds == 0)
+            .map(|(key, _)| key.clone())
+    }
+
+    /// Removes the least recently used entry that is neither held, being
+    /// computed nor vetoed, returning `None` if there is none.
+    ///
+    /// Placeholders of computations in flight are never evicted: waiters
+    /// would claim the key again and load it a second time.
+    pub(crate) fn pop_victim(
+        &self,
+        cache: &mut LruCache<K, CacheEntry<D>, S>,
+    ) -> Option<(K, CacheEntry<D>)> {
+        let mut vetoes_left = self.max_vetoes;
+        let victim = cache
+            .iter()
+            .rev()
+            .filter(|(_, entry)| entry.holds == 0 && entry.status != EntryStatus::Calculating)
+            .find(|(key, entry)| {
+                let Some(veto) = &self.eviction_veto else {
+                    return true;
+                };
+                if vetoes_left == 0 || entry.status != EntryStatus::Ready {
+                    return true;
+                }
+                match veto(key, &entry.data) {
+                    EvictDecision::Evict => true,
+                    EvictDecision::Skip => {
+                        vetoes_left -= 1;
+                        false
+                    }
+                }
+            })
+            .map(|(key, _)| key.clone())?;
+        let entry = self.unlink(cache, &victim)?;
+        Some((victim, entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn cache(max_vetoes: usize) -> Cache<u32, u32> {
+        Cache::builder(3)
+            .positive_ttl(Duration::from_secs(60))
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| true)
+            .eviction_veto(max_vetoes, |_: &u32, data: &u32| {
+                if *data == 0 {
+                    EvictDecision::Skip
+                } else {
+                    EvictDecision::Evict
+                }
+            })
+            .build()
+    }
+
+    #[test]
+    fn vetoed_entries_are_skipped() {
+        let cache = cache(5);
+        cache.insert(1, 0);
+        cache.insert(2, 1);
+        cache.insert(3, 1);
+        cache.insert(4, 1);
+
+        assert_eq!(cache.get(&1), Some(0));
+        assert_eq!(cache.get(&2), None);
+    }
+
+    #[test]
+    fn set_capacity_evicts_from_the_cold_end() {
+        let cache = cache(0);
+        for key in 1..=3 {
+            cache.insert(key, key);
+        }
+        cache.get(&1);
+
+        assert_eq!(cache.set_capacity(0), Err(ConfigError::ZeroCapacity));
+        assert_eq!(cache.len(), 3);
+
+        cache.set_capacity(1).unwrap();
+        assert_eq!(cache.capacity(), Capacity::Bounded(1));
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.len(), 1);
+
+        cache.set_capacity(5).unwrap();
+        for key in 2..=5 {
+            cache.insert(key, key);
+        }
+        assert_eq!(cache.len(), 5);
+    }
+
+    #[test]
+    fn dead_entries_are_evicted_before_live_ones() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(3)
+            .positive_ttl(Duration::from_secs(60))
+            .negative_ttl(Duration::from_secs(60))
+            .clock(clock.clone())
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| false)
+            .build();
+        cache.insert(1, 1);
+        clock.advance(Duration::from_secs(30));
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        cache.get(&1);
+
+        clock.advance(Duration::from_secs(40));
+        cache.insert(4, 4);
+        assert_eq!(cache.get_entry(&1), None);
+        assert_eq!(cache.len(), 3);
+
+        cache.remove(&4);
+        cache.retrieve_or_compute(&0);
+        cache.get(&2);
+        cache.insert(5, 5);
+        assert_eq!(cache.get_entry(&0), None);
+        assert_eq!(cache.peek(&3), Some(3));
+        assert_eq!(cache.failed_len(), 0);
+    }
+
+    #[test]
+    fn computations_in_flight_are_not_evicted() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let cache = {
+            let loads = loads.clone();
+            Arc::new(
+                Cache::builder(2)
+                    .positive_ttl(Duration::from_secs(60))
+                    .miss_handler(move |key: &u32, data: &mut u32, _: &mut u8| {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(100));
+                        *data = *key;
+                        true
+                    })
+                    .build(),
+            )
+        };
+        let load = |key: u32| {
+            let cache = cache.clone();
+            thread::spawn(move || cache.retrieve_or_compute(&key))
+        };
+
+        let first = load(1);
+        thread::sleep(Duration::from_millis(20));
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        let second = load(1);
+        assert_eq!(first.join().unwrap(), (1, true, 0));
+        assert_eq!(second.join().unwrap(), (1, true, 0));
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn vetoes_are_bounded_per_eviction() {
+        let cache = cache(1);
+        cache.insert(1, 0);
+        cache.insert(2, 0);
+        cache.insert(3, 1);
+        cache.insert(4, 1);
+
+        assert_eq!(cache.get(&1), Some(0));
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&3), Some(1));
+    }
+
+    #[test]
+    fn pop_lru_consumes_the_cold_end() {
+        let cache = cache(0);
+        for key in 1..=3 {
+            cache.insert(key, key);
+        }
+        cache.get(&1);
+        let _held = cache.hold(&2);
+
+        assert_eq!(cache.peek_lru(), Some((3, 3)));
+        assert_eq!(cache.pop_lru(), Some((3, 3)));
+        assert_eq!(cache.pop_lru(), Some((1, 1)));
+        assert_eq!(cache.pop_lru(), None);
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -0,0 +1,325 @@
+//! An index of entries by expiration, so that expired entries are found
+//! without scanning the cache.
+//!
+//! Every stored entry is recorded in an ordered map under its expiration
+//! and write sequence number. Entries that are replaced, removed or given
+//! another expiration leave their old deadline behind; such deadlines are
+//! recognized as outdated when they are reached and discarded, and the map
+//! is compacted once outdated deadlines outnumber the entries. Finding the
+//! next expired entry then costs O(log n), for evictions under capacity
+//! pressure and for the janitor alike.
+//!
+//! Since every entry that is not being computed has a current deadline,
+//! the index also serves as a stable order to walk the cache in chunks,
+//! resuming after the last deadline seen each time the lock is re-taken.
+
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Bound;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use lru::LruCache;
+
+use crate::cache::{Cache, CacheEntry, EntryStatus};
+use crate::clock::Clock;
+use crate::invalidate::YieldNow;
+use crate::iter::CHUNK_SIZE;
+use crate::lock::{MutexExt, RwLockExt};
+use crate::time::Instant;
+
+/// Size under which the index is never compacted.
+const COMPACT_MIN_LEN: usize = 1024;
+
+/// When an entry expires, and the write sequence number it was stored
+/// with.
+type Deadline = (Instant, u64);
+
+/// Deadlines of the entries of a cache, earliest first.
+pub(crate) struct ExpiryIndex<K> {
+    deadlines: BTreeMap<Deadline, K>,
+}
+
+impl<K> ExpiryIndex<K> {
+    pub(crate) fn new() -> Self {
+        ExpiryIndex {
+            deadlines: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.deadlines.clear();
+    }
+}
+
+/// Returns `true` if `deadline` is the current expiration of the entry for
+/// `key`.
+fn is_current<K, D, S>(cache: &LruCache<K, CacheEntry<D>, S>, key: &K, deadline: Deadline) -> bool
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    cache.peek(key).is_some_and(|entry| {
+        (entry.expiration, entry.seq) == deadline && entry.status != EntryStatus::Calculating
+    })
+}
+
+/// A walk over the entries of a cache in order of expiration, in chunks
+/// of [`CHUNK_SIZE`] entries; see [`Cache::next_chunk`].
+pub(crate) struct Walk {
+    /// The last deadline seen, `None` before the first chunk.
+    after: Option<Deadline>,
+    /// Write sequence number of the last entry stored before the walk
+    /// started; entries stored since are skipped.
+    until_seq: u64,
+    done: bool,
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Records that the entry for `key` with write sequence number `seq`
+    /// now expires at `expiration`.
+    pub(crate) fn index_expiration(
+        &self,
+        cache: &LruCache<K, CacheEntry<D>, S>,
+        key: K,
+        expiration: Instant,
+        seq: u64,
+    ) {
+        let mut index = self.expiry_index.lock_or_recover();
+        index.deadlines.insert((expiration, seq), key);
+        if index.deadlines.len() > (2 * cache.len()).max(COMPACT_MIN_LEN) {
+            index
+                .deadlines
+                .retain(|&deadline, key| is_current(cache, key, deadline));
+        }
+    }
+
+    /// Starts a walk over the entries stored so far.
+    pub(crate) fn walk(&self) -> Walk {
+        Walk {
+            after: None,
+            until_seq: self.write_seq.load(Ordering::Relaxed),
+            done: false,
+        }
+    }
+
+    /// Returns the keys of the next entries of `walk`, at most
+    /// [`CHUNK_SIZE`], or `None` once every entry has been seen.
+    ///
+    /// The caller holds the cache lock for the chunk and releases it
+    /// before asking for the next one. Entries being computed are not
+    /// indexed and never returned. An entry whose expiration is pushed
+    /// back past the walk position after it was returned is returned again.
+    pub(crate) fn next_chunk(
+        &self,
+        cache: &LruCache<K, CacheEntry<D>, S>,
+        walk: &mut Walk,
+    ) -> Option<Vec<K>> {
+        if walk.done {
+            return None;
+        }
+        let index = self.expiry_index.lock_or_recover();
+        let from = walk.after.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut keys = Vec::new();
+        let mut deadlines = index.deadlines.range((from, Bound::Unbounded));
+        for (&deadline, key) in deadlines.by_ref() {
+            walk.after = Some(deadline);
+            if deadline.1 <= walk.until_seq && is_current(cache, key, deadline) {
+                keys.push(key.clone());
+                if keys.len() == CHUNK_SIZE {
+                    break;
+                }
+            }
+        }
+        walk.done = deadlines.next().is_none();
+        Some(keys)
+    }
+
+    /// Calls `f` for every entry that is not being computed, soonest
+    /// expiration first.
+    pub(crate) fn visit_by_expiration<F>(&self, cache: &LruCache<K, CacheEntry<D>, S>, mut f: F)
+    where
+        F: FnMut(&K, &CacheEntry<D>),
+    {
+        let index = self.expiry_index.lock_or_recover();
+        for (&deadline, key) in &index.deadlines {
+            if is_current(cache, key, deadline) {
+                if let Some(entry) = cache.peek(key) {
+                    f(key, entry);
+                }
+            }
+        }
+    }
+
+    /// Returns the key of the entry that expired first, skipping held
+    /// entries, if any expired by `now`. Its deadline leaves the index, so
+    /// the caller is expected to drop the entry.
+    pub(crate) fn next_expired(
+        &self,
+        cache: &LruCache<K, CacheEntry<D>, S>,
+        now: Instant,
+    ) -> Option<K> {
+        let mut index = self.expiry_index.lock_or_recover();
+        let mut held = Vec::new();
+        let expired = loop {
+            let Some(first) = index.deadlines.first_entry() else {
+                break None;
+            };
+            if first.key().0 > now {
+                break None;
+            }
+            let (deadline, key) = first.remove_entry();
+            if !is_current(cache, &key, deadline) {
+                continue;
+            }
+            if cache.peek(&key).is_some_and(|entry| entry.holds > 0) {
+                held.push((deadline, key));
+                continue;
+            }
+            break Some(key);
+        };
+        index.deadlines.extend(held);
+        expired
+    }
+
+    /// Drops every entry whose TTL has passed, returning how many were
+    /// dropped.
+    ///
+    /// Unlike [`purge`](Self::purge) with
+    /// [`PurgeLevel::Expired`](crate::PurgeLevel::Expired), this does not
+    /// scan the cache: it takes O(log n) per expired entry, however large
+    /// the cache. Invalidated entries are left to lookups and evictions.
+    /// The write lock is released every 256 entries, so that a large batch
+    /// of expirations does not stall readers. See also
+    /// [`spawn_janitor`](Self::spawn_janitor).
+    pub fn evict_expired(&self) -> usize {
+        let now = self.now();
+        let mut evicted = 0;
+        loop {
+            let (chunk, done) = self.evict_expired_chunk(now);
+            evicted += chunk;
+            if done {
+                return evicted;
+            }
+        }
+    }
+
+    /// Like [`evict_expired`](Self::evict_expired), for async callers: the
+    /// task yields to the executor between chunks of 256 entries, so that
+    /// a periodic sweep never blocks a runtime worker thread for long.
+    pub async fn evict_expired_async(&self) -> usize {
+        let now = self.now();
+        let mut evicted = 0;
+        loop {
+            let (chunk, done) = self.evict_expired_chunk(now);
+            evicted += chunk;
+            if done {
+                return evicted;
+            }
+            YieldNow(false).await;
+        }
+    }
+
+    /// Drops up to [`CHUNK_SIZE`] entries that expired by `now`, returning
+    /// how many were dropped and whether none are left.
+    fn evict_expired_chunk(&self, now: Instant) -> (usize, bool) {
+        let mut cache = self.lru_cache.write_or_recover();
+        let mut evicted = 0;
+        for _ in 0..CHUNK_SIZE {
+            let Some(key) = self.next_expired(&cache, now) else {
+                return (evicted, true);
+            };
+            if let Some(entry) = self.unlink(&mut cache, &key) {
+                self.expired(&key, &entry, now);
+                evicted += 1;
+            }
+        }
+        (evicted, false)
+    }
+
+    /// Starts a thread calling [`evict_expired`](Self::evict_expired)
+    /// every `interval`, until the cache is dropped, so that expired
+    /// entries release their memory even if never looked up again.
+    pub fn spawn_janitor(self: &Arc<Self>, interval: Duration)
+    where
+        K: Send + Sync + 'static,
+        D: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+        C: 'static,
+    {
+        let cache = Arc::downgrade(self);
+        let interval = interval.max(Duration::from_millis(1));
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match cache.upgrade() {
+                Some(cache) => {
+                    cache.evict_expired();
+                }
+                None => break,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, CacheEvent, EntryState, ManualClock};
+    use std::sync::mpsc::Receiver;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn expired(events: &Receiver<CacheEvent<u32>>) -> Vec<u32> {
+        let mut keys: Vec<u32> = events
+            .try_iter()
+            .filter_map(|event| match event {
+                CacheEvent::Expire(key) => Some(key),
+                _ => None,
+            })
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn expired_entries_are_found_through_the_index() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .clock(clock.clone())
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| false)
+            .build();
+        let events = cache.subscribe();
+        for key in 0..5 {
+            cache.insert(key, key);
+        }
+        cache.insert(0, 10);
+        clock.advance(Duration::from_secs(10));
+        assert!(cache.touch(&1));
+        cache.remove(&2);
+        let held = cache.hold(&3);
+
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(cache.evict_expired(), 2);
+        assert_eq!(expired(&events), [0, 4]);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.status(&0), EntryState::Vacant);
+        assert_eq!(cache.peek(&1), Some(1));
+        drop(held);
+        assert_eq!(cache.evict_expired(), 1);
+        assert_eq!(expired(&events), [3]);
+        assert_eq!(cache.len(), 1);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.evict_expired(), 1);
+        assert_eq!(expired(&events), [1]);
+        assert!(cache.is_empty());
+    }
+}
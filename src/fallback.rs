@@ -0,0 +1,102 @@
+//! Falling back on other loaders when the miss handler fails.
+//!
+//! Loaders set with [`fallback_loader`](crate::CacheBuilder::fallback_loader)
+//! are tried in order after a failed miss handler, say a local disk, then a
+//! remote service, then a default value. Each one caches what it produces
+//! for its own TTL.
+
+use std::time::Duration;
+
+use crate::cache::MissHandler;
+use crate::load;
+
+/// Loaders tried in order after the miss handler fails, with the TTL of
+/// their values.
+pub(crate) type Fallbacks<K, D> = Vec<(Duration, Box<MissHandler<K, D>>)>;
+
+/// Chains `fallbacks` after `miss_handler`. Each loader starts over from
+/// the default value; if all of them fail, the last one's outcome stands.
+pub(crate) fn chain<K, D>(
+    miss_handler: Box<MissHandler<K, D>>,
+    fallbacks: Fallbacks<K, D>,
+) -> Box<MissHandler<K, D>>
+where
+    K: 'static,
+    D: Default + 'static,
+{
+    Box::new(move |key: &K, data: &mut D, adhoc_code: &mut u8| {
+        if miss_handler(key, data, adhoc_code) {
+            return true;
+        }
+        for (ttl, fallback) in &fallbacks {
+            *data = D::default();
+            *adhoc_code = 0;
+            load::set_ttl(None);
+            if fallback(key, data, adhoc_code) {
+                load::set_ttl(Some(*ttl));
+                return true;
+            }
+        }
+        false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, CacheBuilder, ManualClock};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn chained(
+        clock: Arc<ManualClock>,
+    ) -> CacheBuilder<u32, u32, crate::DefaultHasher, Arc<ManualClock>> {
+        Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .negative_ttl(Duration::from_secs(1))
+            .clock(clock)
+            .miss_handler(|key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                *key < 10
+            })
+            .fallback_loader(
+                Duration::from_secs(30),
+                |key: &u32, data: &mut u32, _: &mut u8| {
+                    *data = *key * 10;
+                    key.is_multiple_of(2)
+                },
+            )
+            .fallback_loader(
+                Duration::from_secs(5),
+                |key: &u32, data: &mut u32, adhoc_code: &mut u8| {
+                    assert_eq!(*data, 0);
+                    *data = *key * 100;
+                    *adhoc_code = 7;
+                    *key < 100
+                },
+            )
+    }
+
+    #[test]
+    fn fallbacks_are_tried_in_order_with_their_ttls() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = chained(clock).build();
+        assert_eq!(cache.retrieve_or_compute(&1), (1, true, 0));
+        assert_eq!(cache.time_to_live(&1), Some(Duration::from_secs(60)));
+        assert_eq!(cache.retrieve_or_compute(&12), (120, true, 0));
+        assert_eq!(cache.time_to_live(&12), Some(Duration::from_secs(30)));
+        assert_eq!(cache.retrieve_or_compute(&13), (1300, true, 7));
+        assert_eq!(cache.time_to_live(&13), Some(Duration::from_secs(5)));
+        assert_eq!(cache.retrieve_or_compute(&101), (10100, false, 7));
+        assert_eq!(cache.time_to_live(&101), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn fallback_ttls_reach_the_cache_from_workers() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = chained(clock).worker_pool(1).build();
+        assert_eq!(cache.retrieve_or_compute(&13), (1300, true, 7));
+        assert_eq!(cache.time_to_live(&13), Some(Duration::from_secs(5)));
+        assert_eq!(cache.retrieve_or_compute(&2), (2, true, 0));
+        assert_eq!(cache.time_to_live(&2), Some(Duration::from_secs(60)));
+    }
+}
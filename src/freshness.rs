@@ -0,0 +1,102 @@
+//! Lookups reporting how fresh the value they return is, so callers can
+//! decide whether to trust it or refresh it.
+
+use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
+
+use crate::cache::{Cache, CacheEntry};
+use crate::clock::Clock;
+use crate::events::CacheEvent;
+use crate::time::Instant;
+
+/// How fresh a value returned by [`Cache::get_with_status`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Freshness {
+    /// The value is live and not about to expire.
+    Fresh,
+    /// The value is past its
+    /// [soft TTL](crate::CacheBuilder::soft_ttl), or expired and is served
+    /// because refreshing it failed; see
+    /// [`CacheBuilder::stale_if_error`](crate::CacheBuilder::stale_if_error).
+    Stale,
+    /// The value expires within the window set by
+    /// [`CacheBuilder::near_expiry`](crate::CacheBuilder::near_expiry), in
+    /// the given time.
+    NearExpiry(Duration),
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Like [`get`](Self::get), also telling how fresh the value is.
+    pub fn get_with_status(&self, key: &K) -> Option<(D, Freshness)> {
+        let found = self.lookup(key);
+        self.readiness.record_lookup(found.is_some());
+        self.record_window(found.is_some());
+        if found.is_some() {
+            self.record_hit(key);
+        }
+        self.publish(|| match found {
+            Some(_) => CacheEvent::Hit(key.clone()),
+            None => CacheEvent::Miss(key.clone()),
+        });
+        found
+    }
+
+    /// Classifies a live, ready entry.
+    pub(crate) fn freshness(&self, entry: &CacheEntry<D>, now: Instant) -> Freshness {
+        if entry.stale_until.is_some() || self.is_soft_expired(entry, now) {
+            return Freshness::Stale;
+        }
+        let remaining = entry.expiration.saturating_duration_since(now);
+        match self.near_expiry {
+            Some(window) if entry.holds == 0 && remaining <= window => {
+                Freshness::NearExpiry(remaining)
+            }
+            _ => Freshness::Fresh,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use std::sync::Arc;
+
+    #[test]
+    fn values_are_reported_with_their_freshness() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .negative_ttl(Duration::from_secs(5))
+            .stale_if_error(Duration::from_secs(60))
+            .near_expiry(Duration::from_secs(10))
+            .clock(clock.clone())
+            .miss_handler(|key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                *key != 0
+            })
+            .build();
+
+        assert_eq!(cache.get_with_status(&1), None);
+        cache.insert(1, 10);
+        assert_eq!(cache.get_with_status(&1), Some((10, Freshness::Fresh)));
+        clock.advance(Duration::from_secs(55));
+        assert_eq!(
+            cache.get_with_status(&1),
+            Some((10, Freshness::NearExpiry(Duration::from_secs(5))))
+        );
+
+        cache.insert(0, 7);
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(cache.retrieve_or_compute(&0), (7, true, 0));
+        assert_eq!(cache.get_with_status(&0), Some((7, Freshness::Stale)));
+        assert_eq!(cache.get_with_status(&1), None);
+    }
+}
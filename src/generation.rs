@@ -0,0 +1,150 @@
+//! Warm/cold generations, for revalidating the whole cache gradually.
+//!
+//! Every entry records the generation it was stored in. Rotating makes the
+//! current generation the previous one: its entries are revalidated on
+//! their next access, and those not accessed before the following rotation
+//! are dropped. Rotation is O(1) and can happen on a fixed period, giving
+//! "everything revalidates every N minutes" without sweeping the cache.
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use lru::LruCache;
+
+use crate::cache::{Cache, CacheEntry, EntryStatus};
+use crate::clock::Clock;
+use crate::time::Instant;
+
+/// Hook deciding whether an entry of the previous generation is still
+/// valid, e.g. by comparing an ETag or version with the source. Entries it
+/// rejects are recomputed.
+pub type Revalidator<K, D> = dyn Fn(&K, &D) -> bool + Send + Sync;
+
+/// The current generation: the explicit rotations plus the periods elapsed
+/// since the cache was built, if rotating on a period.
+pub(crate) struct Generations {
+    rotations: AtomicU64,
+    period: Option<(Instant, Duration)>,
+}
+
+impl Generations {
+    pub(crate) fn new(period: Option<Duration>, origin: Instant) -> Self {
+        Generations {
+            rotations: AtomicU64::new(0),
+            period: period.map(|period| (origin, period)),
+        }
+    }
+
+    pub(crate) fn current(&self, now: Instant) -> u64 {
+        let elapsed = self.period.map_or(0, |(origin, period)| {
+            let periods = now.saturating_duration_since(origin).as_nanos() / period.as_nanos();
+            periods as u64
+        });
+        self.rotations.load(Ordering::Acquire) + elapsed
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Moves every entry to the previous generation.
+    ///
+    /// Entries of the previous generation are handed to the revalidator on
+    /// their next access and kept if it accepts them; without a
+    /// revalidator, or if it rejects them, they are treated as misses and
+    /// recomputed. Entries still in the previous generation at the next
+    /// rotation are dropped.
+    pub fn rotate_generation(&self) {
+        self.generations.rotations.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Number of rotations so far, explicit or periodic.
+    pub fn generation(&self) -> u64 {
+        self.generations.current(self.now())
+    }
+
+    /// Moves the entry for `key` to the current generation if it belongs
+    /// to the previous one and the revalidator accepts it.
+    pub(crate) fn revalidate(
+        &self,
+        cache: &mut LruCache<K, CacheEntry<D>, S>,
+        key: &K,
+        now: Instant,
+    ) {
+        let Some(revalidator) = &self.revalidator else {
+            return;
+        };
+        let current = self.generations.current(now);
+        let Some(entry) = cache.peek_mut(key) else {
+            return;
+        };
+        if entry.status != EntryStatus::Ready
+            || entry.generation + 1 != current
+            || entry.is_expired(now)
+        {
+            return;
+        }
+        if revalidator(key, &entry.data) {
+            entry.generation = current;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, ManualClock, ManualClockCache};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn cache(clock: Arc<ManualClock>, calls: Arc<AtomicUsize>) -> ManualClockCache<u32, u32> {
+        Cache::builder(10)
+            .positive_ttl(Duration::from_secs(3600))
+            .clock(clock)
+            .generations(Duration::from_secs(60))
+            .revalidator(|key: &u32, _: &u32| key.is_multiple_of(2))
+            .miss_handler(move |key: &u32, data: &mut u32, _: &mut u8| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                *data = *key;
+                true
+            })
+            .build()
+    }
+
+    #[test]
+    fn previous_generation_is_revalidated_on_access() {
+        let clock = Arc::new(ManualClock::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = cache(clock.clone(), calls.clone());
+        cache.retrieve_or_compute(&1);
+        cache.retrieve_or_compute(&2);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(cache.generation(), 1);
+        // 2 passes revalidation, 1 does not and is recomputed.
+        cache.retrieve_or_compute(&2);
+        assert_eq!(cache.get(&1), None);
+        cache.retrieve_or_compute(&1);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn entries_skipping_a_generation_are_dropped() {
+        let clock = Arc::new(ManualClock::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = cache(clock, calls.clone());
+        cache.insert(2, 2);
+        cache.rotate_generation();
+        cache.rotate_generation();
+
+        assert_eq!(cache.get(&2), None);
+        cache.retrieve_or_compute(&2);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
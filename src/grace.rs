@@ -0,0 +1,122 @@
+//! Serving the last good value when a refresh fails ("stale-if-error").
+//!
+//! With a grace period configured, a lookup that finds an expired value
+//! keeps it aside while recomputing. If the computation fails, the old
+//! value is served again, flagged as stale in [`EntryInfo`], and retried
+//! after the negative TTL, until the grace period is over. Upstream
+//! outages then degrade to slightly old data instead of errors.
+//!
+//! [`EntryInfo`]: crate::EntryInfo
+
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+
+use lru::LruCache;
+
+use crate::cache::{Cache, CacheEntry, EntryStatus};
+use crate::clock::Clock;
+use crate::time::Instant;
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Returns until when `entry`, about to be recomputed, may stand in
+    /// for a failed computation, if at all.
+    ///
+    /// Only values that expired are eligible: invalidated ones must never
+    /// be served again. The grace period runs from the first expiration
+    /// and is capped by the maximum staleness.
+    pub(crate) fn stale_deadline(&self, entry: &CacheEntry<D>, now: Instant) -> Option<Instant> {
+        let grace = self.stale_if_error?;
+        if entry.status != EntryStatus::Ready || !self.is_current(entry, now) {
+            return None;
+        }
+        let deadline = entry.stale_until.unwrap_or_else(|| {
+            let grace = self.max_staleness.map_or(grace, |max| grace.min(max));
+            entry.expiration + grace
+        });
+        (now < deadline).then_some(deadline)
+    }
+
+    /// Puts the stale value carried by our placeholder for `key` back in
+    /// place of a failed computation, returning it, if still in grace.
+    pub(crate) fn serve_stale(
+        &self,
+        cache: &mut LruCache<K, CacheEntry<D>, S>,
+        key: &K,
+        started: u64,
+        now: Instant,
+    ) -> Option<(D, bool, u8)> {
+        let placeholder = cache
+            .peek_mut(key)
+            .filter(|entry| entry.seq == started && entry.status == EntryStatus::Calculating)?;
+        let deadline = placeholder.stale_until.filter(|&deadline| now < deadline)?;
+        let data = mem::take(&mut placeholder.data);
+        let adhoc_code = placeholder.adhoc_code;
+        let retry_at = deadline.min(now + self.negative_ttl());
+        let mut entry = CacheEntry::new(data.clone(), EntryStatus::Ready, adhoc_code, retry_at);
+        entry.stale_until = Some(deadline);
+        self.store(cache, key.clone(), entry);
+        Some((data, true, adhoc_code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, ManualClock};
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn failed_refreshes_serve_the_last_good_value() {
+        let clock = Arc::new(ManualClock::new());
+        let failing = Arc::new(AtomicBool::new(false));
+        let version = Arc::new(AtomicU32::new(0));
+        let cache = {
+            let failing = failing.clone();
+            Cache::builder(10)
+                .positive_ttl(Duration::from_secs(10))
+                .negative_ttl(Duration::from_secs(1))
+                .stale_if_error(Duration::from_secs(60))
+                .clock(clock.clone())
+                .miss_handler(move |_: &u32, data: &mut u32, _: &mut u8| {
+                    *data = version.fetch_add(1, Ordering::SeqCst) + 1;
+                    !failing.load(Ordering::SeqCst)
+                })
+                .build()
+        };
+        assert_eq!(cache.retrieve_or_compute(&1), (1, true, 0));
+
+        failing.store(true, Ordering::SeqCst);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.retrieve_or_compute(&1), (1, true, 0));
+        assert!(cache.get_entry(&1).unwrap().stale);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(cache.retrieve_or_compute(&1), (1, true, 0));
+
+        // The grace period ran out 60 seconds after the first expiration.
+        clock.advance(Duration::from_secs(30));
+        assert!(!cache.retrieve_or_compute(&1).1);
+        failing.store(false, Ordering::SeqCst);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.retrieve_or_compute(&1), (5, true, 0));
+        assert!(!cache.get_entry(&1).unwrap().stale);
+    }
+
+    #[test]
+    fn invalidated_values_are_never_served_stale() {
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(10))
+            .stale_if_error(Duration::from_secs(60))
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| false)
+            .build();
+        cache.insert(1, 1);
+        cache.invalidate_all();
+        assert_eq!(cache.retrieve_or_compute(&1), (0, false, 0));
+    }
+}
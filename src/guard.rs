@@ -0,0 +1,123 @@
+//! Borrowing cached values in place.
+
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Deref;
+use std::sync::RwLockReadGuard;
+
+use lru::{DefaultHasher, LruCache};
+
+use crate::cache::{Cache, CacheEntry, EntryStatus};
+use crate::clock::Clock;
+use crate::lock::RwLockExt;
+
+/// Read access to a cached value without cloning it; returned by
+/// [`Cache::get_ref`].
+///
+/// The guard holds the cache's read lock: other readers proceed, but
+/// every write to the cache, including computing a missing key, waits
+/// until it is dropped. Keep it for the duration of one read, and never
+/// call back into the cache while holding it, which can deadlock.
+pub struct CacheReadGuard<'a, K, D, S = DefaultHasher>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    guard: RwLockReadGuard<'a, LruCache<K, CacheEntry<D>, S>>,
+    key: K,
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Like [`peek`](Self::peek), borrowing the value instead of cloning
+    /// it, for large values read often.
+    ///
+    /// Like `peek`, this neither promotes the key nor counts as a lookup,
+    /// and never computes a missing value.
+    pub fn get_ref(&self, key: &K) -> Option<CacheReadGuard<'_, K, D, S>> {
+        if self.migration.is_some() {
+            self.migrate(&mut self.lru_cache.write_or_recover(), key);
+        }
+        let now = self.now();
+        let guard = self.lru_cache.read_or_recover();
+        guard.peek(key).filter(|entry| {
+            entry.status == EntryStatus::Ready
+                && entry.version == self.value_version
+                && self.is_live(entry, now)
+        })?;
+        Some(CacheReadGuard {
+            guard,
+            key: key.clone(),
+        })
+    }
+}
+
+impl<K, D, S> CacheReadGuard<'_, K, D, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// The key of the borrowed value.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<K, D, S> Deref for CacheReadGuard<'_, K, D, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    type Target = D;
+
+    fn deref(&self) -> &D {
+        &self
+            .guard
+            .peek(&self.key)
+            .expect("entries stay cached while read-locked")
+            .data
+    }
+}
+
+impl<K, D, S> fmt::Debug for CacheReadGuard<'_, K, D, S>
+where
+    K: Hash + Eq,
+    D: fmt::Debug,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+    use std::time::Duration;
+
+    #[test]
+    fn values_are_borrowed_in_place() {
+        let cache = Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut Vec<u8>, _: &mut u8| false,
+        );
+        cache.insert(1, vec![7; 1 << 20]);
+        cache.retrieve_or_compute(&2);
+
+        let value = cache.get_ref(&1).unwrap();
+        assert_eq!(value.len(), 1 << 20);
+        assert_eq!(*value.key(), 1);
+        assert_eq!(format!("{:?}", value).len(), 3 << 20);
+        drop(value);
+        assert_eq!(cache.peek(&1).map(|v| v[0]), Some(7));
+        assert!(cache.get_ref(&2).is_none());
+        assert!(cache.get_ref(&3).is_none());
+    }
+}
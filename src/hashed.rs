@@ -0,0 +1,306 @@
+//! Caches keyed by a 128-bit hash of the key instead of the key itself.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lru::LruCache;
+
+use crate::cache::{capacity, Cache, MissHandler};
+use crate::lock::MutexExt;
+use crate::ops::{CacheOps, DynCache};
+use crate::stats::CacheStats;
+
+/// Whether a [`HashedKeyCache`] checks that a hash belongs to the key being
+/// looked up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyVerification {
+    /// Trust the hash. Two keys colliding on all 128 bits silently share an
+    /// entry; with random hashing that is astronomically unlikely, but not
+    /// impossible.
+    Disabled,
+    /// Keep the full key of one hash in `n` (1 if `n` is 0) and compare it
+    /// on every lookup of that hash, counting mismatches in
+    /// [`HashedKeyCache::collisions`]. Costs one stored key per `n`
+    /// entries; a colliding key is computed without being cached.
+    Sampled(u32),
+}
+
+/// Full keys of the in-flight computations, for the miss handler, with the
+/// number of callers relying on each.
+type PendingKeys<K> = Mutex<HashMap<u128, (K, usize)>>;
+
+/// A cache that stores a 128-bit hash of each key rather than the key, for
+/// key spaces of long strings or paths where the keys dominate memory.
+///
+/// The trade-off is correctness under hash collisions, made explicit by
+/// [`KeyVerification`]. Hashes are randomly seeded per cache, so collisions
+/// cannot be engineered from outside the process.
+pub struct HashedKeyCache<K, D> {
+    cache: Cache<u128, D>,
+    hashers: [RandomState; 2],
+    loader: Arc<MissHandler<K, D>>,
+    pending: Arc<PendingKeys<K>>,
+    /// One in how many hashes is verified, and their full keys.
+    verified: Option<(u128, Mutex<LruCache<u128, K>>)>,
+    collisions: AtomicU64,
+}
+
+impl<K, D> HashedKeyCache<K, D>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    D: Clone + Default + 'static,
+{
+    /// Creates a cache of at most `size` entries computing values with
+    /// `miss_handler`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn new<F>(
+        size: usize,
+        positive_ttl: Duration,
+        negative_ttl: Duration,
+        verification: KeyVerification,
+        miss_handler: F,
+    ) -> Self
+    where
+        F: Fn(&K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        let loader: Arc<MissHandler<K, D>> = Arc::new(miss_handler);
+        let pending: Arc<PendingKeys<K>> = Arc::default();
+        let cache = {
+            let loader = loader.clone();
+            let pending = pending.clone();
+            Cache::new(
+                size,
+                positive_ttl,
+                negative_ttl,
+                move |hash: &u128, data: &mut D, adhoc_code: &mut u8| {
+                    let key = pending
+                        .lock_or_recover()
+                        .get(hash)
+                        .map(|(key, _)| key.clone());
+                    key.is_some_and(|key| loader(&key, data, adhoc_code))
+                },
+            )
+        };
+        let verified = match verification {
+            KeyVerification::Disabled => None,
+            KeyVerification::Sampled(n) => Some((
+                u128::from(n.max(1)),
+                Mutex::new(LruCache::new(capacity(size))),
+            )),
+        };
+        HashedKeyCache {
+            cache,
+            hashers: [RandomState::new(), RandomState::new()],
+            loader,
+            pending,
+            verified,
+            collisions: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached value of `key`, if any. See [`Cache::get`].
+    pub fn get(&self, key: &K) -> Option<D> {
+        let hash = self.hash(key);
+        if !self.verify(hash, key) {
+            return None;
+        }
+        self.cache.get(&hash)
+    }
+
+    /// Caches `data` under `key`. See [`Cache::insert`].
+    pub fn insert(&self, key: K, data: D) {
+        let hash = self.hash(&key);
+        self.forget(hash);
+        self.verify(hash, &key);
+        self.cache.insert(hash, data);
+    }
+
+    /// Returns the value of `key`, computing it on a miss. See
+    /// [`Cache::retrieve_or_compute`].
+    pub fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        let hash = self.hash(key);
+        if !self.verify(hash, key) {
+            let mut data = D::default();
+            let mut adhoc_code = 0;
+            let success = (self.loader)(key, &mut data, &mut adhoc_code);
+            return (data, success, adhoc_code);
+        }
+        self.pending
+            .lock_or_recover()
+            .entry(hash)
+            .or_insert_with(|| (key.clone(), 0))
+            .1 += 1;
+        let result = self.cache.retrieve_or_compute(&hash);
+        let mut pending = self.pending.lock_or_recover();
+        if let Some((_, callers)) = pending.get_mut(&hash) {
+            *callers -= 1;
+            if *callers == 0 {
+                pending.remove(&hash);
+            }
+        }
+        result
+    }
+
+    /// Drops `key`, returning its value if it was cached. See
+    /// [`Cache::remove`].
+    pub fn remove(&self, key: &K) -> Option<D> {
+        let hash = self.hash(key);
+        if !self.verify(hash, key) {
+            return None;
+        }
+        self.forget(hash);
+        self.cache.remove(&hash)
+    }
+
+    /// Number of lookups whose key did not match the verified key of its
+    /// hash. Always 0 with [`KeyVerification::Disabled`].
+    pub fn collisions(&self) -> u64 {
+        self.collisions.load(Ordering::Relaxed)
+    }
+
+    /// Number of entries held.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Returns `true` if no entries are held.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    fn hash(&self, key: &K) -> u128 {
+        let [high, low] = &self.hashers;
+        (u128::from(high.hash_one(key)) << 64) | u128::from(low.hash_one(key))
+    }
+
+    /// Returns `false` if `hash` is sampled and belongs to another key,
+    /// recording `key` as the owner of a sampled hash seen for the first
+    /// time.
+    fn verify(&self, hash: u128, key: &K) -> bool {
+        let Some((one_in, keys)) = &self.verified else {
+            return true;
+        };
+        if !hash.is_multiple_of(*one_in) {
+            return true;
+        }
+        let mut keys = keys.lock_or_recover();
+        match keys.get(&hash) {
+            Some(owner) if owner != key => {
+                self.collisions.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            Some(_) => true,
+            None => {
+                keys.push(hash, key.clone());
+                true
+            }
+        }
+    }
+
+    /// Releases the sampled hash `hash`, if recorded, to a new owner.
+    fn forget(&self, hash: u128) {
+        if let Some((_, keys)) = &self.verified {
+            keys.lock_or_recover().pop(&hash);
+        }
+    }
+}
+
+impl<K, D> CacheOps<K, D> for HashedKeyCache<K, D>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    D: Clone + Default + 'static,
+{
+    fn get(&self, key: &K) -> Option<D> {
+        HashedKeyCache::get(self, key)
+    }
+
+    fn insert(&self, key: K, data: D) {
+        HashedKeyCache::insert(self, key, data)
+    }
+
+    fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        HashedKeyCache::retrieve_or_compute(self, key)
+    }
+
+    fn remove(&self, key: &K) -> Option<D> {
+        HashedKeyCache::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        HashedKeyCache::len(self)
+    }
+}
+
+impl<K, D> DynCache<K, D> for HashedKeyCache<K, D>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    D: Clone + Default + Send + Sync + 'static,
+{
+    fn invalidate(&self, key: &K) {
+        HashedKeyCache::remove(self, key);
+    }
+
+    fn invalidate_all(&self) {
+        self.cache.invalidate_all();
+        if let Some((_, keys)) = &self.verified {
+            keys.lock_or_recover().clear();
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(verification: KeyVerification) -> HashedKeyCache<String, usize> {
+        HashedKeyCache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            verification,
+            |key: &String, data: &mut usize, _: &mut u8| {
+                *data = key.len();
+                true
+            },
+        )
+    }
+
+    #[test]
+    fn keys_are_stored_as_hashes() {
+        let cache = cache(KeyVerification::Sampled(1));
+        let path = "/var/lib/some/very/long/path".to_string();
+
+        assert_eq!(cache.retrieve_or_compute(&path), (path.len(), true, 0));
+        assert_eq!(cache.get(&path), Some(path.len()));
+        assert!(cache.pending.lock().unwrap().is_empty());
+        cache.insert("other".to_string(), 1);
+        assert_eq!(cache.remove(&"other".to_string()), Some(1));
+        assert_eq!((cache.len(), cache.collisions()), (1, 0));
+    }
+
+    #[test]
+    fn sampled_verification_detects_collisions() {
+        let cache = cache(KeyVerification::Sampled(1));
+        let key = "a".to_string();
+        let hash = cache.hash(&key);
+        cache.insert(key, 100);
+        // Claim the hash for another key, as a collision would.
+        let (_, keys) = cache.verified.as_ref().unwrap();
+        keys.lock().unwrap().put(hash, "b".to_string());
+
+        assert_eq!(cache.get(&"a".to_string()), None);
+        assert_eq!(cache.retrieve_or_compute(&"a".to_string()), (1, true, 0));
+        assert_eq!(cache.collisions(), 2);
+    }
+}
@@ -0,0 +1,52 @@
+//! Hashers for [`CacheBuilder::with_hasher`](crate::CacheBuilder::with_hasher).
+//!
+//! The default hasher resists collision attacks on untrusted keys. When
+//! keys are trusted and cheap to hash, such as small integers, these
+//! faster ones can noticeably cut lookup costs.
+
+#[cfg(any(feature = "ahash", feature = "fxhash"))]
+use crate::cache::Cache;
+
+/// Builds [aHash](https://docs.rs/ahash) hashers. Requires the `ahash`
+/// feature.
+#[cfg(feature = "ahash")]
+pub type AHash = ahash::RandomState;
+
+/// A cache hashing keys with [`AHash`].
+#[cfg(feature = "ahash")]
+pub type AHashCache<K, D> = Cache<K, D, AHash>;
+
+/// Builds the Fx hasher used by rustc. Not collision resistant; only use
+/// it with trusted keys. Requires the `fxhash` feature.
+#[cfg(feature = "fxhash")]
+pub type FxHash = rustc_hash::FxBuildHasher;
+
+/// A cache hashing keys with [`FxHash`].
+#[cfg(feature = "fxhash")]
+pub type FxCache<K, D> = Cache<K, D, FxHash>;
+
+#[cfg(all(test, feature = "ahash", feature = "fxhash"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caches_work_with_custom_hashers() {
+        let double = |key: &u64, data: &mut u64, _: &mut u8| {
+            *data = key * 2;
+            true
+        };
+        let fx: FxCache<u64, u64> = Cache::builder(10)
+            .miss_handler(double)
+            .with_hasher(FxHash::default())
+            .build();
+        let ahash: AHashCache<u64, u64> = Cache::builder(10)
+            .miss_handler(double)
+            .with_hasher(AHash::new())
+            .build();
+
+        assert_eq!(fx.retrieve_or_compute(&21), (42, true, 0));
+        assert_eq!(ahash.retrieve_or_compute(&21), (42, true, 0));
+        let _guard = fx.hold(&21).unwrap();
+        assert_eq!(fx.get(&21), Some(42));
+    }
+}
@@ -0,0 +1,207 @@
+//! Pinning entries against expiration and eviction.
+
+use std::hash::{BuildHasher, Hash};
+
+use lru::DefaultHasher;
+
+use crate::cache::{Cache, EntryStatus};
+use crate::clock::{Clock, SystemClock};
+use crate::lock::RwLockExt;
+
+/// Keeps an entry alive while it exists; returned by [`Cache::hold`].
+///
+/// A held entry neither expires nor gets evicted to make room for other
+/// entries. It can still be replaced by [`Cache::insert`] or dropped by
+/// [`Cache::remove`]; the hold then applies to the replacement, or lapses.
+/// Invalidation is not held off: once the entry is invalidated, e.g. by
+/// [`Cache::invalidate_all`], the guard no longer returns it.
+pub struct HoldGuard<'a, K, D, S = DefaultHasher, C = SystemClock>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    cache: &'a Cache<K, D, S, C>,
+    key: K,
+    /// The `held_seq` of the entry pinned.
+    held_seq: u64,
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Pins the entry for `key` until the returned guard is dropped.
+    ///
+    /// Returns `None` if there is no successfully computed, unexpired entry
+    /// to hold. Holds nest: the entry is released when the last guard is
+    /// dropped.
+    pub fn hold(&self, key: &K) -> Option<HoldGuard<'_, K, D, S, C>> {
+        let now = self.now();
+        let mut cache = self.lru_cache.write_or_recover();
+        let live = cache
+            .peek(key)
+            .is_some_and(|entry| entry.status == EntryStatus::Ready && self.is_live(entry, now));
+        if !live {
+            self.promote_from_l2(&mut cache, key, now)?;
+        }
+        let entry = cache.peek_mut(key)?;
+        if entry.holds == 0 {
+            entry.held_seq = entry.seq;
+        }
+        entry.holds += 1;
+        Some(HoldGuard {
+            cache: self,
+            key: key.clone(),
+            held_seq: entry.held_seq,
+        })
+    }
+}
+
+impl<K, D, S, C> HoldGuard<'_, K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// The held key.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// The current value of the held entry, without promoting it.
+    ///
+    /// Returns `None` if the entry was removed or invalidated while held.
+    pub fn get(&self) -> Option<D> {
+        let now = self.cache.now();
+        let cache = self.cache.lru_cache.read_or_recover();
+        cache
+            .peek(&self.key)
+            .filter(|entry| entry.status == EntryStatus::Ready && self.cache.is_current(entry, now))
+            .map(|entry| entry.data.clone())
+    }
+}
+
+impl<K, D, S, C> Drop for HoldGuard<'_, K, D, S, C>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    fn drop(&mut self) {
+        let mut cache = self.cache.lru_cache.write_or_recover();
+        if let Some(entry) = cache.peek_mut(&self.key) {
+            if entry.held_seq == self.held_seq {
+                entry.holds = entry.holds.saturating_sub(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, ManualClock, ManualClockCache};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn cache(size: usize, clock: Arc<ManualClock>) -> ManualClockCache<u32, u32> {
+        Cache::builder(size)
+            .positive_ttl(Duration::from_secs(30))
+            .negative_ttl(Duration::from_secs(30))
+            .clock(clock)
+            .miss_handler(|key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                true
+            })
+            .build()
+    }
+
+    #[test]
+    fn held_entries_are_not_evicted() {
+        let cache = cache(2, Arc::new(ManualClock::new()));
+        cache.insert(1, 10);
+        cache.insert(2, 20);
+        let guard = cache.hold(&1).unwrap();
+        cache.insert(3, 30);
+
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(guard.get(), Some(10));
+        drop(guard);
+
+        cache.insert(4, 40);
+        assert_eq!(cache.get(&1), None);
+    }
+
+    #[test]
+    fn held_entries_do_not_expire_until_released() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = cache(2, clock.clone());
+        cache.insert(1, 10);
+        let guard = cache.hold(&1).unwrap();
+        let nested = cache.hold(&1).unwrap();
+        clock.advance(Duration::from_secs(40));
+
+        assert_eq!(cache.get(&1), Some(10));
+        drop(guard);
+        assert_eq!(cache.get(&1), Some(10));
+        drop(nested);
+        assert_eq!(cache.get(&1), None);
+    }
+
+    #[test]
+    fn max_staleness_bounds_held_entries() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(2)
+            .positive_ttl(Duration::from_secs(20))
+            .max_staleness(Duration::from_secs(30))
+            .clock(clock.clone())
+            .miss_handler(|_: &u32, data: &mut u32, _: &mut u8| {
+                *data = 99;
+                true
+            })
+            .build();
+        cache.insert(1, 10);
+        let _guard = cache.hold(&1).unwrap();
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(cache.get(&1), Some(10));
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.retrieve_or_compute(&1), (99, true, 0));
+    }
+
+    #[test]
+    fn nothing_to_hold_when_missing() {
+        let cache = cache(2, Arc::new(ManualClock::new()));
+        assert!(cache.hold(&1).is_none());
+    }
+
+    #[test]
+    fn guards_of_removed_entries_leave_new_holds_alone() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = cache(2, clock.clone());
+        cache.insert(1, 10);
+        let stale = cache.hold(&1).unwrap();
+        cache.remove(&1);
+        cache.insert(1, 11);
+        let held = cache.hold(&1).unwrap();
+
+        drop(stale);
+        clock.advance(Duration::from_secs(40));
+        assert_eq!(held.get(), Some(11));
+        assert_eq!(cache.get(&1), Some(11));
+    }
+
+    #[test]
+    fn invalidation_is_not_held_off() {
+        let cache = cache(2, Arc::new(ManualClock::new()));
+        cache.insert(1, 10);
+        let guard = cache.hold(&1).unwrap();
+
+        cache.invalidate_all();
+        assert_eq!(guard.get(), None);
+        assert_eq!(cache.get(&1), None);
+    }
+}
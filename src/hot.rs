@@ -0,0 +1,176 @@
+//! Finding the keys that take most of the traffic.
+//!
+//! With tracking enabled, one hit in every `sample_every` is counted
+//! against its key in two generations rotated every window, so that
+//! [`Cache::hot_keys`] reports the keys hit most over the last one to two
+//! windows. Operators can use it to spot skew and pick keys to pin or
+//! pre-warm.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::cache::Cache;
+use crate::clock::Clock;
+use crate::lock::MutexExt;
+use crate::time::Instant;
+
+/// Keys counted per generation; once full, keys sampled only once are
+/// dropped to make room.
+const MAX_TRACKED: usize = 4096;
+
+/// Sampled hit counts per key.
+pub(crate) struct HotKeys<K> {
+    sample_every: u64,
+    window: Duration,
+    hits: AtomicU64,
+    state: Mutex<HotState<K>>,
+}
+
+struct HotState<K> {
+    current: HashMap<K, u64>,
+    previous: HashMap<K, u64>,
+    rotated_at: Option<Instant>,
+}
+
+impl<K: Hash + Eq + Clone> HotKeys<K> {
+    pub(crate) fn new(sample_every: u32, window: Duration) -> Self {
+        HotKeys {
+            sample_every: u64::from(sample_every.max(1)),
+            window,
+            hits: AtomicU64::new(0),
+            state: Mutex::new(HotState {
+                current: HashMap::new(),
+                previous: HashMap::new(),
+                rotated_at: None,
+            }),
+        }
+    }
+
+    /// Returns `true` if the next hit should be counted.
+    fn sampled(&self) -> bool {
+        self.hits
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.sample_every)
+    }
+
+    /// Counts a hit of `key`.
+    fn record(&self, key: &K, now: Instant) {
+        let mut state = self.state(now);
+        if let Some(count) = state.current.get_mut(key) {
+            *count += 1;
+            return;
+        }
+        if state.current.len() >= MAX_TRACKED {
+            state.current.retain(|_, count| *count > 1);
+            if state.current.len() >= MAX_TRACKED {
+                return;
+            }
+        }
+        state.current.insert(key.clone(), 1);
+    }
+
+    /// The `n` keys with the most hits, with their estimated hit count.
+    fn top(&self, n: usize, now: Instant) -> Vec<(K, u64)> {
+        let state = self.state(now);
+        let mut counts = state.previous.clone();
+        for (key, count) in &state.current {
+            *counts.entry(key.clone()).or_insert(0) += count;
+        }
+        drop(state);
+        let mut top: Vec<(K, u64)> = counts
+            .into_iter()
+            .map(|(key, count)| (key, count * self.sample_every))
+            .collect();
+        top.sort_unstable_by_key(|&(_, count)| Reverse(count));
+        top.truncate(n);
+        top
+    }
+
+    /// Locks the counts, first rotating generations if a window has
+    /// elapsed.
+    fn state(&self, now: Instant) -> MutexGuard<'_, HotState<K>> {
+        let mut state = self.state.lock_or_recover();
+        let rotated_at = *state.rotated_at.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(rotated_at);
+        if elapsed >= self.window {
+            let state = &mut *state;
+            if elapsed >= 2 * self.window {
+                state.previous.clear();
+            } else {
+                mem::swap(&mut state.previous, &mut state.current);
+            }
+            state.current.clear();
+            state.rotated_at = Some(now);
+        }
+        state
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// The `n` keys hit most over the last one to two windows, most hit
+    /// first, with their hit count estimated from the samples. Empty
+    /// unless tracking was enabled with
+    /// [`hot_key_tracking`](crate::CacheBuilder::hot_key_tracking).
+    pub fn hot_keys(&self, n: usize) -> Vec<(K, u64)> {
+        match &self.hot_keys {
+            Some(hot_keys) => hot_keys.top(n, self.now()),
+            None => Vec::new(),
+        }
+    }
+
+    /// Counts a hit of `key` if it is sampled.
+    pub(crate) fn record_hit(&self, key: &K) {
+        if let Some(hot_keys) = &self.hot_keys {
+            if hot_keys.sampled() {
+                hot_keys.record(key, self.now());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, ManualClock};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn the_most_hit_keys_are_reported() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(10)
+            .clock(clock.clone())
+            .positive_ttl(Duration::from_secs(3600))
+            .hot_key_tracking(1, Duration::from_secs(60))
+            .miss_handler(|key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                true
+            })
+            .build();
+        for (key, hits) in [(1, 3), (2, 10), (3, 6)] {
+            cache.retrieve_or_compute(&key);
+            for _ in 0..hits {
+                cache.get(&key);
+            }
+        }
+        assert_eq!(cache.hot_keys(2), vec![(2, 10), (3, 6)]);
+
+        clock.advance(Duration::from_secs(61));
+        for _ in 0..20 {
+            cache.get(&1);
+        }
+        assert_eq!(cache.hot_keys(2), vec![(1, 23), (2, 10)]);
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(cache.hot_keys(3), vec![(1, 20)]);
+    }
+}
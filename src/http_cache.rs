@@ -0,0 +1,277 @@
+//! HTTP caching semantics, behind the `http` feature, for caching the
+//! responses of an HTTP client.
+//!
+//! [`freshness`] turns `Cache-Control`, `Expires`, `Date` and `Age` into a
+//! [`CacheDecision`], which
+//! [`http_cache_policy`](CacheBuilder::http_cache_policy) applies to every
+//! load. [`CachedResponse`] keeps the `ETag` and `Last-Modified` of a
+//! response, so that a
+//! [`revalidating_miss_handler`](CacheBuilder::revalidating_miss_handler)
+//! can send a conditional request for an expired one and keep it on
+//! `304 Not Modified`.
+
+use std::hash::{BuildHasher, Hash};
+use std::time::{Duration, SystemTime};
+
+use http::header::{
+    AGE, CACHE_CONTROL, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use http::{HeaderMap, HeaderValue, StatusCode};
+
+use crate::builder::CacheBuilder;
+use crate::cache_policy::{CacheDecision, LoadMeta};
+use crate::clock::Clock;
+
+/// A response as cached: status, headers and a buffered body.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CachedResponse<B> {
+    /// Status of the response.
+    pub status: StatusCode,
+    /// Headers of the response, updated by revalidations.
+    pub headers: HeaderMap,
+    /// Buffered body of the response.
+    pub body: B,
+}
+
+impl<B> CachedResponse<B> {
+    /// Creates a response to cache.
+    pub fn new(status: StatusCode, headers: HeaderMap, body: B) -> Self {
+        CachedResponse {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    /// Headers making a request conditional on this response having
+    /// changed: `If-None-Match` with its `ETag` and `If-Modified-Since`
+    /// with its `Last-Modified`. Empty if it has neither.
+    pub fn conditional_headers(&self) -> HeaderMap {
+        let mut conditional = HeaderMap::new();
+        if let Some(etag) = self.headers.get(ETAG) {
+            conditional.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = self.headers.get(LAST_MODIFIED) {
+            conditional.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        conditional
+    }
+
+    /// This response with the headers of a `304 Not Modified` answering a
+    /// conditional request for it, which replace the stored ones of the
+    /// same name.
+    pub fn revalidated(&self, not_modified: &HeaderMap) -> Self
+    where
+        B: Clone,
+    {
+        let mut response = self.clone();
+        for name in not_modified.keys() {
+            response.headers.remove(name);
+            for value in not_modified.get_all(name) {
+                response.headers.append(name, value.clone());
+            }
+        }
+        response
+    }
+
+    /// How long the response may be cached; see [`freshness`].
+    pub fn freshness(&self, now: SystemTime) -> CacheDecision {
+        freshness(&self.headers, now)
+    }
+}
+
+/// How long a response with `headers` may be cached, as of `now`.
+///
+/// `no-store` skips caching and `no-cache` caches the response already
+/// expired, so that it is revalidated before every use. Otherwise the
+/// lifetime comes from `max-age`, or from `Expires` minus `Date`, less the
+/// `Age` of the response; an unparsable `Expires` means already expired.
+/// Without any of those, the TTLs of the cache apply.
+pub fn freshness(headers: &HeaderMap, now: SystemTime) -> CacheDecision {
+    let mut max_age = None;
+    for directive in headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+    {
+        let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+        let name = name.trim();
+        if name.eq_ignore_ascii_case("no-store") {
+            return CacheDecision::Skip;
+        }
+        if name.eq_ignore_ascii_case("no-cache") {
+            return CacheDecision::CacheFor(Duration::ZERO);
+        }
+        if name.eq_ignore_ascii_case("max-age") {
+            max_age = value
+                .trim()
+                .trim_matches('"')
+                .parse()
+                .ok()
+                .map(Duration::from_secs);
+        }
+    }
+    let lifetime = match (max_age, headers.get(EXPIRES)) {
+        (Some(max_age), _) => max_age,
+        (None, Some(expires)) => {
+            let date = headers.get(DATE).and_then(parse_http_date).unwrap_or(now);
+            parse_http_date(expires)
+                .and_then(|expires| expires.duration_since(date).ok())
+                .unwrap_or(Duration::ZERO)
+        }
+        (None, None) => return CacheDecision::Cache,
+    };
+    let age = headers
+        .get(AGE)
+        .and_then(|age| age.to_str().ok()?.trim().parse().ok())
+        .map_or(Duration::ZERO, Duration::from_secs);
+    CacheDecision::CacheFor(lifetime.saturating_sub(age))
+}
+
+impl<K, B, S, C> CacheBuilder<K, CachedResponse<B>, S, C>
+where
+    K: Hash + Eq + Clone,
+    B: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Caches successfully loaded responses for as long as their headers
+    /// allow, as decided by [`freshness`]; failures keep the negative TTL.
+    pub fn http_cache_policy(self) -> Self {
+        self.cache_policy(|_: &K, response: &CachedResponse<B>, meta: &LoadMeta| {
+            if meta.success {
+                response.freshness(SystemTime::now())
+            } else {
+                CacheDecision::Cache
+            }
+        })
+    }
+}
+
+/// Parses an IMF-fixdate such as `Sun, 06 Nov 1994 08:49:37 GMT`, the only
+/// date format senders may generate.
+fn parse_http_date(value: &HeaderValue) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let mut parts = value.to_str().ok()?.split_once(", ")?.1.split(' ');
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|&name| name == month)? as u64 + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(str::parse::<u64>);
+    let (hours, minutes, seconds) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if parts.next() != Some("GMT") || !(1..=31).contains(&day) || year < 1970 {
+        return None;
+    }
+    // Days since the epoch of a proleptic Gregorian date, counting years
+    // from March so that leap days come last.
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let days =
+        year * 365 + year / 4 - year / 100 + year / 400 + (153 * month + 2) / 5 + day - 1 - 719_468;
+    let seconds = days * 86_400 + hours * 3_600 + minutes * 60 + seconds;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cache, ManualClock};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn headers(pairs: &[(http::header::HeaderName, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn freshness_follows_the_headers() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let decide = |pairs: &[_]| freshness(&headers(pairs), now);
+
+        assert_eq!(decide(&[]), CacheDecision::Cache);
+        assert_eq!(
+            decide(&[(CACHE_CONTROL, "public, max-age=600"), (AGE, "100")]),
+            CacheDecision::CacheFor(Duration::from_secs(500))
+        );
+        assert_eq!(
+            decide(&[(CACHE_CONTROL, "max-age=60, No-Store")]),
+            CacheDecision::Skip
+        );
+        assert_eq!(
+            decide(&[(CACHE_CONTROL, "no-cache")]),
+            CacheDecision::CacheFor(Duration::ZERO)
+        );
+        assert_eq!(
+            decide(&[
+                (DATE, "Sun, 06 Nov 1994 08:49:37 GMT"),
+                (EXPIRES, "Sun, 06 Nov 1994 09:49:37 GMT"),
+            ]),
+            CacheDecision::CacheFor(Duration::from_secs(3_600))
+        );
+        assert_eq!(
+            decide(&[(EXPIRES, "Sun, 06 Nov 1994 08:59:37 GMT")]),
+            CacheDecision::CacheFor(Duration::from_secs(600))
+        );
+        assert_eq!(
+            decide(&[(EXPIRES, "0")]),
+            CacheDecision::CacheFor(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn expired_responses_are_revalidated() {
+        let clock = Arc::new(ManualClock::new());
+        let not_modified = Arc::new(AtomicUsize::new(0));
+        let cache = {
+            let not_modified = not_modified.clone();
+            Cache::builder(10)
+                .clock(clock.clone())
+                .revalidating_miss_handler(
+                    move |_: &&str,
+                          response: &mut CachedResponse<&str>,
+                          _: &mut u8,
+                          previous: Option<&CachedResponse<&str>>| {
+                        let fresh = headers(&[(CACHE_CONTROL, "max-age=30")]);
+                        *response = match previous {
+                            Some(previous)
+                                if previous.conditional_headers().get(IF_NONE_MATCH)
+                                    == Some(&HeaderValue::from_static("\"v1\"")) =>
+                            {
+                                not_modified.fetch_add(1, Ordering::SeqCst);
+                                previous.revalidated(&fresh)
+                            }
+                            _ => CachedResponse::new(
+                                StatusCode::OK,
+                                headers(&[(ETAG, "\"v1\""), (CACHE_CONTROL, "max-age=60")]),
+                                "body",
+                            ),
+                        };
+                        true
+                    },
+                )
+                .http_cache_policy()
+                .build()
+        };
+
+        assert_eq!(cache.retrieve_or_compute(&"/a").0.body, "body");
+        assert_eq!(cache.time_to_live(&"/a"), Some(Duration::from_secs(60)));
+        clock.advance(Duration::from_secs(61));
+
+        let (response, success, _) = cache.retrieve_or_compute(&"/a");
+        assert!(success);
+        assert_eq!(response.body, "body");
+        assert_eq!(response.headers[ETAG], "\"v1\"");
+        assert_eq!(response.headers[CACHE_CONTROL], "max-age=30");
+        assert_eq!(not_modified.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.time_to_live(&"/a"), Some(Duration::from_secs(30)));
+    }
+}
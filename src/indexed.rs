@@ -0,0 +1,260 @@
+//! Entries addressable by a secondary key derived from their value.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::cache::Cache;
+use crate::lock::{MutexExt, RwLockExt};
+use crate::ops::{CacheOps, DynCache};
+use crate::stats::CacheStats;
+
+/// Derives the secondary key of a value.
+type SecondaryKey<D, S> = dyn Fn(&D) -> S + Send + Sync;
+
+/// A cache whose entries can also be looked up and invalidated by a
+/// secondary key extracted from their value, e.g. sessions addressable by
+/// session ID and by user ID.
+///
+/// Several entries may share a secondary key. The index is maintained
+/// lazily: primary keys that were evicted, expired or now hold a value with
+/// another secondary key are pruned when they are next reached through the
+/// index, and whenever the index grows past twice the cache capacity.
+pub struct IndexedCache<K, S, D> {
+    cache: Cache<K, D>,
+    secondary_key: Box<SecondaryKey<D, S>>,
+    index: Mutex<HashMap<S, HashSet<K>>>,
+}
+
+impl<K, S, D> IndexedCache<K, S, D>
+where
+    K: Hash + Eq + Clone,
+    S: Hash + Eq,
+    D: Clone + Default,
+{
+    /// Creates a cache holding at most `size` entries, indexing every
+    /// successfully computed or inserted value under `secondary_key(value)`.
+    pub fn new<F, G>(
+        size: usize,
+        positive_ttl: Duration,
+        negative_ttl: Duration,
+        secondary_key: G,
+        miss_handler: F,
+    ) -> Self
+    where
+        F: Fn(&K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+        G: Fn(&D) -> S + Send + Sync + 'static,
+    {
+        IndexedCache {
+            cache: Cache::new(size, positive_ttl, negative_ttl, miss_handler),
+            secondary_key: Box::new(secondary_key),
+            index: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// See [`Cache::get`].
+    pub fn get(&self, key: &K) -> Option<D> {
+        self.cache.get(key)
+    }
+
+    /// See [`Cache::insert`].
+    pub fn insert(&self, key: K, data: D) {
+        let secondary = (self.secondary_key)(&data);
+        self.cache.insert(key.clone(), data);
+        self.index(secondary, key);
+    }
+
+    /// See [`Cache::retrieve_or_compute`].
+    pub fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        let result = self.cache.retrieve_or_compute(key);
+        if result.1 {
+            self.index((self.secondary_key)(&result.0), key.clone());
+        }
+        result
+    }
+
+    /// See [`Cache::remove`].
+    pub fn remove(&self, key: &K) -> Option<D> {
+        let data = self.cache.remove(key)?;
+        let secondary = (self.secondary_key)(&data);
+        let mut index = self.index.lock_or_recover();
+        if let Some(keys) = index.get_mut(&secondary) {
+            keys.remove(key);
+            if keys.is_empty() {
+                index.remove(&secondary);
+            }
+        }
+        Some(data)
+    }
+
+    /// Returns every cached entry whose secondary key is `secondary`, in no
+    /// particular order.
+    pub fn get_by_secondary(&self, secondary: &S) -> Vec<(K, D)> {
+        let mut index = self.index.lock_or_recover();
+        let Some(keys) = index.get_mut(secondary) else {
+            return Vec::new();
+        };
+        let mut found = Vec::new();
+        keys.retain(|key| match self.cache.get(key) {
+            Some(data) if (self.secondary_key)(&data) == *secondary => {
+                found.push((key.clone(), data));
+                true
+            }
+            _ => false,
+        });
+        if keys.is_empty() {
+            index.remove(secondary);
+        }
+        found
+    }
+
+    /// Removes every entry whose secondary key is `secondary` and returns
+    /// how many were removed.
+    pub fn remove_by_secondary(&self, secondary: &S) -> usize {
+        let Some(keys) = self.index.lock_or_recover().remove(secondary) else {
+            return 0;
+        };
+        keys.iter()
+            .filter(|&key| {
+                let matches = self
+                    .cache
+                    .get(key)
+                    .is_some_and(|data| (self.secondary_key)(&data) == *secondary);
+                matches && self.cache.remove(key).is_some()
+            })
+            .count()
+    }
+
+    /// Number of entries held.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Returns `true` if no entries are held.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// The underlying cache. Entries inserted through it are not indexed.
+    pub fn inner(&self) -> &Cache<K, D> {
+        &self.cache
+    }
+
+    fn index(&self, secondary: S, key: K) {
+        let mut index = self.index.lock_or_recover();
+        index.entry(secondary).or_default().insert(key);
+        let cache = self.cache.lru_cache.read_or_recover();
+        if index.len() > cache.cap().get().saturating_mul(2) {
+            index.retain(|_, keys| {
+                keys.retain(|key| cache.contains(key));
+                !keys.is_empty()
+            });
+        }
+    }
+}
+
+impl<K, S, D> CacheOps<K, D> for IndexedCache<K, S, D>
+where
+    K: Hash + Eq + Clone,
+    S: Hash + Eq,
+    D: Clone + Default,
+{
+    fn get(&self, key: &K) -> Option<D> {
+        IndexedCache::get(self, key)
+    }
+
+    fn insert(&self, key: K, data: D) {
+        IndexedCache::insert(self, key, data)
+    }
+
+    fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        IndexedCache::retrieve_or_compute(self, key)
+    }
+
+    fn remove(&self, key: &K) -> Option<D> {
+        IndexedCache::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        IndexedCache::len(self)
+    }
+}
+
+impl<K, S, D> DynCache<K, D> for IndexedCache<K, S, D>
+where
+    K: Hash + Eq + Clone + Send + Sync,
+    S: Hash + Eq + Send,
+    D: Clone + Default + Send + Sync,
+{
+    fn invalidate(&self, key: &K) {
+        IndexedCache::remove(self, key);
+    }
+
+    fn invalidate_all(&self) {
+        self.cache.invalidate_all();
+        self.index.lock_or_recover().clear();
+    }
+
+    fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Default, PartialEq)]
+    struct Session {
+        user: u32,
+    }
+
+    fn sessions() -> IndexedCache<u64, u32, Session> {
+        IndexedCache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |session: &Session| session.user,
+            |id: &u64, session: &mut Session, _: &mut u8| {
+                session.user = (*id / 100) as u32;
+                true
+            },
+        )
+    }
+
+    #[test]
+    fn entries_are_reachable_by_secondary_key() {
+        let cache = sessions();
+        cache.insert(1, Session { user: 7 });
+        cache.insert(2, Session { user: 7 });
+        cache.insert(3, Session { user: 8 });
+        cache.retrieve_or_compute(&701);
+
+        let mut found = cache.get_by_secondary(&7);
+        found.sort_by_key(|(id, _)| *id);
+        let ids: Vec<u64> = found.into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, [1, 2, 701]);
+
+        cache.insert(2, Session { user: 8 });
+        assert_eq!(cache.get_by_secondary(&7).len(), 2);
+        assert_eq!(cache.get_by_secondary(&8).len(), 2);
+        assert!(cache.get_by_secondary(&9).is_empty());
+    }
+
+    #[test]
+    fn invalidates_by_either_key() {
+        let cache = sessions();
+        cache.insert(1, Session { user: 7 });
+        cache.insert(2, Session { user: 7 });
+        cache.insert(3, Session { user: 8 });
+
+        assert_eq!(cache.remove_by_secondary(&7), 2);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&3), Some(Session { user: 8 }));
+
+        cache.remove(&3);
+        assert!(cache.get_by_secondary(&8).is_empty());
+        assert!(cache.is_empty());
+    }
+}
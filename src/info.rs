@@ -0,0 +1,224 @@
+//! Inspecting single entries, for debugging and admin dashboards.
+
+use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
+
+use crate::cache::{Cache, EntryStatus};
+use crate::clock::Clock;
+use crate::lock::RwLockExt;
+use crate::time::Instant;
+
+/// Snapshot of an entry and its metadata, returned by
+/// [`Cache::get_entry`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryInfo<D> {
+    /// The cached value; the default value while calculating.
+    pub data: D,
+    /// Whether the value is an expired one served because refreshing it
+    /// failed; see [`CacheBuilder::stale_if_error`](crate::CacheBuilder::stale_if_error).
+    pub stale: bool,
+    /// Whether the value is being computed, was computed or failed.
+    pub status: EntryStatus,
+    /// The adhoc code set by the miss handler.
+    pub adhoc_code: u8,
+    /// When the entry was stored, according to the cache's clock.
+    pub created: Instant,
+    /// Time left before the entry expires, or `None` while calculating.
+    pub expires_in: Option<Duration>,
+    /// Number of lookups the entry has served.
+    pub hits: u64,
+}
+
+/// Where the entry of a key is in its lifecycle, as reported by
+/// [`Cache::status`].
+///
+/// A key starts [`Vacant`](Self::Vacant) and is [`Loading`](Self::Loading)
+/// while its value is first computed, which ends [`Ready`](Self::Ready) or
+/// [`Failed`](Self::Failed). A ready value becomes [`Stale`](Self::Stale)
+/// once it expires, or when served stale after a failed refresh, and
+/// [`Refreshing`](Self::Refreshing) while recomputed with the old value
+/// still at hand. A failure that expires, and an invalidated entry, leave
+/// the key vacant again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EntryState {
+    /// Nothing is cached for the key.
+    Vacant,
+    /// The value is being computed for the first time, or after a failure.
+    Loading,
+    /// A live value is cached.
+    Ready,
+    /// A live failure is cached.
+    Failed,
+    /// The value expired and is still held, is served past its expiration
+    /// because refreshing it failed, or is past its
+    /// [soft TTL](crate::CacheBuilder::soft_ttl).
+    Stale,
+    /// The value is being recomputed, early or after expiring, while the
+    /// old one is kept.
+    Refreshing,
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Returns the entry for `key` with its metadata, without counting as
+    /// a lookup or refreshing its LRU position.
+    ///
+    /// Calculating entries are returned too; expired and invalidated ones
+    /// are not. Spilled entries are not read back from the second tier.
+    pub fn get_entry(&self, key: &K) -> Option<EntryInfo<D>> {
+        let now = self.now();
+        let cache = self.lru_cache.read_or_recover();
+        let entry = cache.peek(key).filter(|entry| self.is_live(entry, now))?;
+        let expires_in = (entry.status != EntryStatus::Calculating)
+            .then(|| entry.expiration.saturating_duration_since(now));
+        let calculating = entry.status == EntryStatus::Calculating;
+        Some(EntryInfo {
+            // Placeholders may carry the value they replace, for
+            // stale-if-error; it is not theirs to show.
+            data: if calculating {
+                D::default()
+            } else {
+                entry.data.clone()
+            },
+            stale: !calculating && entry.stale_until.is_some(),
+            status: entry.status,
+            adhoc_code: entry.adhoc_code,
+            created: entry.created,
+            expires_in,
+            hits: entry.hits,
+        })
+    }
+
+    /// Where the entry for `key` is in its lifecycle; see [`EntryState`].
+    ///
+    /// Like [`get_entry`](Self::get_entry), this is not a lookup. Spilled
+    /// entries are reported vacant.
+    pub fn status(&self, key: &K) -> EntryState {
+        let now = self.now();
+        let cache = self.lru_cache.read_or_recover();
+        let Some(entry) = cache.peek(key) else {
+            return EntryState::Vacant;
+        };
+        match entry.status {
+            EntryStatus::Calculating if entry.stale_until.is_some() || entry.carries_previous => {
+                EntryState::Refreshing
+            }
+            EntryStatus::Calculating => EntryState::Loading,
+            _ if !self.is_current(entry, now) => EntryState::Vacant,
+            EntryStatus::Ready if entry.refreshing => EntryState::Refreshing,
+            EntryStatus::Ready
+                if entry.stale_until.is_some()
+                    || !self.is_live(entry, now)
+                    || self.is_soft_expired(entry, now) =>
+            {
+                EntryState::Stale
+            }
+            EntryStatus::Ready => EntryState::Ready,
+            EntryStatus::Failed if self.is_live(entry, now) => EntryState::Failed,
+            EntryStatus::Failed => EntryState::Vacant,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, ManualClock};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn get_entry_reports_metadata() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .negative_ttl(Duration::from_secs(5))
+            .clock(clock.clone())
+            .miss_handler(|_: &u32, _: &mut u32, adhoc_code: &mut u8| {
+                *adhoc_code = 7;
+                false
+            })
+            .build();
+        let inserted_at = clock.now();
+        cache.insert(1, 10);
+        clock.advance(Duration::from_secs(20));
+        cache.get(&1);
+        cache.retrieve_or_compute(&1);
+        cache.retrieve_or_compute(&2);
+
+        let info = cache.get_entry(&1).unwrap();
+        assert_eq!(
+            (info.data, info.status, info.hits),
+            (10, EntryStatus::Ready, 2)
+        );
+        assert_eq!(info.created, inserted_at);
+        assert_eq!(info.expires_in, Some(Duration::from_secs(40)));
+
+        let info = cache.get_entry(&2).unwrap();
+        assert_eq!(
+            (info.status, info.adhoc_code, info.hits),
+            (EntryStatus::Failed, 7, 0)
+        );
+        assert_eq!(info.expires_in, Some(Duration::from_secs(5)));
+        assert_eq!(cache.get_entry(&3), None);
+    }
+
+    #[test]
+    fn status_follows_the_lifecycle() {
+        let clock = Arc::new(ManualClock::new());
+        let slow = Arc::new(AtomicBool::new(false));
+        let cache = {
+            let slow = slow.clone();
+            Arc::new(
+                Cache::builder(10)
+                    .positive_ttl(Duration::from_secs(60))
+                    .negative_ttl(Duration::from_secs(5))
+                    .stale_if_error(Duration::from_secs(60))
+                    .clock(clock.clone())
+                    .miss_handler(move |key: &u32, data: &mut u32, _: &mut u8| {
+                        if slow.load(Ordering::SeqCst) {
+                            thread::sleep(Duration::from_millis(100));
+                        }
+                        *data = *key;
+                        *key != 0
+                    })
+                    .build(),
+            )
+        };
+        let load_slowly = |key: u32| {
+            slow.store(true, Ordering::SeqCst);
+            let cache = cache.clone();
+            let loading = thread::spawn(move || cache.retrieve_or_compute(&key));
+            thread::sleep(Duration::from_millis(30));
+            loading
+        };
+
+        assert_eq!(cache.status(&1), EntryState::Vacant);
+        let loading = load_slowly(1);
+        assert_eq!(cache.status(&1), EntryState::Loading);
+        loading.join().unwrap();
+        assert_eq!(cache.status(&1), EntryState::Ready);
+
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(cache.status(&1), EntryState::Stale);
+        let refreshing = load_slowly(1);
+        assert_eq!(cache.status(&1), EntryState::Refreshing);
+        refreshing.join().unwrap();
+        assert_eq!(cache.status(&1), EntryState::Ready);
+
+        slow.store(false, Ordering::SeqCst);
+        cache.retrieve_or_compute(&0);
+        assert_eq!(cache.status(&0), EntryState::Failed);
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(cache.status(&0), EntryState::Vacant);
+        cache.invalidate_all();
+        assert_eq!(cache.status(&1), EntryState::Vacant);
+    }
+}
@@ -0,0 +1,434 @@
+//! Bulk invalidation.
+
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::bus::Invalidation;
+use crate::cache::{Cache, EntryStatus, SWEEP_MIN_LEN};
+use crate::clock::Clock;
+use crate::lock::RwLockExt;
+
+/// Future returning `Pending` once, so that the executor can run other
+/// tasks before resuming the caller.
+pub(crate) struct YieldNow(pub(crate) bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// How much [`Cache::purge`] drops, from the mildest level to the most
+/// drastic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PurgeLevel {
+    /// Entries that have expired or were invalidated but are still held
+    /// in memory.
+    Expired,
+    /// Expired entries, then the least recently used half of the rest.
+    ColdHalf,
+    /// Every entry not pinned by a [`HoldGuard`](crate::HoldGuard).
+    AllButPinned,
+    /// Every entry, pinned or not.
+    Everything,
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Invalidates every entry in O(1), without holding the write lock.
+    ///
+    /// Bumps the cache epoch so that all entries stored before the call are
+    /// treated as misses; their memory is reclaimed lazily as they are looked
+    /// up or evicted, and they keep counting towards [`len`](Self::len)
+    /// until then. Computations already in flight complete and are stored
+    /// normally. The second tier, if any, is cleared.
+    ///
+    /// Prefer this over [`clear`](Self::clear) for large caches, where
+    /// clearing under the write lock stalls readers.
+    ///
+    /// If the cache joined an [`InvalidationBus`](crate::InvalidationBus),
+    /// its peers are invalidated too, on a best effort basis.
+    pub fn invalidate_all(&self) {
+        self.invalidate_all_locally();
+        let _ = self.broadcast(&Invalidation::All);
+    }
+
+    /// Like [`invalidate_all`](Self::invalidate_all), without telling the
+    /// peers of the cache.
+    pub(crate) fn invalidate_all_locally(&self) {
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        if let Some(l2) = &self.l2 {
+            l2.clear();
+        }
+    }
+
+    /// Drops the entry for `key` once `grace` has elapsed, unless it is
+    /// written again in the meantime.
+    ///
+    /// Smooths over upstream systems that deliver invalidation and update
+    /// events out of order: an update arriving shortly after the
+    /// invalidation it should have preceded replaces the entry and cancels
+    /// the deletion. The entry keeps being served during the grace period,
+    /// and an earlier expiration is kept.
+    ///
+    /// Returns `false` if there is no entry to mark, including when the key
+    /// is still being computed.
+    pub fn invalidate_after(&self, key: &K, grace: Duration) -> bool {
+        let now = self.now();
+        let mut cache = self.lru_cache.write_or_recover();
+        if !cache.contains(key) && self.promote_from_l2(&mut cache, key, now).is_none() {
+            return false;
+        }
+        let (expiration, seq) = match cache.peek_mut(key) {
+            Some(entry) if entry.status != EntryStatus::Calculating => {
+                entry.expiration = entry.expiration.min(now + grace);
+                (entry.expiration, entry.seq)
+            }
+            _ => return false,
+        };
+        self.index_expiration(&cache, key.clone(), expiration, seq);
+        true
+    }
+
+    /// Drops entries from memory to relieve memory pressure, e.g. from
+    /// admin tooling during an incident, and returns how many were
+    /// dropped.
+    ///
+    /// Computations in flight are never dropped, so that their waiters are
+    /// not left to compute the key again. Dropped entries are not spilled,
+    /// and the second tier, if any, is left as is.
+    pub fn purge(&self, level: PurgeLevel) -> usize {
+        let now = self.now();
+        let mut cache = self.lru_cache.write_or_recover();
+        let candidates = cache
+            .iter()
+            .rev()
+            .filter(|(_, entry)| entry.status != EntryStatus::Calculating);
+        let doomed: Vec<K> = match level {
+            PurgeLevel::Expired => candidates
+                .filter(|(_, entry)| !self.is_live(entry, now))
+                .map(|(key, _)| key.clone())
+                .collect(),
+            PurgeLevel::ColdHalf => {
+                let (dead, live): (Vec<_>, Vec<_>) =
+                    candidates.partition(|(_, entry)| !self.is_live(entry, now));
+                let unpinned: Vec<_> = live
+                    .into_iter()
+                    .filter(|(_, entry)| entry.holds == 0)
+                    .collect();
+                let half = unpinned.len() / 2;
+                dead.into_iter()
+                    .chain(unpinned.into_iter().take(half))
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            }
+            PurgeLevel::AllButPinned => candidates
+                .filter(|(_, entry)| entry.holds == 0)
+                .map(|(key, _)| key.clone())
+                .collect(),
+            PurgeLevel::Everything => candidates.map(|(key, _)| key.clone()).collect(),
+        };
+        for key in &doomed {
+            self.unlink(&mut cache, key);
+        }
+        doomed.len()
+    }
+
+    /// Keeps only the successfully computed entries for which `f` returns
+    /// `true`. Failed and calculating entries are left alone.
+    ///
+    /// Runs in a single pass under the write lock. Entries of the second
+    /// tier, if any, are then read back and tested one at a time; those
+    /// that cannot be read back, or were spilled under an older
+    /// [`value_version`](crate::CacheBuilder::value_version), are dropped.
+    pub fn retain<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &D) -> bool,
+    {
+        let mut cache = self.lru_cache.write_or_recover();
+        let doomed: Vec<K> = cache
+            .iter()
+            .filter(|(key, entry)| entry.status == EntryStatus::Ready && !f(key, &entry.data))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &doomed {
+            self.unlink(&mut cache, key);
+        }
+        drop(cache);
+        self.retain_l2(&mut f);
+    }
+
+    /// Applies [`retain`](Self::retain) to the second tier, if any.
+    fn retain_l2<F>(&self, f: &mut F)
+    where
+        F: FnMut(&K, &D) -> bool,
+    {
+        if let Some(l2) = &self.l2 {
+            l2.retain(&mut |key, spilled| {
+                spilled.version == self.value_version && f(key, &spilled.data)
+            });
+        }
+    }
+
+    /// Like [`retain`](Self::retain), for async callers: the write lock is
+    /// taken for at most 256 entries at a time, keys included, and the task
+    /// yields to the executor in between, so sweeping a very large cache
+    /// never blocks a runtime worker thread for long.
+    ///
+    /// Entries are visited in order of expiration; those stored since the
+    /// call started are kept. `f` runs while the write lock is held and
+    /// must not call back into the cache.
+    pub async fn retain_async<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &D) -> bool,
+    {
+        let mut walk = self.walk();
+        loop {
+            {
+                let mut cache = self.lru_cache.write_or_recover();
+                let Some(keys) = self.next_chunk(&cache, &mut walk) else {
+                    break;
+                };
+                for key in &keys {
+                    let doomed = cache.peek(key).is_some_and(|entry| {
+                        entry.status == EntryStatus::Ready && !f(key, &entry.data)
+                    });
+                    if doomed {
+                        self.unlink(&mut cache, key);
+                    }
+                }
+            }
+            YieldNow(false).await;
+        }
+        self.retain_l2(&mut f);
+    }
+
+    /// Drops the entries that are no longer live, because they expired or
+    /// were invalidated, and returns how many were dropped. The write lock
+    /// is taken for at most 256 entries at a time and the task yields to
+    /// the executor in between.
+    ///
+    /// An unbounded cache otherwise sweeps them on the insert that doubles
+    /// its size since the last sweep, under the write lock; sweeping from
+    /// a periodic task keeps that pause off the insert path.
+    pub async fn sweep_async(&self) -> usize {
+        let mut walk = self.walk();
+        let mut swept = 0;
+        loop {
+            {
+                let now = self.now();
+                let mut cache = self.lru_cache.write_or_recover();
+                let Some(keys) = self.next_chunk(&cache, &mut walk) else {
+                    let sweep_at = (2 * cache.len()).max(SWEEP_MIN_LEN);
+                    self.sweep_at.store(sweep_at, Ordering::Relaxed);
+                    return swept;
+                };
+                for key in &keys {
+                    if cache
+                        .peek(key)
+                        .is_some_and(|entry| !self.is_live(entry, now))
+                    {
+                        if let Some(entry) = self.unlink(&mut cache, key) {
+                            self.expired(key, &entry, now);
+                            swept += 1;
+                        }
+                    }
+                }
+            }
+            YieldNow(false).await;
+        }
+    }
+
+    /// Drops every successfully computed entry matching `pred`, e.g. all
+    /// keys of one tenant. See [`retain`](Self::retain).
+    pub fn invalidate_entries_if<F>(&self, mut pred: F)
+    where
+        F: FnMut(&K, &D) -> bool,
+    {
+        self.retain(|key, data| !pred(key, data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, ManualClock, PurgeLevel};
+    use futures::task::noop_waker_ref;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn invalidate_all_turns_every_entry_into_a_miss() {
+        let cache = Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |key: &u32, data: &mut u32, _: &mut u8| {
+                *data = key + 100;
+                true
+            },
+        );
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+
+        cache.invalidate_all();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.retrieve_or_compute(&2), (102, true, 0));
+        cache.insert(1, 1);
+        assert_eq!(cache.get(&1), Some(1));
+    }
+
+    #[test]
+    fn invalidate_after_is_cancelled_by_a_write() {
+        let cache = Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut u32, _: &mut u8| false,
+        );
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+
+        assert!(cache.invalidate_after(&1, Duration::from_millis(20)));
+        assert!(cache.invalidate_after(&2, Duration::from_millis(20)));
+        assert!(!cache.invalidate_after(&3, Duration::from_millis(20)));
+        cache.insert(2, 20);
+        assert_eq!(cache.get(&1), Some(1));
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some(20));
+    }
+
+    #[test]
+    fn retain_async_yields_between_chunks() {
+        let cache = Cache::new(
+            1000,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut u32, _: &mut u8| false,
+        );
+        for key in 0..1000 {
+            cache.insert(key, key);
+        }
+
+        let mut sweep = pin!(cache.retain_async(|_, &data| data % 10 == 0));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut polls = 1;
+        while sweep.as_mut().poll(&mut cx).is_pending() {
+            polls += 1;
+        }
+        assert_eq!(polls, 5);
+        assert_eq!(cache.len(), 100);
+        assert_eq!(cache.get(&20), Some(20));
+    }
+
+    /// Runs `future` to completion, returning its output and how many
+    /// times it was polled.
+    fn polled<F: Future>(future: F) -> (F::Output, usize) {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut polls = 1;
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return (output, polls);
+            }
+            polls += 1;
+        }
+    }
+
+    #[test]
+    fn async_maintenance_yields_between_chunks() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(1000)
+            .positive_ttl(Duration::from_secs(60))
+            .clock(clock.clone())
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| false)
+            .build();
+        for key in 0..600 {
+            cache.insert(key, key);
+        }
+        clock.advance(Duration::from_secs(30));
+        for key in 600..1000 {
+            cache.insert(key, key);
+        }
+
+        let (entries, polls) = polled(cache.iter_async());
+        assert_eq!(polls, 5);
+        assert_eq!(entries.len(), 1000);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(polled(cache.evict_expired_async()), (600, 3));
+        cache.invalidate_all();
+        assert_eq!(polled(cache.sweep_async()), (400, 3));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn invalidate_entries_if_drops_matching_entries() {
+        let cache = Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &(u32, u32), _: &mut u32, _: &mut u8| false,
+        );
+        for tenant in 0..3 {
+            cache.insert((tenant, 1), 1);
+            cache.insert((tenant, 2), 2);
+        }
+        cache.retrieve_or_compute(&(1, 3));
+
+        cache.invalidate_entries_if(|&(tenant, _), _| tenant == 1);
+        assert_eq!(cache.get(&(1, 1)), None);
+        assert_eq!(cache.get(&(0, 1)), Some(1));
+        assert_eq!(cache.len(), 5);
+
+        cache.retain(|_, &data| data == 2);
+        assert_eq!(cache.get(&(0, 1)), None);
+        assert_eq!(cache.get(&(2, 2)), Some(2));
+    }
+
+    #[test]
+    fn purge_levels_drop_increasingly_more() {
+        let cache = Cache::new(
+            10,
+            Duration::from_millis(20),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut u32, _: &mut u8| false,
+        );
+        cache.insert(0, 0);
+        thread::sleep(Duration::from_millis(30));
+        cache.set_positive_ttl(Duration::from_secs(60));
+        for key in 1..=6 {
+            cache.insert(key, key);
+        }
+        let _held = cache.hold(&1);
+
+        assert_eq!(cache.purge(PurgeLevel::Expired), 1);
+        assert_eq!(cache.purge(PurgeLevel::ColdHalf), 2);
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.get(&4), Some(4));
+        assert_eq!(cache.purge(PurgeLevel::AllButPinned), 3);
+        assert_eq!(cache.purge(PurgeLevel::Everything), 1);
+        assert!(cache.is_empty());
+    }
+}
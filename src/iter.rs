@@ -0,0 +1,242 @@
+//! Visiting the entries of a live cache.
+
+use std::hash::{BuildHasher, Hash};
+use std::vec;
+
+use crate::cache::{Cache, EntryStatus};
+use crate::clock::Clock;
+use crate::invalidate::YieldNow;
+use crate::lock::RwLockExt;
+use crate::time::Instant;
+
+/// Number of entries visited per lock acquisition.
+pub(crate) const CHUNK_SIZE: usize = 256;
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Calls `f` for every successfully computed, unexpired entry held in
+    /// memory, without promoting any of them.
+    ///
+    /// The read lock is taken for at most 256 entries at a time, finding
+    /// them included, and released in between, so writers are never
+    /// blocked for the whole walk. Entries are visited in order of
+    /// expiration: those removed or expired before being reached are
+    /// skipped, and those inserted or replaced since the call started are
+    /// not visited.
+    ///
+    /// `f` runs while the read lock is held and must not call back into the
+    /// cache.
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &D),
+    {
+        let mut walk = self.walk();
+        loop {
+            let now = self.now();
+            let cache = self.lru_cache.read_or_recover();
+            let Some(keys) = self.next_chunk(&cache, &mut walk) else {
+                return;
+            };
+            for key in &keys {
+                if let Some(entry) = cache.peek(key) {
+                    if entry.status == EntryStatus::Ready && self.is_live(entry, now) {
+                        f(key, &entry.data);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the successfully computed, unexpired entries held in memory
+    /// as `(key, data, expiration)`, soonest expiration first.
+    ///
+    /// Useful for finding what is about to expire and refreshing it ahead of
+    /// time. The entries are a snapshot taken under a single read lock, in
+    /// the order the cache already keeps them by expiration, so the call
+    /// costs `O(n)` with no sorting.
+    pub fn iter_by_expiration(&self) -> vec::IntoIter<(K, D, Instant)> {
+        let now = self.now();
+        let mut entries = Vec::new();
+        let cache = self.lru_cache.read_or_recover();
+        self.visit_by_expiration(&cache, |key, entry| {
+            if entry.status == EntryStatus::Ready && self.is_live(entry, now) {
+                entries.push((key.clone(), entry.data.clone(), entry.expiration));
+            }
+        });
+        drop(cache);
+        entries.into_iter()
+    }
+
+    /// Returns the keys of the successfully computed, unexpired entries
+    /// held in memory, most recently used first.
+    pub fn keys(&self) -> vec::IntoIter<K> {
+        let keys: Vec<K> = self.snapshot(false).map(|(key, _)| key).collect();
+        keys.into_iter()
+    }
+
+    /// Returns a snapshot of the successfully computed, unexpired entries
+    /// held in memory as `(key, data)`, most recently used first, without
+    /// promoting any of them.
+    ///
+    /// The snapshot is taken under a single read lock; prefer
+    /// [`for_each`](Self::for_each) for very large caches.
+    pub fn iter(&self) -> vec::IntoIter<(K, D)> {
+        self.snapshot(false)
+    }
+
+    /// Like [`iter`](Self::iter), for async callers: the snapshot is taken
+    /// under the read lock 256 entries at a time, and the task yields to
+    /// the executor in between. Entries come in order of expiration, and
+    /// those stored since the call started are left out.
+    pub async fn iter_async(&self) -> vec::IntoIter<(K, D)> {
+        let mut walk = self.walk();
+        let mut entries = Vec::new();
+        loop {
+            {
+                let now = self.now();
+                let cache = self.lru_cache.read_or_recover();
+                let Some(keys) = self.next_chunk(&cache, &mut walk) else {
+                    break;
+                };
+                entries.extend(keys.into_iter().filter_map(|key| {
+                    let entry = cache.peek(&key)?;
+                    let ready = entry.status == EntryStatus::Ready && self.is_live(entry, now);
+                    ready.then(|| (key, entry.data.clone()))
+                }));
+            }
+            YieldNow(false).await;
+        }
+        entries.into_iter()
+    }
+
+    /// Like [`iter`](Self::iter), including entries that have expired or
+    /// were invalidated but not dropped yet, e.g. to export everything the
+    /// cache still holds.
+    pub fn iter_including_expired(&self) -> vec::IntoIter<(K, D)> {
+        self.snapshot(true)
+    }
+
+    /// Removes every entry and returns the successfully computed, unexpired
+    /// ones, most recently used first, e.g. to persist them on shutdown.
+    ///
+    /// Computations in flight are left to complete and store their result.
+    /// The second tier, if any, is cleared.
+    pub fn drain(&self) -> vec::IntoIter<(K, D)> {
+        let now = self.now();
+        let mut cache = self.lru_cache.write_or_recover();
+        let keys: Vec<K> = cache
+            .iter()
+            .filter(|(_, entry)| entry.status != EntryStatus::Calculating)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut drained = Vec::new();
+        for key in keys {
+            let Some(entry) = self.unlink(&mut cache, &key) else {
+                continue;
+            };
+            if entry.status == EntryStatus::Ready && self.is_live(&entry, now) {
+                drained.push((key, entry.data));
+            }
+        }
+        drop(cache);
+        if let Some(l2) = &self.l2 {
+            l2.clear();
+        }
+        drained.into_iter()
+    }
+
+    fn snapshot(&self, include_expired: bool) -> vec::IntoIter<(K, D)> {
+        let now = self.now();
+        let cache = self.lru_cache.read_or_recover();
+        let entries: Vec<(K, D)> = cache
+            .iter()
+            .filter(|(_, entry)| {
+                entry.status == EntryStatus::Ready && (include_expired || self.is_live(entry, now))
+            })
+            .map(|(key, entry)| (key.clone(), entry.data.clone()))
+            .collect();
+        entries.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, Clock, ManualClock};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn for_each_visits_ready_entries_only() {
+        let cache = Cache::new(
+            1000,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                false
+            },
+        );
+        for key in 0..600 {
+            cache.insert(key, key * 2);
+        }
+        cache.retrieve_or_compute(&1000);
+
+        let mut visited = Vec::new();
+        cache.for_each(|key, data| visited.push((*key, *data)));
+        visited.sort();
+        assert_eq!(
+            visited,
+            (0..600).map(|key| (key, key * 2)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn iter_by_expiration_is_ordered_by_expiry() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .clock(clock.clone())
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| true)
+            .build();
+        for key in [3, 1, 2, 4] {
+            cache.insert(key, key * 10);
+            clock.advance(Duration::from_secs(1));
+        }
+        cache.get(&3);
+        cache.touch(&1);
+        cache.remove(&4);
+
+        let ordered: Vec<_> = cache.iter_by_expiration().collect();
+        let keys: Vec<u32> = ordered.iter().map(|(key, _, _)| *key).collect();
+        assert_eq!(keys, vec![3, 2, 1]);
+        assert_eq!(ordered[0].1, 30);
+        assert_eq!(ordered[0].2, clock.now() + Duration::from_secs(56));
+    }
+
+    #[test]
+    fn iter_and_drain_skip_expired_entries() {
+        let cache = Cache::new(
+            10,
+            Duration::from_millis(20),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut u32, _: &mut u8| false,
+        );
+        cache.insert(1, 10);
+        thread::sleep(Duration::from_millis(30));
+        cache.insert(2, 20);
+        cache.insert(3, 30);
+        cache.retrieve_or_compute(&4);
+
+        assert_eq!(cache.keys().collect::<Vec<_>>(), vec![3, 2]);
+        assert_eq!(cache.iter().collect::<Vec<_>>(), vec![(3, 30), (2, 20)]);
+        assert_eq!(cache.iter_including_expired().count(), 3);
+        assert_eq!(cache.drain().collect::<Vec<_>>(), vec![(3, 30), (2, 20)]);
+        assert!(cache.is_empty());
+    }
+}
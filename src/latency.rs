@@ -0,0 +1,164 @@
+//! Distribution of miss handler run times.
+//!
+//! Every load is counted in a log-linear histogram of microseconds, with
+//! eight buckets per power of two, so percentiles are reported within
+//! 12.5% while the histogram stays a few kilobytes whatever the range.
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::cache::Cache;
+use crate::clock::Clock;
+
+/// Bits of each value kept below its leading bit.
+const SUB_BITS: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << SUB_BITS;
+/// Leading bit of the largest value told apart, about 25 days in
+/// microseconds; longer loads share the last bucket.
+const MAX_EXPONENT: u32 = 41;
+const BUCKETS: usize = ((MAX_EXPONENT - SUB_BITS + 2) as usize) << SUB_BITS;
+
+/// Percentiles of the miss handler run times, returned by
+/// [`Cache::load_latency`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LoadLatency {
+    /// Loads counted.
+    pub count: u64,
+    /// Median run time.
+    pub p50: Duration,
+    /// 95th percentile run time.
+    pub p95: Duration,
+    /// 99th percentile run time.
+    pub p99: Duration,
+    /// Longest run time.
+    pub max: Duration,
+}
+
+/// Histogram of load times.
+pub(crate) struct LatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+    max: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub(crate) fn new() -> Self {
+        LatencyHistogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, load_time: Duration) {
+        let micros = u64::try_from(load_time.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Percentiles of the loads counted so far, `None` before the first.
+    fn snapshot(&self) -> Option<LoadLatency> {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let count: u64 = counts.iter().sum();
+        if count == 0 {
+            return None;
+        }
+        let max = self.max.load(Ordering::Relaxed);
+        let percentile = |fraction: f64| {
+            let rank = ((fraction * count as f64).ceil() as u64).max(1);
+            let mut seen = 0;
+            let index = counts
+                .iter()
+                .position(|&n| {
+                    seen += n;
+                    seen >= rank
+                })
+                .unwrap_or(BUCKETS - 1);
+            Duration::from_micros(upper_bound(index).min(max))
+        };
+        Some(LoadLatency {
+            count,
+            p50: percentile(0.50),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            max: Duration::from_micros(max),
+        })
+    }
+}
+
+/// Index of the bucket counting `micros`.
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exponent = 63 - micros.leading_zeros();
+    if exponent > MAX_EXPONENT {
+        return BUCKETS - 1;
+    }
+    let shift = exponent - SUB_BITS;
+    let sub = (micros >> shift) & (SUB_BUCKETS - 1);
+    (((shift + 1) as usize) << SUB_BITS) + sub as usize
+}
+
+/// Largest value counted in bucket `index`.
+fn upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS as usize {
+        return index as u64;
+    }
+    let shift = (index >> SUB_BITS) as u32 - 1;
+    let sub = (index as u64) & (SUB_BUCKETS - 1);
+    ((SUB_BUCKETS + sub) << shift) + (1 << shift) - 1
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Percentiles of how long the miss handler took to run, over every
+    /// load since the cache was created, or `None` before the first. A
+    /// batch counts as one load.
+    ///
+    /// Percentiles are upper bounds, at most 12.5% above the exact value.
+    pub fn load_latency(&self) -> Option<LoadLatency> {
+        self.load_latency.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_round_trip() {
+        for micros in [0, 7, 8, 15, 16, 1000, 123_456, 1 << 40] {
+            let index = bucket(micros);
+            assert!(upper_bound(index) >= micros);
+            assert!(upper_bound(index) - micros <= micros / 8);
+            assert!(index == 0 || upper_bound(index - 1) < micros);
+        }
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn percentiles_are_reported() {
+        let histogram = LatencyHistogram::new();
+        assert_eq!(histogram.snapshot(), None);
+        for millis in 1..=100 {
+            histogram.record(Duration::from_millis(millis));
+        }
+        let latency = histogram.snapshot().unwrap();
+        assert_eq!(latency.count, 100);
+        assert_eq!(latency.max, Duration::from_millis(100));
+        for (percentile, exact) in [(latency.p50, 50), (latency.p95, 95), (latency.p99, 99)] {
+            let exact = Duration::from_millis(exact);
+            assert!(percentile >= exact && percentile <= exact + exact / 8);
+        }
+    }
+}
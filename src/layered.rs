@@ -0,0 +1,214 @@
+//! Stacking two caches into one, e.g. a small fast cache in front of a
+//! large one.
+
+use crate::ops::{CacheOps, DynCache};
+use crate::stats::CacheStats;
+
+/// Where a [`LayeredCache`] writes inserted values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    /// Insert into both layers.
+    #[default]
+    Through,
+    /// Insert into the second layer only and drop the key from the first,
+    /// so that it is promoted on its next read. Suits values written far
+    /// more often than they are read.
+    Around,
+}
+
+/// Two caches used as one: lookups try the first layer, then the second,
+/// promoting second-layer hits into the first.
+///
+/// Misses of both layers are computed by the second layer, so the miss
+/// handler of the first is never run; successful values are then promoted
+/// too. Removals and invalidations drop the key from the second layer
+/// before the first, so that a concurrent lookup does not promote the
+/// value back. Layers nest: a `LayeredCache<A, LayeredCache<B, C>>` has
+/// three levels.
+///
+/// The layered cache is used through [`CacheOps`] and [`DynCache`], and
+/// the layers should not be written to directly.
+pub struct LayeredCache<L1, L2> {
+    l1: L1,
+    l2: L2,
+    write_mode: WriteMode,
+}
+
+impl<L1, L2> LayeredCache<L1, L2> {
+    /// Layers `l1` over `l2`, writing through to both.
+    pub fn new(l1: L1, l2: L2) -> Self {
+        LayeredCache {
+            l1,
+            l2,
+            write_mode: WriteMode::default(),
+        }
+    }
+
+    /// Sets where inserted values are written.
+    pub fn write_mode(mut self, write_mode: WriteMode) -> Self {
+        self.write_mode = write_mode;
+        self
+    }
+
+    /// The first layer.
+    pub fn l1(&self) -> &L1 {
+        &self.l1
+    }
+
+    /// The second layer.
+    pub fn l2(&self) -> &L2 {
+        &self.l2
+    }
+}
+
+impl<L1, L2, K, D> CacheOps<K, D> for LayeredCache<L1, L2>
+where
+    L1: CacheOps<K, D>,
+    L2: CacheOps<K, D>,
+    K: Clone,
+    D: Clone,
+{
+    fn get(&self, key: &K) -> Option<D> {
+        if let Some(data) = self.l1.get(key) {
+            return Some(data);
+        }
+        let data = self.l2.get(key)?;
+        self.l1.insert(key.clone(), data.clone());
+        Some(data)
+    }
+
+    fn insert(&self, key: K, data: D) {
+        match self.write_mode {
+            WriteMode::Through => {
+                self.l2.insert(key.clone(), data.clone());
+                self.l1.insert(key, data);
+            }
+            WriteMode::Around => {
+                self.l2.insert(key.clone(), data);
+                self.l1.remove(&key);
+            }
+        }
+    }
+
+    fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        if let Some(data) = self.l1.get(key) {
+            return (data, true, 0);
+        }
+        let (data, success, adhoc_code) = self.l2.retrieve_or_compute(key);
+        if success {
+            self.l1.insert(key.clone(), data.clone());
+        }
+        (data, success, adhoc_code)
+    }
+
+    /// Removes a value from both layers, returning the first layer's if it
+    /// had one.
+    fn remove(&self, key: &K) -> Option<D> {
+        let l2 = self.l2.remove(key);
+        self.l1.remove(key).or(l2)
+    }
+
+    /// Entries of the second layer, which holds every value of the first
+    /// unless a layer was written to directly.
+    fn len(&self) -> usize {
+        self.l2.len()
+    }
+}
+
+/// Statistics add up the hits of both layers, the misses being those of
+/// the second layer, which only sees the first one's misses.
+impl<L1, L2, K, D> DynCache<K, D> for LayeredCache<L1, L2>
+where
+    L1: DynCache<K, D>,
+    L2: DynCache<K, D>,
+    K: Clone,
+    D: Clone,
+{
+    fn invalidate(&self, key: &K) {
+        self.l2.invalidate(key);
+        self.l1.invalidate(key);
+    }
+
+    fn invalidate_all(&self) {
+        self.l2.invalidate_all();
+        self.l1.invalidate_all();
+    }
+
+    fn stats(&self) -> CacheStats {
+        let (l1, l2) = (self.l1.stats(), self.l2.stats());
+        CacheStats {
+            hits: l1.hits + l2.hits,
+            misses: l2.misses,
+            len: l2.len,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cache;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    type Layers = LayeredCache<Cache<u32, u32>, Cache<u32, u32>>;
+
+    fn layers(loads: Arc<AtomicUsize>) -> Layers {
+        let l1 = Cache::new(
+            2,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut u32, _: &mut u8| unreachable!("the first layer never loads"),
+        );
+        let l2 = Cache::new(
+            100,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            move |key: &u32, data: &mut u32, _: &mut u8| {
+                loads.fetch_add(1, Ordering::SeqCst);
+                *data = key * 2;
+                *key != 0
+            },
+        );
+        LayeredCache::new(l1, l2)
+    }
+
+    #[test]
+    fn second_layer_hits_are_promoted() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let cache = layers(loads.clone());
+        for key in 0..4 {
+            cache.retrieve_or_compute(&key);
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 4);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.len), (0, 4, 4));
+        assert_eq!(cache.l1().len(), 2);
+        assert_eq!(cache.l1().get(&0), None);
+        assert_eq!(cache.len(), 4);
+
+        assert_eq!(cache.get(&1), Some(2));
+        assert_eq!(cache.l1().get(&1), Some(2));
+        assert_eq!(cache.retrieve_or_compute(&0), (0, false, 0));
+        assert_eq!(loads.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn writes_and_invalidations_reach_both_layers() {
+        let cache = layers(Arc::new(AtomicUsize::new(0)));
+        cache.insert(1, 10);
+        assert_eq!(cache.l1().get(&1), Some(10));
+        assert_eq!(cache.l2().get(&1), Some(10));
+
+        let cache = cache.write_mode(WriteMode::Around);
+        cache.insert(1, 11);
+        assert_eq!(cache.l1().get(&1), None);
+        assert_eq!(cache.get(&1), Some(11));
+        assert_eq!(cache.l1().get(&1), Some(11));
+
+        assert_eq!(cache.remove(&1), Some(11));
+        assert_eq!(cache.l1().get(&1), None);
+        assert_eq!(cache.l2().get(&1), None);
+    }
+}
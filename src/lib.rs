@@ -0,0 +1,181 @@
+//! A thread-safe LRU cache with compute-on-miss semantics.
+//!
+//! Values are produced by a user supplied miss handler the first time a key
+//! is requested. Successful computations are kept for the positive TTL and
+//! failed ones for the negative TTL, so a failing backend is not hammered on
+//! every request. Concurrent requests for a key that is being computed wait
+//! for the first computation instead of starting their own.
+//!
+//! ```
+//! use std::time::Duration;
+//! use rust_cache::Cache;
+//!
+//! let cache = Cache::new(
+//!     100,
+//!     Duration::from_secs(60),
+//!     Duration::from_secs(5),
+//!     |key: &u32, data: &mut String, _adhoc_code: &mut u8| {
+//!         *data = format!("value-{key}");
+//!         true
+//!     },
+//! );
+//!
+//! let (data, success, _) = cache.retrieve_or_compute(&7);
+//! assert!(success);
+//! assert_eq!(data, "value-7");
+//! ```
+
+#[cfg(feature = "admin")]
+mod admin;
+mod arc;
+mod batch;
+mod builder;
+mod bus;
+mod cache;
+mod cache_policy;
+mod cancel;
+mod cas;
+mod checksum;
+mod clock;
+mod codec;
+#[cfg(feature = "compression")]
+mod compress;
+mod config;
+mod conflict;
+mod debounce;
+mod degrade;
+#[cfg(feature = "disk")]
+mod disk;
+mod early;
+mod entry;
+mod events;
+mod eviction;
+mod expiry;
+mod fallback;
+mod freshness;
+mod generation;
+mod grace;
+mod guard;
+mod hashed;
+mod hashers;
+mod hold;
+mod hot;
+#[cfg(feature = "http")]
+mod http_cache;
+mod indexed;
+mod info;
+mod invalidate;
+mod iter;
+mod latency;
+mod layered;
+mod limit;
+mod load;
+mod load_result;
+mod lock;
+mod memory;
+#[cfg(feature = "tower")]
+mod middleware;
+mod migrate;
+mod mirror;
+mod multi;
+mod namespace;
+mod negative;
+mod ops;
+mod oversize;
+#[cfg(feature = "mmap")]
+mod persistent;
+mod pool;
+mod rate;
+mod readiness;
+mod redact;
+mod refresh;
+mod retry;
+mod scope;
+#[cfg(feature = "shm")]
+mod shared;
+mod sketch;
+#[cfg(feature = "snapshot")]
+mod snapshot;
+mod soft;
+mod stats;
+#[cfg(feature = "stream")]
+mod stream;
+mod tags;
+mod tier;
+mod tiered;
+mod time;
+mod timeout;
+mod trace;
+mod ttl;
+mod unwind;
+mod wait;
+mod warm;
+mod weak;
+mod write_behind;
+
+#[cfg(feature = "admin")]
+pub use admin::{Admin, AdminServer};
+pub use arc::ArcCache;
+pub use builder::CacheBuilder;
+#[cfg(feature = "redis")]
+pub use bus::RedisBus;
+pub use bus::{BroadcastBus, Invalidation, InvalidationBus, InvalidationListener};
+pub use cache::{Cache, EntryStatus, MissHandler, StoreError, StoreHandler};
+pub use cache_policy::{CacheDecision, CachePolicy, LoadMeta};
+pub use cancel::{CancellationToken, Cancelled};
+pub use checksum::CorruptionListener;
+pub use clock::{Clock, DynClockCache, ManualClock, ManualClockCache, SystemClock};
+pub use codec::{EncodedKeys, KeyCodec, StrKeys};
+#[cfg(feature = "compression")]
+pub use compress::CompressedCache;
+pub use config::{Bound, Capacity, ConfigError};
+pub use conflict::{ConflictListener, ConflictPolicy};
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+pub use events::{CacheEvent, EVENT_BUFFER};
+pub use eviction::{EvictDecision, EvictionVeto};
+pub use freshness::Freshness;
+pub use generation::Revalidator;
+pub use guard::CacheReadGuard;
+pub use hashed::{HashedKeyCache, KeyVerification};
+#[cfg(feature = "ahash")]
+pub use hashers::{AHash, AHashCache};
+#[cfg(feature = "fxhash")]
+pub use hashers::{FxCache, FxHash};
+pub use hold::HoldGuard;
+#[cfg(feature = "http")]
+pub use http_cache::{freshness, CachedResponse};
+pub use indexed::IndexedCache;
+pub use info::{EntryInfo, EntryState};
+pub use invalidate::PurgeLevel;
+pub use latency::LoadLatency;
+pub use layered::{LayeredCache, WriteMode};
+pub use limit::{LoadGroup, Overloaded};
+pub use load_result::LoadResult;
+pub use lru::DefaultHasher;
+pub use memory::{MemSize, SizeHint, Weigher};
+#[cfg(feature = "tower")]
+pub use middleware::{CacheLayer, CacheService, ResponseFuture};
+pub use migrate::ValueMigration;
+pub use mirror::{MirrorCache, MirrorReport};
+pub use namespace::{Namespace, NamespacedCache};
+pub use ops::{CacheOps, DynCache, NoopCache, UnboundedCache};
+pub use oversize::{Oversize, OversizePolicy};
+#[cfg(feature = "mmap")]
+pub use persistent::{BytesMissHandler, PersistentCache};
+pub use pool::Computing;
+pub use rate::RateLimited;
+pub use readiness::Readiness;
+pub use redact::{hashed_key, KeyRedactor};
+pub use retry::RetryPolicy;
+pub use scope::RequestCache;
+#[cfg(feature = "shm")]
+pub use shared::SharedCache;
+pub use stats::{CacheStats, IntervalStats};
+#[cfg(feature = "stream")]
+pub use stream::{PartialFailure, StreamFailure};
+pub use tiered::{BackendError, CacheBackend, TieredCache};
+pub use time::Instant;
+pub use timeout::Timeout;
+pub use unwind::LoadPanicked;
+pub use wait::WaitStrategy;
+pub use weak::WeakCache;
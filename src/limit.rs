@@ -0,0 +1,264 @@
+//! Bounding how many miss handlers run at once.
+//!
+//! On a cold start every key misses at the same time, and each miss runs
+//! the miss handler against the backend. A load limit caps how many run
+//! concurrently, overall and per group of keys, e.g. per table or tenant.
+//! Callers over the limit wait for a slot, or give up with [`Overloaded`].
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::panic;
+use std::sync::{Condvar, Mutex, PoisonError};
+
+use crate::cache::{Cache, EntryStatus, Lookup};
+use crate::clock::Clock;
+use crate::lock::{MutexExt, RwLockExt};
+
+/// Hook naming the group a key is loaded in, for
+/// [`CacheBuilder::max_concurrent_loads_per`](crate::CacheBuilder::max_concurrent_loads_per).
+pub type LoadGroup<K> = dyn Fn(&K) -> String + Send + Sync;
+
+/// Error returned when the miss handler could not run because the load
+/// limit was reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overloaded;
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("too many miss handlers are running")
+    }
+}
+
+impl Error for Overloaded {}
+
+/// Miss handlers running, overall and per group.
+#[derive(Default)]
+struct Running {
+    total: usize,
+    groups: HashMap<String, usize>,
+}
+
+/// Counts running miss handlers against the configured limits.
+pub(crate) struct LoadLimiter<K> {
+    max: Option<usize>,
+    per_group: Option<(usize, Box<LoadGroup<K>>)>,
+    running: Mutex<Running>,
+    released: Condvar,
+}
+
+impl<K> LoadLimiter<K> {
+    pub(crate) fn new(max: Option<usize>, per_group: Option<(usize, Box<LoadGroup<K>>)>) -> Self {
+        LoadLimiter {
+            max,
+            per_group,
+            running: Mutex::new(Running::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Takes one slot for loading `keys` together, plus one in each of
+    /// their groups, waiting for them to free up if `wait` is set.
+    fn acquire<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a K>,
+        wait: bool,
+    ) -> Result<LoadPermit<'_, K>, Overloaded>
+    where
+        K: 'a,
+    {
+        let mut groups: Vec<String> = match &self.per_group {
+            Some((_, group)) => keys.into_iter().map(group).collect(),
+            None => Vec::new(),
+        };
+        groups.sort_unstable();
+        groups.dedup();
+        let group_max = self.per_group.as_ref().map_or(usize::MAX, |&(max, _)| max);
+        let full = |running: &Running| {
+            self.max.is_some_and(|max| running.total >= max)
+                || groups
+                    .iter()
+                    .any(|group| running.groups.get(group).is_some_and(|&n| n >= group_max))
+        };
+
+        let mut running = self.running.lock_or_recover();
+        while full(&running) {
+            if !wait {
+                return Err(Overloaded);
+            }
+            running = self
+                .released
+                .wait(running)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        running.total += 1;
+        for group in &groups {
+            *running.groups.entry(group.clone()).or_insert(0) += 1;
+        }
+        Ok(LoadPermit {
+            limiter: self,
+            groups,
+        })
+    }
+}
+
+/// A slot taken from a [`LoadLimiter`], given back when dropped.
+pub(crate) struct LoadPermit<'a, K> {
+    limiter: &'a LoadLimiter<K>,
+    groups: Vec<String>,
+}
+
+impl<K> Drop for LoadPermit<'_, K> {
+    fn drop(&mut self) {
+        let mut running = self.limiter.running.lock_or_recover();
+        running.total -= 1;
+        for group in &self.groups {
+            if let Some(n) = running.groups.get_mut(group) {
+                *n -= 1;
+                if *n == 0 {
+                    running.groups.remove(group);
+                }
+            }
+        }
+        drop(running);
+        self.limiter.released.notify_all();
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Like [`retrieve_or_compute`](Self::retrieve_or_compute), but returns
+    /// [`Overloaded`] instead of waiting if the miss handler would have to
+    /// run and the load limit is reached.
+    ///
+    /// Callers waiting on another thread's computation of the key still
+    /// wait for it.
+    pub fn retrieve_or_compute_unless_overloaded(
+        &self,
+        key: &K,
+    ) -> Result<(D, bool, u8), Overloaded> {
+        match self.lookup_or_take_over(key) {
+            Lookup::Found(found) => Ok(found),
+            Lookup::Panicked | Lookup::Cancelled | Lookup::Unavailable => {
+                Ok((D::default(), false, 0))
+            }
+            Lookup::Claimed(started) => {
+                if !self.admits_load(key) {
+                    return Ok(self
+                        .shed_load(key, started)
+                        .unwrap_or_else(|| (D::default(), false, 0)));
+                }
+                let Ok(_permit) = self.load_permit([key], false) else {
+                    return self.abandon(key, started).ok_or(Overloaded);
+                };
+                Ok(self
+                    .run_miss_handler(key, started)
+                    .unwrap_or_else(|payload| panic::resume_unwind(payload)))
+            }
+        }
+    }
+
+    /// Takes a slot for running the miss handler on `keys`, `None` without
+    /// a load limit.
+    pub(crate) fn load_permit<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a K>,
+        wait: bool,
+    ) -> Result<Option<LoadPermit<'_, K>>, Overloaded>
+    where
+        K: 'a,
+    {
+        self.load_limiter
+            .as_ref()
+            .map(|limiter| limiter.acquire(keys, wait))
+            .transpose()
+    }
+
+    /// Drops the placeholder claimed for `key` without computing it. If
+    /// the claim was to refresh a live entry, the entry is left for a later
+    /// caller to refresh and its value returned instead.
+    pub(crate) fn abandon(&self, key: &K, started: u64) -> Option<(D, bool, u8)> {
+        let mut cache = self.lru_cache.write_or_recover();
+        let entry = cache.peek_mut(key).filter(|entry| entry.seq == started)?;
+        if entry.status != EntryStatus::Calculating {
+            entry.refreshing = false;
+            return Some((
+                entry.data.clone(),
+                entry.status == EntryStatus::Ready,
+                entry.adhoc_code,
+            ));
+        }
+        self.unlink(&mut cache, key);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn slow_cache(peak: Arc<AtomicUsize>) -> Arc<Cache<String, u32>> {
+        let running = Arc::new(AtomicUsize::new(0));
+        Arc::new(
+            Cache::builder(100)
+                .miss_handler(move |_: &String, _: &mut u32, _: &mut u8| {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    true
+                })
+                .max_concurrent_loads(2)
+                .max_concurrent_loads_per(1, |key: &String| key[..1].to_string())
+                .build(),
+        )
+    }
+
+    #[test]
+    fn excess_loads_wait_for_a_slot() {
+        let peak = Arc::new(AtomicUsize::new(0));
+        let cache = slow_cache(peak.clone());
+        let loads: Vec<_> = ["a1", "a2", "b1", "b2", "c1", "c2"]
+            .into_iter()
+            .map(|key| {
+                let cache = cache.clone();
+                thread::spawn(move || cache.retrieve_or_compute(&key.to_string()))
+            })
+            .collect();
+        for load in loads {
+            assert!(load.join().unwrap().1);
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.len(), 6);
+    }
+
+    #[test]
+    fn overloaded_callers_give_up() {
+        let peak = Arc::new(AtomicUsize::new(0));
+        let cache = slow_cache(peak);
+        let load = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.retrieve_or_compute(&"a1".to_string()))
+        };
+        thread::sleep(Duration::from_millis(5));
+
+        let key = "a2".to_string();
+        assert_eq!(
+            cache.retrieve_or_compute_unless_overloaded(&key),
+            Err(Overloaded)
+        );
+        assert!(cache.get_entry(&key).is_none());
+        load.join().unwrap();
+        assert!(cache.retrieve_or_compute_unless_overloaded(&key).unwrap().1);
+    }
+}
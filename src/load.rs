@@ -0,0 +1,207 @@
+//! What the caller of a computation hands down to its miss handler.
+//!
+//! The miss handler may run on the caller's thread or on a worker, so the
+//! arguments travel with the computation and are installed on whichever
+//! thread runs it, for the duration of the call.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cache::{Cache, Lookup};
+use crate::cancel::CancellationToken;
+use crate::clock::Clock;
+use crate::time::Instant;
+
+/// Per-computation arguments of the miss handler.
+#[derive(Clone, Default)]
+pub(crate) struct LoadArgs {
+    /// Trips when the computation is cancelled; see
+    /// [`Cache::cancel`](crate::Cache::cancel).
+    pub(crate) token: Option<CancellationToken>,
+    /// When the caller stops waiting; see
+    /// [`Cache::retrieve_or_compute_with_deadline`](crate::Cache::retrieve_or_compute_with_deadline).
+    pub(crate) deadline: Option<Instant>,
+    /// Request-scoped data of the caller; see
+    /// [`Cache::retrieve_or_compute_ctx`].
+    pub(crate) context: Option<Arc<dyn Any + Send + Sync>>,
+    /// The expired value being recomputed; see
+    /// [`CacheBuilder::revalidating_miss_handler`](crate::CacheBuilder::revalidating_miss_handler).
+    pub(crate) previous: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+/// Shares an expired value with the miss handler recomputing it; captured
+/// by [`CacheBuilder::revalidating_miss_handler`](crate::CacheBuilder::revalidating_miss_handler)
+/// where its `'static` bounds hold.
+pub(crate) type SharePrevious<D> = fn(&D) -> Arc<dyn Any + Send + Sync>;
+
+thread_local! {
+    /// Arguments of the call running on this thread.
+    static CURRENT: RefCell<LoadArgs> = RefCell::new(LoadArgs::default());
+
+    /// TTL the loader chose for the value last computed on this thread,
+    /// `None` for the TTLs of the cache.
+    static LOAD_TTL: Cell<Option<Duration>> = const { Cell::new(None) };
+}
+
+/// Puts back the arguments of the enclosing call, even on unwinding.
+struct Restore(Option<LoadArgs>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(outer) = self.0.take() {
+            CURRENT.with(|current| current.replace(outer));
+        }
+    }
+}
+
+/// Runs `f` with `args` as the current thread's arguments.
+pub(crate) fn with_args<R>(args: LoadArgs, f: impl FnOnce() -> R) -> R {
+    let _restore = Restore(Some(CURRENT.with(|current| current.replace(args))));
+    f()
+}
+
+/// Arguments of the call running on this thread.
+pub(crate) fn current_args() -> LoadArgs {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Sets the TTL of the value being computed on this thread, overriding
+/// the TTLs of the cache.
+pub(crate) fn set_ttl(ttl: Option<Duration>) {
+    LOAD_TTL.set(ttl);
+}
+
+/// Takes the TTL set for the value last computed on this thread.
+pub(crate) fn take_ttl() -> Option<Duration> {
+    LOAD_TTL.take()
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Like [`retrieve_or_compute`](Self::retrieve_or_compute), handing
+    /// `ctx` to the miss handler if it was set with
+    /// [`contextual_miss_handler`](crate::CacheBuilder::contextual_miss_handler)
+    /// for the same context type.
+    ///
+    /// Meant for request-scoped data such as an auth token or a locale.
+    /// The outcome is cached and shared like any other, so callers waiting
+    /// on the key get the value computed under the first caller's context.
+    pub fn retrieve_or_compute_ctx<X>(&self, key: &K, ctx: &X) -> (D, bool, u8)
+    where
+        X: Clone + Send + Sync + 'static,
+    {
+        match self.lookup_or_take_over(key) {
+            Lookup::Found(found) => found,
+            Lookup::Panicked | Lookup::Cancelled | Lookup::Unavailable => (D::default(), false, 0),
+            Lookup::Claimed(started) => {
+                let args = LoadArgs {
+                    context: Some(Arc::new(ctx.clone())),
+                    ..current_args()
+                };
+                with_args(args, || self.compute(key, started))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, ManualClock};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct Locale(&'static str);
+
+    #[test]
+    fn the_context_reaches_the_miss_handler() {
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .contextual_miss_handler(
+                |key: &u32, data: &mut String, _: &mut u8, locale: Option<&Locale>| {
+                    *data = format!("{key}@{}", locale.map_or("default", |locale| locale.0));
+                    true
+                },
+            )
+            .build();
+        assert_eq!(cache.retrieve_or_compute_ctx(&1, &Locale("fr")).0, "1@fr");
+        assert_eq!(cache.retrieve_or_compute_ctx(&1, &Locale("de")).0, "1@fr");
+        assert_eq!(cache.retrieve_or_compute(&2).0, "2@default");
+        assert_eq!(
+            cache.retrieve_or_compute_ctx(&3, &"unrelated").0,
+            "3@default"
+        );
+    }
+
+    #[test]
+    fn expired_values_reach_a_revalidating_miss_handler() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .clock(clock.clone())
+            .revalidating_miss_handler(
+                |key: &u32, data: &mut Vec<u32>, _: &mut u8, previous: Option<&Vec<u32>>| {
+                    *data = previous.cloned().unwrap_or_default();
+                    data.push(*key);
+                    true
+                },
+            )
+            .build();
+        assert_eq!(cache.retrieve_or_compute(&1).0, [1]);
+        assert_eq!(cache.retrieve_or_compute(&1).0, [1]);
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(cache.retrieve_or_compute(&1).0, [1, 1]);
+
+        cache.invalidate_all();
+        assert_eq!(cache.retrieve_or_compute(&1).0, [1]);
+    }
+
+    #[test]
+    fn the_miss_handler_can_choose_ttls() {
+        const NXDOMAIN: u8 = 3;
+        const SERVFAIL: u8 = 2;
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .negative_ttl(Duration::from_secs(60))
+            .clock(Arc::new(ManualClock::new()))
+            .ttl_miss_handler(|name: &&str| match *name {
+                "example.com" => ("93.184.215.14", Duration::from_secs(300), 0),
+                "missing.example" => ("", Duration::from_secs(900), NXDOMAIN),
+                _ => ("", Duration::from_secs(2), SERVFAIL),
+            })
+            .build();
+
+        assert_eq!(
+            cache.retrieve_or_compute(&"example.com"),
+            ("93.184.215.14", true, 0)
+        );
+        assert_eq!(
+            cache.retrieve_or_compute(&"missing.example"),
+            ("", false, NXDOMAIN)
+        );
+        assert_eq!(
+            cache.retrieve_or_compute(&"down.example"),
+            ("", false, SERVFAIL)
+        );
+        assert_eq!(
+            cache.time_to_live(&"example.com"),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            cache.time_to_live(&"missing.example"),
+            Some(Duration::from_secs(900))
+        );
+        assert_eq!(
+            cache.time_to_live(&"down.example"),
+            Some(Duration::from_secs(2))
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Telling "there is no such value" apart from "loading it failed".
+//!
+//! A cache of [`LoadResult`]s stores all three outcomes of a
+//! [`result_miss_handler`](crate::CacheBuilder::result_miss_handler): found
+//! values for the positive TTL, definite absences for a TTL of their own,
+//! and errors for the negative TTL, subject to the retry policy like any
+//! other failure. Errors are cached with the entry, so callers sharing a
+//! computation or looking the key up afterwards see the same error.
+//!
+//! Lookups that never reach the loader, because it panicked, was cancelled,
+//! disabled or rate limited, come back as
+//! [`Unavailable`](LoadResult::Unavailable), never as a definite absence.
+
+use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
+
+use crate::builder::CacheBuilder;
+use crate::clock::Clock;
+use crate::load;
+
+/// What a [`result_miss_handler`](crate::CacheBuilder::result_miss_handler)
+/// found for a key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum LoadResult<D, E> {
+    /// The value of the key.
+    Loaded(D),
+    /// The source answered that the key has no value.
+    NotFound,
+    /// The source could not be asked.
+    Error(E),
+    /// The loader produced no outcome, e.g. it panicked or is disabled.
+    /// What callers get for keys that could not be computed at all.
+    #[default]
+    Unavailable,
+}
+
+impl<D, E> LoadResult<D, E> {
+    /// Returns `true` for [`Loaded`](Self::Loaded).
+    pub fn is_loaded(&self) -> bool {
+        matches!(self, LoadResult::Loaded(_))
+    }
+
+    /// The value, if any, or the error, which is `None` for
+    /// [`Unavailable`](Self::Unavailable).
+    pub fn ok(self) -> Result<Option<D>, Option<E>> {
+        match self {
+            LoadResult::Loaded(data) => Ok(Some(data)),
+            LoadResult::NotFound => Ok(None),
+            LoadResult::Error(error) => Err(Some(error)),
+            LoadResult::Unavailable => Err(None),
+        }
+    }
+}
+
+impl<K, D, E, S, C> CacheBuilder<K, LoadResult<D, E>, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone,
+    E: Clone,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Like [`miss_handler`](Self::miss_handler), for loaders telling a
+    /// missing value apart from a failure.
+    ///
+    /// [`Loaded`](LoadResult::Loaded) and
+    /// [`NotFound`](LoadResult::NotFound) are successes, cached for the
+    /// positive TTL and `not_found_ttl` respectively.
+    /// [`Error`](LoadResult::Error) and
+    /// [`Unavailable`](LoadResult::Unavailable) are failures, cached for
+    /// the negative TTL.
+    pub fn result_miss_handler<F>(self, not_found_ttl: Duration, miss_handler: F) -> Self
+    where
+        F: Fn(&K) -> LoadResult<D, E> + Send + Sync + 'static,
+    {
+        self.miss_handler(move |key: &K, data: &mut LoadResult<D, E>, _: &mut u8| {
+            *data = miss_handler(key);
+            match data {
+                LoadResult::Loaded(_) => true,
+                LoadResult::NotFound => {
+                    load::set_ttl(Some(not_found_ttl));
+                    true
+                }
+                LoadResult::Error(_) | LoadResult::Unavailable => false,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cache, ManualClock};
+    use std::sync::Arc;
+
+    #[test]
+    fn each_outcome_has_its_own_ttl() {
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .negative_ttl(Duration::from_secs(5))
+            .clock(Arc::new(ManualClock::new()))
+            .result_miss_handler(Duration::from_secs(600), |id: &u32| match id {
+                0 => LoadResult::Error("database unavailable"),
+                1..=9 => LoadResult::Loaded(id * 10),
+                _ => LoadResult::NotFound,
+            })
+            .build();
+
+        assert_eq!(
+            cache.retrieve_or_compute(&1),
+            (LoadResult::Loaded(10), true, 0)
+        );
+        assert_eq!(
+            cache.retrieve_or_compute(&10),
+            (LoadResult::NotFound, true, 0)
+        );
+        assert_eq!(
+            cache.retrieve_or_compute(&0),
+            (LoadResult::Error("database unavailable"), false, 0)
+        );
+        assert_eq!(cache.time_to_live(&1), Some(Duration::from_secs(60)));
+        assert_eq!(cache.time_to_live(&10), Some(Duration::from_secs(600)));
+        assert_eq!(cache.time_to_live(&0), Some(Duration::from_secs(5)));
+
+        assert_eq!(
+            cache.retrieve_or_compute(&0).0.ok(),
+            Err(Some("database unavailable"))
+        );
+        assert_eq!(cache.retrieve_or_compute(&10).0.ok(), Ok(None));
+    }
+
+    #[test]
+    fn keys_left_unloaded_are_not_absent() {
+        let cache: Cache<u32, LoadResult<u32, &str>> = Cache::builder(10)
+            .result_miss_handler(Duration::from_secs(600), |_: &u32| LoadResult::NotFound)
+            .build();
+        cache.set_loader_enabled(false);
+
+        let (result, success, _) = cache.retrieve_or_compute(&1);
+        assert!(!success);
+        assert_eq!(result, LoadResult::Unavailable);
+        assert_eq!(result.ok(), Err(None));
+    }
+}
@@ -0,0 +1,65 @@
+//! Lock acquisition that survives poisoning.
+//!
+//! A thread panicking while holding one of the cache's locks, e.g. in an
+//! eviction veto or a conflict listener, poisons it. Every structure the
+//! locks protect is left consistent between two statements, so instead of
+//! turning that one panic into a cascade across every thread using the
+//! cache, the locks are taken regardless of poisoning.
+
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+pub(crate) trait RwLockExt<T> {
+    /// Takes the read lock, ignoring poisoning.
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T>;
+
+    /// Takes the write lock, ignoring poisoning.
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> RwLockExt<T> for RwLock<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub(crate) trait MutexExt<T> {
+    /// Takes the lock, ignoring poisoning.
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> MutexExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, EvictDecision};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::time::Duration;
+
+    #[test]
+    fn a_panicking_hook_does_not_poison_the_cache() {
+        let cache = Cache::builder(1)
+            .positive_ttl(Duration::from_secs(60))
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| true)
+            .eviction_veto(1, |_: &u32, data: &u32| {
+                assert_ne!(*data, 0, "veto hook failed");
+                EvictDecision::Evict
+            })
+            .build();
+        cache.insert(1, 0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| cache.insert(2, 2)));
+        assert!(result.is_err());
+
+        assert_eq!(cache.get(&1), Some(0));
+        cache.remove(&1);
+        cache.insert(3, 3);
+        assert_eq!(cache.get(&3), Some(3));
+    }
+}
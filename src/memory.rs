@@ -0,0 +1,236 @@
+//! Approximate accounting of the memory held by a cache.
+
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use lru::LruCache;
+
+use crate::cache::{Cache, CacheEntry, EntryStatus};
+use crate::clock::Clock;
+
+/// Heap memory owned by a value, for sizing cache entries; see
+/// [`CacheBuilder::mem_size`](crate::CacheBuilder::mem_size).
+///
+/// Only memory behind pointers counts: the inline size of keys and values
+/// is accounted for by the cache itself.
+pub trait MemSize {
+    /// Bytes allocated on the heap by this value.
+    fn heap_size(&self) -> usize;
+}
+
+/// Hook estimating the heap memory of an entry in bytes.
+pub type SizeHint<K, D> = dyn Fn(&K, &D) -> usize + Send + Sync;
+
+/// Hook weighing an entry in application-defined units, for
+/// [`Bound::Weight`](crate::Bound::Weight).
+pub type Weigher<K, D> = dyn Fn(&K, &D) -> u64 + Send + Sync;
+
+macro_rules! inline_mem_size {
+    ($($ty:ty),*) => {
+        $(impl MemSize for $ty {
+            fn heap_size(&self) -> usize {
+                0
+            }
+        })*
+    };
+}
+
+inline_mem_size!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64
+);
+
+impl MemSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl MemSize for Box<str> {
+    fn heap_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T: MemSize> MemSize for Box<T> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() + (**self).heap_size()
+    }
+}
+
+/// Counts the shared value in full, as if the entry were its only owner.
+impl<T: MemSize> MemSize for Arc<T> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() + (**self).heap_size()
+    }
+}
+
+impl<T: MemSize> MemSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * mem::size_of::<T>() + self.iter().map(T::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: MemSize> MemSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, T::heap_size)
+    }
+}
+
+impl<A: MemSize, B: MemSize> MemSize for (A, B) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size()
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Approximate bytes held by the entries in memory: their inline size,
+    /// the bookkeeping of the LRU, and the heap memory reported by the size
+    /// hint, if any.
+    pub fn memory_usage(&self) -> usize {
+        self.mem_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Total weight of the entries in memory according to the weigher, or
+    /// 0 without one.
+    pub fn total_weight(&self) -> u64 {
+        self.total_weight.load(Ordering::Relaxed)
+    }
+
+    /// Records the memory and weight of an entry about to be stored; see
+    /// [`charge`](Self::charge).
+    pub(crate) fn measure(&self, key: &K, entry: &mut CacheEntry<D>) {
+        // Inline key and entry, plus the list links and table slot of the
+        // LRU node.
+        let overhead =
+            mem::size_of::<K>() + mem::size_of::<CacheEntry<D>>() + 3 * mem::size_of::<usize>();
+        let heap = self
+            .size_hint
+            .as_ref()
+            .map_or(0, |size_hint| size_hint(key, &entry.data));
+        entry.bytes = overhead + heap;
+        entry.weight = self
+            .weigher
+            .as_ref()
+            .map_or(0, |weigher| weigher(key, &entry.data));
+    }
+
+    /// Adds the measured memory and weight of an entry being stored to the
+    /// totals, and counts it if failed.
+    pub(crate) fn charge(&self, entry: &CacheEntry<D>) {
+        self.mem_bytes.fetch_add(entry.bytes, Ordering::Relaxed);
+        self.total_weight.fetch_add(entry.weight, Ordering::Relaxed);
+        if entry.status == EntryStatus::Failed {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Releases the memory and weight recorded for an entry that left the
+    /// cache.
+    pub(crate) fn uncharge(&self, entry: &CacheEntry<D>) {
+        self.mem_bytes.fetch_sub(entry.bytes, Ordering::Relaxed);
+        self.total_weight.fetch_sub(entry.weight, Ordering::Relaxed);
+        if entry.status == EntryStatus::Failed {
+            self.failed.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Removes the entry for `key`, releasing its memory.
+    pub(crate) fn unlink(
+        &self,
+        cache: &mut LruCache<K, CacheEntry<D>, S>,
+        key: &K,
+    ) -> Option<CacheEntry<D>> {
+        let (_, entry) = cache.pop_entry(key)?;
+        self.uncharge(&entry);
+        Some(entry)
+    }
+
+    /// Evicts entries until the byte and weight bounds, if any, are met
+    /// again. The last entry is always kept, however large.
+    pub(crate) fn trim_to_bounds(&self, cache: &mut LruCache<K, CacheEntry<D>, S>) {
+        let over = || {
+            self.max_bytes.is_some_and(|max| self.memory_usage() > max)
+                || self.max_weight.is_some_and(|max| self.total_weight() > max)
+        };
+        while over() && cache.len() > 1 {
+            if self.drop_dead(cache) {
+                continue;
+            }
+            let Some((key, entry)) = self.pop_victim(cache) else {
+                break;
+            };
+            self.evicted(key, entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+    use std::time::Duration;
+
+    fn cache(max_bytes: usize) -> Cache<u32, String> {
+        Cache::builder(100)
+            .positive_ttl(Duration::from_secs(60))
+            .miss_handler(|_: &u32, _: &mut String, _: &mut u8| false)
+            .mem_size()
+            .max_bytes(max_bytes)
+            .build()
+    }
+
+    #[test]
+    fn usage_follows_inserts_and_removals() {
+        let cache = cache(usize::MAX);
+        cache.insert(1, String::new());
+        let overhead = cache.memory_usage();
+        assert!(overhead > 0);
+
+        cache.insert(2, "x".repeat(1000));
+        assert_eq!(cache.memory_usage(), 2 * overhead + 1000);
+        cache.insert(2, "x".repeat(10));
+        assert_eq!(cache.memory_usage(), 2 * overhead + 10);
+        cache.remove(&1);
+        assert_eq!(cache.memory_usage(), overhead + 10);
+        cache.clear();
+        assert_eq!(cache.memory_usage(), 0);
+    }
+
+    #[test]
+    fn max_bytes_evicts_the_coldest_entries() {
+        let cache = cache(3000);
+        for key in 0..3 {
+            cache.insert(key, "x".repeat(1000));
+        }
+        assert!(cache.memory_usage() <= 3000);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&0), None);
+
+        cache.insert(3, "x".repeat(5000));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&3).map(|data| data.len()), Some(5000));
+    }
+}
@@ -0,0 +1,331 @@
+//! Response caching middleware for `tower`, behind the `tower` feature, so
+//! that axum, tonic and other tower-based stacks can put a cache in front
+//! of a service.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::cache::{Cache, Lookup};
+use crate::clock::{Clock, SystemClock};
+use crate::lock::MutexExt;
+use crate::pool::Loaded;
+use crate::time::Instant;
+use crate::timeout::Timeout;
+use crate::DefaultHasher;
+
+/// Wakers of the requests waiting for the response to another request
+/// with the same key.
+type Waiters<K> = Mutex<HashMap<K, Vec<Waker>>>;
+
+/// A [`Layer`] caching the responses of the services it wraps in a
+/// [`Cache`], keyed by a function of the request.
+///
+/// The key function returns `None` for requests that must not be cached,
+/// e.g. anything but `GET`, which then go straight to the inner service.
+/// Successful responses are inserted for the positive TTL of the cache;
+/// errors are passed through and not cached. The miss handler of the cache
+/// is not involved.
+///
+/// Concurrent misses of a key are coalesced: one request reaches the inner
+/// service while the others wait for its response without blocking the
+/// executor. If it fails, the next waiter sends its own request. The inner
+/// service is only polled for readiness by requests that reach it, so hits
+/// do not hold its capacity.
+///
+/// Responses must be [`Clone`] and [`Default`], so streamed bodies need
+/// buffering first, e.g. into a `(StatusCode, HeaderMap, Bytes)`.
+pub struct CacheLayer<K, Res, F, S = DefaultHasher, C = SystemClock> {
+    cache: Arc<Cache<K, Res, S, C>>,
+    key: Arc<F>,
+    waiters: Arc<Waiters<K>>,
+}
+
+impl<K, Res, F, S, C> CacheLayer<K, Res, F, S, C> {
+    /// Caches responses in `cache` under the key `key` extracts from each
+    /// request.
+    pub fn new(cache: Arc<Cache<K, Res, S, C>>, key: F) -> Self {
+        CacheLayer {
+            cache,
+            key: Arc::new(key),
+            waiters: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<K, Res, F, S, C> Clone for CacheLayer<K, Res, F, S, C> {
+    fn clone(&self) -> Self {
+        CacheLayer {
+            cache: self.cache.clone(),
+            key: self.key.clone(),
+            waiters: self.waiters.clone(),
+        }
+    }
+}
+
+impl<K, Res, F, S, C> fmt::Debug for CacheLayer<K, Res, F, S, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacheLayer").finish_non_exhaustive()
+    }
+}
+
+impl<Svc, K, Res, F, S, C> Layer<Svc> for CacheLayer<K, Res, F, S, C> {
+    type Service = CacheService<Svc, K, Res, F, S, C>;
+
+    fn layer(&self, inner: Svc) -> Self::Service {
+        CacheService {
+            inner,
+            cache: self.cache.clone(),
+            key: self.key.clone(),
+            waiters: self.waiters.clone(),
+        }
+    }
+}
+
+/// The [`Service`] made by a [`CacheLayer`].
+pub struct CacheService<Svc, K, Res, F, S = DefaultHasher, C = SystemClock> {
+    inner: Svc,
+    cache: Arc<Cache<K, Res, S, C>>,
+    key: Arc<F>,
+    waiters: Arc<Waiters<K>>,
+}
+
+impl<Svc, K, Res, F, S, C> CacheService<Svc, K, Res, F, S, C> {
+    /// The wrapped service.
+    pub fn get_ref(&self) -> &Svc {
+        &self.inner
+    }
+}
+
+impl<Svc: Clone, K, Res, F, S, C> Clone for CacheService<Svc, K, Res, F, S, C> {
+    fn clone(&self) -> Self {
+        CacheService {
+            inner: self.inner.clone(),
+            cache: self.cache.clone(),
+            key: self.key.clone(),
+            waiters: self.waiters.clone(),
+        }
+    }
+}
+
+impl<Svc: fmt::Debug, K, Res, F, S, C> fmt::Debug for CacheService<Svc, K, Res, F, S, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacheService")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The future of a [`CacheService`] response.
+pub type ResponseFuture<Res, E> = Pin<Box<dyn Future<Output = Result<Res, E>> + Send>>;
+
+/// The placeholder of a key whose response is being fetched. Dropping it
+/// wakes the waiters, and drops the placeholder unless the response was
+/// cached, e.g. because the request failed or was cancelled.
+struct Fetching<'a, K, Res, S, C>
+where
+    K: Hash + Eq + Clone,
+    Res: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    cache: &'a Cache<K, Res, S, C>,
+    waiters: &'a Waiters<K>,
+    key: &'a K,
+    started: u64,
+}
+
+impl<K, Res, S, C> Drop for Fetching<'_, K, Res, S, C>
+where
+    K: Hash + Eq + Clone,
+    Res: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    fn drop(&mut self) {
+        let _ = self.cache.abandon(self.key, self.started);
+        let woken = self.waiters.lock_or_recover().remove(self.key);
+        for waker in woken.into_iter().flatten() {
+            waker.wake();
+        }
+    }
+}
+
+impl<Svc, Req, K, Res, F, S, C> Service<Req> for CacheService<Svc, K, Res, F, S, C>
+where
+    Svc: Service<Req, Response = Res> + Clone + Send + 'static,
+    Svc::Future: Send + 'static,
+    Svc::Error: Send,
+    Req: Send + 'static,
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    Res: Clone + Default + Send + Sync + 'static,
+    F: Fn(&Req) -> Option<K>,
+    S: BuildHasher + Send + Sync + 'static,
+    C: Clock + 'static,
+{
+    type Response = Res;
+    type Error = Svc::Error;
+    type Future = ResponseFuture<Res, Svc::Error>;
+
+    /// Always ready: the inner service is only polled by the requests
+    /// that reach it.
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = mem::replace(&mut self.inner, clone);
+        let key = (self.key)(&request);
+        let cache = self.cache.clone();
+        let waiters = self.waiters.clone();
+        Box::pin(async move {
+            let Some(key) = key else {
+                poll_fn(|cx| inner.poll_ready(cx)).await?;
+                return inner.call(request).await;
+            };
+            let lookup = poll_fn(|cx| {
+                let mut waiting = waiters.lock_or_recover();
+                match cache.lookup_or_claim(&key, Some(Instant::now())) {
+                    Ok(lookup) => Poll::Ready(lookup),
+                    Err(Timeout) => {
+                        let wakers = waiting.entry(key.clone()).or_default();
+                        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                            wakers.push(cx.waker().clone());
+                        }
+                        Poll::Pending
+                    }
+                }
+            })
+            .await;
+            let started = match lookup {
+                Lookup::Found((response, true, _)) => return Ok(response),
+                Lookup::Claimed(started) => started,
+                Lookup::Found(_) | Lookup::Panicked | Lookup::Cancelled | Lookup::Unavailable => {
+                    poll_fn(|cx| inner.poll_ready(cx)).await?;
+                    return inner.call(request).await;
+                }
+            };
+            let fetching = Fetching {
+                cache: &cache,
+                waiters: &waiters,
+                key: &key,
+                started,
+            };
+            poll_fn(|cx| inner.poll_ready(cx)).await?;
+            let load_start = Instant::now();
+            let response = inner.call(request).await?;
+            let loaded = Loaded::from((response.clone(), true, 0));
+            cache.complete(&key, started, loaded, load_start.elapsed());
+            drop(fetching);
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+    use futures::future::join3;
+    use std::future::{ready, Ready};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Answers a path with its length, failing on empty paths.
+    #[derive(Clone, Default)]
+    struct Lengths {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Service<(&'static str, &'static str)> for Lengths {
+        type Response = usize;
+        type Error = &'static str;
+        type Future = Ready<Result<usize, &'static str>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, (_, path): (&'static str, &'static str)) -> Self::Future {
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            ready(if path.is_empty() {
+                Err("empty path")
+            } else {
+                Ok(path.len() + calls * 100)
+            })
+        }
+    }
+
+    /// Answers with the value sent on its channel, which only one call may
+    /// wait for.
+    #[derive(Clone)]
+    struct Pending(Arc<Mutex<Option<oneshot::Receiver<usize>>>>);
+
+    impl Service<&'static str> for Pending {
+        type Response = usize;
+        type Error = oneshot::Canceled;
+        type Future = ResponseFuture<usize, oneshot::Canceled>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: &'static str) -> Self::Future {
+            let reply = self.0.lock().unwrap().take().expect("called twice");
+            Box::pin(reply)
+        }
+    }
+
+    fn cache() -> Arc<Cache<&'static str, usize>> {
+        Arc::new(Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &&str, _: &mut usize, _: &mut u8| false,
+        ))
+    }
+
+    #[test]
+    fn cacheable_responses_are_served_from_the_cache() {
+        let cache = cache();
+        let layer = CacheLayer::new(cache.clone(), |&(method, path): &(&str, &'static str)| {
+            (method == "GET").then_some(path)
+        });
+        let mut service = layer.layer(Lengths::default());
+
+        assert_eq!(block_on(service.call(("GET", "/a"))), Ok(102));
+        assert_eq!(block_on(service.call(("GET", "/a"))), Ok(102));
+        assert_eq!(block_on(service.call(("POST", "/a"))), Ok(202));
+        assert_eq!(block_on(service.call(("GET", ""))), Err("empty path"));
+        assert_eq!(block_on(service.call(("GET", ""))), Err("empty path"));
+        assert_eq!(service.get_ref().calls.load(Ordering::SeqCst), 4);
+        assert_eq!(cache.get(&"/a"), Some(102));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn concurrent_misses_reach_the_service_once() {
+        let cache = cache();
+        let (reply, replied) = oneshot::channel();
+        let layer = CacheLayer::new(cache.clone(), |&path: &&'static str| Some(path));
+        let mut service = layer.layer(Pending(Arc::new(Mutex::new(Some(replied)))));
+
+        let first = service.call("/a");
+        let second = service.call("/a");
+        let (first, second, ()) = block_on(join3(first, second, async {
+            reply.send(7).unwrap();
+        }));
+        assert_eq!((first, second), (Ok(7), Ok(7)));
+        assert_eq!(cache.get(&"/a"), Some(7));
+    }
+}
@@ -0,0 +1,119 @@
+//! Upgrading values stored under an older layout.
+//!
+//! Every entry records the layout version its value was stored under. A
+//! cache built with [`CacheBuilder::value_version`](crate::CacheBuilder::value_version)
+//! upgrades entries of other versions on first access, so that values
+//! imported from an old snapshot, or kept behind an `Arc<dyn Any>` whose
+//! concrete type changed, survive a schema change.
+
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+
+use lru::LruCache;
+
+use crate::cache::{Cache, CacheEntry, EntryStatus};
+use crate::clock::Clock;
+use crate::lock::RwLockExt;
+
+/// Hook upgrading a value from the version it was stored under to the
+/// current one, or returning `None` to drop it.
+pub type ValueMigration<K, D> = dyn Fn(&K, D, u32) -> Option<D> + Send + Sync;
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Inserts a value laid out as of `version`, to be upgraded by the
+    /// migration hook when first accessed.
+    ///
+    /// Meant for importers restoring values persisted by an older build.
+    /// The value is not written through to the store handler. Without a
+    /// migration hook it is served as is.
+    pub fn insert_versioned(&self, key: K, data: D, version: u32) {
+        let mut cache = self.lru_cache.write_or_recover();
+        let entry = CacheEntry::new(
+            data,
+            EntryStatus::Ready,
+            0,
+            self.now() + self.jittered(self.positive_ttl()),
+        );
+        if self.store(&mut cache, key.clone(), entry) != 0 {
+            if let Some(entry) = cache.peek_mut(&key) {
+                entry.version = version;
+            }
+        }
+    }
+
+    /// Upgrades the entry for `key` to the current value version, dropping
+    /// it if the migration hook declines.
+    pub(crate) fn migrate(&self, cache: &mut LruCache<K, CacheEntry<D>, S>, key: &K) {
+        let Some(migration) = &self.migration else {
+            return;
+        };
+        let Some(entry) = cache.peek_mut(key) else {
+            return;
+        };
+        if entry.version == self.value_version || entry.status != EntryStatus::Ready {
+            return;
+        }
+        match migration(key, mem::take(&mut entry.data), entry.version) {
+            Some(data) => {
+                entry.data = data;
+                entry.version = self.value_version;
+            }
+            None => {
+                self.unlink(cache, key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+    use std::any::Any;
+    use std::sync::Arc;
+
+    type Value = Arc<dyn Any + Send + Sync>;
+
+    #[derive(Debug, PartialEq)]
+    struct UserV2 {
+        name: String,
+        admin: bool,
+    }
+
+    #[test]
+    fn old_values_are_upgraded_on_first_access() {
+        let cache: Cache<u32, Option<Value>> = Cache::builder(10)
+            .miss_handler(|_: &u32, _: &mut Option<Value>, _: &mut u8| false)
+            .value_version(2, |_: &u32, data: Option<Value>, version: u32| {
+                let name = data?.downcast_ref::<String>()?.clone();
+                (version == 1).then(|| Some(Arc::new(UserV2 { name, admin: false }) as Value))
+            })
+            .build();
+        cache.insert_versioned(1, Some(Arc::new("ada".to_string())), 1);
+        cache.insert_versioned(2, Some(Arc::new("bob".to_string())), 0);
+        cache.insert(
+            3,
+            Some(Arc::new(UserV2 {
+                name: "eve".to_string(),
+                admin: true,
+            })),
+        );
+
+        let upgraded = cache.get(&1).flatten().unwrap();
+        assert_eq!(
+            upgraded.downcast_ref::<UserV2>(),
+            Some(&UserV2 {
+                name: "ada".to_string(),
+                admin: false,
+            })
+        );
+        assert!(cache.get(&2).is_none());
+        assert!(!cache.retrieve_or_compute(&2).1);
+        assert!(cache.get(&3).flatten().unwrap().is::<UserV2>());
+    }
+}
@@ -0,0 +1,183 @@
+//! Shadowing a cache with another configuration to compare hit rates.
+
+use std::hash::Hash;
+
+use crate::cache::Cache;
+use crate::ops::{CacheOps, DynCache};
+use crate::stats::CacheStats;
+
+/// Hit rates of the two caches of a [`MirrorCache`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MirrorReport {
+    /// Lookups served so far.
+    pub lookups: u64,
+    /// Fraction of lookups the primary cache answered without loading.
+    pub primary_hit_rate: Option<f64>,
+    /// Fraction of lookups the shadow cache would have answered.
+    pub shadow_hit_rate: Option<f64>,
+}
+
+/// A cache serving every request from a primary cache while replaying it
+/// against a shadow cache configured differently, e.g. with another size
+/// or eviction veto, to compare hit rates in production before switching.
+///
+/// The shadow never runs its miss handler: on a shadow miss the value
+/// returned by the primary is inserted into it, as if the shadow had
+/// loaded it. Failed loads are not mirrored, so negative hits only count
+/// for the primary.
+pub struct MirrorCache<K, D> {
+    primary: Cache<K, D>,
+    shadow: Cache<K, D>,
+}
+
+impl<K, D> MirrorCache<K, D>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+{
+    /// Serves from `primary` and shadows every request on `shadow`.
+    ///
+    /// Both caches should be fresh: the report is based on their lookup
+    /// counters since creation.
+    pub fn new(primary: Cache<K, D>, shadow: Cache<K, D>) -> Self {
+        MirrorCache { primary, shadow }
+    }
+
+    /// Returns the primary's value for `key`. See [`Cache::get`].
+    pub fn get(&self, key: &K) -> Option<D> {
+        let found = self.primary.get(key);
+        self.mirror(key, found.as_ref());
+        found
+    }
+
+    /// Returns the primary's value for `key`, computing it on a miss. See
+    /// [`Cache::retrieve_or_compute`].
+    pub fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        let (data, success, adhoc_code) = self.primary.retrieve_or_compute(key);
+        self.mirror(key, success.then_some(&data));
+        (data, success, adhoc_code)
+    }
+
+    /// Inserts a value into both caches.
+    pub fn insert(&self, key: K, data: D) {
+        self.shadow.insert(key.clone(), data.clone());
+        self.primary.insert(key, data);
+    }
+
+    /// Removes a value from both caches, returning the primary's.
+    pub fn remove(&self, key: &K) -> Option<D> {
+        self.shadow.remove(key);
+        self.primary.remove(key)
+    }
+
+    /// Compares the hit rates of both caches.
+    pub fn report(&self) -> MirrorReport {
+        let primary = self.primary.readiness();
+        MirrorReport {
+            lookups: primary.lookups,
+            primary_hit_rate: primary.hit_rate,
+            shadow_hit_rate: self.shadow.readiness().hit_rate,
+        }
+    }
+
+    /// The cache serving requests.
+    pub fn primary(&self) -> &Cache<K, D> {
+        &self.primary
+    }
+
+    /// The cache under evaluation.
+    pub fn shadow(&self) -> &Cache<K, D> {
+        &self.shadow
+    }
+
+    /// Looks `key` up in the shadow, filling it with the primary's value on
+    /// a miss.
+    fn mirror(&self, key: &K, found: Option<&D>) {
+        if self.shadow.get(key).is_some() {
+            return;
+        }
+        if let Some(data) = found {
+            self.shadow.insert(key.clone(), data.clone());
+        }
+    }
+}
+
+impl<K, D> CacheOps<K, D> for MirrorCache<K, D>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+{
+    fn get(&self, key: &K) -> Option<D> {
+        MirrorCache::get(self, key)
+    }
+
+    fn insert(&self, key: K, data: D) {
+        MirrorCache::insert(self, key, data)
+    }
+
+    fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        MirrorCache::retrieve_or_compute(self, key)
+    }
+
+    fn remove(&self, key: &K) -> Option<D> {
+        MirrorCache::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        self.primary.len()
+    }
+}
+
+impl<K, D> DynCache<K, D> for MirrorCache<K, D>
+where
+    K: Hash + Eq + Clone + Send + Sync,
+    D: Clone + Default + Send + Sync,
+{
+    fn invalidate(&self, key: &K) {
+        MirrorCache::remove(self, key);
+    }
+
+    fn invalidate_all(&self) {
+        self.primary.invalidate_all();
+        self.shadow.invalidate_all();
+    }
+
+    /// The primary's statistics.
+    fn stats(&self) -> CacheStats {
+        self.primary.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn cache(size: usize) -> Cache<u32, u32> {
+        Cache::new(
+            size,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |key: &u32, data: &mut u32, _: &mut u8| {
+                *data = key * 2;
+                true
+            },
+        )
+    }
+
+    #[test]
+    fn shadow_replays_the_primary_traffic() {
+        let mirror = MirrorCache::new(cache(4), cache(2));
+        for _ in 0..2 {
+            for key in 0..3 {
+                assert_eq!(mirror.retrieve_or_compute(&key), (key * 2, true, 0));
+            }
+        }
+
+        let report = mirror.report();
+        assert_eq!(report.lookups, 6);
+        assert_eq!(report.primary_hit_rate, Some(0.5));
+        assert_eq!(report.shadow_hit_rate, Some(0.0));
+        assert_eq!(mirror.shadow().len(), 2);
+    }
+}
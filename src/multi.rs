@@ -0,0 +1,108 @@
+//! Writing several keys at once.
+
+use std::hash::{BuildHasher, Hash};
+
+use crate::cache::{Cache, CacheEntry, EntryStatus, StoreError};
+use crate::clock::Clock;
+use crate::events::CacheEvent;
+use crate::lock::RwLockExt;
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Inserts every pair of `items` under a single lock acquisition, so
+    /// that readers see either none or all of the new values.
+    ///
+    /// The values are written through to the store handler first, in
+    /// order; if it rejects one, the cache is left unchanged, although the
+    /// store may already hold the earlier values. The new values share one
+    /// expiration, so they also expire together.
+    pub fn insert_many(&self, items: &[(K, D)]) -> Result<(), StoreError> {
+        self.swap_many(items).map(drop)
+    }
+
+    /// Like [`insert_many`](Self::insert_many), returning for each pair the
+    /// value it replaced, if that value could have been served.
+    pub fn swap_many(&self, items: &[(K, D)]) -> Result<Vec<Option<D>>, StoreError> {
+        let now = self.now();
+        let expiration = now + self.jittered(self.positive_ttl());
+        let mut cache = self.lru_cache.write_or_recover();
+        for (key, data) in items {
+            self.write_through(key, data)?;
+        }
+        let replaced = items
+            .iter()
+            .map(|(key, data)| {
+                let replaced = cache
+                    .peek(key)
+                    .filter(|entry| entry.status == EntryStatus::Ready && self.is_live(entry, now))
+                    .map(|entry| entry.data.clone());
+                let entry = CacheEntry::new(data.clone(), EntryStatus::Ready, 0, expiration);
+                self.publish(|| CacheEvent::Insert(key.clone()));
+                self.store(&mut cache, key.clone(), entry);
+                replaced
+            })
+            .collect();
+        Ok(replaced)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn readers_never_see_a_mixed_state() {
+        let cache = Arc::new(Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut u32, _: &mut u8| false,
+        ));
+        cache.insert_many(&[(1, 0), (2, 0)]).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (cache, done) = (cache.clone(), done.clone());
+            thread::spawn(move || {
+                for round in 1..=2000 {
+                    cache.insert_many(&[(1, round), (2, round)]).unwrap();
+                }
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+        while !done.load(Ordering::SeqCst) {
+            let snapshot: Vec<u32> = cache.iter().map(|(_, data)| data).collect();
+            assert_eq!(snapshot.len(), 2);
+            assert_eq!(snapshot[0], snapshot[1]);
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn swaps_return_the_replaced_values() {
+        let cache = Cache::builder(10)
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| false)
+            .store_handler(|key: &u32, _: &u32| match key {
+                0 => Err("read-only key"),
+                _ => Ok(()),
+            })
+            .build();
+        cache.insert(1, 1);
+        cache.retrieve_or_compute(&2);
+        assert_eq!(
+            cache.swap_many(&[(1, 10), (2, 20), (3, 30)]).unwrap(),
+            vec![Some(1), None, None]
+        );
+        assert!(cache.insert_many(&[(4, 40), (0, 0)]).is_err());
+        assert_eq!(cache.get(&4), None);
+        assert_eq!(cache.get(&2), Some(20));
+    }
+}
@@ -0,0 +1,282 @@
+//! Namespaced views sharing the capacity of one cache.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lru::LruCache;
+
+use crate::cache::Cache;
+use crate::config::ConfigError;
+use crate::lock::MutexExt;
+
+/// Key of the shared cache: the user key qualified by its namespace and the
+/// namespace epoch it was stored under.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct NsKey<K> {
+    namespace: Arc<str>,
+    epoch: u64,
+    key: K,
+}
+
+struct NamespaceState<K> {
+    name: Arc<str>,
+    epoch: AtomicU64,
+    /// Recency of the namespace's keys, bounded by its quota. Keys pushed
+    /// out are removed from the shared cache.
+    quota: Mutex<Option<LruCache<K, ()>>>,
+}
+
+/// A cache partitioned into namespaces that share one capacity budget.
+///
+/// Each namespace can be invalidated as a whole in O(1): its epoch is bumped
+/// and entries stored under the old epoch simply stop matching, to be
+/// evicted as they age out of the LRU. A namespace can also be given a quota
+/// so that it cannot crowd the others out.
+pub struct NamespacedCache<K, D> {
+    cache: Cache<NsKey<K>, D>,
+    namespaces: Mutex<HashMap<Arc<str>, Arc<NamespaceState<K>>>>,
+}
+
+/// Handle on one namespace of a [`NamespacedCache`].
+pub struct Namespace<'a, K, D> {
+    cache: &'a Cache<NsKey<K>, D>,
+    state: Arc<NamespaceState<K>>,
+}
+
+impl<K, D> NamespacedCache<K, D>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+{
+    /// Creates a cache holding at most `size` entries across all
+    /// namespaces. The miss handler receives the namespace name along with
+    /// the key.
+    ///
+    /// Fails with [`ConfigError::ZeroCapacity`] if `size` is zero.
+    pub fn new<F>(
+        size: usize,
+        positive_ttl: Duration,
+        negative_ttl: Duration,
+        miss_handler: F,
+    ) -> Result<Self, ConfigError>
+    where
+        F: Fn(&str, &K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        let cache = Cache::builder(size)
+            .positive_ttl(positive_ttl)
+            .negative_ttl(negative_ttl)
+            .miss_handler(move |key: &NsKey<K>, data: &mut D, adhoc_code: &mut u8| {
+                miss_handler(&key.namespace, &key.key, data, adhoc_code)
+            })
+            .try_build()?;
+        Ok(NamespacedCache {
+            cache,
+            namespaces: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns a handle on the namespace `name`, creating it if needed.
+    pub fn namespace(&self, name: &str) -> Namespace<'_, K, D> {
+        Namespace {
+            cache: &self.cache,
+            state: self.state(name),
+        }
+    }
+
+    /// Limits the namespace `name` to at most `quota` entries, or lifts the
+    /// limit with `None`.
+    ///
+    /// When the namespace is full its least recently used key is dropped to
+    /// make room. Entries evicted from the shared cache still count until
+    /// they are pushed out of the quota, so the quota is an upper bound.
+    ///
+    /// Fails with [`ConfigError::ZeroCapacity`] if `quota` is `Some(0)`,
+    /// leaving the namespace as it was.
+    pub fn set_quota(&self, name: &str, quota: Option<usize>) -> Result<(), ConfigError> {
+        let quota = quota
+            .map(|quota| NonZeroUsize::new(quota).ok_or(ConfigError::ZeroCapacity))
+            .transpose()?;
+        let state = self.state(name);
+        let mut keys = state.quota.lock_or_recover();
+        match (quota, keys.as_mut()) {
+            (None, _) => *keys = None,
+            (Some(quota), Some(lru)) => lru.resize(quota),
+            (Some(quota), None) => *keys = Some(LruCache::new(quota)),
+        }
+        Ok(())
+    }
+
+    /// Number of entries held across all namespaces, including those of
+    /// invalidated epochs that have not been evicted yet.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Returns `true` if no entries are held.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    fn state(&self, name: &str) -> Arc<NamespaceState<K>> {
+        let mut namespaces = self.namespaces.lock_or_recover();
+        if let Some(state) = namespaces.get(name) {
+            return state.clone();
+        }
+        let name: Arc<str> = Arc::from(name);
+        let state = Arc::new(NamespaceState {
+            name: name.clone(),
+            epoch: AtomicU64::new(0),
+            quota: Mutex::new(None),
+        });
+        namespaces.insert(name, state.clone());
+        state
+    }
+}
+
+impl<K, D> Namespace<'_, K, D>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+{
+    /// The namespace name.
+    pub fn name(&self) -> &str {
+        &self.state.name
+    }
+
+    /// See [`Cache::get`].
+    pub fn get(&self, key: &K) -> Option<D> {
+        let data = self.cache.get(&self.qualify(key))?;
+        self.touch(key);
+        Some(data)
+    }
+
+    /// See [`Cache::insert`].
+    pub fn insert(&self, key: K, data: D) {
+        self.cache.insert(self.qualify(&key), data);
+        self.touch(&key);
+    }
+
+    /// See [`Cache::remove`].
+    pub fn remove(&self, key: &K) -> Option<D> {
+        if let Some(keys) = self.state.quota.lock_or_recover().as_mut() {
+            keys.pop(key);
+        }
+        self.cache.remove(&self.qualify(key))
+    }
+
+    /// See [`Cache::retrieve_or_compute`].
+    pub fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        let result = self.cache.retrieve_or_compute(&self.qualify(key));
+        self.touch(key);
+        result
+    }
+
+    /// Invalidates every entry of the namespace in O(1).
+    pub fn invalidate_all(&self) {
+        let mut keys = self.state.quota.lock_or_recover();
+        self.state.epoch.fetch_add(1, Ordering::SeqCst);
+        if let Some(keys) = keys.as_mut() {
+            keys.clear();
+        }
+    }
+
+    fn qualify(&self, key: &K) -> NsKey<K> {
+        NsKey {
+            namespace: self.state.name.clone(),
+            epoch: self.state.epoch.load(Ordering::SeqCst),
+            key: key.clone(),
+        }
+    }
+
+    /// Records a use of `key` against the quota, dropping whichever key
+    /// falls out of it.
+    fn touch(&self, key: &K) {
+        let mut keys = self.state.quota.lock_or_recover();
+        let Some(keys) = keys.as_mut() else {
+            return;
+        };
+        if let Some((evicted, ())) = keys.push(key.clone(), ()) {
+            if evicted != *key {
+                self.cache.remove(&self.qualify(&evicted));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> NamespacedCache<u32, String> {
+        NamespacedCache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |namespace: &str, key: &u32, data: &mut String, _: &mut u8| {
+                *data = format!("{namespace}:{key}");
+                true
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn namespaces_do_not_share_keys() {
+        let cache = cache();
+        let users = cache.namespace("users");
+        let orgs = cache.namespace("orgs");
+
+        assert_eq!(users.retrieve_or_compute(&1).0, "users:1");
+        assert_eq!(orgs.retrieve_or_compute(&1).0, "orgs:1");
+        orgs.insert(2, "custom".to_string());
+        assert_eq!(users.get(&2), None);
+        assert_eq!(cache.namespace("orgs").get(&2), Some("custom".to_string()));
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn invalidate_all_only_affects_one_namespace() {
+        let cache = cache();
+        let users = cache.namespace("users");
+        let orgs = cache.namespace("orgs");
+        users.insert(1, "a".to_string());
+        orgs.insert(1, "b".to_string());
+
+        users.invalidate_all();
+        assert_eq!(users.get(&1), None);
+        assert_eq!(orgs.get(&1), Some("b".to_string()));
+        assert_eq!(users.retrieve_or_compute(&1).0, "users:1");
+    }
+
+    #[test]
+    fn quota_caps_a_namespace() {
+        let cache = cache();
+        assert_eq!(
+            cache.set_quota("users", Some(0)),
+            Err(ConfigError::ZeroCapacity)
+        );
+        let empty = NamespacedCache::<u32, String>::new(
+            0,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &str, _: &u32, _: &mut String, _: &mut u8| true,
+        );
+        assert_eq!(empty.err(), Some(ConfigError::ZeroCapacity));
+        cache.set_quota("users", Some(2)).unwrap();
+        let users = cache.namespace("users");
+        let orgs = cache.namespace("orgs");
+        for key in 0..5 {
+            users.insert(key, key.to_string());
+            orgs.insert(key, key.to_string());
+        }
+
+        assert_eq!(users.get(&2), None);
+        assert_eq!(users.get(&4), Some("4".to_string()));
+        assert_eq!(orgs.get(&0), Some("0".to_string()));
+        assert_eq!(cache.len(), 7);
+    }
+}
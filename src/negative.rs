@@ -0,0 +1,133 @@
+//! Controls on how failures are cached.
+//!
+//! By default a failure takes a regular LRU entry for the negative TTL,
+//! competing for slots with successes. A cache can instead cap the number
+//! of failed entries it holds, keep failures in a constant-size
+//! [`negative_sketch`](crate::CacheBuilder::negative_sketch), or not cache
+//! them at all.
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::Ordering;
+
+use lru::LruCache;
+
+use crate::cache::{Cache, CacheEntry, EntryStatus};
+use crate::clock::Clock;
+use crate::lock::RwLockExt;
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Number of failed entries held in memory, including expired ones
+    /// that have not been dropped yet.
+    pub fn failed_len(&self) -> usize {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Records `key` as known not to exist, as if its computation had just
+    /// failed, e.g. for IDs found deleted by another process. The key goes
+    /// into the negative sketch if there is one, and is otherwise cached as
+    /// failed for the negative TTL; either way its value stops being
+    /// served.
+    pub fn mark_missing(&self, key: K) {
+        let now = self.now();
+        let ttl = self.negative_ttl();
+        let mut cache = self.lru_cache.write_or_recover();
+        if let Some(l2) = &self.l2 {
+            l2.remove(&key);
+        }
+        match &self.negative_sketch {
+            Some(sketch) => {
+                sketch.insert(&key, ttl, now);
+                self.unlink(&mut cache, &key);
+            }
+            None if !self.negative_caching => {
+                self.unlink(&mut cache, &key);
+            }
+            None => {
+                let expiration = now + self.jittered(ttl);
+                let entry = CacheEntry::new(D::default(), EntryStatus::Failed, 0, expiration);
+                self.store(&mut cache, key, entry);
+                self.trim_failed(&mut cache);
+            }
+        }
+    }
+
+    /// Drops the least recently used failed entries until at most
+    /// [`max_failed`](crate::CacheBuilder::max_failed) remain.
+    pub(crate) fn trim_failed(&self, cache: &mut LruCache<K, CacheEntry<D>, S>) {
+        let Some(max_failed) = self.max_failed else {
+            return;
+        };
+        while self.failed_len() > max_failed {
+            let oldest = cache
+                .iter()
+                .rev()
+                .find(|(_, entry)| entry.status == EntryStatus::Failed && entry.holds == 0)
+                .map(|(key, _)| key.clone());
+            let Some(key) = oldest else {
+                break;
+            };
+            self.unlink(cache, &key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+    use std::time::Duration;
+
+    fn builder() -> crate::CacheBuilder<u32, u32> {
+        Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .negative_ttl(Duration::from_secs(60))
+            .miss_handler(|key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                key.is_multiple_of(2)
+            })
+    }
+
+    #[test]
+    fn failed_entries_are_capped_separately() {
+        let cache = builder().max_failed(2).build();
+        for key in 0..10 {
+            cache.retrieve_or_compute(&key);
+        }
+        assert_eq!(cache.failed_len(), 2);
+        assert_eq!(cache.len(), 7);
+        // The most recent failures are the ones kept.
+        assert!(cache.get_entry(&9).is_some());
+        assert!(cache.get_entry(&5).is_none());
+
+        cache.clear();
+        assert_eq!(cache.failed_len(), 0);
+    }
+
+    #[test]
+    fn negative_caching_can_be_disabled() {
+        let cache = builder().negative_caching(false).build();
+        assert!(!cache.retrieve_or_compute(&1).1);
+        assert!(cache.get_entry(&1).is_none());
+        assert_eq!((cache.len(), cache.failed_len()), (0, 0));
+    }
+
+    #[test]
+    fn keys_can_be_marked_missing() {
+        let cache = builder().build();
+        assert_eq!(cache.retrieve_or_compute(&2), (2, true, 0));
+        cache.mark_missing(2);
+        assert_eq!(cache.retrieve_or_compute(&2), (0, false, 0));
+        assert_eq!(cache.failed_len(), 1);
+
+        let cache = builder().negative_sketch(1000, 0.01).build();
+        cache.insert(4, 4);
+        cache.mark_missing(4);
+        assert_eq!(cache.retrieve_or_compute(&4), (0, false, 0));
+        assert_eq!(cache.len(), 0);
+    }
+}
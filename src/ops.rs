@@ -0,0 +1,368 @@
+//! The core cache operations as a trait, so that application code can be
+//! written against any cache and tested against a mock.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::cache::{Cache, MissHandler};
+use crate::clock::Clock;
+use crate::lock::RwLockExt;
+use crate::stats::CacheStats;
+use crate::time::Instant;
+
+/// The operations shared by every cache of this crate.
+///
+/// Implemented by every cache of this crate. The trait is object safe, so
+/// layers can hold a `Box<dyn CacheOps<K, D>>`; see [`DynCache`] for a
+/// handle that also invalidates and reports statistics.
+pub trait CacheOps<K, D> {
+    /// Returns the cached value of `key`, if any. See [`Cache::get`].
+    fn get(&self, key: &K) -> Option<D>;
+
+    /// Caches `data` under `key`. See [`Cache::insert`].
+    fn insert(&self, key: K, data: D);
+
+    /// Returns the value of `key`, computing it on a miss. See
+    /// [`Cache::retrieve_or_compute`].
+    fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8);
+
+    /// Drops `key`, returning its value if it was cached. See
+    /// [`Cache::remove`].
+    fn remove(&self, key: &K) -> Option<D>;
+
+    /// Number of entries held.
+    fn len(&self) -> usize;
+
+    /// Returns `true` if no entries are held.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A cache handle for dependency injection: [`CacheOps`] plus
+/// invalidation and statistics, shareable across threads.
+///
+/// Implemented by every cache of this crate, so application code can hold
+/// an `Arc<dyn DynCache<K, D>>` and be given a [`Cache`], a
+/// [`TieredCache`](crate::TieredCache) or a [`NoopCache`] depending on the
+/// deployment or test.
+pub trait DynCache<K, D>: CacheOps<K, D> + Send + Sync {
+    /// Drops `key` so that its next lookup misses.
+    fn invalidate(&self, key: &K);
+
+    /// Drops every entry.
+    fn invalidate_all(&self);
+
+    /// Lookup counters and size.
+    fn stats(&self) -> CacheStats;
+}
+
+impl<K, D, S, C> CacheOps<K, D> for Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    fn get(&self, key: &K) -> Option<D> {
+        Cache::get(self, key)
+    }
+
+    fn insert(&self, key: K, data: D) {
+        Cache::insert(self, key, data)
+    }
+
+    fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        Cache::retrieve_or_compute(self, key)
+    }
+
+    fn remove(&self, key: &K) -> Option<D> {
+        Cache::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        Cache::len(self)
+    }
+}
+
+impl<K, D, S, C> DynCache<K, D> for Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone + Send + Sync,
+    D: Clone + Default + Send + Sync,
+    S: BuildHasher + Send + Sync,
+    C: Clock,
+{
+    fn invalidate(&self, key: &K) {
+        Cache::remove(self, key);
+    }
+
+    fn invalidate_all(&self) {
+        Cache::invalidate_all(self)
+    }
+
+    fn stats(&self) -> CacheStats {
+        Cache::stats(self)
+    }
+}
+
+/// A cache that caches nothing: every lookup runs the miss handler.
+///
+/// Useful to disable caching without touching the calling code, or to check
+/// that it behaves the same with and without a cache.
+pub struct NoopCache<K, D> {
+    miss_handler: Box<MissHandler<K, D>>,
+    misses: AtomicU64,
+}
+
+impl<K, D> NoopCache<K, D> {
+    /// Creates a cache that computes every value with `miss_handler`.
+    pub fn new<F>(miss_handler: F) -> Self
+    where
+        F: Fn(&K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        NoopCache {
+            miss_handler: Box::new(miss_handler),
+            misses: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<K, D: Default> CacheOps<K, D> for NoopCache<K, D> {
+    fn get(&self, _: &K) -> Option<D> {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    fn insert(&self, _: K, _: D) {}
+
+    fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let mut data = D::default();
+        let mut adhoc_code = 0;
+        let success = (self.miss_handler)(key, &mut data, &mut adhoc_code);
+        (data, success, adhoc_code)
+    }
+
+    fn remove(&self, _: &K) -> Option<D> {
+        None
+    }
+
+    fn len(&self) -> usize {
+        0
+    }
+}
+
+impl<K, D: Default> DynCache<K, D> for NoopCache<K, D> {
+    fn invalidate(&self, _: &K) {}
+
+    fn invalidate_all(&self) {}
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            misses: self.misses.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        }
+    }
+}
+
+/// Value of an [`UnboundedCache`] entry with its outcome and expiration.
+type UnboundedEntry<D> = (D, bool, u8, Instant);
+
+/// A cache without a capacity bound, for small key spaces and tests.
+///
+/// Entries only go away when they expire or are removed. Unlike [`Cache`],
+/// concurrent misses on the same key are not coalesced: each caller runs
+/// the miss handler.
+pub struct UnboundedCache<K, D> {
+    entries: RwLock<HashMap<K, UnboundedEntry<D>>>,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    miss_handler: Box<MissHandler<K, D>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<K, D> UnboundedCache<K, D>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+{
+    /// Creates an empty cache. See [`Cache::new`] for the meaning of the
+    /// TTLs and the miss handler.
+    pub fn new<F>(positive_ttl: Duration, negative_ttl: Duration, miss_handler: F) -> Self
+    where
+        F: Fn(&K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        UnboundedCache {
+            entries: RwLock::new(HashMap::new()),
+            positive_ttl,
+            negative_ttl,
+            miss_handler: Box::new(miss_handler),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Drops every entry.
+    pub fn clear(&self) {
+        self.entries.write_or_recover().clear();
+    }
+
+    fn lookup(&self, key: &K) -> Option<(D, bool, u8)> {
+        let found = {
+            let entries = self.entries.read_or_recover();
+            entries
+                .get(key)
+                .filter(|(_, _, _, expiration)| *expiration > Instant::now())
+                .map(|(data, success, adhoc_code, _)| (data.clone(), *success, *adhoc_code))
+        };
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+}
+
+impl<K, D> CacheOps<K, D> for UnboundedCache<K, D>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+{
+    fn get(&self, key: &K) -> Option<D> {
+        self.lookup(key)
+            .and_then(|(data, success, _)| success.then_some(data))
+    }
+
+    fn insert(&self, key: K, data: D) {
+        let expiration = Instant::now() + self.positive_ttl;
+        self.entries
+            .write_or_recover()
+            .insert(key, (data, true, 0, expiration));
+    }
+
+    fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        if let Some(found) = self.lookup(key) {
+            return found;
+        }
+        let mut data = D::default();
+        let mut adhoc_code = 0;
+        let success = (self.miss_handler)(key, &mut data, &mut adhoc_code);
+        let ttl = if success {
+            self.positive_ttl
+        } else {
+            self.negative_ttl
+        };
+        self.entries.write_or_recover().insert(
+            key.clone(),
+            (data.clone(), success, adhoc_code, Instant::now() + ttl),
+        );
+        (data, success, adhoc_code)
+    }
+
+    fn remove(&self, key: &K) -> Option<D> {
+        let (data, success, _, _) = self.entries.write_or_recover().remove(key)?;
+        success.then_some(data)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.read_or_recover().len()
+    }
+}
+
+impl<K, D> DynCache<K, D> for UnboundedCache<K, D>
+where
+    K: Hash + Eq + Clone + Send + Sync,
+    D: Clone + Default + Send + Sync,
+{
+    fn invalidate(&self, key: &K) {
+        self.entries.write_or_recover().remove(key);
+    }
+
+    fn invalidate_all(&self) {
+        self.clear();
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            len: CacheOps::len(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn doubler(calls: Arc<AtomicUsize>) -> impl Fn(&u32, &mut u32, &mut u8) -> bool {
+        move |key, data, _| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            *data = key * 2;
+            *key != 0
+        }
+    }
+
+    /// Runs the same workload against any cache and returns the number of
+    /// miss handler calls.
+    fn exercise(make: impl FnOnce(Arc<AtomicUsize>) -> Box<dyn CacheOps<u32, u32>>) -> usize {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = make(calls.clone());
+        for _ in 0..3 {
+            assert_eq!(cache.retrieve_or_compute(&4), (8, true, 0));
+            assert!(!cache.retrieve_or_compute(&0).1);
+        }
+        cache.insert(5, 50);
+        if let Some(data) = cache.get(&5) {
+            assert_eq!(data, 50);
+            assert_eq!(cache.remove(&5), Some(50));
+        }
+        assert_eq!(cache.get(&5), None);
+        calls.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn handles_share_invalidation_and_stats() {
+        let ttl = Duration::from_secs(60);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let caches: Vec<Arc<dyn DynCache<u32, u32>>> = vec![
+            Arc::new(Cache::new(10, ttl, ttl, doubler(calls.clone()))),
+            Arc::new(UnboundedCache::new(ttl, ttl, doubler(calls.clone()))),
+        ];
+        for cache in caches {
+            cache.retrieve_or_compute(&4);
+            cache.retrieve_or_compute(&4);
+            cache.invalidate(&4);
+            cache.retrieve_or_compute(&4);
+            cache.insert(5, 50);
+            cache.invalidate_all();
+            assert_eq!(cache.get(&5), None);
+
+            let stats = cache.stats();
+            assert_eq!((stats.hits, stats.misses), (1, 3));
+        }
+        let noop: Arc<dyn DynCache<u32, u32>> = Arc::new(NoopCache::new(doubler(calls)));
+        noop.retrieve_or_compute(&4);
+        assert_eq!(noop.stats().misses, 1);
+    }
+
+    #[test]
+    fn implementations_are_interchangeable() {
+        let ttl = Duration::from_secs(60);
+        let lru = exercise(|calls| Box::new(Cache::new(10, ttl, ttl, doubler(calls))));
+        let unbounded = exercise(|calls| Box::new(UnboundedCache::new(ttl, ttl, doubler(calls))));
+        let noop = exercise(|calls| Box::new(NoopCache::new(doubler(calls))));
+
+        assert_eq!(lru, 2);
+        assert_eq!(unbounded, 2);
+        assert_eq!(noop, 6);
+    }
+}
@@ -0,0 +1,129 @@
+//! Keeping single huge values from flushing the working set.
+
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+use crate::cache::{Cache, CacheEntry, EntryStatus};
+use crate::clock::Clock;
+
+/// What happens to a value larger than
+/// [`max_entry_bytes`](crate::CacheBuilder::max_entry_bytes) or
+/// [`max_entry_weight`](crate::CacheBuilder::max_entry_weight); set with
+/// [`CacheBuilder::oversize_policy`](crate::CacheBuilder::oversize_policy).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// The value is not cached. A computed value is still returned to the
+    /// callers of the computation, and an entry it replaces is dropped.
+    #[default]
+    Reject,
+    /// The value is cached without counting towards the byte and weight
+    /// bounds, nor towards [`Cache::memory_usage`] and
+    /// [`Cache::total_weight`]. It still takes one entry of the capacity.
+    Uncounted,
+}
+
+/// Error of a write through an [`OccupiedEntry`](crate::OccupiedEntry)
+/// of a value the [`OversizePolicy::Reject`] policy turns away. The entry
+/// is left unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Oversize;
+
+impl fmt::Display for Oversize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the value is over the per-entry limits")
+    }
+}
+
+impl Error for Oversize {}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Returns `true` if the charged `entry` is a value over the per-entry
+    /// limits.
+    pub(crate) fn is_oversize(&self, entry: &CacheEntry<D>) -> bool {
+        entry.status == EntryStatus::Ready
+            && (self.max_entry_bytes.is_some_and(|max| entry.bytes > max)
+                || self.max_entry_weight.is_some_and(|max| entry.weight > max))
+    }
+
+    /// Returns `true` if storing `entry` under `key` would be turned away
+    /// by the [`OversizePolicy::Reject`] policy.
+    pub(crate) fn rejects(&self, key: &K, entry: &mut CacheEntry<D>) -> bool {
+        self.measure(key, entry);
+        self.oversize_policy == OversizePolicy::Reject && self.is_oversize(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Bound, Cache, Entry, OversizePolicy};
+    use std::time::Duration;
+
+    fn cache(policy: OversizePolicy) -> Cache<u32, Vec<u8>> {
+        Cache::builder(100)
+            .positive_ttl(Duration::from_secs(60))
+            .weigher(|_: &u32, data: &Vec<u8>| data.len() as u64)
+            .bound(Bound::Weight(100))
+            .max_entry_weight(50)
+            .oversize_policy(policy)
+            .miss_handler(|key: &u32, data: &mut Vec<u8>, _: &mut u8| {
+                *data = vec![0; *key as usize];
+                true
+            })
+            .build()
+    }
+
+    #[test]
+    fn oversize_values_are_passed_through() {
+        let cache = cache(OversizePolicy::Reject);
+        cache.retrieve_or_compute(&40);
+        cache.retrieve_or_compute(&30);
+        assert_eq!(cache.retrieve_or_compute(&80).0.len(), 80);
+        assert_eq!(cache.get(&80), None);
+        assert_eq!(cache.get(&40).map(|data| data.len()), Some(40));
+        assert_eq!(cache.total_weight(), 70);
+
+        cache.insert(30, vec![0; 60]);
+        assert_eq!(cache.get(&30), None);
+        assert_eq!(cache.total_weight(), 40);
+    }
+
+    #[test]
+    fn uncounted_values_do_not_evict_others() {
+        let cache = cache(OversizePolicy::Uncounted);
+        cache.retrieve_or_compute(&40);
+        cache.retrieve_or_compute(&30);
+        cache.retrieve_or_compute(&80);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.total_weight(), 70);
+        assert_eq!(cache.get(&80).map(|data| data.len()), Some(80));
+
+        cache.remove(&80);
+        assert_eq!(cache.total_weight(), 70);
+    }
+
+    #[test]
+    fn occupied_entries_keep_their_value_on_oversize_writes() {
+        let cache = cache(OversizePolicy::Reject);
+        cache.insert(1, vec![0; 10]);
+        let Entry::Occupied(mut entry) = cache.entry(1) else {
+            panic!("the entry is live");
+        };
+        assert_eq!(entry.insert(vec![0; 60]).len(), 60);
+        assert_eq!(entry.get().len(), 10);
+        drop(entry);
+
+        let modified = cache.entry(1).and_modify(|data| data.resize(60, 0));
+        assert!(matches!(modified, Entry::Occupied(_)));
+        drop(modified);
+        let version = cache.get_versioned(&1).unwrap().1;
+        assert_eq!(cache.insert_if_version(1, vec![0; 60], version), None);
+        assert_eq!(cache.get(&1).map(|data| data.len()), Some(10));
+    }
+}
@@ -0,0 +1,540 @@
+//! A cache kept in a memory-mapped file, behind the `mmap` feature, so that
+//! its entries survive restarts without being deserialized.
+//!
+//! Keys and values are byte strings, as in resolver-style workloads where
+//! they already are wire-format names and answers. Records are appended to
+//! the file after a 16-byte header (`RCMM`, the format version and the end
+//! of the last complete record):
+//!
+//! ```text
+//! crc32 u32 | key_len u32 | value_len u32 | status u8 | adhoc_code u8 | 0u16
+//! | expires_at u64 (ms since the Unix epoch) | key | value
+//! ```
+//!
+//! all little-endian, the CRC-32 covering everything after itself. Opening
+//! the file walks the record headers to rebuild the index; values are only
+//! read, and checked, on lookup. A record is counted in the header only
+//! once it is fully written, so a crash mid-write loses that record alone.
+//! When the file is full it is rewritten without dead records, through a
+//! temporary file renamed over it, and grown if still needed.
+//!
+//! The file is held under an exclusive `flock` while it is open, so only
+//! one cache at a time maps it.
+
+use std::collections::HashMap;
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use memmap2::MmapMut;
+
+use crate::checksum::{crc32, Corrupted};
+use crate::lock::RwLockExt;
+
+const MAGIC: &[u8; 4] = b"RCMM";
+const VERSION: u32 = 1;
+const FILE_HEADER: u64 = 16;
+const RECORD_HEADER: u64 = 24;
+/// Size of a new file.
+const INITIAL_LEN: u64 = 1 << 20;
+
+const FAILED: u8 = 0;
+const READY: u8 = 1;
+const REMOVED: u8 = 2;
+
+/// Function used by a [`PersistentCache`] to compute missing values.
+pub type BytesMissHandler = dyn Fn(&[u8], &mut Vec<u8>, &mut u8) -> bool + Send + Sync;
+
+/// Where a live record is, and what the index needs to know without
+/// reading it.
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    offset: u64,
+    len: u64,
+    status: u8,
+    adhoc_code: u8,
+    expires_at: u64,
+}
+
+/// Milliseconds since the Unix epoch.
+fn wall_clock_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Takes the `flock` of a cache file, held until the file is closed.
+fn lock(file: &File) -> io::Result<()> {
+    file.try_lock().map_err(|error| match error {
+        TryLockError::WouldBlock => io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "the cache file is open in another cache",
+        ),
+        TryLockError::Error(error) => error,
+    })
+}
+
+/// The mapped file and the index of its live records.
+struct MappedLog {
+    path: PathBuf,
+    file: File,
+    map: MmapMut,
+    index: HashMap<Vec<u8>, Slot>,
+    end: u64,
+}
+
+impl MappedLog {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        lock(&file)?;
+        let created = match file.metadata()?.len() {
+            0 => {
+                file.set_len(INITIAL_LEN)?;
+                true
+            }
+            len if len < FILE_HEADER => return Err(invalid("not a cache file of this version")),
+            _ => false,
+        };
+        // SAFETY: the file stays locked while it is open, and other caches
+        // refuse to map a locked file.
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        if created {
+            map[..4].copy_from_slice(MAGIC);
+            map[4..8].copy_from_slice(&VERSION.to_le_bytes());
+            map[8..16].copy_from_slice(&FILE_HEADER.to_le_bytes());
+        }
+        if &map[..4] != MAGIC || read_u32(&map, 4) != VERSION {
+            return Err(invalid("not a cache file of this version"));
+        }
+        let mut log = MappedLog {
+            path: path.to_path_buf(),
+            file,
+            map,
+            index: HashMap::new(),
+            end: FILE_HEADER,
+        };
+        log.scan(read_u64(&log.map, 8).min(log.map.len() as u64));
+        Ok(log)
+    }
+
+    /// Rebuilds the index from the record headers up to `end`.
+    fn scan(&mut self, end: u64) {
+        let mut offset = FILE_HEADER;
+        while offset + RECORD_HEADER <= end {
+            let header = &self.map[offset as usize..(offset + RECORD_HEADER) as usize];
+            let key_len = u64::from(read_u32(header, 4));
+            let len = RECORD_HEADER + key_len + u64::from(read_u32(header, 8));
+            if offset + len > end {
+                break;
+            }
+            let slot = Slot {
+                offset,
+                len,
+                status: header[12],
+                adhoc_code: header[13],
+                expires_at: read_u64(header, 16),
+            };
+            let key_start = (offset + RECORD_HEADER) as usize;
+            let key = self.map[key_start..key_start + key_len as usize].to_vec();
+            if slot.status == REMOVED {
+                self.index.remove(&key);
+            } else {
+                self.index.insert(key, slot);
+            }
+            offset += len;
+        }
+        self.end = offset;
+    }
+
+    /// The value of `slot`, or `Corrupted` if the record no longer matches
+    /// its checksum.
+    fn read(&self, slot: Slot) -> Result<&[u8], Corrupted> {
+        let record = self
+            .map
+            .get(slot.offset as usize..(slot.offset + slot.len) as usize)
+            .ok_or(Corrupted)?;
+        if crc32(&record[4..]) != read_u32(record, 0) {
+            return Err(Corrupted);
+        }
+        let key_len = read_u32(record, 4) as usize;
+        Ok(&record[RECORD_HEADER as usize + key_len..])
+    }
+
+    fn append(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        status: u8,
+        adhoc_code: u8,
+        expires_at: u64,
+    ) -> io::Result<()> {
+        let len = RECORD_HEADER + key.len() as u64 + value.len() as u64;
+        if self.end + len > self.map.len() as u64 {
+            self.compact(len)?;
+        }
+        let offset = self.end;
+        let record = &mut self.map[offset as usize..(offset + len) as usize];
+        record[4..8].copy_from_slice(&(key.len() as u32).to_le_bytes());
+        record[8..12].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        record[12] = status;
+        record[13] = adhoc_code;
+        record[14..16].fill(0);
+        record[16..24].copy_from_slice(&expires_at.to_le_bytes());
+        let body = &mut record[RECORD_HEADER as usize..];
+        body[..key.len()].copy_from_slice(key);
+        body[key.len()..].copy_from_slice(value);
+        let checksum = crc32(&record[4..]);
+        record[..4].copy_from_slice(&checksum.to_le_bytes());
+        self.end += len;
+        self.map[8..16].copy_from_slice(&self.end.to_le_bytes());
+        if status == REMOVED {
+            self.index.remove(key);
+        } else {
+            let slot = Slot {
+                offset,
+                len,
+                status,
+                adhoc_code,
+                expires_at,
+            };
+            self.index.insert(key.to_vec(), slot);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the unexpired records into a fresh file, at least large
+    /// enough for another `needed` bytes, and maps it in place of this one.
+    fn compact(&mut self, needed: u64) -> io::Result<()> {
+        let now = wall_clock_ms();
+        self.index.retain(|_, slot| slot.expires_at > now);
+        let live: u64 = self.index.values().map(|slot| slot.len).sum();
+        let mut len = self.map.len() as u64;
+        while FILE_HEADER + live + needed > len / 2 {
+            len *= 2;
+        }
+        let tmp_path = self.path.with_extension("compact");
+        let tmp = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        lock(&tmp)?;
+        tmp.set_len(len)?;
+        // SAFETY: the temporary file is ours until it is renamed.
+        let mut map = unsafe { MmapMut::map_mut(&tmp)? };
+        map[..4].copy_from_slice(MAGIC);
+        map[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        let mut offset = FILE_HEADER;
+        for slot in self.index.values_mut() {
+            let (from, to) = (slot.offset as usize, offset as usize);
+            map[to..to + slot.len as usize]
+                .copy_from_slice(&self.map[from..from + slot.len as usize]);
+            slot.offset = offset;
+            offset += slot.len;
+        }
+        map[8..16].copy_from_slice(&offset.to_le_bytes());
+        map.flush()?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = tmp;
+        self.map = map;
+        self.end = offset;
+        Ok(())
+    }
+}
+
+/// A cache of byte strings kept in a memory-mapped file, so that a
+/// restarted process finds its entries in place instead of reloading or
+/// deserializing them.
+///
+/// It has the lookup API of [`Cache`](crate::Cache) for byte keys and
+/// values, without eviction: the file grows as needed and expired entries
+/// are dropped whenever it fills up. Concurrent misses of a key each run
+/// the miss handler. Entries expire by the wall clock, so they also expire
+/// while the process is down.
+///
+/// Writes reach the file through the page cache and survive the process
+/// crashing; [`flush`](Self::flush) also makes them survive the machine
+/// crashing. A record found corrupted on read is treated as a miss and
+/// counted in [`corruptions`](Self::corruptions).
+pub struct PersistentCache {
+    log: RwLock<MappedLog>,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    miss_handler: Box<BytesMissHandler>,
+    corruptions: AtomicU64,
+}
+
+impl PersistentCache {
+    /// Opens the cache file at `path`, creating it if needed, and indexes
+    /// the entries it holds.
+    ///
+    /// Fails with [`InvalidData`](io::ErrorKind::InvalidData) if the file
+    /// is not a cache file of this format, and with
+    /// [`WouldBlock`](io::ErrorKind::WouldBlock) if another cache, in this
+    /// process or another, has it open.
+    pub fn open<F>(
+        path: impl AsRef<Path>,
+        positive_ttl: Duration,
+        negative_ttl: Duration,
+        miss_handler: F,
+    ) -> io::Result<Self>
+    where
+        F: Fn(&[u8], &mut Vec<u8>, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        Ok(PersistentCache {
+            log: RwLock::new(MappedLog::open(path.as_ref())?),
+            positive_ttl,
+            negative_ttl,
+            miss_handler: Box::new(miss_handler),
+            corruptions: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the value of a successfully computed, unexpired entry.
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.lookup(key) {
+            Some((data, true, _)) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Returns `(data, success, adhoc_code)` for `key`, computing it with
+    /// the miss handler and caching the outcome if it is missing or
+    /// expired.
+    pub fn retrieve_or_compute(&self, key: &[u8]) -> (Vec<u8>, bool, u8) {
+        if let Some(found) = self.lookup(key) {
+            return found;
+        }
+        let mut data = Vec::new();
+        let mut adhoc_code = 0;
+        let success = (self.miss_handler)(key, &mut data, &mut adhoc_code);
+        let (status, ttl) = if success {
+            (READY, self.positive_ttl)
+        } else {
+            (FAILED, self.negative_ttl)
+        };
+        self.write(key, &data, status, adhoc_code, ttl);
+        (data, success, adhoc_code)
+    }
+
+    /// Caches `data` under `key` for the positive TTL. The write is dropped
+    /// if the file cannot grow.
+    pub fn insert(&self, key: &[u8], data: &[u8]) {
+        self.write(key, data, READY, 0, self.positive_ttl);
+    }
+
+    /// Drops `key`, returning its value if it was cached.
+    pub fn remove(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if !self.log.read_or_recover().index.contains_key(key) {
+            return None;
+        }
+        let removed = self.get(key);
+        self.write(key, &[], REMOVED, 0, Duration::ZERO);
+        removed
+    }
+
+    /// Number of entries indexed, including expired ones that have not
+    /// been dropped yet.
+    pub fn len(&self) -> usize {
+        self.log.read_or_recover().index.len()
+    }
+
+    /// Returns `true` if no entries are indexed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes the mapped pages back to the file.
+    pub fn flush(&self) -> io::Result<()> {
+        self.log.read_or_recover().map.flush()
+    }
+
+    /// Number of records found corrupted on read.
+    pub fn corruptions(&self) -> u64 {
+        self.corruptions.load(Ordering::Relaxed)
+    }
+
+    fn lookup(&self, key: &[u8]) -> Option<(Vec<u8>, bool, u8)> {
+        let log = self.log.read_or_recover();
+        let slot = *log.index.get(key)?;
+        if slot.expires_at <= wall_clock_ms() {
+            return None;
+        }
+        if slot.status == FAILED {
+            return Some((Vec::new(), false, slot.adhoc_code));
+        }
+        match log.read(slot) {
+            Ok(data) => Some((data.to_vec(), true, slot.adhoc_code)),
+            Err(Corrupted) => {
+                self.corruptions.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn write(&self, key: &[u8], data: &[u8], status: u8, adhoc_code: u8, ttl: Duration) {
+        let expires_at = wall_clock_ms().saturating_add(ttl.as_millis() as u64);
+        let mut log = self.log.write_or_recover();
+        if log
+            .append(key, data, status, adhoc_code, expires_at)
+            .is_err()
+        {
+            log.index.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    fn temp_path(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "rust-cache-{}-{}-{}.mmap",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    fn open(path: &Path, loads: Arc<AtomicUsize>) -> PersistentCache {
+        PersistentCache::open(
+            path,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            move |key: &[u8], data: &mut Vec<u8>, _: &mut u8| {
+                loads.fetch_add(1, Ordering::SeqCst);
+                if key.is_empty() {
+                    return false;
+                }
+                data.extend_from_slice(key);
+                data.extend_from_slice(b"-answer");
+                true
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn entries_survive_reopening() {
+        let path = temp_path("reopen");
+        let loads = Arc::new(AtomicUsize::new(0));
+        {
+            let cache = open(&path, loads.clone());
+            assert_eq!(
+                cache.retrieve_or_compute(b"a"),
+                (b"a-answer".to_vec(), true, 0)
+            );
+            assert_eq!(cache.retrieve_or_compute(b""), (Vec::new(), false, 0));
+            cache.insert(b"b", b"bee");
+            cache.insert(b"c", b"sea");
+            assert_eq!(cache.remove(b"c"), Some(b"sea".to_vec()));
+            cache.flush().unwrap();
+        }
+        let cache = open(&path, loads.clone());
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(b"a"), Some(b"a-answer".to_vec()));
+        assert_eq!(cache.get(b"b"), Some(b"bee".to_vec()));
+        assert_eq!(cache.get(b"c"), None);
+        assert_eq!(cache.retrieve_or_compute(b""), (Vec::new(), false, 0));
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn full_files_are_compacted_and_grown() {
+        let path = temp_path("grow");
+        let cache = open(&path, Arc::new(AtomicUsize::new(0)));
+        let value = vec![7; 64 * 1024];
+        for round in 0..3 {
+            for key in 0..16u8 {
+                cache.insert(&[key], &value[round..]);
+            }
+        }
+        assert_eq!(cache.len(), 16);
+        assert_eq!(cache.get(&[3]), Some(value[2..].to_vec()));
+        assert!(fs::metadata(&path).unwrap().len() > INITIAL_LEN);
+        drop(cache);
+
+        let cache = open(&path, Arc::new(AtomicUsize::new(0)));
+        assert_eq!(cache.get(&[15]), Some(value[2..].to_vec()));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn open_files_are_locked() {
+        let path = temp_path("locked");
+        let cache = open(&path, Arc::new(AtomicUsize::new(0)));
+        let error = PersistentCache::open(
+            &path,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &[u8], _: &mut Vec<u8>, _: &mut u8| true,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+
+        let end = cache.log.read().unwrap().end;
+        assert_eq!(cache.remove(b"missing"), None);
+        assert_eq!(cache.log.read().unwrap().end, end);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn foreign_files_are_left_alone() {
+        let path = temp_path("foreign");
+        for contents in [&b"RCMM"[..], &[0; 64][..], b"not a cache file"] {
+            fs::write(&path, contents).unwrap();
+            let error = PersistentCache::open(
+                &path,
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+                |_: &[u8], _: &mut Vec<u8>, _: &mut u8| true,
+            )
+            .err()
+            .unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(fs::read(&path).unwrap(), contents);
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_records_are_misses() {
+        let path = temp_path("corrupt");
+        let cache = open(&path, Arc::new(AtomicUsize::new(0)));
+        cache.insert(b"k", b"value");
+        let offset = (FILE_HEADER + RECORD_HEADER) as usize + 1;
+        cache.log.write().unwrap().map[offset] ^= 0xff;
+
+        assert_eq!(cache.get(b"k"), None);
+        assert_eq!(cache.corruptions(), 1);
+        assert_eq!(cache.retrieve_or_compute(b"k").0, b"k-answer");
+        fs::remove_file(path).unwrap();
+    }
+}
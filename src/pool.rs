@@ -0,0 +1,316 @@
+//! Running the miss handler on worker threads.
+//!
+//! With a worker pool, callers never run the miss handler themselves: they
+//! hand the key to one of a fixed number of workers and block on the
+//! result, or, through
+//! [`retrieve_or_compute_in_background`](Cache::retrieve_or_compute_in_background),
+//! return right away and pick the value up on a later lookup.
+
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::cache::{Cache, Lookup, MissHandler};
+use crate::clock::Clock;
+use crate::load::{self, current_args, with_args, LoadArgs};
+use crate::lock::{MutexExt, RwLockExt};
+use crate::time::Instant;
+use crate::timeout::Timeout;
+
+/// Error returned when the key is being computed in the background and
+/// the caller chose not to wait for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Computing;
+
+impl fmt::Display for Computing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the value is being computed")
+    }
+}
+
+impl Error for Computing {}
+
+/// What the miss handler computed for a key.
+pub(crate) struct Loaded<D> {
+    pub(crate) data: D,
+    pub(crate) success: bool,
+    pub(crate) adhoc_code: u8,
+    /// TTL of the fallback loader that produced `data`, if one did.
+    pub(crate) ttl: Option<Duration>,
+}
+
+impl<D> From<(D, bool, u8)> for Loaded<D> {
+    fn from((data, success, adhoc_code): (D, bool, u8)) -> Self {
+        Loaded {
+            data,
+            success,
+            adhoc_code,
+            ttl: None,
+        }
+    }
+}
+
+/// What the miss handler computed, or the payload of its panic.
+pub(crate) type Outcome<D> = thread::Result<Loaded<D>>;
+
+/// Runs `miss_handler` on `key` with `args`, catching a panic.
+pub(crate) fn load<K, D: Default>(
+    miss_handler: &MissHandler<K, D>,
+    key: &K,
+    args: LoadArgs,
+) -> Outcome<D> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        with_args(args, || {
+            let mut data = D::default();
+            let mut adhoc_code = 0;
+            let success = miss_handler(key, &mut data, &mut adhoc_code);
+            Loaded {
+                data,
+                success,
+                adhoc_code,
+                ttl: load::take_ttl(),
+            }
+        })
+    }))
+}
+
+/// A key to compute, with the write sequence number of its placeholder.
+/// Without a reply channel the outcome is left for the cache to collect.
+struct Job<K, D> {
+    key: K,
+    started: u64,
+    args: LoadArgs,
+    reply: Option<SyncSender<Outcome<D>>>,
+}
+
+/// Computation finished in the background, waiting to be stored.
+type Finished<K, D> = (K, u64, Outcome<D>, Duration);
+
+/// Worker threads sharing one job queue. They stop once the pool is
+/// dropped with its cache.
+pub(crate) struct WorkerPool<K, D> {
+    jobs: Sender<Job<K, D>>,
+    finished: Arc<Mutex<Vec<Finished<K, D>>>>,
+    any_finished: Arc<AtomicBool>,
+}
+
+impl<K, D> WorkerPool<K, D>
+where
+    K: Send + 'static,
+    D: Default + Send + 'static,
+{
+    /// Starts `threads` workers running `miss_handler`.
+    pub(crate) fn spawn(miss_handler: Arc<MissHandler<K, D>>, threads: usize) -> Self {
+        let (jobs, queue) = mpsc::channel();
+        let queue: Arc<Mutex<Receiver<Job<K, D>>>> = Arc::new(Mutex::new(queue));
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let any_finished = Arc::new(AtomicBool::new(false));
+        for _ in 0..threads.max(1) {
+            let (miss_handler, queue) = (miss_handler.clone(), queue.clone());
+            let (finished, any_finished) = (finished.clone(), any_finished.clone());
+            thread::spawn(move || loop {
+                let Ok(job) = queue.lock_or_recover().recv() else {
+                    break;
+                };
+                let load_start = Instant::now();
+                let outcome = load(&*miss_handler, &job.key, job.args);
+                match job.reply {
+                    Some(reply) => {
+                        let _ = reply.send(outcome);
+                    }
+                    None => {
+                        let load_time = load_start.elapsed();
+                        let done = (job.key, job.started, outcome, load_time);
+                        finished.lock_or_recover().push(done);
+                        any_finished.store(true, Ordering::Release);
+                    }
+                }
+            });
+        }
+        WorkerPool {
+            jobs,
+            finished,
+            any_finished,
+        }
+    }
+}
+
+impl<K, D> WorkerPool<K, D> {
+    /// Computes `key` on a worker and waits for the outcome.
+    fn run(&self, key: K, started: u64, args: LoadArgs) -> Outcome<D> {
+        let (reply, outcome) = mpsc::sync_channel(1);
+        let job = Job {
+            key,
+            started,
+            args,
+            reply: Some(reply),
+        };
+        if self.jobs.send(job).is_err() {
+            return Err(Box::new("the worker pool has stopped"));
+        }
+        outcome
+            .recv()
+            .unwrap_or_else(|_| Err(Box::new("the worker pool has stopped")))
+    }
+
+    /// Queues `key` for a worker without waiting.
+    fn dispatch(&self, key: K, started: u64, args: LoadArgs) {
+        let job = Job {
+            key,
+            started,
+            args,
+            reply: None,
+        };
+        let _ = self.jobs.send(job);
+    }
+
+    fn take_finished(&self) -> Vec<Finished<K, D>> {
+        if !self.any_finished.swap(false, Ordering::Acquire) {
+            return Vec::new();
+        }
+        mem::take(&mut *self.finished.lock_or_recover())
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Like [`retrieve_or_compute`](Self::retrieve_or_compute), but never
+    /// waits for the miss handler: a missing key is handed to the worker
+    /// pool and [`Computing`] is returned, as it is while the key is being
+    /// computed by anyone else.
+    ///
+    /// The outcome is stored by a later lookup of any key once the worker
+    /// is done. Without a worker pool, the miss handler runs on the
+    /// caller's thread as usual.
+    pub fn retrieve_or_compute_in_background(&self, key: &K) -> Result<(D, bool, u8), Computing> {
+        self.store_finished();
+        match self.lookup_or_claim(key, Some(Instant::now())) {
+            Err(Timeout) => Err(Computing),
+            Ok(Lookup::Found(found)) => Ok(found),
+            Ok(Lookup::Panicked | Lookup::Cancelled | Lookup::Unavailable) => {
+                Ok((D::default(), false, 0))
+            }
+            Ok(Lookup::Claimed(started)) => match &self.worker_pool {
+                Some(pool) => {
+                    pool.dispatch(key.clone(), started, self.load_args(key, started));
+                    self.refreshed_value(key, started).ok_or(Computing)
+                }
+                None => Ok(self.compute(key, started)),
+            },
+        }
+    }
+
+    /// Runs the miss handler on `key`, on a worker if there is a pool.
+    pub(crate) fn load(&self, key: &K, started: u64) -> Outcome<D> {
+        let args = self.load_args(key, started);
+        match &self.worker_pool {
+            Some(pool) => pool.run(key.clone(), started, args),
+            None => load(&*self.miss_handler, key, args),
+        }
+    }
+
+    /// Arguments for computing `key`: the caller's, with the token of the
+    /// placeholder with write sequence number `started`.
+    fn load_args(&self, key: &K, started: u64) -> LoadArgs {
+        LoadArgs {
+            token: self.cancellation(key, started),
+            previous: self.previous(key, started),
+            ..current_args()
+        }
+    }
+
+    /// The expired value the placeholder with write sequence number
+    /// `started` carries for a revalidating miss handler.
+    fn previous(&self, key: &K, started: u64) -> Option<Arc<dyn Any + Send + Sync>> {
+        let share = self.share_previous?;
+        let cache = self.lru_cache.read_or_recover();
+        cache
+            .peek(key)
+            .filter(|entry| entry.seq == started && entry.carries_previous)
+            .map(|entry| share(&entry.data))
+    }
+
+    /// Stores the outcome of computations finished in the background.
+    pub(crate) fn store_finished(&self) {
+        let Some(pool) = &self.worker_pool else {
+            return;
+        };
+        for (key, started, outcome, load_time) in pool.take_finished() {
+            self.load_latency.record(load_time);
+            match outcome {
+                Ok(loaded) => {
+                    self.complete(&key, started, loaded, load_time);
+                }
+                Err(_) => self.complete_panicked(&key, started),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pooled_cache(threads: Arc<Mutex<Vec<thread::ThreadId>>>) -> Cache<u32, u32> {
+        Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .miss_handler(move |key: &u32, data: &mut u32, _: &mut u8| {
+                threads.lock().unwrap().push(thread::current().id());
+                thread::sleep(Duration::from_millis(10));
+                *data = *key * 2;
+                true
+            })
+            .worker_pool(2)
+            .build()
+    }
+
+    #[test]
+    fn the_miss_handler_runs_on_a_worker() {
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let cache = pooled_cache(threads.clone());
+        assert_eq!(cache.retrieve_or_compute(&2), (4, true, 0));
+        assert_eq!(cache.get(&2), Some(4));
+        assert_ne!(threads.lock().unwrap()[0], thread::current().id());
+    }
+
+    #[test]
+    fn background_loads_are_picked_up_later() {
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let cache = pooled_cache(threads.clone());
+        assert_eq!(cache.retrieve_or_compute_in_background(&3), Err(Computing));
+        assert_eq!(cache.retrieve_or_compute_in_background(&3), Err(Computing));
+
+        let mut polls = 0;
+        let found = loop {
+            polls += 1;
+            match cache.retrieve_or_compute_in_background(&3) {
+                Ok(found) => break found,
+                Err(Computing) => thread::sleep(Duration::from_millis(1)),
+            }
+        };
+        assert_eq!(found, (6, true, 0));
+        assert_eq!(threads.lock().unwrap().len(), 1);
+        assert!(polls > 1);
+    }
+
+    #[test]
+    fn waiters_collect_background_loads() {
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let cache = pooled_cache(threads);
+        assert_eq!(cache.retrieve_or_compute_in_background(&5), Err(Computing));
+        assert_eq!(cache.retrieve_or_compute(&5), (10, true, 0));
+    }
+}
@@ -0,0 +1,314 @@
+//! Bounding how often miss handlers run.
+//!
+//! A load limit caps how many miss handlers run at once, but a hot key
+//! that keeps failing or expiring can still hit the backend over and over.
+//! A rate limit caps loads per second, overall and per key, with token
+//! buckets that allow a burst of one second's worth. Misses over the limit
+//! are served the stale value kept by
+//! [`stale_if_error`](crate::CacheBuilder::stale_if_error) if there is
+//! one, and fail otherwise, or give up with [`RateLimited`].
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::panic;
+use std::sync::Mutex;
+
+use crate::cache::{Cache, Lookup};
+use crate::clock::Clock;
+use crate::lock::{MutexExt, RwLockExt};
+use crate::time::Instant;
+
+/// Number of per-key buckets above which full ones are dropped.
+const PRUNE_LEN: usize = 1024;
+
+/// Error returned when the miss handler could not run because the load
+/// rate limit was reached and there was no stale value to serve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimited;
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("too many loads in the last second")
+    }
+}
+
+impl Error for RateLimited {}
+
+/// Loads allowed right now, refilled at the rate per second up to one
+/// second's worth.
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn full(rate: u32, now: Instant) -> Self {
+        Bucket {
+            tokens: f64::from(rate),
+            refilled: now,
+        }
+    }
+
+    /// Refills the tokens earned since the last refill, returning `true`
+    /// if the bucket is full.
+    fn refill(&mut self, rate: u32, now: Instant) -> bool {
+        let earned = now.saturating_duration_since(self.refilled).as_secs_f64() * f64::from(rate);
+        self.tokens = (self.tokens + earned).min(f64::from(rate));
+        self.refilled = now;
+        self.tokens >= f64::from(rate)
+    }
+}
+
+/// Per-key buckets, pruned of full ones as they grow.
+struct KeyBuckets<K> {
+    buckets: HashMap<K, Bucket>,
+    prune_at: usize,
+}
+
+/// Counts loads against the configured rates.
+pub(crate) struct RateLimiter<K> {
+    global: Option<(u32, Mutex<Bucket>)>,
+    per_key: Option<(u32, Mutex<KeyBuckets<K>>)>,
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    /// Starts with full buckets at `now`, by the clock of the cache.
+    pub(crate) fn new(global: Option<u32>, per_key: Option<u32>, now: Instant) -> Self {
+        RateLimiter {
+            global: global.map(|rate| (rate, Mutex::new(Bucket::full(rate, now)))),
+            per_key: per_key.map(|rate| {
+                let buckets = KeyBuckets {
+                    buckets: HashMap::new(),
+                    prune_at: PRUNE_LEN,
+                };
+                (rate, Mutex::new(buckets))
+            }),
+        }
+    }
+
+    /// Takes a token for loading `key` from its bucket and the global one,
+    /// or neither if either is empty, refilling them up to `now`.
+    fn try_acquire(&self, key: &K, now: Instant) -> bool {
+        let mut per_key = self.per_key.as_ref().map(|(rate, buckets)| {
+            let mut buckets = buckets.lock_or_recover();
+            if buckets.buckets.len() >= buckets.prune_at {
+                buckets
+                    .buckets
+                    .retain(|_, bucket| !bucket.refill(*rate, now));
+                buckets.prune_at = PRUNE_LEN.max(buckets.buckets.len() * 2);
+            }
+            (*rate, buckets)
+        });
+        let per_key_bucket = per_key.as_mut().map(|(rate, buckets)| {
+            let bucket = buckets
+                .buckets
+                .entry(key.clone())
+                .or_insert_with(|| Bucket::full(*rate, now));
+            bucket.refill(*rate, now);
+            bucket
+        });
+        let mut global = self.global.as_ref().map(|(rate, bucket)| {
+            let mut bucket = bucket.lock_or_recover();
+            bucket.refill(*rate, now);
+            bucket
+        });
+        let admitted = per_key_bucket
+            .as_ref()
+            .is_none_or(|bucket| bucket.tokens >= 1.0)
+            && global.as_ref().is_none_or(|bucket| bucket.tokens >= 1.0);
+        if admitted {
+            if let Some(bucket) = per_key_bucket {
+                bucket.tokens -= 1.0;
+            }
+            if let Some(bucket) = &mut global {
+                bucket.tokens -= 1.0;
+            }
+        }
+        admitted
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Like [`retrieve_or_compute`](Self::retrieve_or_compute), but returns
+    /// [`RateLimited`] instead of a failure if the miss handler would have
+    /// to run, the load rate limit is reached and there is no stale value
+    /// to serve.
+    pub fn retrieve_or_compute_unless_rate_limited(
+        &self,
+        key: &K,
+    ) -> Result<(D, bool, u8), RateLimited> {
+        match self.lookup_or_take_over(key) {
+            Lookup::Found(found) => Ok(found),
+            Lookup::Panicked | Lookup::Cancelled | Lookup::Unavailable => {
+                Ok((D::default(), false, 0))
+            }
+            Lookup::Claimed(started) => {
+                if !self.admits_load(key) {
+                    return self.shed_load(key, started).ok_or(RateLimited);
+                }
+                Ok(self
+                    .try_compute_admitted(key, started)
+                    .unwrap_or_else(|payload| panic::resume_unwind(payload)))
+            }
+        }
+    }
+
+    /// Takes a token for loading `key`, `true` without a rate limit.
+    pub(crate) fn admits_load(&self, key: &K) -> bool {
+        self.rate_limiter
+            .as_ref()
+            .is_none_or(|limiter| limiter.try_acquire(key, self.now()))
+    }
+
+    /// Gives up computing `key` over the rate limit: the stale value our
+    /// placeholder carries is put back if still in grace, and the live
+    /// value returned if the claim was a refresh; otherwise the placeholder
+    /// is dropped and `None` returned.
+    pub(crate) fn shed_load(&self, key: &K, started: u64) -> Option<(D, bool, u8)> {
+        let now = self.now();
+        let stale = {
+            let mut cache = self.lru_cache.write_or_recover();
+            self.serve_stale(&mut cache, key, started, now)
+        };
+        stale.or_else(|| self.abandon(key, started))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn each_key_loads_at_most_at_its_rate() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let cache = {
+            let loads = loads.clone();
+            Cache::builder(10)
+                .negative_caching(false)
+                .miss_handler(move |_: &u32, _: &mut u32, _: &mut u8| {
+                    loads.fetch_add(1, Ordering::SeqCst);
+                    false
+                })
+                .max_loads_per_key_per_second(2)
+                .max_loads_per_second(3)
+                .build()
+        };
+        for _ in 0..5 {
+            cache.retrieve_or_compute(&1);
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            cache.retrieve_or_compute_unless_rate_limited(&1),
+            Err(RateLimited)
+        );
+        assert_eq!(
+            cache.retrieve_or_compute_unless_rate_limited(&2),
+            Ok((0, false, 0))
+        );
+        assert_eq!(
+            cache.retrieve_or_compute_unless_rate_limited(&3),
+            Err(RateLimited)
+        );
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+        assert!(cache.get_entry(&3).is_none());
+    }
+
+    #[test]
+    fn limited_refreshes_serve_stale_values() {
+        let clock = Arc::new(ManualClock::new());
+        let version = Arc::new(AtomicU32::new(0));
+        let cache = {
+            let version = version.clone();
+            Cache::builder(10)
+                .positive_ttl(Duration::from_millis(100))
+                .stale_if_error(Duration::from_secs(60))
+                .clock(clock.clone())
+                .miss_handler(move |_: &u32, data: &mut u32, _: &mut u8| {
+                    *data = version.fetch_add(1, Ordering::SeqCst) + 1;
+                    true
+                })
+                .max_loads_per_key_per_second(1)
+                .build()
+        };
+        assert_eq!(cache.retrieve_or_compute(&1), (1, true, 0));
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(cache.retrieve_or_compute(&1), (1, true, 0));
+        assert_eq!(
+            cache.retrieve_or_compute_unless_rate_limited(&1),
+            Ok((1, true, 0))
+        );
+        assert_eq!(cache.retrieve_or_compute(&2), (2, true, 0));
+        assert_eq!(version.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn limited_refreshes_keep_the_live_value() {
+        let clock = Arc::new(ManualClock::new());
+        let version = Arc::new(AtomicU32::new(0));
+        let cache = {
+            let version = version.clone();
+            Cache::builder(10)
+                .positive_ttl(Duration::from_secs(60))
+                .soft_ttl(Duration::from_millis(100))
+                .clock(clock.clone())
+                .miss_handler(move |_: &u32, data: &mut u32, _: &mut u8| {
+                    *data = version.fetch_add(1, Ordering::SeqCst) + 1;
+                    true
+                })
+                .max_loads_per_key_per_second(1)
+                .build()
+        };
+        assert_eq!(cache.retrieve_or_compute(&1), (1, true, 0));
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(cache.retrieve_or_compute(&1), (1, true, 0));
+        assert_eq!(
+            cache.retrieve_or_compute_unless_rate_limited(&1),
+            Ok((1, true, 0))
+        );
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.retrieve_or_compute(&1), (2, true, 0));
+    }
+
+    #[test]
+    fn buckets_refill_by_the_cache_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let loads = Arc::new(AtomicUsize::new(0));
+        let cache = {
+            let loads = loads.clone();
+            Cache::builder(10)
+                .negative_caching(false)
+                .clock(clock.clone())
+                .miss_handler(move |_: &u32, _: &mut u32, _: &mut u8| {
+                    loads.fetch_add(1, Ordering::SeqCst);
+                    false
+                })
+                .max_loads_per_second(1)
+                .build()
+        };
+        cache.retrieve_or_compute(&1);
+        assert_eq!(
+            cache.retrieve_or_compute_unless_rate_limited(&1),
+            Err(RateLimited)
+        );
+
+        clock.advance(Duration::from_millis(1001));
+        assert_eq!(
+            cache.retrieve_or_compute_unless_rate_limited(&1),
+            Ok((0, false, 0))
+        );
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,138 @@
+//! Telling orchestration when a freshly started cache is warm.
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::cache::Cache;
+use crate::clock::Clock;
+
+/// Warm-up progress of a cache, returned by [`Cache::readiness`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Readiness {
+    /// Fraction of the preload completed, from 0 to 1, or `None` if no
+    /// preload was reported.
+    pub preload_progress: Option<f64>,
+    /// Fraction of lookups served from the cache since it was created, or
+    /// `None` before the first lookup.
+    pub hit_rate: Option<f64>,
+    /// Number of lookups since the cache was created.
+    pub lookups: u64,
+    /// The hit rate the cache must reach to be ready, if any.
+    pub target_hit_rate: Option<f64>,
+    /// Whether the preload, if any, is complete and the hit rate target,
+    /// if any, is met over enough lookups.
+    pub ready: bool,
+}
+
+/// Counters behind [`Readiness`].
+#[derive(Debug)]
+pub(crate) struct ReadinessState {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    preload_done: AtomicUsize,
+    preload_total: AtomicUsize,
+    /// Target hit rate and the number of lookups it must hold over.
+    target: Option<(f64, u64)>,
+}
+
+impl ReadinessState {
+    pub(crate) fn new(target: Option<(f64, u64)>) -> Self {
+        ReadinessState {
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            preload_done: AtomicUsize::new(0),
+            preload_total: AtomicUsize::new(0),
+            target,
+        }
+    }
+
+    pub(crate) fn record_lookup(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Hits and misses so far.
+    pub(crate) fn lookups(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Reports that `done` out of `total` entries have been preloaded, for
+    /// [`readiness`](Self::readiness). Call it as the preload advances.
+    pub fn report_preload(&self, done: usize, total: usize) {
+        let state = &self.readiness;
+        state.preload_total.store(total, Ordering::Relaxed);
+        state.preload_done.store(done.min(total), Ordering::Relaxed);
+    }
+
+    /// Returns the warm-up progress of the cache, so that orchestration
+    /// can hold traffic back until it is warm.
+    ///
+    /// Lookups through [`get`](Self::get) and
+    /// [`retrieve_or_compute`](Self::retrieve_or_compute) count towards
+    /// the hit rate; a caller that waited for another's computation counts
+    /// as a hit.
+    pub fn readiness(&self) -> Readiness {
+        let state = &self.readiness;
+        let (hits, misses) = state.lookups();
+        let lookups = hits + misses;
+        let hit_rate = (lookups > 0).then(|| hits as f64 / lookups as f64);
+        let total = state.preload_total.load(Ordering::Relaxed);
+        let preload_progress =
+            (total > 0).then(|| state.preload_done.load(Ordering::Relaxed) as f64 / total as f64);
+
+        let preloaded = preload_progress.is_none_or(|progress| progress >= 1.0);
+        let warm = state.target.is_none_or(|(target, min_lookups)| {
+            lookups >= min_lookups && hit_rate.is_some_and(|rate| rate >= target)
+        });
+        Readiness {
+            preload_progress,
+            hit_rate,
+            lookups,
+            target_hit_rate: state.target.map(|(target, _)| target),
+            ready: preloaded && warm,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+
+    #[test]
+    fn ready_once_preloaded_and_hitting_the_target() {
+        let cache = Cache::builder(10)
+            .readiness_target(0.5, 4)
+            .miss_handler(|key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                true
+            })
+            .build();
+        cache.report_preload(1, 2);
+        assert!(!cache.readiness().ready);
+        assert_eq!(cache.readiness().preload_progress, Some(0.5));
+
+        cache.report_preload(2, 2);
+        cache.retrieve_or_compute(&1);
+        cache.retrieve_or_compute(&1);
+        cache.get(&2);
+        let readiness = cache.readiness();
+        assert!(!readiness.ready);
+        assert_eq!(readiness.lookups, 3);
+
+        cache.get(&1);
+        let readiness = cache.readiness();
+        assert_eq!(readiness.hit_rate, Some(0.5));
+        assert!(readiness.ready);
+    }
+}
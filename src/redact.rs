@@ -0,0 +1,228 @@
+//! Keeping keys out of logs.
+//!
+//! Keys often carry personal data such as emails or tokens. A cache built
+//! with a [`KeyRedactor`] renders keys through it wherever they are shown
+//! outside the application: in its `Debug` output, in the events of
+//! [`Cache::subscribe_redacted`] and in anything built on
+//! [`Cache::key_label`].
+
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+use crate::cache::Cache;
+use crate::clock::Clock;
+use crate::config::Capacity;
+use crate::lock::RwLockExt;
+
+/// Renders a key for display in place of its `Debug` form.
+pub type KeyRedactor<K> = dyn Fn(&K) -> String + Send + Sync;
+
+/// SipHash-2-4, a keyed hash: without the key, labels cannot be matched
+/// against the hashes of guessed keys. Written out rather than taken from
+/// std, whose hasher may change across Rust releases. Integers are hashed
+/// little-endian and `usize` as 64 bits, so that labels also match across
+/// platforms.
+#[derive(Clone)]
+struct Sip {
+    v: [u64; 4],
+    /// Bytes written since the last full word, in its low bytes.
+    tail: u64,
+    length: usize,
+}
+
+impl Sip {
+    fn new(secret: [u8; 16]) -> Self {
+        let (k0, k1) = secret.split_at(8);
+        let k0 = u64::from_le_bytes(k0.try_into().unwrap());
+        let k1 = u64::from_le_bytes(k1.try_into().unwrap());
+        Sip {
+            v: [
+                k0 ^ 0x736f_6d65_7073_6575,
+                k1 ^ 0x646f_7261_6e64_6f6d,
+                k0 ^ 0x6c79_6765_6e65_7261,
+                k1 ^ 0x7465_6462_7974_6573,
+            ],
+            tail: 0,
+            length: 0,
+        }
+    }
+
+    fn round(&mut self) {
+        let [v0, v1, v2, v3] = &mut self.v;
+        *v0 = v0.wrapping_add(*v1);
+        *v1 = v1.rotate_left(13) ^ *v0;
+        *v0 = v0.rotate_left(32);
+        *v2 = v2.wrapping_add(*v3);
+        *v3 = v3.rotate_left(16) ^ *v2;
+        *v0 = v0.wrapping_add(*v3);
+        *v3 = v3.rotate_left(21) ^ *v0;
+        *v2 = v2.wrapping_add(*v1);
+        *v1 = v1.rotate_left(17) ^ *v2;
+        *v2 = v2.rotate_left(32);
+    }
+
+    fn compress(&mut self, word: u64) {
+        self.v[3] ^= word;
+        self.round();
+        self.round();
+        self.v[0] ^= word;
+    }
+}
+
+impl Hasher for Sip {
+    fn finish(&self) -> u64 {
+        let mut sip = self.clone();
+        sip.compress(((self.length as u64) << 56) | self.tail);
+        sip.v[2] ^= 0xff;
+        for _ in 0..4 {
+            sip.round();
+        }
+        sip.v.iter().fold(0, |hash, v| hash ^ v)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.tail |= u64::from(byte) << (8 * (self.length % 8));
+            self.length += 1;
+            if self.length.is_multiple_of(8) {
+                self.compress(self.tail);
+                self.tail = 0;
+            }
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+}
+
+/// A redactor replacing every key with its hash keyed by `secret`, e.g.
+/// `key#9f1c...`.
+///
+/// Equal keys map to the same label wherever the secret is shared, so log
+/// lines can still be correlated across processes, but the key itself
+/// cannot be read back, nor found by hashing likely keys. Draw the secret
+/// once per deployment and keep it with the other credentials. Use it with
+/// [`CacheBuilder::key_redactor`](crate::CacheBuilder::key_redactor).
+pub fn hashed_key<K: Hash>(secret: [u8; 16]) -> impl Fn(&K) -> String + Send + Sync + 'static {
+    move |key: &K| {
+        let mut hasher = Sip::new(secret);
+        key.hash(&mut hasher);
+        format!("key#{:016x}", hasher.finish())
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C> {
+    /// Renders `key` for logs, metrics labels and other output leaving the
+    /// application: through the key redactor if one is configured, as its
+    /// `Debug` form otherwise.
+    pub fn key_label(&self, key: &K) -> String
+    where
+        K: fmt::Debug,
+    {
+        match &self.key_redactor {
+            Some(key_redactor) => key_redactor(key),
+            None => format!("{key:?}"),
+        }
+    }
+}
+
+/// Writes a label as is, without the quotes `Debug` adds to strings.
+struct Label(String);
+
+impl fmt::Debug for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<K, D, S, C> fmt::Debug for Cache<K, D, S, C>
+where
+    K: fmt::Debug + Hash + Eq,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Lists the keys held in memory, most recently used first, rendered
+    /// with [`key_label`](Cache::key_label). Values are never shown.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cache = self.lru_cache.read_or_recover();
+        let keys: Vec<Label> = cache
+            .iter()
+            .map(|(key, _)| Label(self.key_label(key)))
+            .collect();
+        f.debug_struct("Cache")
+            .field("capacity", &Capacity::of(&cache))
+            .field("positive_ttl", &self.positive_ttl.get())
+            .field("negative_ttl", &self.negative_ttl.get())
+            .field("keys", &keys)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const SECRET: [u8; 16] = *b"0123456789abcdef";
+
+    fn cache(redact: bool) -> Cache<String, u32> {
+        let mut builder = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .miss_handler(|_: &String, _: &mut u32, _: &mut u8| true);
+        if redact {
+            builder = builder.key_redactor(hashed_key(SECRET));
+        }
+        let cache = builder.build();
+        cache.insert("ada@example.com".to_string(), 1);
+        cache
+    }
+
+    #[test]
+    fn debug_output_redacts_keys() {
+        let plain = format!("{:?}", cache(false));
+        assert!(plain.contains("\"ada@example.com\""));
+
+        let redacted = format!("{:?}", cache(true));
+        assert!(!redacted.contains("ada"));
+        assert!(redacted.contains("keys: [key#"));
+    }
+
+    #[test]
+    fn hashed_labels_are_stable_per_secret() {
+        let cache = cache(true);
+        let key = "ada@example.com".to_string();
+        let label = hashed_key(SECRET);
+        assert_eq!(cache.key_label(&key), label(&key));
+        assert_ne!(label(&key), label(&"bob@example.com".to_string()));
+        assert_ne!(label(&key), hashed_key([0; 16])(&key));
+    }
+
+    #[test]
+    fn sip_matches_the_reference_vectors() {
+        let secret: [u8; 16] = std::array::from_fn(|i| i as u8);
+        let hash = |len: u8| {
+            let mut sip = Sip::new(secret);
+            sip.write(&(0..len).collect::<Vec<u8>>());
+            sip.finish()
+        };
+        assert_eq!(hash(0), 0x726f_db47_dd0e_0e31);
+        assert_eq!(hash(15), 0xa129_ca61_49be_45e5);
+    }
+}
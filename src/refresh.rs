@@ -0,0 +1,254 @@
+//! Proactive recomputation of entries.
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use crate::cache::{Cache, EntryStatus};
+use crate::clock::Clock;
+use crate::lock::{MutexExt, RwLockExt};
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Recomputes `key` with the miss handler whether or not it is cached,
+    /// and stores the outcome.
+    ///
+    /// Readers keep getting the previous value until the new one is ready.
+    /// A key that is missing, expired or being computed is retrieved as by
+    /// [`retrieve_or_compute`](Self::retrieve_or_compute), joining the
+    /// computation in flight if any, and a key another caller is already
+    /// refreshing returns the cached value. With the loader disabled, the
+    /// cached value is returned too.
+    pub fn refresh(&self, key: &K) -> (D, bool, u8) {
+        if !self.loader_enabled() {
+            return self.retrieve_or_compute(key);
+        }
+        let claimed = {
+            let now = self.now();
+            let mut cache = self.lru_cache.write_or_recover();
+            match cache.peek_mut(key) {
+                Some(entry)
+                    if entry.status != EntryStatus::Calculating
+                        && !entry.refreshing
+                        && self.is_live(entry, now) =>
+                {
+                    entry.refreshing = true;
+                    Some(entry.seq)
+                }
+                _ => None,
+            }
+        };
+        match claimed {
+            Some(started) => self.compute(key, started),
+            None => self.retrieve_or_compute(key),
+        }
+    }
+
+    /// Refreshes every key in `keys`, running at most `concurrency` miss
+    /// handlers at a time, and returns the outcome for each key in order.
+    ///
+    /// Meant for batch jobs that rebuild known-critical entries ahead of
+    /// traffic. A `concurrency` of zero is treated as one.
+    pub fn refresh_many(&self, keys: &[K], concurrency: usize) -> Vec<(D, bool, u8)>
+    where
+        K: Send + Sync,
+        D: Send + Sync,
+        S: Send + Sync,
+    {
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(vec![None; keys.len()]);
+        thread::scope(|scope| {
+            for _ in 0..concurrency.clamp(1, keys.len().max(1)) {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(key) = keys.get(i) else {
+                        break;
+                    };
+                    let outcome = self.refresh(key);
+                    results.lock_or_recover()[i] = Some(outcome);
+                });
+            }
+        });
+        results
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+            .into_iter()
+            .map(|outcome| outcome.expect("every key is refreshed"))
+            .collect()
+    }
+
+    /// Refreshes the computed entries that expire within `lead` and were
+    /// read within the last `recent`, returning how many were refreshed.
+    ///
+    /// Called periodically, e.g. from an async task, this keeps hot keys
+    /// from ever missing while letting cold ones expire. Entries that were
+    /// inserted rather than computed are left alone, as are all entries
+    /// while the loader is disabled. See also
+    /// [`spawn_refresher`](Self::spawn_refresher).
+    pub fn refresh_due(&self, recent: Duration, lead: Duration) -> usize {
+        if !self.loader_enabled() {
+            return 0;
+        }
+        let now = self.now();
+        let due: Vec<K> = {
+            let cache = self.lru_cache.read_or_recover();
+            cache
+                .iter()
+                .filter(|(_, entry)| {
+                    entry.status == EntryStatus::Ready
+                        && !entry.refreshing
+                        && entry.computed
+                        && entry.expiration <= now + lead
+                        && now.saturating_duration_since(entry.accessed) <= recent
+                        && self.is_live(entry, now)
+                })
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
+        for key in &due {
+            self.refresh(key);
+        }
+        due.len()
+    }
+
+    /// Starts a thread calling [`refresh_due`](Self::refresh_due) every
+    /// half `lead`, until the cache is dropped.
+    pub fn spawn_refresher(self: &Arc<Self>, recent: Duration, lead: Duration)
+    where
+        K: Send + Sync + 'static,
+        D: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+        C: 'static,
+    {
+        let cache = Arc::downgrade(self);
+        let interval = (lead / 2).max(Duration::from_millis(1));
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match cache.upgrade() {
+                Some(cache) => {
+                    cache.refresh_due(recent, lead);
+                }
+                None => break,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, ManualClock};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn only_recently_read_keys_are_refreshed() {
+        let clock = Arc::new(ManualClock::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(10))
+            .clock(clock.clone())
+            .miss_handler(move |_: &u32, _: &mut u32, _: &mut u8| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                true
+            })
+            .build();
+        cache.retrieve_or_compute(&1);
+        cache.retrieve_or_compute(&2);
+        cache.insert(3, 3);
+
+        clock.advance(Duration::from_secs(5));
+        cache.get(&1);
+        cache.get(&3);
+        clock.advance(Duration::from_secs(4));
+        let (recent, lead) = (Duration::from_secs(5), Duration::from_secs(2));
+        assert_eq!(cache.refresh_due(recent, lead), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(cache.time_to_live(&1), Some(Duration::from_secs(10)));
+        assert_eq!(cache.time_to_live(&2), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn refreshes_join_computations_in_flight() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let cache = Arc::new(Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            move |_: &u32, data: &mut usize, _: &mut u8| {
+                *data = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                thread::sleep(Duration::from_millis(100));
+                true
+            },
+        ));
+        let loading = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.retrieve_or_compute(&1))
+        };
+        thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(cache.refresh(&1), (1, true, 0));
+        assert_eq!(loading.join().unwrap(), (1, true, 0));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.refresh(&1), (2, true, 0));
+        assert_eq!(cache.get(&1), Some(2));
+    }
+
+    #[test]
+    fn the_refresher_keeps_hot_keys_cached() {
+        let cache = Arc::new(Cache::new(
+            10,
+            Duration::from_millis(100),
+            Duration::from_millis(100),
+            |key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                true
+            },
+        ));
+        cache.retrieve_or_compute(&1);
+        cache.spawn_refresher(Duration::from_secs(1), Duration::from_millis(60));
+        for _ in 0..30 {
+            assert_eq!(cache.get(&1), Some(1));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn refresh_many_bounds_concurrency() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (handler_running, handler_peak) = (running.clone(), peak.clone());
+        let cache = Cache::new(
+            100,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            move |key: &u32, data: &mut u32, _: &mut u8| {
+                let now = handler_running.fetch_add(1, Ordering::SeqCst) + 1;
+                handler_peak.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(5));
+                handler_running.fetch_sub(1, Ordering::SeqCst);
+                *data = key + 1;
+                *key != 3
+            },
+        );
+        cache.insert(1, 0);
+
+        let keys: Vec<u32> = (0..12).collect();
+        let outcomes = cache.refresh_many(&keys, 3);
+        assert_eq!(outcomes.len(), 12);
+        assert_eq!(outcomes[1], (2, true, 0));
+        assert_eq!(outcomes[3], (4, false, 0));
+        assert_eq!(cache.get(&1), Some(2));
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+    }
+}
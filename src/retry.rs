@@ -0,0 +1,185 @@
+//! Backing off from keys whose computation keeps failing.
+//!
+//! Without a retry policy, a failing key is recomputed every negative TTL
+//! for as long as it is looked up. With one, each consecutive failure
+//! doubles the time until the next attempt, so a struggling backend sees
+//! less and less of that key's traffic until it recovers.
+
+use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
+
+use lru::LruCache;
+
+use crate::cache::{Cache, CacheEntry};
+use crate::clock::Clock;
+use crate::early::sample;
+use crate::time::Instant;
+
+/// How long a key given up on by [`RetryPolicy::max_attempts`] stays
+/// failed: in practice, until it is evicted, replaced or invalidated.
+const GIVEN_UP: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// Exponential backoff for failed keys; see
+/// [`CacheBuilder::retry_policy`](crate::CacheBuilder::retry_policy).
+///
+/// The first failure of a key is cached for the negative TTL, and every
+/// consecutive one for twice as long as the previous one, up to
+/// `max_backoff`. A success resets the count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Longest time a failure is cached for. Never shorter than the
+    /// negative TTL.
+    pub max_backoff: Duration,
+    /// Consecutive failures after which the key is no longer retried: the
+    /// failure stays cached until the entry is evicted, replaced or
+    /// invalidated. `None` retries forever.
+    pub max_attempts: Option<u32>,
+    /// Fraction of each backoff taken off at random, from 0.0 to 1.0, so
+    /// that keys which failed together are not retried together.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    /// Backs off up to 5 minutes with 10% jitter, retrying forever.
+    fn default() -> Self {
+        RetryPolicy {
+            max_backoff: Duration::from_secs(5 * 60),
+            max_attempts: None,
+            jitter: 0.1,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub(crate) fn is_valid(&self) -> bool {
+        (0.0..=1.0).contains(&self.jitter) && self.max_attempts != Some(0)
+    }
+
+    /// Time until the next attempt after `failures` consecutive failures,
+    /// `None` once the key is given up on.
+    fn backoff(&self, negative_ttl: Duration, failures: u32) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| failures >= max) {
+            return None;
+        }
+        let doublings = 2u32.saturating_pow(failures.saturating_sub(1));
+        let backoff = negative_ttl
+            .saturating_mul(doublings)
+            .min(self.max_backoff.max(negative_ttl));
+        Some(backoff.mul_f64(1.0 - self.jitter * sample()))
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Counts `entry`, the failure of the computation that replaced the
+    /// entry with write sequence number `started`, and reschedules it
+    /// according to the retry policy, if any.
+    pub(crate) fn back_off(
+        &self,
+        cache: &LruCache<K, CacheEntry<D>, S>,
+        key: &K,
+        started: u64,
+        entry: &mut CacheEntry<D>,
+        now: Instant,
+    ) {
+        let previous = cache
+            .peek(key)
+            .filter(|placeholder| placeholder.seq == started)
+            .map_or(0, |placeholder| placeholder.failures);
+        entry.failures = previous.saturating_add(1);
+        let Some(policy) = &self.retry_policy else {
+            return;
+        };
+        entry.expiration = match policy.backoff(self.negative_ttl(), entry.failures) {
+            Some(backoff) => now + backoff,
+            None => now.checked_add(GIVEN_UP).unwrap_or(entry.expiration),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConfigError, ManualClock, ManualClockCache};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn cache(
+        clock: Arc<ManualClock>,
+        policy: RetryPolicy,
+        failing: Arc<AtomicBool>,
+        calls: Arc<AtomicUsize>,
+    ) -> ManualClockCache<u32, u32> {
+        Cache::builder(10)
+            .negative_ttl(Duration::from_secs(1))
+            .clock(clock)
+            .retry_policy(policy)
+            .miss_handler(move |_: &u32, _: &mut u32, _: &mut u8| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                !failing.load(Ordering::SeqCst)
+            })
+            .build()
+    }
+
+    #[test]
+    fn consecutive_failures_back_off_exponentially() {
+        let clock = Arc::new(ManualClock::new());
+        let failing = Arc::new(AtomicBool::new(true));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let policy = RetryPolicy {
+            max_backoff: Duration::from_secs(4),
+            max_attempts: None,
+            jitter: 0.0,
+        };
+        let cache = cache(clock.clone(), policy, failing.clone(), calls.clone());
+
+        // Attempts at 0s, 1s, 3s, 7s and 11s: backoffs of 1, 2, 4 and 4.
+        for _ in 0..12 {
+            cache.retrieve_or_compute(&1);
+            clock.advance(Duration::from_secs(1));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+
+        failing.store(false, Ordering::SeqCst);
+        clock.advance(Duration::from_secs(4));
+        assert!(cache.retrieve_or_compute(&1).1);
+        cache.remove(&1);
+        failing.store(true, Ordering::SeqCst);
+        cache.retrieve_or_compute(&1);
+        clock.advance(Duration::from_secs(1));
+        cache.retrieve_or_compute(&1);
+        assert_eq!(calls.load(Ordering::SeqCst), 8);
+    }
+
+    #[test]
+    fn keys_are_given_up_on_after_max_attempts() {
+        let clock = Arc::new(ManualClock::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let policy = RetryPolicy {
+            max_attempts: Some(2),
+            ..RetryPolicy::default()
+        };
+        let failing = Arc::new(AtomicBool::new(true));
+        let cache = cache(clock.clone(), policy, failing, calls.clone());
+
+        for _ in 0..10 {
+            cache.retrieve_or_compute(&1);
+            clock.advance(Duration::from_secs(3600));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let invalid = Cache::<u32, u32>::builder(1)
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| true)
+            .retry_policy(RetryPolicy {
+                jitter: 2.0,
+                ..RetryPolicy::default()
+            })
+            .try_build();
+        assert_eq!(invalid.err(), Some(ConfigError::InvalidRetryPolicy));
+    }
+}
@@ -0,0 +1,126 @@
+//! Request-scoped views with repeatable reads.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Mutex;
+
+use lru::DefaultHasher;
+
+use crate::cache::Cache;
+use crate::clock::{Clock, SystemClock};
+use crate::lock::MutexExt;
+
+/// Outcome of the first read of each key, `None` for a miss of
+/// [`RequestCache::get`].
+type Reads<K, D> = Mutex<HashMap<K, Option<(D, bool, u8)>>>;
+
+/// A view of a cache for the duration of one request; returned by
+/// [`Cache::request_scope`].
+///
+/// The first read of a key goes to the shared cache and its outcome,
+/// including a miss, is remembered: later reads of the key through the
+/// scope return the same result even if the shared cache is refreshed or
+/// invalidated underneath. Dropping the scope releases what it remembered.
+pub struct RequestCache<'a, K, D, S = DefaultHasher, C = SystemClock> {
+    cache: &'a Cache<K, D, S, C>,
+    reads: Reads<K, D>,
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Opens a scope with repeatable reads over this cache, e.g. for one
+    /// HTTP request.
+    pub fn request_scope(&self) -> RequestCache<'_, K, D, S, C> {
+        RequestCache {
+            cache: self,
+            reads: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K, D, S, C> RequestCache<'_, K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Returns the value of `key` as first read in this scope. See
+    /// [`Cache::get`].
+    pub fn get(&self, key: &K) -> Option<D> {
+        let mut reads = self.reads.lock_or_recover();
+        let read = reads
+            .entry(key.clone())
+            .or_insert_with(|| self.cache.get(key).map(|data| (data, true, 0)));
+        read.as_ref()
+            .filter(|(_, success, _)| *success)
+            .map(|(data, _, _)| data.clone())
+    }
+
+    /// Returns the value of `key` as first read in this scope, computing it
+    /// on a miss. See [`Cache::retrieve_or_compute`].
+    ///
+    /// A key that [`get`](Self::get) found missing is computed, and the
+    /// computed value is what the scope reads from then on.
+    pub fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        if let Some(Some(read)) = self.reads.lock_or_recover().get(key) {
+            return read.clone();
+        }
+        let read = self.cache.retrieve_or_compute(key);
+        let mut reads = self.reads.lock_or_recover();
+        let first = reads.entry(key.clone()).or_insert(None);
+        first.get_or_insert(read).clone()
+    }
+
+    /// The keys read so far in this scope, in no particular order.
+    pub fn keys_read(&self) -> Vec<K> {
+        self.reads.lock_or_recover().keys().cloned().collect()
+    }
+
+    /// The shared cache.
+    pub fn cache(&self) -> &Cache<K, D, S, C> {
+        self.cache
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+    use std::time::Duration;
+
+    #[test]
+    fn reads_are_repeatable_within_a_scope() {
+        let cache = Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                true
+            },
+        );
+        cache.insert(1, 10);
+
+        let scope = cache.request_scope();
+        assert_eq!(scope.get(&1), Some(10));
+        assert_eq!(scope.get(&2), None);
+        cache.insert(1, 11);
+        cache.insert(2, 20);
+        assert_eq!(scope.get(&1), Some(10));
+        assert_eq!(scope.retrieve_or_compute(&1), (10, true, 0));
+        assert_eq!(scope.get(&2), None);
+        assert_eq!(scope.retrieve_or_compute(&2), (20, true, 0));
+        assert_eq!(scope.get(&2), Some(20));
+
+        let mut keys = scope.keys_read();
+        keys.sort();
+        assert_eq!(keys, vec![1, 2]);
+        drop(scope);
+        assert_eq!(cache.request_scope().get(&1), Some(11));
+    }
+}
@@ -0,0 +1,590 @@
+//! A cache shared by processes through a memory-mapped segment, behind the
+//! `shm` feature.
+//!
+//! Processes cannot share heap pointers, so the segment is a fixed table of
+//! fixed-size slots: a 64-byte header (`RCSH`, the format version, the
+//! number of slots, the value capacity and a use counter) followed by
+//! `slots` records of
+//!
+//! ```text
+//! hash u64 | expires_at u64 (ms since the Unix epoch) | last_used u64
+//! | value_len u32 | key_len u16 | status u8 | adhoc_code u8 | crc32 u32
+//! | 0u32 | key [MAX_KEY_LEN] | value [value capacity]
+//! ```
+//!
+//! all little-endian, the CRC-32 covering every field but `last_used` and
+//! itself, and the key and value. Slots whose lengths are out of range or
+//! whose checksum does not match, e.g. after a bad writer in another
+//! process, are dropped and reported as corrupted. A key may only live in the [`WAYS`] slots of the set
+//! its hash picks, and a full set gives up its least recently used slot.
+//! Every access holds an exclusive `flock` on the segment file, which the
+//! kernel releases if its holder dies; slots are marked empty while being
+//! rewritten, so a holder dying mid-write loses that slot alone.
+
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use memmap2::MmapMut;
+
+use crate::checksum::{crc32, Corrupted, CorruptionListener};
+use crate::lock::MutexExt;
+use crate::persistent::BytesMissHandler;
+
+const MAGIC: &[u8; 4] = b"RCSH";
+const VERSION: u32 = 2;
+const HEADER: usize = 64;
+const SLOT_HEADER: usize = 40;
+const MAX_KEY_LEN: usize = 256;
+const WAYS: usize = 8;
+
+const EMPTY: u8 = 0;
+const FAILED: u8 = 1;
+const READY: u8 = 2;
+
+/// Milliseconds since the Unix epoch.
+fn wall_clock_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// FNV-1a, which unlike the hashers of `std` is the same in every process.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// Holds the `flock` of the segment file until dropped.
+struct FileLock<'a>(&'a File);
+
+impl<'a> FileLock<'a> {
+    fn acquire(file: &'a File) -> io::Result<Self> {
+        file.lock()?;
+        Ok(FileLock(file))
+    }
+}
+
+impl Drop for FileLock<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// The mapped slot table.
+struct Segment {
+    map: MmapMut,
+    slots: usize,
+    value_cap: usize,
+    slot_len: usize,
+}
+
+impl Segment {
+    fn slot(&self, index: usize) -> &[u8] {
+        let start = HEADER + index * self.slot_len;
+        &self.map[start..start + self.slot_len]
+    }
+
+    fn slot_mut(&mut self, index: usize) -> &mut [u8] {
+        let start = HEADER + index * self.slot_len;
+        &mut self.map[start..start + self.slot_len]
+    }
+
+    fn set(&self, hash: u64) -> Range<usize> {
+        let start = (hash % (self.slots / WAYS) as u64) as usize * WAYS;
+        start..start + WAYS
+    }
+
+    /// The key and value lengths of the slot at `index`, or `None` if they
+    /// are out of range or the slot does not match its checksum.
+    fn verify(&self, index: usize) -> Option<(usize, usize)> {
+        let slot = self.slot(index);
+        let key_len = usize::from(read_u16(slot, 28));
+        let value_len = read_u32(slot, 24) as usize;
+        if key_len > MAX_KEY_LEN || value_len > self.value_cap {
+            return None;
+        }
+        let expected = checksum(slot, slot[30], key_len, value_len);
+        (read_u32(slot, 32) == expected).then_some((key_len, value_len))
+    }
+
+    /// Finds the slot holding `key`. A slot of the same hash that fails
+    /// verification is marked empty and reported as `Corrupted`.
+    fn find(&mut self, hash: u64, key: &[u8]) -> Result<Option<usize>, Corrupted> {
+        for index in self.set(hash) {
+            let slot = self.slot(index);
+            if slot[30] == EMPTY || read_u64(slot, 0) != hash {
+                continue;
+            }
+            let Some((key_len, _)) = self.verify(index) else {
+                self.slot_mut(index)[30] = EMPTY;
+                return Err(Corrupted);
+            };
+            if &self.slot(index)[SLOT_HEADER..SLOT_HEADER + key_len] == key {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Bumps and returns the use counter of the segment.
+    fn tick(&mut self) -> u64 {
+        let tick = read_u64(&self.map, 16) + 1;
+        self.map[16..24].copy_from_slice(&tick.to_le_bytes());
+        tick
+    }
+
+    fn get(
+        &mut self,
+        hash: u64,
+        key: &[u8],
+        now: u64,
+    ) -> Result<Option<(Vec<u8>, bool, u8)>, Corrupted> {
+        let Some(index) = self.find(hash, key)? else {
+            return Ok(None);
+        };
+        if read_u64(self.slot(index), 8) <= now {
+            return Ok(None);
+        }
+        let tick = self.tick();
+        let slot = self.slot_mut(index);
+        slot[16..24].copy_from_slice(&tick.to_le_bytes());
+        if slot[30] == FAILED {
+            return Ok(Some((Vec::new(), false, slot[31])));
+        }
+        let value_start = SLOT_HEADER + MAX_KEY_LEN;
+        let value_len = read_u32(slot, 24) as usize;
+        Ok(Some((
+            slot[value_start..value_start + value_len].to_vec(),
+            true,
+            slot[31],
+        )))
+    }
+
+    /// Stores a record in the slot of `key`, else in a free or expired slot
+    /// of its set, else in the least recently used one.
+    fn put(
+        &mut self,
+        hash: u64,
+        key: &[u8],
+        value: &[u8],
+        status: u8,
+        adhoc_code: u8,
+        expires_at: u64,
+    ) {
+        let now = wall_clock_ms();
+        let index = self.find(hash, key).ok().flatten().unwrap_or_else(|| {
+            self.set(hash)
+                .min_by_key(|&index| {
+                    let slot = self.slot(index);
+                    if slot[30] == EMPTY || read_u64(slot, 8) <= now {
+                        0
+                    } else {
+                        read_u64(slot, 16)
+                    }
+                })
+                .unwrap()
+        });
+        let tick = self.tick();
+        let slot = self.slot_mut(index);
+        slot[30] = EMPTY;
+        slot[0..8].copy_from_slice(&hash.to_le_bytes());
+        slot[8..16].copy_from_slice(&expires_at.to_le_bytes());
+        slot[16..24].copy_from_slice(&tick.to_le_bytes());
+        slot[24..28].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        slot[28..30].copy_from_slice(&(key.len() as u16).to_le_bytes());
+        slot[31] = adhoc_code;
+        slot[SLOT_HEADER..SLOT_HEADER + key.len()].copy_from_slice(key);
+        let value_start = SLOT_HEADER + MAX_KEY_LEN;
+        slot[value_start..value_start + value.len()].copy_from_slice(value);
+        let crc = checksum(slot, status, key.len(), value.len());
+        slot[32..36].copy_from_slice(&crc.to_le_bytes());
+        slot[30] = status;
+    }
+}
+
+/// CRC-32 of a slot as it reads with `status`, over everything but the
+/// use counter, the checksum itself and the unused parts of the key and
+/// value.
+fn checksum(slot: &[u8], status: u8, key_len: usize, value_len: usize) -> u32 {
+    let value_start = SLOT_HEADER + MAX_KEY_LEN;
+    let mut covered = Vec::with_capacity(16 + 8 + key_len + value_len);
+    covered.extend_from_slice(&slot[..16]);
+    covered.extend_from_slice(&slot[24..30]);
+    covered.extend_from_slice(&[status, slot[31]]);
+    covered.extend_from_slice(&slot[SLOT_HEADER..SLOT_HEADER + key_len]);
+    covered.extend_from_slice(&slot[value_start..value_start + value_len]);
+    crc32(&covered)
+}
+
+/// A cache of byte strings in a memory-mapped segment shared by every
+/// process that opens the same file, so that pre-forked workers on a host
+/// keep one copy of the cache instead of one each.
+///
+/// The segment has a fixed number of slots, each holding a key of up to
+/// [`MAX_KEY_LEN`](Self::MAX_KEY_LEN) bytes and a value of up to the
+/// capacity it was created with; larger keys and values are computed but
+/// not cached. Placing the file on a memory file system, such as
+/// `/dev/shm` on Linux, keeps it off the disk.
+///
+/// Concurrent misses of a key each run the miss handler, in this process
+/// and in others. Entries expire by the wall clock, which all processes on
+/// the host share. A slot found corrupted on read is dropped, treated as a
+/// miss, counted in [`corruptions`](Self::corruptions) and reported to the
+/// listener set with [`on_corruption`](Self::on_corruption).
+pub struct SharedCache {
+    file: File,
+    segment: Mutex<Segment>,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    miss_handler: Box<BytesMissHandler>,
+    corruption_listener: Option<Box<CorruptionListener<[u8]>>>,
+    corruptions: AtomicU64,
+}
+
+impl SharedCache {
+    /// Longest key the segment stores; longer keys are computed every time.
+    pub const MAX_KEY_LEN: usize = MAX_KEY_LEN;
+
+    /// Number of slots a key may be stored in.
+    pub const WAYS: usize = WAYS;
+
+    /// Opens the segment at `path`, creating it with room for `slots`
+    /// entries of up to `max_value_len` bytes if it does not exist yet.
+    ///
+    /// `slots` is rounded up to a multiple of [`WAYS`](Self::WAYS). Fails
+    /// with [`InvalidData`](io::ErrorKind::InvalidData) if the file exists
+    /// with another layout or format.
+    pub fn open<F>(
+        path: impl AsRef<Path>,
+        slots: usize,
+        max_value_len: usize,
+        positive_ttl: Duration,
+        negative_ttl: Duration,
+        miss_handler: F,
+    ) -> io::Result<Self>
+    where
+        F: Fn(&[u8], &mut Vec<u8>, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        if slots == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a shared cache needs slots",
+            ));
+        }
+        let slots = slots.next_multiple_of(WAYS);
+        let slot_len = (SLOT_HEADER + MAX_KEY_LEN + max_value_len).next_multiple_of(8);
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let segment = {
+            let _lock = FileLock::acquire(&file)?;
+            if file.metadata()?.len() == 0 {
+                file.set_len((HEADER + slots * slot_len) as u64)?;
+            }
+            // SAFETY: every access to the mapping, from any process, holds
+            // the lock of the file.
+            let mut map = unsafe { MmapMut::map_mut(&file)? };
+            if map.len() < HEADER {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a shared cache segment",
+                ));
+            }
+            if map[..4] == [0; 4] {
+                map[..4].copy_from_slice(MAGIC);
+                map[4..8].copy_from_slice(&VERSION.to_le_bytes());
+                map[8..12].copy_from_slice(&(slots as u32).to_le_bytes());
+                map[12..16].copy_from_slice(&(max_value_len as u32).to_le_bytes());
+            }
+            let matches = &map[..4] == MAGIC
+                && read_u32(&map, 4) == VERSION
+                && read_u32(&map, 8) as usize == slots
+                && read_u32(&map, 12) as usize == max_value_len
+                && map.len() >= HEADER + slots * slot_len;
+            if !matches {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the segment has another layout or format",
+                ));
+            }
+            Segment {
+                map,
+                slots,
+                value_cap: max_value_len,
+                slot_len,
+            }
+        };
+        Ok(SharedCache {
+            file,
+            segment: Mutex::new(segment),
+            positive_ttl,
+            negative_ttl,
+            miss_handler: Box::new(miss_handler),
+            corruption_listener: None,
+            corruptions: AtomicU64::new(0),
+        })
+    }
+
+    /// Has `listener` called with the key of every slot found corrupted on
+    /// read.
+    pub fn on_corruption<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        self.corruption_listener = Some(Box::new(listener));
+        self
+    }
+
+    /// Number of slots dropped because they were found corrupted.
+    pub fn corruptions(&self) -> u64 {
+        self.corruptions.load(Ordering::Relaxed)
+    }
+
+    /// Returns the value of a successfully computed, unexpired entry.
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.lookup(key) {
+            Some((data, true, _)) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Returns `(data, success, adhoc_code)` for `key`, computing it with
+    /// the miss handler and caching the outcome if it is missing or
+    /// expired.
+    pub fn retrieve_or_compute(&self, key: &[u8]) -> (Vec<u8>, bool, u8) {
+        if let Some(found) = self.lookup(key) {
+            return found;
+        }
+        let mut data = Vec::new();
+        let mut adhoc_code = 0;
+        let success = (self.miss_handler)(key, &mut data, &mut adhoc_code);
+        let (status, ttl) = if success {
+            (READY, self.positive_ttl)
+        } else {
+            (FAILED, self.negative_ttl)
+        };
+        self.write(key, &data, status, adhoc_code, ttl);
+        (data, success, adhoc_code)
+    }
+
+    /// Caches `data` under `key` for the positive TTL, unless either is
+    /// larger than a slot holds.
+    pub fn insert(&self, key: &[u8], data: &[u8]) {
+        self.write(key, data, READY, 0, self.positive_ttl);
+    }
+
+    /// Drops `key`, returning its value if it was cached.
+    pub fn remove(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let hash = fnv1a(key);
+        let mut segment = self.segment.lock_or_recover();
+        let _lock = FileLock::acquire(&self.file).ok()?;
+        let removed = self.checked(key, segment.get(hash, key, wall_clock_ms()));
+        if let Ok(Some(index)) = segment.find(hash, key) {
+            segment.slot_mut(index)[30] = EMPTY;
+        }
+        removed.and_then(|(data, success, _)| success.then_some(data))
+    }
+
+    /// Number of unexpired entries in the segment, counted by walking
+    /// every slot.
+    pub fn len(&self) -> usize {
+        let segment = self.segment.lock_or_recover();
+        let Ok(_lock) = FileLock::acquire(&self.file) else {
+            return 0;
+        };
+        let now = wall_clock_ms();
+        (0..segment.slots)
+            .filter(|&index| {
+                let slot = segment.slot(index);
+                slot[30] != EMPTY && read_u64(slot, 8) > now
+            })
+            .count()
+    }
+
+    /// Returns `true` if the segment holds no unexpired entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of slots in the segment.
+    pub fn capacity(&self) -> usize {
+        self.segment.lock_or_recover().slots
+    }
+
+    fn lookup(&self, key: &[u8]) -> Option<(Vec<u8>, bool, u8)> {
+        if key.len() > MAX_KEY_LEN {
+            return None;
+        }
+        let hash = fnv1a(key);
+        let mut segment = self.segment.lock_or_recover();
+        let _lock = FileLock::acquire(&self.file).ok()?;
+        self.checked(key, segment.get(hash, key, wall_clock_ms()))
+    }
+
+    /// Counts and reports a slot of `key` found corrupted, as a miss.
+    fn checked<T>(&self, key: &[u8], found: Result<Option<T>, Corrupted>) -> Option<T> {
+        found.unwrap_or_else(|Corrupted| {
+            self.corruptions.fetch_add(1, Ordering::Relaxed);
+            if let Some(corruption_listener) = &self.corruption_listener {
+                corruption_listener(key);
+            }
+            None
+        })
+    }
+
+    fn write(&self, key: &[u8], data: &[u8], status: u8, adhoc_code: u8, ttl: Duration) {
+        let hash = fnv1a(key);
+        let expires_at = wall_clock_ms().saturating_add(ttl.as_millis() as u64);
+        let mut segment = self.segment.lock_or_recover();
+        if key.len() > MAX_KEY_LEN || data.len() > segment.value_cap {
+            return;
+        }
+        let Ok(_lock) = FileLock::acquire(&self.file) else {
+            return;
+        };
+        segment.put(hash, key, data, status, adhoc_code, expires_at);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn temp_path(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "rust-cache-{}-{}-{}.shm",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    fn open(path: &Path, slots: usize, loads: Arc<AtomicUsize>) -> SharedCache {
+        SharedCache::open(
+            path,
+            slots,
+            16,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            move |key: &[u8], data: &mut Vec<u8>, _: &mut u8| {
+                loads.fetch_add(1, Ordering::SeqCst);
+                data.extend_from_slice(key);
+                true
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn handles_on_one_segment_share_entries() {
+        let path = temp_path("share");
+        let loads = Arc::new(AtomicUsize::new(0));
+        // Each handle opens the file itself, as another process would.
+        let first = open(&path, 64, loads.clone());
+        let second = open(&path, 64, loads.clone());
+
+        assert_eq!(first.retrieve_or_compute(b"a"), (b"a".to_vec(), true, 0));
+        assert_eq!(second.retrieve_or_compute(b"a"), (b"a".to_vec(), true, 0));
+        second.insert(b"b", b"bee");
+        assert_eq!(first.get(b"b"), Some(b"bee".to_vec()));
+        assert_eq!(first.remove(b"b"), Some(b"bee".to_vec()));
+        assert_eq!(second.get(b"b"), None);
+        assert_eq!(second.len(), 1);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        let other_layout = SharedCache::open(
+            &path,
+            128,
+            16,
+            Duration::ZERO,
+            Duration::ZERO,
+            |_: &[u8], _: &mut Vec<u8>, _: &mut u8| true,
+        );
+        assert_eq!(
+            other_layout.err().map(|error| error.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn full_sets_give_up_their_least_recently_used_slot() {
+        let path = temp_path("evict");
+        let loads = Arc::new(AtomicUsize::new(0));
+        let cache = open(&path, 1, loads.clone());
+        assert_eq!(cache.capacity(), WAYS);
+
+        for key in 0..WAYS as u8 {
+            cache.insert(&[key], &[key]);
+        }
+        assert_eq!(cache.get(&[0]), Some(vec![0]));
+        cache.insert(&[100], &[100]);
+        assert_eq!(cache.len(), WAYS);
+        assert_eq!(cache.get(&[0]), Some(vec![0]));
+        assert_eq!(cache.get(&[1]), None);
+
+        let long = [7; 17];
+        assert_eq!(cache.retrieve_or_compute(&long).0, long);
+        assert_eq!(cache.get(&long), None);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_slots_are_dropped_and_reported() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let path = temp_path("corrupt");
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let cache = {
+            let reported = reported.clone();
+            open(&path, 1, Arc::new(AtomicUsize::new(0)))
+                .on_corruption(move |key| reported.lock().unwrap().push(key.to_vec()))
+        };
+        cache.insert(b"a", b"apple");
+        cache.insert(b"b", b"banana");
+        let slot_len = (SLOT_HEADER + MAX_KEY_LEN + 16).next_multiple_of(8);
+        let corrupt = |offset: usize, bytes: &[u8]| {
+            let mut file = File::options().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(offset as u64)).unwrap();
+            file.write_all(bytes).unwrap();
+        };
+        // An out-of-range value length in the first slot, a flipped value
+        // byte in the second.
+        corrupt(HEADER + 24, &u32::MAX.to_le_bytes());
+        corrupt(HEADER + slot_len + SLOT_HEADER + MAX_KEY_LEN, b"B");
+
+        assert_eq!(cache.get(b"a"), None);
+        assert_eq!(cache.get(b"b"), None);
+        assert_eq!(cache.corruptions(), 2);
+        assert_eq!(*reported.lock().unwrap(), [b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.retrieve_or_compute(b"a"), (b"a".to_vec(), true, 0));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+//! Remembering failed keys in constant memory.
+//!
+//! Caching a failure normally takes a full entry for the negative TTL,
+//! which lets high-cardinality miss traffic (scans, random IDs) flush
+//! useful entries out of the LRU. In sketch mode failures are recorded in a
+//! Bloom filter instead: memory stays constant, at the price of a small
+//! rate of keys wrongly reported as failing.
+
+use std::collections::hash_map::RandomState;
+use std::f64::consts::LN_2;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::lock::MutexExt;
+use crate::time::Instant;
+
+/// Bloom filter with two generations, rotated every negative TTL so that
+/// failures are forgotten after one to two TTLs.
+pub(crate) struct NegativeSketch {
+    state: Mutex<SketchState>,
+    hasher: RandomState,
+    bits: u64,
+    hashes: u32,
+}
+
+struct SketchState {
+    current: Vec<u64>,
+    previous: Vec<u64>,
+    rotated_at: Option<Instant>,
+}
+
+impl NegativeSketch {
+    /// Sizes the filter for `capacity` failed keys per generation at the
+    /// given false positive rate.
+    pub(crate) fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let capacity = capacity.max(1) as f64;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let bits = (-capacity * rate.ln() / (LN_2 * LN_2)).ceil().max(64.0) as u64;
+        let hashes = ((bits as f64 / capacity) * LN_2).round().max(1.0) as u32;
+        let words = bits.div_ceil(64) as usize;
+        NegativeSketch {
+            state: Mutex::new(SketchState {
+                current: vec![0; words],
+                previous: vec![0; words],
+                rotated_at: None,
+            }),
+            hasher: RandomState::new(),
+            bits,
+            hashes,
+        }
+    }
+
+    /// Records `key` as failed.
+    pub(crate) fn insert<K: Hash>(&self, key: &K, ttl: Duration, now: Instant) {
+        let mut state = self.state(ttl, now);
+        for bit in self.bits(key) {
+            state.current[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns `true` if `key` probably failed within the last one to two
+    /// negative TTLs.
+    pub(crate) fn contains<K: Hash>(&self, key: &K, ttl: Duration, now: Instant) -> bool {
+        let state = self.state(ttl, now);
+        let set = |words: &[u64], bit: u64| words[(bit / 64) as usize] & (1 << (bit % 64)) != 0;
+        let mut bits = self.bits(key);
+        bits.clone().all(|bit| set(&state.current, bit))
+            || bits.all(|bit| set(&state.previous, bit))
+    }
+
+    /// Locks the filter, first rotating generations if `ttl` has elapsed.
+    fn state(&self, ttl: Duration, now: Instant) -> MutexGuard<'_, SketchState> {
+        let mut state = self.state.lock_or_recover();
+        let rotated_at = *state.rotated_at.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(rotated_at);
+        if elapsed >= ttl {
+            let state = &mut *state;
+            if elapsed >= 2 * ttl {
+                state.previous.fill(0);
+            } else {
+                mem::swap(&mut state.previous, &mut state.current);
+            }
+            state.current.fill(0);
+            state.rotated_at = Some(now);
+        }
+        state
+    }
+
+    /// Bit positions of `key`, by double hashing.
+    fn bits<K: Hash>(&self, key: &K) -> impl Iterator<Item = u64> + Clone {
+        let hash = self.hasher.hash_one(key);
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let bits = self.bits;
+        (0..u64::from(self.hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn failures_are_remembered_without_entries() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let cache = Cache::builder(10)
+            .negative_ttl(Duration::from_millis(20))
+            .negative_sketch(1000, 0.01)
+            .miss_handler(move |key: &u32, data: &mut u32, _: &mut u8| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                *data = *key;
+                *key >= 100
+            })
+            .build();
+
+        for key in 0..100 {
+            assert!(!cache.retrieve_or_compute(&key).1);
+        }
+        assert_eq!(cache.retrieve_or_compute(&100), (100, true, 0));
+        assert_eq!(cache.len(), 1);
+        for key in 0..100 {
+            assert!(!cache.retrieve_or_compute(&key).1);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 101);
+
+        thread::sleep(Duration::from_millis(45));
+        assert!(!cache.retrieve_or_compute(&7).1);
+        assert_eq!(calls.load(Ordering::SeqCst), 102);
+    }
+}
@@ -0,0 +1,215 @@
+//! Saving the cache to a file and loading it back, behind the `snapshot`
+//! feature, so that a restarted process does not start cold.
+//!
+//! A snapshot holds the successfully computed, unexpired entries with the
+//! TTL they had left, and the wall-clock time it was taken at: loading it
+//! deducts the time since from every TTL, dropping entries that expired
+//! while the process was down. Files are written to a temporary file and
+//! renamed over the previous snapshot, so a crash mid-write never leaves a
+//! truncated one, and carry a CRC-32 checked on load.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hash};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::cache::{Cache, CacheEntry, EntryStatus};
+use crate::checksum::crc32;
+use crate::clock::Clock;
+use crate::events::CacheEvent;
+use crate::lock::RwLockExt;
+
+/// `(key, data, adhoc_code, ttl left)` of a saved entry.
+type SavedEntry<K, D> = (K, D, u8, Duration);
+
+/// Time since the Unix epoch, or zero for a clock set before it.
+fn wall_clock() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone + Serialize + DeserializeOwned,
+    D: Clone + Default + Serialize + DeserializeOwned,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Writes the successfully computed, unexpired entries to `path`,
+    /// atomically replacing any previous snapshot, and returns how many
+    /// were saved.
+    pub fn save_to(&self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let now = self.now();
+        let entries: Vec<SavedEntry<K, D>> = {
+            let cache = self.lru_cache.read_or_recover();
+            cache
+                .iter()
+                .filter(|(_, entry)| entry.status == EntryStatus::Ready && self.is_live(entry, now))
+                .map(|(key, entry)| {
+                    let ttl = entry.expiration.saturating_duration_since(now);
+                    (key.clone(), entry.data.clone(), entry.adhoc_code, ttl)
+                })
+                .collect()
+        };
+        let payload =
+            bincode::serde::encode_to_vec((wall_clock(), &entries), bincode::config::standard())
+                .map_err(|error| invalid(&error.to_string()))?;
+
+        let path = path.as_ref();
+        let mut tmp_path = OsString::from(path);
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&crc32(&payload).to_le_bytes())?;
+        tmp.write_all(&payload)?;
+        tmp.sync_all()?;
+        drop(tmp);
+        fs::rename(&tmp_path, path)?;
+        Ok(entries.len())
+    }
+
+    /// Loads the snapshot at `path`, as written by
+    /// [`save_to`](Self::save_to), and returns how many entries were
+    /// cached.
+    ///
+    /// Entries keep the TTL they had left minus the time since the
+    /// snapshot was taken; those that expired in the meantime are skipped,
+    /// as are keys the cache already holds. A damaged snapshot is reported
+    /// as [`InvalidData`](io::ErrorKind::InvalidData) and leaves the cache
+    /// unchanged.
+    pub fn load_from(&self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let bytes = fs::read(path)?;
+        let (checksum, payload) = bytes
+            .split_first_chunk::<4>()
+            .ok_or_else(|| invalid("truncated snapshot"))?;
+        if crc32(payload) != u32::from_le_bytes(*checksum) {
+            return Err(invalid("corrupted snapshot"));
+        }
+        let ((saved_at, entries), _): ((Duration, Vec<SavedEntry<K, D>>), _) =
+            bincode::serde::decode_from_slice(payload, bincode::config::standard())
+                .map_err(|error| invalid(&error.to_string()))?;
+        let elapsed = wall_clock().saturating_sub(saved_at);
+
+        let now = self.now();
+        let mut cache = self.lru_cache.write_or_recover();
+        let mut loaded = 0;
+        for (key, data, adhoc_code, ttl) in entries {
+            if ttl <= elapsed || cache.peek(&key).is_some() {
+                continue;
+            }
+            let expiration = now + (ttl - elapsed);
+            let entry = CacheEntry::new(data, EntryStatus::Ready, adhoc_code, expiration);
+            self.publish(|| CacheEvent::Insert(key.clone()));
+            if self.store(&mut cache, key, entry) != 0 {
+                loaded += 1;
+            }
+        }
+        drop(cache);
+        self.report_preload(loaded, loaded);
+        Ok(loaded)
+    }
+
+    /// Starts a thread saving the cache to `path` every `interval`, until
+    /// the cache is dropped. A failed save is retried at the next interval.
+    pub fn spawn_persister(self: &Arc<Self>, path: impl Into<PathBuf>, interval: Duration)
+    where
+        K: Send + Sync + 'static,
+        D: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+        C: 'static,
+    {
+        let cache = Arc::downgrade(self);
+        let path = path.into();
+        let interval = interval.max(Duration::from_millis(1));
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match cache.upgrade() {
+                Some(cache) => {
+                    let _ = cache.save_to(&path);
+                }
+                None => break,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_path(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "rust-cache-{}-{}-{}.snapshot",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    fn cache(
+        clock: Arc<ManualClock>,
+    ) -> Cache<u32, String, crate::DefaultHasher, Arc<ManualClock>> {
+        Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .clock(clock)
+            .miss_handler(|key: &u32, data: &mut String, _: &mut u8| {
+                *data = format!("computed-{key}");
+                *key != 0
+            })
+            .build()
+    }
+
+    #[test]
+    fn snapshots_restore_entries_with_their_ttl() {
+        let path = temp_path("restore");
+        let clock = Arc::new(ManualClock::new());
+        let saved = cache(clock.clone());
+        saved.retrieve_or_compute(&0);
+        saved.retrieve_or_compute(&1);
+        clock.advance(Duration::from_secs(50));
+        saved.insert(2, "two".to_string());
+        assert_eq!(saved.save_to(&path).unwrap(), 2);
+
+        let clock = Arc::new(ManualClock::new());
+        let loaded = cache(clock.clone());
+        loaded.insert(2, "newer".to_string());
+        assert_eq!(loaded.load_from(&path).unwrap(), 1);
+        assert_eq!(loaded.get(&1), Some("computed-1".to_string()));
+        assert_eq!(loaded.get(&2), Some("newer".to_string()));
+        let ttl = loaded.time_to_live(&1).unwrap();
+        assert!(ttl <= Duration::from_secs(10) && ttl > Duration::from_secs(8));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn damaged_snapshots_are_rejected() {
+        let path = temp_path("damaged");
+        let saved = cache(Arc::new(ManualClock::new()));
+        saved.insert(1, "one".to_string());
+        saved.save_to(&path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        let loaded = cache(Arc::new(ManualClock::new()));
+        let error = loaded.load_from(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(loaded.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+}
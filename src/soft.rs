@@ -0,0 +1,117 @@
+//! Soft TTLs ("stale-while-revalidate").
+//!
+//! With a soft TTL configured, values have two expirations: past the soft
+//! one they are stale but still served, and one caller is chosen to
+//! recompute them while the others keep getting the stale value; past the
+//! hard one, the positive TTL, they are misses like any expired value.
+
+use std::hash::{BuildHasher, Hash};
+
+use lru::LruCache;
+
+use crate::cache::{Cache, CacheEntry, EntryStatus};
+use crate::clock::Clock;
+use crate::lock::RwLockExt;
+use crate::time::Instant;
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Returns `true` if `entry` holds a value past its soft TTL.
+    pub(crate) fn is_soft_expired(&self, entry: &CacheEntry<D>, now: Instant) -> bool {
+        self.soft_ttl.is_some_and(|ttl| {
+            entry.status == EntryStatus::Ready && entry.holds == 0 && entry.created + ttl <= now
+        })
+    }
+
+    /// Decides whether the caller that just hit `entry` recomputes it for
+    /// being past its soft TTL, marking it as being refreshed if so.
+    pub(crate) fn refresh_soft_expired(&self, entry: &mut CacheEntry<D>, now: Instant) -> bool {
+        if entry.refreshing || !self.loader_enabled() || !self.is_soft_expired(entry, now) {
+            return false;
+        }
+        entry.refreshing = true;
+        true
+    }
+
+    /// Returns the value of the entry being refreshed with write sequence
+    /// number `started`, if it is still there, for callers not waiting on
+    /// the refresh.
+    pub(crate) fn refreshed_value(&self, key: &K, started: u64) -> Option<(D, bool, u8)> {
+        let cache = self.lru_cache.read_or_recover();
+        cache
+            .peek(key)
+            .filter(|entry| entry.seq == started && entry.status == EntryStatus::Ready)
+            .map(|entry| (entry.data.clone(), true, entry.adhoc_code))
+    }
+
+    /// Returns the stale value in place of a failed refresh of a value past
+    /// its soft TTL, leaving it cached until its hard TTL. It stays marked
+    /// as being refreshed, so that the failure is not retried on every hit.
+    pub(crate) fn keep_soft_expired(
+        &self,
+        cache: &LruCache<K, CacheEntry<D>, S>,
+        key: &K,
+        started: u64,
+        now: Instant,
+    ) -> Option<(D, bool, u8)> {
+        cache
+            .peek(key)
+            .filter(|entry| {
+                entry.seq == started
+                    && entry.refreshing
+                    && self.is_soft_expired(entry, now)
+                    && self.is_live(entry, now)
+            })
+            .map(|entry| (entry.data.clone(), true, entry.adhoc_code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, Freshness, ManualClock};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn stale_values_are_served_while_one_caller_refreshes_them() {
+        let clock = Arc::new(ManualClock::new());
+        let version = Arc::new(AtomicU32::new(1));
+        let cache = {
+            let version = version.clone();
+            Cache::builder(10)
+                .positive_ttl(Duration::from_secs(60))
+                .soft_ttl(Duration::from_secs(10))
+                .clock(clock.clone())
+                .miss_handler(move |_: &u32, data: &mut u32, _: &mut u8| {
+                    *data = version.load(Ordering::SeqCst);
+                    *data != 0
+                })
+                .build()
+        };
+
+        assert_eq!(cache.retrieve_or_compute(&1), (1, true, 0));
+        assert_eq!(cache.get_with_status(&1), Some((1, Freshness::Fresh)));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.get_with_status(&1), Some((1, Freshness::Stale)));
+
+        version.store(2, Ordering::SeqCst);
+        assert_eq!(cache.retrieve_or_compute(&1), (2, true, 0));
+        assert_eq!(cache.get_with_status(&1), Some((2, Freshness::Fresh)));
+
+        version.store(0, Ordering::SeqCst);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.retrieve_or_compute(&1), (2, true, 0));
+        assert_eq!(cache.retrieve_or_compute(&1), (2, true, 0));
+        assert_eq!(cache.get_with_status(&1), Some((2, Freshness::Stale)));
+
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.retrieve_or_compute(&1), (0, false, 0));
+    }
+}
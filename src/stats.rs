@@ -0,0 +1,219 @@
+//! Usage counters of a cache.
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::cache::Cache;
+use crate::clock::Clock;
+use crate::time::Instant;
+
+/// Counters describing how a cache has been used, as reported by
+/// [`Cache::stats`] and [`DynCache::stats`](crate::DynCache::stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CacheStats {
+    /// Lookups served from the cache.
+    pub hits: u64,
+    /// Lookups that found nothing to serve.
+    pub misses: u64,
+    /// Entries held.
+    pub len: usize,
+}
+
+impl CacheStats {
+    /// Fraction of lookups that were hits, or `None` before the first one.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+}
+
+/// Counters of one interval of the [`stats_window`](Cache::stats_window).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct IntervalStats {
+    /// When the interval started.
+    pub start: Instant,
+    /// Lookups served from the cache.
+    pub hits: u64,
+    /// Lookups that found nothing to serve.
+    pub misses: u64,
+    /// Entries evicted to make room.
+    pub evictions: u64,
+}
+
+impl IntervalStats {
+    /// Fraction of lookups that were hits, or `None` without lookups.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+}
+
+/// Counters of one interval, reused once the ring wraps around.
+#[derive(Default)]
+struct Slot {
+    /// Index of the interval counted, plus one; 0 while unused.
+    epoch: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// Ring buffer of counters for the last intervals.
+///
+/// A slot is reset by the first event of its new interval; events racing
+/// with the reset may be lost, which is fine for trends.
+pub(crate) struct StatsWindow {
+    interval: Duration,
+    started: Instant,
+    slots: Box<[Slot]>,
+}
+
+impl StatsWindow {
+    pub(crate) fn new(interval: Duration, intervals: usize, now: Instant) -> Self {
+        StatsWindow {
+            interval,
+            started: now,
+            slots: (0..intervals).map(|_| Slot::default()).collect(),
+        }
+    }
+
+    fn epoch(&self, now: Instant) -> u64 {
+        let elapsed = now.saturating_duration_since(self.started);
+        (elapsed.as_nanos() / self.interval.as_nanos()) as u64
+    }
+
+    /// The slot of the interval `now` falls in, reset if it still holds
+    /// an older one.
+    fn slot(&self, now: Instant) -> &Slot {
+        let epoch = self.epoch(now) + 1;
+        let slot = &self.slots[(epoch % self.slots.len() as u64) as usize];
+        let seen = slot.epoch.load(Ordering::Acquire);
+        if seen != epoch
+            && slot
+                .epoch
+                .compare_exchange(seen, epoch, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            slot.hits.store(0, Ordering::Relaxed);
+            slot.misses.store(0, Ordering::Relaxed);
+            slot.evictions.store(0, Ordering::Relaxed);
+        }
+        slot
+    }
+
+    pub(crate) fn record_lookup(&self, hit: bool, now: Instant) {
+        let slot = self.slot(now);
+        let counter = if hit { &slot.hits } else { &slot.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_eviction(&self, now: Instant) {
+        self.slot(now).evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters of the intervals still in the ring, oldest first.
+    fn snapshot(&self, now: Instant) -> Vec<IntervalStats> {
+        let last = self.epoch(now);
+        let first = (last + 1).saturating_sub(self.slots.len() as u64);
+        (first..=last)
+            .map(|epoch| {
+                let slot = &self.slots[((epoch + 1) % self.slots.len() as u64) as usize];
+                let counted = slot.epoch.load(Ordering::Acquire) == epoch + 1;
+                let count = |counter: &AtomicU64| {
+                    if counted {
+                        counter.load(Ordering::Relaxed)
+                    } else {
+                        0
+                    }
+                };
+                IntervalStats {
+                    start: self.started
+                        + self
+                            .interval
+                            .saturating_mul(u32::try_from(epoch).unwrap_or(u32::MAX)),
+                    hits: count(&slot.hits),
+                    misses: count(&slot.misses),
+                    evictions: count(&slot.evictions),
+                }
+            })
+            .collect()
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Counts the lookups since the cache was created, as
+    /// [`readiness`](Self::readiness) does, and the entries held.
+    pub fn stats(&self) -> CacheStats {
+        let (hits, misses) = self.readiness.lookups();
+        CacheStats {
+            hits,
+            misses,
+            len: self.len(),
+        }
+    }
+
+    /// Hits, misses and evictions of the last intervals, oldest first and
+    /// ending with the current, partial one, so that dashboards can chart
+    /// the hit rate over time. Empty unless enabled with
+    /// [`stats_window`](crate::CacheBuilder::stats_window).
+    pub fn stats_window(&self) -> Vec<IntervalStats> {
+        match &self.stats_window {
+            Some(window) => window.snapshot(self.now()),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, ManualClock};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn the_window_keeps_the_last_intervals() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(2)
+            .clock(clock.clone())
+            .positive_ttl(Duration::from_secs(3600))
+            .stats_window(Duration::from_secs(60), 3)
+            .miss_handler(|key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                true
+            })
+            .build();
+        for key in 0..3 {
+            cache.retrieve_or_compute(&key);
+        }
+        cache.get(&2);
+        clock.advance(Duration::from_secs(120));
+        cache.get(&2);
+        cache.get(&0);
+
+        let counts = |window: Vec<super::IntervalStats>| {
+            window
+                .iter()
+                .map(|interval| (interval.hits, interval.misses, interval.evictions))
+                .collect::<Vec<_>>()
+        };
+        let window = cache.stats_window();
+        assert_eq!(counts(window.clone()), [(1, 3, 1), (0, 0, 0), (1, 1, 0)]);
+        assert_eq!(window[0].hit_rate(), Some(0.25));
+        assert_eq!(window[2].start - window[0].start, Duration::from_secs(120));
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(
+            counts(cache.stats_window()),
+            [(0, 0, 0), (1, 1, 0), (0, 0, 0)]
+        );
+    }
+}
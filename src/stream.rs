@@ -0,0 +1,164 @@
+//! Caching the collected output of fallible async streams.
+//!
+//! A stream handle cannot be cached and replayed, so the helper here drains
+//! the stream on a miss and caches the resulting `Vec` under a single key,
+//! e.g. every page of a paginated API response.
+
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+
+use futures_util::{Stream, StreamExt};
+
+use crate::cache::{Cache, CacheEntry, EntryStatus};
+use crate::clock::Clock;
+use crate::lock::RwLockExt;
+
+/// What to do with the items collected before a stream failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartialFailure {
+    /// Cache nothing; the next call opens the stream again.
+    Discard,
+    /// Cache the partial items for the negative TTL, so a flaky upstream is
+    /// not hammered while it recovers.
+    CacheForNegativeTtl,
+}
+
+/// A stream that failed after yielding `items`.
+#[derive(Debug)]
+pub struct StreamFailure<T, E> {
+    /// Items yielded before the error.
+    pub items: Vec<T>,
+    /// The error that ended the stream.
+    pub error: E,
+}
+
+impl<T, E: fmt::Display> fmt::Display for StreamFailure<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "stream failed after {} items: {}",
+            self.items.len(),
+            self.error
+        )
+    }
+}
+
+impl<T: fmt::Debug, E: std::error::Error + 'static> std::error::Error for StreamFailure<T, E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl<K, T, H, C> Cache<K, Vec<T>, H, C>
+where
+    K: Hash + Eq + Clone,
+    T: Clone,
+    H: BuildHasher,
+    C: Clock,
+{
+    /// Returns the cached items for `key`, or opens the stream with `open`,
+    /// collects it and caches the items for the positive TTL.
+    ///
+    /// Unlike [`retrieve_or_compute`](Self::retrieve_or_compute), concurrent
+    /// misses are not coalesced: each caller collects its own stream rather
+    /// than blocking the executor while waiting for another task. The
+    /// store handler is not involved.
+    pub async fn retrieve_or_collect<F, Fut, S, E>(
+        &self,
+        key: &K,
+        open: F,
+        on_failure: PartialFailure,
+    ) -> Result<Vec<T>, StreamFailure<T, E>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = S>,
+        S: Stream<Item = Result<T, E>>,
+    {
+        if let Some(items) = self.get(key) {
+            return Ok(items);
+        }
+
+        let mut stream = std::pin::pin!(open().await);
+        let mut items = Vec::new();
+        let mut error = None;
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(item) => items.push(item),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        let ttl = match error {
+            None => self.positive_ttl(),
+            Some(_) if on_failure == PartialFailure::CacheForNegativeTtl => self.negative_ttl(),
+            Some(error) => return Err(StreamFailure { items, error }),
+        };
+        let entry = CacheEntry::new(
+            items.clone(),
+            EntryStatus::Ready,
+            0,
+            self.now() + self.jittered(ttl),
+        );
+        let mut cache = self.lru_cache.write_or_recover();
+        self.store(&mut cache, key.clone(), entry);
+        drop(cache);
+
+        match error {
+            None => Ok(items),
+            Some(error) => Err(StreamFailure { items, error }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::stream;
+    use std::time::Duration;
+
+    fn cache() -> Cache<u32, Vec<u32>> {
+        Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut Vec<u32>, _: &mut u8| false,
+        )
+    }
+
+    #[test]
+    fn collected_stream_is_cached() {
+        let cache = cache();
+        let pages = || async { stream::iter(vec![Ok::<_, String>(1), Ok(2), Ok(3)]) };
+
+        let items = block_on(cache.retrieve_or_collect(&1, pages, PartialFailure::Discard));
+        assert_eq!(items.unwrap(), vec![1, 2, 3]);
+        let again = block_on(cache.retrieve_or_collect(
+            &1,
+            || async { stream::iter(Vec::<Result<u32, String>>::new()) },
+            PartialFailure::Discard,
+        ));
+        assert_eq!(again.unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn partial_failures_follow_the_policy() {
+        let cache = cache();
+        let failing = || async { stream::iter(vec![Ok(1), Err("page 2"), Ok(3)]) };
+
+        let failure =
+            block_on(cache.retrieve_or_collect(&1, failing, PartialFailure::Discard)).unwrap_err();
+        assert_eq!((failure.items, failure.error), (vec![1], "page 2"));
+        assert_eq!(cache.get(&1), None);
+
+        let failure =
+            block_on(cache.retrieve_or_collect(&1, failing, PartialFailure::CacheForNegativeTtl))
+                .unwrap_err();
+        assert_eq!(failure.items, vec![1]);
+        assert_eq!(cache.get(&1), Some(vec![1]));
+    }
+}
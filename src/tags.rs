@@ -0,0 +1,86 @@
+//! Tag-based invalidation.
+//!
+//! Every tag has an epoch. Tagged entries remember the epoch of each of
+//! their tags when they were inserted, and invalidating a tag just bumps its
+//! epoch: entries carrying an older epoch are treated as misses from then on
+//! and get dropped as they are encountered or evicted.
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+use crate::cache::{Cache, StoreError};
+use crate::clock::Clock;
+use crate::lock::RwLockExt;
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Inserts a value carrying `tags`, so that it can later be dropped
+    /// together with every other entry sharing one of them by
+    /// [`invalidate_tag`](Self::invalidate_tag).
+    ///
+    /// Tagged entries are not spilled to the second tier.
+    pub fn insert_tagged(&self, key: K, data: D, tags: &[&str]) -> Result<(), StoreError> {
+        let tags = {
+            let mut tag_epochs = self.tag_epochs.write_or_recover();
+            tags.iter()
+                .map(|&tag| match tag_epochs.get_key_value(tag) {
+                    Some((tag, &epoch)) => (tag.clone(), epoch),
+                    None => {
+                        let tag: Arc<str> = Arc::from(tag);
+                        tag_epochs.insert(tag.clone(), 0);
+                        (tag, 0)
+                    }
+                })
+                .collect()
+        };
+        self.insert_with_tags(key, data, Some(tags))
+    }
+
+    /// Invalidates every entry carrying `tag`, in O(1).
+    ///
+    /// The entries stop being served immediately; their memory is reclaimed
+    /// lazily, when they are next looked up or evicted.
+    pub fn invalidate_tag(&self, tag: &str) {
+        if let Some(epoch) = self.tag_epochs.write_or_recover().get_mut(tag) {
+            *epoch += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+    use std::time::Duration;
+
+    #[test]
+    fn invalidate_tag_drops_every_tagged_entry() {
+        let cache = Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |key: &u32, data: &mut u32, _: &mut u8| {
+                *data = key + 100;
+                true
+            },
+        );
+        cache.insert_tagged(1, 1, &["user:42", "org:7"]).unwrap();
+        cache.insert_tagged(2, 2, &["user:42"]).unwrap();
+        cache.insert_tagged(3, 3, &["org:7"]).unwrap();
+        cache.insert(4, 4);
+
+        cache.invalidate_tag("user:42");
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.retrieve_or_compute(&2), (102, true, 0));
+        assert_eq!(cache.get(&3), Some(3));
+        assert_eq!(cache.get(&4), Some(4));
+
+        cache.insert_tagged(1, 11, &["user:42"]).unwrap();
+        assert_eq!(cache.get(&1), Some(11));
+        cache.invalidate_tag("unknown");
+    }
+}
@@ -0,0 +1,35 @@
+//! Second-tier storage for entries evicted from the in-memory LRU.
+
+use crate::checksum::Corrupted;
+use crate::time::Instant;
+
+/// An entry handed to (or recovered from) a spill tier.
+///
+/// Only successfully computed entries are spilled, so the status is
+/// implicitly `Ready`.
+pub(crate) struct SpilledEntry<D> {
+    pub(crate) data: D,
+    pub(crate) adhoc_code: u8,
+    pub(crate) expiration: Instant,
+    pub(crate) version: u32,
+}
+
+/// Storage that receives entries evicted from the in-memory LRU.
+///
+/// `take` removes the entry from the tier: a hit is promoted back into
+/// memory, so keeping a second copy around would only waste space.
+pub(crate) trait SpillTier<K, D>: Send + Sync {
+    /// Stores `entry`, and may drop the entries that expired by `now` to
+    /// make room.
+    fn spill(&self, key: &K, entry: SpilledEntry<D>, now: Instant);
+    /// Removes and returns the entry for `key` unless it expired by `now`.
+    /// An entry that fails verification is removed and reported as
+    /// corrupted.
+    fn take(&self, key: &K, now: Instant) -> Result<Option<SpilledEntry<D>>, Corrupted>;
+    fn remove(&self, key: &K);
+    /// Keeps only the entries for which `f` returns `true`, dropping those
+    /// that cannot be read back.
+    fn retain(&self, f: &mut dyn FnMut(&K, &SpilledEntry<D>) -> bool);
+    fn clear(&self);
+    fn len(&self) -> usize;
+}
@@ -0,0 +1,264 @@
+//! Remote second tier (Redis, memcached, ...) behind the in-process LRU.
+
+use std::error::Error;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::builder::CacheBuilder;
+use crate::cache::Cache;
+use crate::config::ConfigError;
+use crate::load;
+use crate::ops::{CacheOps, DynCache};
+use crate::stats::CacheStats;
+
+/// Error reported by a [`CacheBackend`].
+pub type BackendError = Box<dyn Error + Send + Sync>;
+
+/// A shared cache store used as the second tier of a [`TieredCache`].
+///
+/// Implementations typically wrap a Redis or memcached client. Backend
+/// errors never fail a lookup: the tiered cache treats them as misses and
+/// falls through to the loader.
+pub trait CacheBackend<K, D>: Send + Sync {
+    /// Fetches a value with the time it has left to live, returning `None`
+    /// if the backend does not have it.
+    fn get(&self, key: &K) -> Result<Option<(D, Duration)>, BackendError>;
+
+    /// Stores a value that the backend should expire after `ttl`.
+    fn put(&self, key: &K, data: &D, ttl: Duration) -> Result<(), BackendError>;
+
+    /// Deletes a value.
+    fn remove(&self, key: &K) -> Result<(), BackendError>;
+}
+
+/// An in-process LRU layered over a shared [`CacheBackend`].
+///
+/// Local misses are looked up in the backend first and only then computed
+/// by the loader. Values found in the backend are cached locally for the
+/// time they have left there, so that both tiers expire together.
+/// Successful loads are written through to the backend so that other
+/// processes can reuse them; failed loads are cached locally for the
+/// negative TTL and never written to the backend, so one process's failure
+/// does not poison its peers.
+pub struct TieredCache<K, D, B> {
+    local: Cache<K, D>,
+    backend: Arc<B>,
+    positive_ttl: Duration,
+}
+
+impl<K, D, B> TieredCache<K, D, B>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    B: CacheBackend<K, D> + 'static,
+{
+    /// Creates a tiered cache whose local tier is built by `builder`, with
+    /// `loader` as its miss handler behind the backend.
+    ///
+    /// The positive TTL of the builder also applies to the values written
+    /// to the backend. Fails if the builder's configuration is invalid; see
+    /// [`CacheBuilder::try_build`].
+    pub fn new<F>(builder: CacheBuilder<K, D>, backend: B, loader: F) -> Result<Self, ConfigError>
+    where
+        F: Fn(&K, &mut D, &mut u8) -> bool + Send + Sync + 'static,
+    {
+        let positive_ttl = builder.configured_positive_ttl();
+        let backend = Arc::new(backend);
+        let remote = backend.clone();
+        let local = builder
+            .miss_handler(move |key: &K, data: &mut D, adhoc_code: &mut u8| {
+                if let Ok(Some((found, ttl))) = remote.get(key) {
+                    *data = found;
+                    load::set_ttl(Some(ttl));
+                    return true;
+                }
+                let success = loader(key, data, adhoc_code);
+                if success {
+                    let _ = remote.put(key, data, positive_ttl);
+                }
+                success
+            })
+            .try_build()?;
+        Ok(TieredCache {
+            local,
+            backend,
+            positive_ttl,
+        })
+    }
+
+    /// Returns the value for `key` from the local tier, the backend or the
+    /// loader, in that order. See [`Cache::retrieve_or_compute`].
+    pub fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        self.local.retrieve_or_compute(key)
+    }
+
+    /// Returns a value held by the local tier.
+    pub fn get(&self, key: &K) -> Option<D> {
+        self.local.get(key)
+    }
+
+    /// Inserts a value into both tiers.
+    pub fn insert(&self, key: K, data: D) -> Result<(), BackendError> {
+        let result = self.backend.put(&key, &data, self.positive_ttl);
+        self.local.insert(key, data);
+        result
+    }
+
+    /// Removes a value from both tiers.
+    pub fn remove(&self, key: &K) -> Result<(), BackendError> {
+        self.local.remove(key);
+        self.backend.remove(key)
+    }
+
+    /// The in-process tier.
+    pub fn local(&self) -> &Cache<K, D> {
+        &self.local
+    }
+
+    /// The shared tier.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+/// Backend errors are ignored, as they are for lookups: a failed write
+/// leaves the value in the local tier only.
+impl<K, D, B> CacheOps<K, D> for TieredCache<K, D, B>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    B: CacheBackend<K, D> + 'static,
+{
+    fn get(&self, key: &K) -> Option<D> {
+        TieredCache::get(self, key)
+    }
+
+    fn insert(&self, key: K, data: D) {
+        let _ = TieredCache::insert(self, key, data);
+    }
+
+    fn retrieve_or_compute(&self, key: &K) -> (D, bool, u8) {
+        TieredCache::retrieve_or_compute(self, key)
+    }
+
+    /// Removes a value from both tiers, returning the local one.
+    fn remove(&self, key: &K) -> Option<D> {
+        let _ = self.backend.remove(key);
+        self.local.remove(key)
+    }
+
+    fn len(&self) -> usize {
+        self.local.len()
+    }
+}
+
+/// The backend has no bulk delete, so `invalidate_all` only drops the
+/// local tier; statistics are those of the local tier.
+impl<K, D, B> DynCache<K, D> for TieredCache<K, D, B>
+where
+    K: Hash + Eq + Clone + Send + Sync,
+    D: Clone + Default + Send + Sync,
+    B: CacheBackend<K, D> + 'static,
+{
+    fn invalidate(&self, key: &K) {
+        let _ = TieredCache::remove(self, key);
+    }
+
+    fn invalidate_all(&self) {
+        self.local.invalidate_all();
+    }
+
+    fn stats(&self) -> CacheStats {
+        self.local.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MapBackend(Mutex<HashMap<u32, (u32, Duration)>>);
+
+    impl CacheBackend<u32, u32> for MapBackend {
+        fn get(&self, key: &u32) -> Result<Option<(u32, Duration)>, BackendError> {
+            Ok(self.0.lock().unwrap().get(key).copied())
+        }
+
+        fn put(&self, key: &u32, data: &u32, ttl: Duration) -> Result<(), BackendError> {
+            self.0.lock().unwrap().insert(*key, (*data, ttl));
+            Ok(())
+        }
+
+        fn remove(&self, key: &u32) -> Result<(), BackendError> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    fn tiered(calls: Arc<AtomicUsize>) -> TieredCache<u32, u32, MapBackend> {
+        TieredCache::new(
+            Cache::builder(10)
+                .positive_ttl(Duration::from_secs(60))
+                .negative_ttl(Duration::from_secs(60)),
+            MapBackend::default(),
+            move |key: &u32, data: &mut u32, _: &mut u8| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                *data = key * 2;
+                *key != 0
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn loads_are_written_through() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = tiered(calls.clone());
+
+        assert_eq!(cache.retrieve_or_compute(&4), (8, true, 0));
+        assert_eq!(
+            cache.backend().get(&4).unwrap(),
+            Some((8, Duration::from_secs(60)))
+        );
+
+        cache.local().clear();
+        assert_eq!(cache.retrieve_or_compute(&4), (8, true, 0));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn backend_values_keep_their_remaining_ttl() {
+        let cache = tiered(Arc::new(AtomicUsize::new(0)));
+        let remaining = Duration::from_secs(5);
+        cache.backend().0.lock().unwrap().insert(3, (9, remaining));
+
+        assert_eq!(cache.retrieve_or_compute(&3), (9, true, 0));
+        assert!(cache.local().time_to_live(&3).unwrap() <= remaining);
+    }
+
+    #[test]
+    fn failures_stay_local() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = tiered(calls.clone());
+
+        assert!(!cache.retrieve_or_compute(&0).1);
+        assert!(!cache.retrieve_or_compute(&0).1);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.backend().get(&0).unwrap(), None);
+    }
+
+    #[test]
+    fn remove_clears_both_tiers() {
+        let cache = tiered(Arc::new(AtomicUsize::new(0)));
+        cache.insert(1, 10).unwrap();
+        cache.remove(&1).unwrap();
+
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.backend().get(&1).unwrap(), None);
+    }
+}
@@ -0,0 +1,14 @@
+//! The instant type used throughout the crate.
+//!
+//! `std::time::Instant::now` panics on `wasm32-unknown-unknown`; with the
+//! `wasm` feature, `web-time` provides an `Instant` backed by
+//! `performance.now()` there. On every other target `web-time` re-exports
+//! the standard type, so enabling the feature changes nothing.
+//!
+//! Write-behind flushing runs on a background thread and is not available
+//! on `wasm32-unknown-unknown`.
+
+#[cfg(not(feature = "wasm"))]
+pub use std::time::Instant;
+#[cfg(feature = "wasm")]
+pub use web_time::Instant;
@@ -0,0 +1,186 @@
+//! Bounding the wait on computations running in other threads.
+
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
+
+use crate::cache::{Cache, Lookup};
+use crate::clock::Clock;
+use crate::load::{current_args, with_args, LoadArgs};
+use crate::time::Instant;
+
+/// Error returned when a key was still being computed by another thread
+/// when the caller's deadline passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("timed out waiting for an in-flight computation")
+    }
+}
+
+impl Error for Timeout {}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Like [`retrieve_or_compute`](Self::retrieve_or_compute), but gives
+    /// up with [`Timeout`] if another thread is still computing the key
+    /// after `timeout`.
+    ///
+    /// The timeout only bounds the wait: if this call ends up running the
+    /// miss handler itself, it runs it to completion. A timed out caller
+    /// leaves the computation in place for others to share.
+    pub fn retrieve_or_compute_timeout(
+        &self,
+        key: &K,
+        timeout: Duration,
+    ) -> Result<(D, bool, u8), Timeout> {
+        match self.lookup_or_claim(key, Some(Instant::now() + timeout))? {
+            Lookup::Found(found) => Ok(found),
+            Lookup::Panicked | Lookup::Cancelled | Lookup::Unavailable => {
+                Ok((D::default(), false, 0))
+            }
+            Lookup::Claimed(started) => Ok(self.compute(key, started)),
+        }
+    }
+
+    /// Like [`retrieve_or_compute_timeout`](Self::retrieve_or_compute_timeout)
+    /// with an absolute `deadline`, which is also handed to the miss
+    /// handler if it was set with
+    /// [`deadline_aware_miss_handler`](crate::CacheBuilder::deadline_aware_miss_handler),
+    /// so that it can budget its own backend calls.
+    ///
+    /// Deadlines nest: a miss handler looking up other keys with a later
+    /// deadline passes its own, earlier one on.
+    pub fn retrieve_or_compute_with_deadline(
+        &self,
+        key: &K,
+        deadline: Instant,
+    ) -> Result<(D, bool, u8), Timeout> {
+        let outer = current_args();
+        let deadline = outer.deadline.map_or(deadline, |outer| outer.min(deadline));
+        match self.lookup_or_claim(key, Some(deadline))? {
+            Lookup::Found(found) => Ok(found),
+            Lookup::Panicked | Lookup::Cancelled | Lookup::Unavailable => {
+                Ok((D::default(), false, 0))
+            }
+            Lookup::Claimed(started) => {
+                let args = LoadArgs {
+                    deadline: Some(deadline),
+                    ..outer
+                };
+                Ok(with_args(args, || self.compute(key, started)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn slow_cache(calls: Arc<AtomicUsize>, max_wait: Option<Duration>) -> Arc<Cache<u32, u32>> {
+        let mut builder =
+            Cache::builder(10).miss_handler(move |key: &u32, data: &mut u32, _: &mut u8| {
+                if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    thread::sleep(Duration::from_millis(200));
+                }
+                *data = *key;
+                true
+            });
+        if let Some(max_wait) = max_wait {
+            builder = builder.max_wait(max_wait);
+        }
+        Arc::new(builder.build())
+    }
+
+    #[test]
+    fn waiting_callers_time_out() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = slow_cache(calls.clone(), None);
+        let computing = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.retrieve_or_compute(&1))
+        };
+        thread::sleep(Duration::from_millis(20));
+
+        let waited = Instant::now();
+        assert_eq!(
+            cache.retrieve_or_compute_timeout(&1, Duration::from_millis(30)),
+            Err(Timeout)
+        );
+        assert!(waited.elapsed() < Duration::from_millis(150));
+        assert_eq!(computing.join().unwrap(), (1, true, 0));
+        assert_eq!(
+            cache.retrieve_or_compute_timeout(&1, Duration::from_millis(30)),
+            Ok((1, true, 0))
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn max_wait_takes_over_stuck_computations() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = slow_cache(calls.clone(), Some(Duration::from_millis(30)));
+        let computing = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.retrieve_or_compute(&1))
+        };
+        thread::sleep(Duration::from_millis(20));
+
+        let waited = Instant::now();
+        assert_eq!(cache.retrieve_or_compute(&1), (1, true, 0));
+        assert!(waited.elapsed() < Duration::from_millis(150));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        computing.join().unwrap();
+    }
+
+    #[test]
+    fn deadlines_reach_the_miss_handler() {
+        let cache = Arc::new(
+            Cache::builder(10)
+                .deadline_aware_miss_handler(
+                    |key: &u32,
+                     data: &mut Option<Instant>,
+                     _: &mut u8,
+                     deadline: Option<Instant>| {
+                        if *key == 0 {
+                            thread::sleep(Duration::from_millis(200));
+                        }
+                        *data = deadline;
+                        true
+                    },
+                )
+                .build(),
+        );
+        let deadline = Instant::now() + Duration::from_secs(60);
+        assert_eq!(
+            cache.retrieve_or_compute_with_deadline(&1, deadline),
+            Ok((Some(deadline), true, 0))
+        );
+        assert_eq!(cache.retrieve_or_compute(&2), (None, true, 0));
+
+        let computing = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.retrieve_or_compute_with_deadline(&0, deadline))
+        };
+        thread::sleep(Duration::from_millis(20));
+        let soon = Instant::now() + Duration::from_millis(30);
+        assert_eq!(
+            cache.retrieve_or_compute_with_deadline(&0, soon),
+            Err(Timeout)
+        );
+        assert!(Instant::now() < soon + Duration::from_millis(120));
+        assert_eq!(computing.join().unwrap(), Ok((Some(deadline), true, 0)));
+    }
+}
@@ -0,0 +1,191 @@
+//! Tracing instrumentation, behind the `tracing` feature.
+//!
+//! Every run of the miss handler happens in a `rust_cache::load` span
+//! recording the key, the load time and the outcome, so slow loads show up
+//! in distributed traces under the request that triggered them. Evictions
+//! and expirations are `DEBUG` events with target `rust_cache`.
+//!
+//! Keys are recorded as rendered by the
+//! [`key_redactor`](crate::CacheBuilder::key_redactor), and left out
+//! without one, so that nothing sensitive reaches a collector by accident.
+//! A redactor of `|key| format!("{key:?}")` records their `Debug` form.
+//!
+//! Without the feature every hook here compiles to nothing.
+
+use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
+
+use crate::cache::Cache;
+use crate::clock::Clock;
+
+/// Span around one load, closed by [`LoadSpan::finish`].
+pub(crate) struct LoadSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::span::EnteredSpan,
+}
+
+impl LoadSpan {
+    /// Records the outcome of the load and closes the span.
+    pub(crate) fn finish(self, success: bool, load_time: Duration) {
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("success", success);
+            self.span
+                .record("load_time_us", load_time.as_micros() as u64);
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = (success, load_time);
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Opens the span of a load of `keys` keys, `key` being the first.
+    pub(crate) fn load_span(&self, key: &K, keys: usize) -> LoadSpan {
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::debug_span!(
+                target: "rust_cache",
+                "rust_cache::load",
+                key = tracing::field::Empty,
+                keys,
+                success = tracing::field::Empty,
+                load_time_us = tracing::field::Empty,
+            );
+            if let Some(key_redactor) = &self.key_redactor {
+                span.record("key", key_redactor(key));
+            }
+            LoadSpan {
+                span: span.entered(),
+            }
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = (key, keys);
+            LoadSpan {}
+        }
+    }
+
+    /// Reports that `key` was evicted to make room.
+    pub(crate) fn trace_eviction(&self, key: &K) {
+        #[cfg(feature = "tracing")]
+        {
+            let key = self
+                .key_redactor
+                .as_ref()
+                .map(|key_redactor| key_redactor(key));
+            tracing::debug!(target: "rust_cache", key = key.as_deref(), "entry evicted");
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = key;
+    }
+
+    /// Reports that the entry for `key` was found expired.
+    pub(crate) fn trace_expiration(&self, key: &K) {
+        #[cfg(feature = "tracing")]
+        {
+            let key = self
+                .key_redactor
+                .as_ref()
+                .map(|key_redactor| key_redactor(key));
+            tracing::debug!(target: "rust_cache", key = key.as_deref(), "entry expired");
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = key;
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::{Cache, ManualClock};
+    use std::fmt::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Renders the fields of one span or event as `name=value` pairs.
+    #[derive(Default)]
+    struct Line(String);
+
+    impl Visit for Line {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            let _ = write!(self.0, "{}={value:?} ", field.name());
+        }
+    }
+
+    /// Collects one line per span, span update and event.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Recorder {
+        fn push(&self, record: impl FnOnce(&mut Line)) {
+            let mut line = Line::default();
+            record(&mut line);
+            self.0.lock().unwrap().push(line.0);
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            self.push(|line| span.record(line));
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, values: &Record<'_>) {
+            self.push(|line| values.record(line));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            self.push(|line| event.record(line));
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn loads_evictions_and_expirations_are_traced() {
+        let recorder = Recorder::default();
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(1)
+            .positive_ttl(Duration::from_secs(60))
+            .clock(clock.clone())
+            .key_redactor(|key: &u32| format!("{key:?}"))
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| true)
+            .build();
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            cache.retrieve_or_compute(&1);
+            cache.retrieve_or_compute(&2);
+            clock.advance(Duration::from_secs(60));
+            cache.get(&2);
+        });
+
+        let lines = recorder.0.lock().unwrap().clone();
+        let has = |fields: &[&str]| {
+            lines
+                .iter()
+                .any(|line| fields.iter().all(|field| line.contains(field)))
+        };
+        assert!(has(&["keys=1"]), "{lines:?}");
+        assert!(has(&["key=\"1\""]), "{lines:?}");
+        assert!(has(&["success=true"]), "{lines:?}");
+        assert!(has(&["load_time_us="]), "{lines:?}");
+        assert!(has(&["entry evicted", "key=\"1\""]), "{lines:?}");
+        assert!(has(&["entry expired", "key=\"2\""]), "{lines:?}");
+    }
+}
@@ -0,0 +1,181 @@
+//! Adjusting TTLs of a live cache.
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::cache::{Cache, EntryStatus};
+use crate::clock::Clock;
+use crate::early::sample;
+use crate::lock::RwLockExt;
+
+/// A `Duration` that can be read and replaced concurrently, stored as
+/// nanoseconds. Durations beyond `u64::MAX` nanoseconds (some 584 years)
+/// saturate.
+#[derive(Debug)]
+pub(crate) struct AtomicDuration(AtomicU64);
+
+impl AtomicDuration {
+    pub(crate) fn new(duration: Duration) -> Self {
+        AtomicDuration(AtomicU64::new(Self::nanos(duration)))
+    }
+
+    pub(crate) fn get(&self) -> Duration {
+        Duration::from_nanos(self.0.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set(&self, duration: Duration) {
+        self.0.store(Self::nanos(duration), Ordering::Relaxed);
+    }
+
+    fn nanos(duration: Duration) -> u64 {
+        duration.as_nanos().try_into().unwrap_or(u64::MAX)
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// How long successfully computed entries stay valid.
+    pub fn positive_ttl(&self) -> Duration {
+        self.positive_ttl.get()
+    }
+
+    /// How long failed computations are remembered before being retried.
+    pub fn negative_ttl(&self) -> Duration {
+        self.negative_ttl.get()
+    }
+
+    /// Spreads `ttl` by the configured jitter, so that entries created
+    /// together do not all expire together; see
+    /// [`CacheBuilder::ttl_jitter`](crate::CacheBuilder::ttl_jitter).
+    pub(crate) fn jittered(&self, ttl: Duration) -> Duration {
+        match self.ttl_jitter {
+            Some(jitter) => ttl.mul_f64(1.0 + jitter * (2.0 * sample() - 1.0)),
+            None => ttl,
+        }
+    }
+
+    /// Changes the positive TTL, e.g. to lengthen it during an upstream
+    /// outage. Only entries stored from now on are affected.
+    pub fn set_positive_ttl(&self, ttl: Duration) {
+        self.positive_ttl.set(ttl);
+    }
+
+    /// Changes the negative TTL. Only failures cached from now on are
+    /// affected.
+    pub fn set_negative_ttl(&self, ttl: Duration) {
+        self.negative_ttl.set(ttl);
+    }
+
+    /// Time left before the entry for `key` expires, or `None` if there is
+    /// no live entry or it is still being computed.
+    pub fn time_to_live(&self, key: &K) -> Option<Duration> {
+        let now = self.now();
+        let cache = self.lru_cache.read_or_recover();
+        cache
+            .peek(key)
+            .filter(|entry| entry.status != EntryStatus::Calculating && self.is_live(entry, now))
+            .map(|entry| entry.expiration.saturating_duration_since(now))
+    }
+
+    /// Keeps the successfully computed entry for `key` warm: its expiration
+    /// is reset to now plus the positive TTL and it becomes the most
+    /// recently used entry, without recomputing it.
+    ///
+    /// Returns `false` if there is no such live entry.
+    pub fn touch(&self, key: &K) -> bool {
+        let now = self.now();
+        let positive_ttl = self.positive_ttl();
+        let mut cache = self.lru_cache.write_or_recover();
+        let seq = match cache.get_mut(key) {
+            Some(entry) if entry.status == EntryStatus::Ready && self.is_live(entry, now) => {
+                entry.expiration = now + positive_ttl;
+                entry.seq
+            }
+            _ => return false,
+        };
+        self.index_expiration(&cache, key.clone(), now + positive_ttl, seq);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, ManualClock};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn jitter_spreads_expirations() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(1000)
+            .positive_ttl(Duration::from_secs(100))
+            .ttl_jitter(0.1)
+            .clock(clock)
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| true)
+            .build();
+        for key in 0..1000 {
+            cache.insert(key, key);
+        }
+        let ttls: Vec<Duration> = (0..1000)
+            .filter_map(|key| cache.time_to_live(&key))
+            .collect();
+        assert_eq!(ttls.len(), 1000);
+        assert!(ttls
+            .iter()
+            .all(|ttl| ttl.as_secs_f64() >= 90.0 && ttl.as_secs_f64() <= 110.0));
+        assert!(ttls.iter().any(|ttl| ttl.as_secs() < 95));
+        assert!(ttls.iter().any(|ttl| ttl.as_secs() >= 105));
+    }
+
+    #[test]
+    fn new_ttls_apply_to_later_entries() {
+        let cache = Cache::new(
+            10,
+            Duration::from_millis(30),
+            Duration::from_millis(30),
+            |key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                *key != 0
+            },
+        );
+        cache.insert(1, 1);
+        cache.set_positive_ttl(Duration::from_secs(60));
+        cache.set_negative_ttl(Duration::from_secs(60));
+        cache.insert(2, 2);
+        cache.retrieve_or_compute(&0);
+        assert_eq!(cache.positive_ttl(), Duration::from_secs(60));
+
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some(2));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.retrieve_or_compute(&0), (0, false, 0));
+    }
+
+    #[test]
+    fn touch_extends_the_remaining_ttl() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Cache::builder(10)
+            .positive_ttl(Duration::from_secs(60))
+            .clock(clock.clone())
+            .miss_handler(|_: &u32, _: &mut u32, _: &mut u8| false)
+            .build();
+        cache.insert(1, 1);
+        cache.retrieve_or_compute(&2);
+        clock.advance(Duration::from_secs(45));
+
+        assert_eq!(cache.time_to_live(&1), Some(Duration::from_secs(15)));
+        assert!(cache.touch(&1));
+        assert_eq!(cache.time_to_live(&1), Some(Duration::from_secs(60)));
+        assert!(!cache.touch(&2));
+        assert!(!cache.touch(&3));
+        assert_eq!(cache.time_to_live(&3), None);
+    }
+}
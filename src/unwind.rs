@@ -0,0 +1,179 @@
+//! Containing panics of miss handlers.
+//!
+//! A panicking miss handler would otherwise leave its entry calculating
+//! forever, with every other caller of the key waiting on it.
+
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+use crate::cache::{Cache, CacheEntry, EntryStatus, Lookup};
+use crate::clock::Clock;
+use crate::events::CacheEvent;
+use crate::lock::RwLockExt;
+use crate::time::Instant;
+
+/// Error returned when the miss handler panicked while computing a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadPanicked;
+
+impl fmt::Display for LoadPanicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the miss handler panicked")
+    }
+}
+
+impl Error for LoadPanicked {}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Like [`retrieve_or_compute`](Self::retrieve_or_compute), but
+    /// returns [`LoadPanicked`] instead of propagating a panic of the miss
+    /// handler.
+    ///
+    /// The failure is cached for the negative TTL like any other, so
+    /// callers looking the key up in the meantime get the error too.
+    pub fn try_retrieve_or_compute(&self, key: &K) -> Result<(D, bool, u8), LoadPanicked> {
+        match self.lookup_or_take_over(key) {
+            Lookup::Found(found) => Ok(found),
+            Lookup::Panicked => Err(LoadPanicked),
+            Lookup::Cancelled | Lookup::Unavailable => Ok((D::default(), false, 0)),
+            Lookup::Claimed(started) => self.try_compute(key, started).map_err(|_| LoadPanicked),
+        }
+    }
+
+    /// Runs the miss handler once the load limit allows and stores the
+    /// outcome, catching a panic and caching it as a failure. Over the
+    /// load rate limit, the miss is served stale or fails instead.
+    pub(crate) fn try_compute(
+        &self,
+        key: &K,
+        started: u64,
+    ) -> Result<(D, bool, u8), Box<dyn Any + Send>> {
+        if !self.admits_load(key) {
+            return Ok(self
+                .shed_load(key, started)
+                .unwrap_or_else(|| (D::default(), false, 0)));
+        }
+        self.try_compute_admitted(key, started)
+    }
+
+    /// Like [`try_compute`](Self::try_compute), regardless of the load
+    /// rate limit.
+    pub(crate) fn try_compute_admitted(
+        &self,
+        key: &K,
+        started: u64,
+    ) -> Result<(D, bool, u8), Box<dyn Any + Send>> {
+        if let Some(debouncer) = &self.debouncer {
+            return self.compute_batched(debouncer, key, started);
+        }
+        let _permit = self.load_permit([key], true);
+        self.run_miss_handler(key, started)
+    }
+
+    /// Like [`try_compute`](Self::try_compute), regardless of the load
+    /// and rate limits.
+    pub(crate) fn run_miss_handler(
+        &self,
+        key: &K,
+        started: u64,
+    ) -> Result<(D, bool, u8), Box<dyn Any + Send>> {
+        let span = self.load_span(key, 1);
+        let load_start = Instant::now();
+        let outcome = self.load(key, started);
+        let load_time = load_start.elapsed();
+        self.load_latency.record(load_time);
+        span.finish(
+            outcome.as_ref().is_ok_and(|loaded| loaded.success),
+            load_time,
+        );
+        match outcome {
+            Ok(loaded) => Ok(self.complete(key, started, loaded, load_time)),
+            Err(payload) => {
+                self.complete_panicked(key, started);
+                Err(payload)
+            }
+        }
+    }
+
+    /// Caches the failure of a computation whose miss handler panicked,
+    /// unless the entry was written in the meantime.
+    pub(crate) fn complete_panicked(&self, key: &K, started: u64) {
+        let now = self.now();
+        let mut cache = self.lru_cache.write_or_recover();
+        let overwritten = cache
+            .peek(key)
+            .is_some_and(|entry| entry.seq != started && entry.status != EntryStatus::Calculating);
+        if overwritten {
+            return;
+        }
+        self.publish(|| CacheEvent::LoadFailed(key.clone()));
+        let mut entry = CacheEntry::new(
+            D::default(),
+            EntryStatus::Failed,
+            0,
+            now + self.jittered(self.negative_ttl()),
+        );
+        entry.panicked = true;
+        self.back_off(&cache, key, started, &mut entry, now);
+        self.store(&mut cache, key.clone(), entry);
+        self.trim_failed(&mut cache);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn panicking_cache(calls: Arc<AtomicUsize>) -> Arc<Cache<u32, u32>> {
+        Arc::new(Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            move |key: &u32, data: &mut u32, _: &mut u8| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(30));
+                assert_ne!(*key, 0, "cannot load key 0");
+                *data = *key;
+                true
+            },
+        ))
+    }
+
+    #[test]
+    fn panics_are_cached_as_failures() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = panicking_cache(calls.clone());
+        let computing = {
+            let cache = cache.clone();
+            thread::spawn(move || cache.retrieve_or_compute(&0))
+        };
+        thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(cache.try_retrieve_or_compute(&0), Err(LoadPanicked));
+        assert!(computing.join().is_err());
+        assert_eq!(cache.retrieve_or_compute(&0), (0, false, 0));
+        assert_eq!(cache.try_retrieve_or_compute(&1), Ok((1, true, 0)));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn try_retrieve_or_compute_catches_the_panic() {
+        let cache = panicking_cache(Arc::new(AtomicUsize::new(0)));
+
+        assert_eq!(cache.try_retrieve_or_compute(&0), Err(LoadPanicked));
+        assert_eq!(cache.get(&0), None);
+        assert_eq!(cache.len(), 1);
+    }
+}
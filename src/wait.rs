@@ -0,0 +1,140 @@
+//! Waiting for a key that another thread is computing.
+
+use std::hint;
+use std::thread;
+use std::time::Duration;
+
+use crate::time::Instant;
+
+/// First sleep of [`WaitStrategy::Backoff`] once it is done spinning and
+/// yielding; each further sleep doubles it.
+const MIN_SLEEP: Duration = Duration::from_micros(10);
+
+/// How callers wait for a key that another thread is computing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitStrategy {
+    /// Sleep for the same interval between checks.
+    Fixed(Duration),
+    /// Busy-spin for `spins` checks, then yield the thread for `yields`
+    /// checks, then sleep between checks with a delay doubling up to
+    /// `max_sleep`.
+    ///
+    /// Short computations are picked up within microseconds without
+    /// burning a core on long ones.
+    Backoff {
+        spins: u32,
+        yields: u32,
+        max_sleep: Duration,
+    },
+}
+
+impl Default for WaitStrategy {
+    /// Spins 8 times, yields 8 times, then sleeps at most 1ms at a time.
+    fn default() -> Self {
+        WaitStrategy::Backoff {
+            spins: 8,
+            yields: 8,
+            max_sleep: Duration::from_millis(1),
+        }
+    }
+}
+
+/// One pause of a [`Backoff`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pause {
+    Spin(u32),
+    Yield,
+    Sleep(Duration),
+}
+
+/// Wait state of one caller, escalating with every check.
+pub(crate) struct Backoff {
+    strategy: WaitStrategy,
+    step: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new(strategy: WaitStrategy) -> Self {
+        Backoff { strategy, step: 0 }
+    }
+
+    /// Pauses before the next check, sleeping no later than `deadline`.
+    pub(crate) fn wait(&mut self, deadline: Option<Instant>) {
+        match self.next_pause() {
+            Pause::Spin(spins) => (0..spins).for_each(|_| hint::spin_loop()),
+            Pause::Yield => thread::yield_now(),
+            Pause::Sleep(sleep) => {
+                let sleep = match deadline {
+                    Some(deadline) => sleep.min(deadline.saturating_duration_since(Instant::now())),
+                    None => sleep,
+                };
+                sleep_for(sleep);
+            }
+        }
+    }
+
+    fn next_pause(&mut self) -> Pause {
+        let step = self.step;
+        self.step = self.step.saturating_add(1);
+        match self.strategy {
+            WaitStrategy::Fixed(interval) => Pause::Sleep(interval),
+            WaitStrategy::Backoff { spins, .. } if step < spins => Pause::Spin(1 << step.min(6)),
+            WaitStrategy::Backoff { spins, yields, .. } if step - spins < yields => Pause::Yield,
+            WaitStrategy::Backoff {
+                spins,
+                yields,
+                max_sleep,
+            } => {
+                let doublings = (step - spins - yields).min(16);
+                Pause::Sleep((MIN_SLEEP * (1 << doublings)).min(max_sleep))
+            }
+        }
+    }
+}
+
+/// Browsers cannot block the main thread, and `thread::sleep` panics on
+/// `wasm32-unknown-unknown`, so waits there spin instead.
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+fn sleep_for(duration: Duration) {
+    thread::sleep(duration);
+}
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+fn sleep_for(duration: Duration) {
+    let until = Instant::now() + duration;
+    while Instant::now() < until {
+        hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_escalates_and_caps_sleeps() {
+        let mut backoff = Backoff::new(WaitStrategy::Backoff {
+            spins: 2,
+            yields: 1,
+            max_sleep: Duration::from_micros(50),
+        });
+        let pauses: Vec<Pause> = (0..8).map(|_| backoff.next_pause()).collect();
+        assert_eq!(
+            pauses,
+            [
+                Pause::Spin(1),
+                Pause::Spin(2),
+                Pause::Yield,
+                Pause::Sleep(Duration::from_micros(10)),
+                Pause::Sleep(Duration::from_micros(20)),
+                Pause::Sleep(Duration::from_micros(40)),
+                Pause::Sleep(Duration::from_micros(50)),
+                Pause::Sleep(Duration::from_micros(50)),
+            ]
+        );
+
+        let mut fixed = Backoff::new(WaitStrategy::Fixed(Duration::from_millis(10)));
+        assert_eq!(fixed.next_pause(), Pause::Sleep(Duration::from_millis(10)));
+        assert_eq!(fixed.next_pause(), Pause::Sleep(Duration::from_millis(10)));
+    }
+}
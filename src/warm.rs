@@ -0,0 +1,116 @@
+//! Filling a cache at startup, before it takes traffic.
+//!
+//! Both entry points report their progress to
+//! [`readiness`](Cache::readiness), so that orchestration can hold traffic
+//! back until the warm-up is done.
+
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::cache::Cache;
+use crate::clock::Clock;
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Inserts every pair of `entries`, e.g. read from a file or a dump of
+    /// another instance, and returns how many were cached.
+    ///
+    /// Pairs the store handler rejects are skipped.
+    pub fn warm_up(&self, entries: impl IntoIterator<Item = (K, D)>) -> usize {
+        let mut cached = 0;
+        for (key, data) in entries {
+            if self.try_insert(key, data).is_ok() {
+                cached += 1;
+            }
+        }
+        self.report_preload(cached, cached);
+        cached
+    }
+
+    /// Computes every key of `keys` that is not cached yet with the miss
+    /// handler, running at most `parallelism` at a time, and returns how
+    /// many are cached successfully.
+    ///
+    /// A `parallelism` of zero is treated as one.
+    pub fn warm_up_parallel(&self, keys: impl IntoIterator<Item = K>, parallelism: usize) -> usize
+    where
+        K: Send + Sync,
+        D: Send + Sync,
+        S: Send + Sync,
+    {
+        let keys: Vec<K> = keys.into_iter().collect();
+        let next = AtomicUsize::new(0);
+        let (done, succeeded) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        self.report_preload(0, keys.len());
+        thread::scope(|scope| {
+            for _ in 0..parallelism.clamp(1, keys.len().max(1)) {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(key) = keys.get(i) else {
+                        break;
+                    };
+                    if self.retrieve_or_compute(key).1 {
+                        succeeded.fetch_add(1, Ordering::Relaxed);
+                    }
+                    let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                    self.report_preload(done, keys.len());
+                });
+            }
+        });
+        succeeded.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Cache;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn warm_up_inserts_the_entries() {
+        let cache = Cache::new(
+            10,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            |_: &u32, _: &mut u32, _: &mut u8| unreachable!("warm entries are not loaded"),
+        );
+        assert_eq!(cache.warm_up((0..5).map(|key| (key, key * 2))), 5);
+        assert_eq!(cache.get(&3), Some(6));
+        assert_eq!(cache.readiness().preload_progress, Some(1.0));
+    }
+
+    #[test]
+    fn warm_up_parallel_loads_concurrently() {
+        let (running, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let cache = {
+            let (running, peak) = (running.clone(), peak.clone());
+            Cache::new(
+                100,
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+                move |key: &u32, data: &mut u32, _: &mut u8| {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    *data = *key;
+                    *key != 0
+                },
+            )
+        };
+        assert_eq!(cache.warm_up_parallel(0..12, 4), 11);
+        assert!(peak.load(Ordering::SeqCst) > 1);
+        assert!(peak.load(Ordering::SeqCst) <= 4);
+        assert_eq!(cache.get(&11), Some(11));
+        assert!(cache.readiness().ready);
+    }
+}
@@ -0,0 +1,151 @@
+//! Caching values without keeping them alive.
+
+use std::hash::Hash;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use crate::cache::Cache;
+use crate::config::Capacity;
+use crate::entry::Entry;
+
+/// A cache holding `Weak` references to values owned elsewhere, e.g. the
+/// objects of a pool: an entry lives only as long as some `Arc` to its
+/// value does, and is dropped once found dead.
+///
+/// There is no miss handler, as a freshly computed value would have no
+/// owner but the caller; use [`get_or_insert_with`](Self::get_or_insert_with)
+/// instead.
+pub struct WeakCache<K, D> {
+    cache: Cache<K, Weak<D>>,
+}
+
+impl<K, D> WeakCache<K, D>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Creates a cache holding at most `size` entries, each for at most
+    /// `ttl`.
+    pub fn new(size: impl Into<Capacity>, ttl: Duration) -> Self {
+        WeakCache {
+            cache: Cache::builder(size)
+                .positive_ttl(ttl)
+                .miss_handler(|_: &K, _: &mut Weak<D>, _: &mut u8| false)
+                .build(),
+        }
+    }
+
+    /// Returns the value of `key` if it is still alive.
+    pub fn get(&self, key: &K) -> Option<Arc<D>> {
+        match self.cache.get(key)?.upgrade() {
+            Some(data) => Some(data),
+            None => {
+                self.remove_dead(key);
+                None
+            }
+        }
+    }
+
+    /// Caches a reference to `data`, which stays cached until its last
+    /// `Arc` is dropped, it expires or it is evicted.
+    pub fn insert(&self, key: K, data: &Arc<D>) {
+        self.cache.insert(key, Arc::downgrade(data));
+    }
+
+    /// Returns the value of `key` if it is still alive, or the result of
+    /// `f`, caching a reference to it.
+    ///
+    /// The check and the insert are atomic, so concurrent callers all get
+    /// the same value. `f` runs under the cache lock and must not call back
+    /// into the cache.
+    pub fn get_or_insert_with(&self, key: K, f: impl FnOnce() -> D) -> Arc<D> {
+        match self.cache.entry(key) {
+            Entry::Occupied(mut entry) => {
+                if let Some(data) = entry.get().upgrade() {
+                    return data;
+                }
+                let data = Arc::new(f());
+                entry.insert(Arc::downgrade(&data));
+                entry.set_time_to_live(self.cache.positive_ttl());
+                data
+            }
+            Entry::Vacant(entry) => {
+                let data = Arc::new(f());
+                entry.insert(Arc::downgrade(&data));
+                data
+            }
+        }
+    }
+
+    /// Removes the entry for `key`, returning its value if it was alive.
+    pub fn remove(&self, key: &K) -> Option<Arc<D>> {
+        self.cache.remove(key)?.upgrade()
+    }
+
+    /// Number of entries, including dead ones that have not been dropped
+    /// yet.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Returns `true` if there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Drops the entries whose value is no longer alive, returning how many
+    /// were dropped.
+    pub fn purge_dead(&self) -> usize {
+        self.cache
+            .iter()
+            .filter(|(_, data)| data.strong_count() == 0)
+            .filter(|(key, _)| self.remove_dead(key))
+            .count()
+    }
+
+    /// Removes the entry for `key` if its value is dead, without racing a
+    /// concurrent insert of a live one.
+    fn remove_dead(&self, key: &K) -> bool {
+        match self.cache.entry(key.clone()) {
+            Entry::Occupied(entry) if entry.get().strong_count() == 0 => {
+                entry.remove();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_live_as_long_as_their_owners() {
+        let cache = WeakCache::new(10, Duration::from_secs(60));
+        let owned = Arc::new("conn-1".to_string());
+        cache.insert(1, &owned);
+        assert!(Arc::ptr_eq(&cache.get(&1).unwrap(), &owned));
+
+        drop(owned);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&1), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn dead_values_are_replaced_and_purged() {
+        let cache = WeakCache::new(10, Duration::from_secs(60));
+        let first = cache.get_or_insert_with(1, || 1);
+        let again = cache.get_or_insert_with(1, || 2);
+        assert!(Arc::ptr_eq(&first, &again));
+
+        drop((first, again));
+        assert_eq!(*cache.get_or_insert_with(1, || 3), 3);
+
+        let kept = cache.get_or_insert_with(2, || 4);
+        drop(cache.get_or_insert_with(3, || 5));
+        assert_eq!(cache.purge_dead(), 2);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.remove(&2), Some(kept));
+    }
+}
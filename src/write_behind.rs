@@ -0,0 +1,343 @@
+//! Asynchronous write-back of cache updates to the store handler.
+
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::cache::{Cache, StoreError, StoreHandler};
+use crate::clock::Clock;
+use crate::lock::MutexExt;
+use crate::time::Instant;
+
+/// Write-behind settings collected by the builder.
+///
+/// `spawn_flusher` is captured where the `Send + 'static` bounds needed by
+/// the flusher thread are known to hold.
+pub(crate) struct WriteBehindConfig<K, D> {
+    pub(crate) max_queue: usize,
+    pub(crate) flush_interval: Option<Duration>,
+    pub(crate) spawn_flusher: fn(&Arc<WriteBehind<K, D>>, Option<Duration>),
+}
+
+/// Wakes the flusher thread when the queue fills up or the cache is
+/// dropped.
+///
+/// Kept apart from [`WriteBehind`] so the flusher can wait on it without
+/// keeping the queue alive.
+#[derive(Default)]
+struct Wakeup {
+    woken: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Wakeup {
+    fn wake(&self) {
+        *self.woken.lock_or_recover() = true;
+        self.condvar.notify_one();
+    }
+
+    /// Waits until woken or until `timeout` elapses.
+    fn wait(&self, timeout: Option<Duration>) {
+        let mut woken = self.woken.lock_or_recover();
+        while !*woken {
+            woken = match timeout {
+                Some(timeout) => {
+                    let (woken, result) = self
+                        .condvar
+                        .wait_timeout(woken, timeout)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    if result.timed_out() {
+                        return;
+                    }
+                    woken
+                }
+                None => self
+                    .condvar
+                    .wait(woken)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+        *woken = false;
+    }
+}
+
+/// Queue of writes waiting to reach the store handler.
+///
+/// Shared between the cache and its flusher thread, which only holds a
+/// weak reference and stops once the cache is dropped. Whatever is still
+/// queued at that point is flushed by `Drop`.
+///
+/// Writers are serialized by `writing`, taken before the queue is drained
+/// and held until the batch is written, so updates reach the store in the
+/// order they were queued.
+pub(crate) struct WriteBehind<K, D> {
+    queue: Mutex<VecDeque<(K, D)>>,
+    writing: Mutex<()>,
+    wakeup: Arc<Wakeup>,
+    max_queue: usize,
+    store_handler: Box<StoreHandler<K, D>>,
+}
+
+impl<K, D> WriteBehind<K, D> {
+    pub(crate) fn new(store_handler: Box<StoreHandler<K, D>>, max_queue: usize) -> Self {
+        WriteBehind {
+            queue: Mutex::new(VecDeque::new()),
+            writing: Mutex::new(()),
+            wakeup: Arc::default(),
+            max_queue: max_queue.max(1),
+            store_handler,
+        }
+    }
+
+    /// Queues a write. Filling the queue hands it to the flusher thread;
+    /// the caller, which may hold the cache lock, never writes itself.
+    pub(crate) fn enqueue(&self, key: K, data: D) {
+        let mut queue = self.queue.lock_or_recover();
+        queue.push_back((key, data));
+        if queue.len() == self.max_queue {
+            self.wakeup.wake();
+        }
+    }
+
+    /// Writes everything queued so far, returning the writes that failed.
+    pub(crate) fn flush(&self) -> Vec<(K, StoreError)> {
+        let _writing = self.writing.lock_or_recover();
+        let batch = mem::take(&mut *self.queue.lock_or_recover());
+        self.write(batch)
+    }
+
+    /// Writes queued updates in order until `deadline`, putting back
+    /// whatever is left, and returns the outcome of each write.
+    pub(crate) fn flush_until(&self, deadline: Instant) -> Vec<(K, Result<(), StoreError>)> {
+        let _writing = self.writing.lock_or_recover();
+        let mut batch = mem::take(&mut *self.queue.lock_or_recover());
+        let mut results = Vec::new();
+        while Instant::now() < deadline {
+            let Some((key, data)) = batch.pop_front() else {
+                break;
+            };
+            let result = (self.store_handler)(&key, &data);
+            results.push((key, result));
+        }
+        if !batch.is_empty() {
+            let mut queue = self.queue.lock_or_recover();
+            batch.append(&mut queue);
+            *queue = batch;
+        }
+        results
+    }
+
+    pub(crate) fn pending(&self) -> usize {
+        self.queue.lock_or_recover().len()
+    }
+
+    fn write(&self, batch: VecDeque<(K, D)>) -> Vec<(K, StoreError)> {
+        batch
+            .into_iter()
+            .filter_map(|(key, data)| (self.store_handler)(&key, &data).err().map(|e| (key, e)))
+            .collect()
+    }
+}
+
+impl<K: PartialEq, D> WriteBehind<K, D> {
+    /// Writes the queued updates of `key` in order, or returns `None` if
+    /// there are none.
+    pub(crate) fn flush_key(&self, key: &K) -> Option<Result<(), StoreError>> {
+        let _writing = self.writing.lock_or_recover();
+        let batch: VecDeque<(K, D)> = {
+            let mut queue = self.queue.lock_or_recover();
+            let (batch, rest) = mem::take(&mut *queue)
+                .into_iter()
+                .partition(|(queued, _)| queued == key);
+            *queue = rest;
+            batch
+        };
+        if batch.is_empty() {
+            return None;
+        }
+        let mut failures = self.write(batch).into_iter();
+        Some(failures.next().map_or(Ok(()), |(_, error)| Err(error)))
+    }
+}
+
+impl<K, D> WriteBehind<K, D>
+where
+    K: Send + 'static,
+    D: Send + 'static,
+{
+    /// Starts the thread flushing the queue whenever it fills up, and
+    /// every `interval` if one is given.
+    pub(crate) fn spawn_flusher(this: &Arc<Self>, interval: Option<Duration>) {
+        let weak: Weak<Self> = Arc::downgrade(this);
+        let wakeup = this.wakeup.clone();
+        thread::spawn(move || loop {
+            wakeup.wait(interval);
+            match weak.upgrade() {
+                Some(write_behind) => {
+                    write_behind.flush();
+                }
+                None => break,
+            }
+        });
+    }
+}
+
+impl<K, D> Drop for WriteBehind<K, D> {
+    fn drop(&mut self) {
+        self.wakeup.wake();
+        let batch = mem::take(self.queue.get_mut().unwrap_or_else(PoisonError::into_inner));
+        for (key, data) in batch {
+            let _ = (self.store_handler)(&key, &data);
+        }
+    }
+}
+
+impl<K, D, S, C> Cache<K, D, S, C>
+where
+    K: Hash + Eq + Clone,
+    D: Clone + Default,
+    S: BuildHasher,
+    C: Clock,
+{
+    /// Writes every queued update to the store handler, returning the keys
+    /// whose write failed. Failed writes are not retried.
+    ///
+    /// Does nothing unless write-behind is enabled.
+    pub fn flush(&self) -> Vec<(K, StoreError)> {
+        self.write_behind
+            .as_ref()
+            .map_or_else(Vec::new, |write_behind| write_behind.flush())
+    }
+
+    /// Writes the queued updates of `key` now, for a critical write that
+    /// must be durable before the caller proceeds; the rest of the queue
+    /// stays asynchronous.
+    ///
+    /// Returns `None` if nothing is queued for `key`, including when
+    /// write-behind is disabled, or the first error of its writes.
+    pub fn flush_key(&self, key: &K) -> Option<Result<(), StoreError>> {
+        self.write_behind.as_ref()?.flush_key(key)
+    }
+
+    /// Writes queued updates until `timeout` elapses and returns the
+    /// outcome of each. Updates not written in time stay queued, in order.
+    ///
+    /// A single slow write can overrun the timeout, which is only checked
+    /// between writes. Failed writes are not retried.
+    pub fn flush_all(&self, timeout: Duration) -> Vec<(K, Result<(), StoreError>)> {
+        let deadline = Instant::now() + timeout;
+        self.write_behind
+            .as_ref()
+            .map_or_else(Vec::new, |write_behind| write_behind.flush_until(deadline))
+    }
+
+    /// Number of updates waiting to be written to the store handler.
+    pub fn pending_writes(&self) -> usize {
+        self.write_behind
+            .as_ref()
+            .map_or(0, |write_behind| write_behind.pending())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cache, StoreError};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    type Written = Arc<Mutex<Vec<(u32, u32)>>>;
+
+    fn cache(max_queue: usize, interval: Option<Duration>) -> (Cache<u32, u32>, Written) {
+        let written = Written::default();
+        let sink = written.clone();
+        let cache = Cache::builder(10)
+            .miss_handler(|key: &u32, data: &mut u32, _: &mut u8| {
+                *data = *key;
+                true
+            })
+            .store_handler(move |key: &u32, data: &u32| {
+                if *key == 0 {
+                    return Err(StoreError::from("rejected"));
+                }
+                sink.lock().unwrap().push((*key, *data));
+                Ok(())
+            })
+            .write_behind(max_queue, interval)
+            .build();
+        (cache, written)
+    }
+
+    #[test]
+    fn writes_are_queued_until_flushed() {
+        let (cache, written) = cache(10, None);
+        cache.insert(1, 10);
+        cache.retrieve_or_compute(&2);
+        cache.insert(0, 0);
+
+        assert_eq!(cache.get(&1), Some(10));
+        assert_eq!(cache.pending_writes(), 3);
+        assert!(written.lock().unwrap().is_empty());
+
+        let failed = cache.flush();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, 0);
+        assert_eq!(*written.lock().unwrap(), vec![(1, 10), (2, 2)]);
+        assert_eq!(cache.pending_writes(), 0);
+    }
+
+    #[test]
+    fn full_queue_is_handed_to_the_flusher() {
+        let (cache, written) = cache(2, None);
+        cache.insert(1, 1);
+        thread::sleep(Duration::from_millis(20));
+        assert!(written.lock().unwrap().is_empty());
+
+        cache.insert(2, 2);
+        for _ in 0..100 {
+            if cache.pending_writes() == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(*written.lock().unwrap(), vec![(1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn flusher_thread_and_drop_flush_the_queue() {
+        let (cache, written) = cache(100, Some(Duration::from_millis(10)));
+        cache.insert(1, 1);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(written.lock().unwrap().len(), 1);
+
+        cache.insert(2, 2);
+        drop(cache);
+        assert_eq!(written.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn flush_key_and_flush_all_report_each_write() {
+        let (cache, written) = cache(10, None);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.insert(1, 11);
+        cache.insert(0, 0);
+
+        assert!(cache.flush_key(&1).unwrap().is_ok());
+        assert!(cache.flush_key(&1).is_none());
+        assert_eq!(*written.lock().unwrap(), vec![(1, 1), (1, 11)]);
+        assert!(cache.flush_all(Duration::ZERO).is_empty());
+        assert_eq!(cache.pending_writes(), 2);
+
+        let results = cache.flush_all(Duration::from_secs(1));
+        let outcomes: Vec<(u32, bool)> = results
+            .iter()
+            .map(|(key, result)| (*key, result.is_ok()))
+            .collect();
+        assert_eq!(outcomes, vec![(2, true), (0, false)]);
+        assert_eq!(cache.pending_writes(), 0);
+    }
+}